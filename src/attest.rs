@@ -0,0 +1,209 @@
+//! Reports on what a device holds after flashing, for traceability
+//!
+//! With a key, reports carry an HMAC-SHA256 over the compact JSON of the
+//! `report` object. That is a MAC under a shared key, not a signature:
+//! it shows that the report was made by someone holding the key, and
+//! anyone who can check it could have made it. The key ID is a name the
+//! operator gives, nothing derived from the key.
+//!
+//! Boot chain manifests map `idbloader` and partition names to SHA-256
+//! hashes. The idbloader hash is over its stages as the boot area stores
+//! them, scrambled where the ID block has them scrambled, and without the
+//! header sectors. A manifest recorded from one device therefore matches
+//! boards written the same way, not the same loader put there by a tool
+//! that scrambles differently.
+
+use std::path::{Path, PathBuf};
+
+use hmac::{Hmac, Mac};
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+
+use crate::json::{self, Value};
+use crate::metrics::Failure;
+use crate::protocol::{self, SECTOR_SIZE};
+use crate::session::Session;
+use crate::{gpt, hexdump, idb, size};
+
+// Hash in pieces of 1 MiB to keep memory usage flat.
+const HASH_CHUNK_SECTORS: u64 = 2048;
+
+pub struct Options {
+    pub output: PathBuf,
+    pub operator: Option<String>,
+    pub key: Option<PathBuf>,
+    pub key_id: Option<String>,
+    /// Partitions to hash; all when empty
+    pub partitions: Vec<String>,
+}
+
+/// Read back a range of sectors and hash it
+pub fn hash_sectors(s: &Session, first: u64, count: u64) -> Result<[u8; 32], Failure> {
+    let mut h = Sha256::new();
+    let mut done = 0;
+    while done < count {
+        let n = (count - done).min(HASH_CHUNK_SECTORS);
+        h.update(&protocol::read_lba(s, (first + done) as u32, n as u32)?);
+        done += n;
+    }
+    Ok(h.finalize().into())
+}
+
+pub fn attest(s: &Session, chip: &crate::chip::Chip, opts: Options) -> Result<(), Failure> {
+    let chip_id = protocol::info(s)?;
+    let flash_id = protocol::flash_id(s)?;
+
+    let mut parts = Vec::new();
+    let mut disk_guid = None;
+    match gpt::read(&mut |lba, n| protocol::read_lba(s, lba as u32, n).map_err(Failure::from))? {
+        Ok(g) => {
+            disk_guid = Some(gpt::guid_to_string(&g.header.disk_guid));
+            for name in &opts.partitions {
+                if g.find(name).is_none() {
+                    return Err(format!("No partition {name}").into());
+                }
+            }
+            for p in g.partitions() {
+                let name = p.name();
+                if !opts.partitions.is_empty() && !opts.partitions.contains(&name) {
+                    continue;
+                }
+                let size = p.sectors() * SECTOR_SIZE as u64;
+                info!("Hash {name}, {}", size::human(size));
+                let digest = hash_sectors(s, p.first_lba, p.sectors())?;
+                parts.push(json::obj([
+                    ("name", name.into()),
+                    ("guid", gpt::guid_to_string(&p.unique_guid).into()),
+                    ("first_lba", p.first_lba.into()),
+                    ("size", size.into()),
+                    ("sha256", hexdump::hex(&digest).into()),
+                ]));
+            }
+        }
+        Err(e) if opts.partitions.is_empty() => warn!("No partitions hashed: {e}"),
+        Err(e) => return Err(e.into()),
+    }
+
+    let operator = opts
+        .operator
+        .or(std::env::var("USER").ok())
+        .unwrap_or_default();
+    let report = json::obj([
+        ("tool", "rk_boot".into()),
+        ("version", env!("CARGO_PKG_VERSION").into()),
+        ("time", jiff::Timestamp::now().to_string().into()),
+        ("operator", operator.into()),
+        ("chip", chip.name.into()),
+        ("chip_id", chip_id.into()),
+        ("flash_id", hexdump::hex(&flash_id).into()),
+        ("disk_guid", disk_guid.into()),
+        ("partitions", Value::Arr(parts)),
+    ]);
+
+    let doc = match opts.key {
+        Some(k) => {
+            let key = std::fs::read(&k).map_err(|e| format!("{}: {e}", k.display()))?;
+            let mut mac =
+                Hmac::<Sha256>::new_from_slice(&key).expect("HMAC takes keys of any size");
+            mac.update(report.to_string().as_bytes());
+            let mac = mac.finalize().into_bytes();
+            json::obj([
+                ("report", report),
+                (
+                    "mac",
+                    json::obj([
+                        ("algorithm", "HMAC-SHA256".into()),
+                        ("key_id", opts.key_id.into()),
+                        ("value", hexdump::hex(&mac).into()),
+                    ]),
+                ),
+            ])
+        }
+        None => json::obj([("report", report)]),
+    };
+    std::fs::write(&opts.output, format!("{doc}\n"))
+        .map_err(|e| format!("{}: {e}", opts.output.display()))?;
+    info!("Report written to {}", opts.output.display());
+    Ok(())
+}
+
+/// Parts of the boot chain that a manifest made with `--record` covers
+const BOOT_CHAIN: [&str; 3] = ["idbloader", "uboot", "trust"];
+
+/// Hash of the idbloader or a partition, None if the device has none
+fn boot_chain_hash(
+    s: &Session,
+    name: &str,
+    table: &Result<gpt::Gpt, String>,
+) -> Result<Option<String>, Failure> {
+    let read = &mut |lba, n| protocol::read_lba(s, lba, n).map_err(Failure::from);
+    if name == "idbloader" {
+        let Ok(stages) = idb::read_stages(read)? else {
+            return Ok(None);
+        };
+        let mut h = Sha256::new();
+        for (_, d) in &stages {
+            h.update(d);
+        }
+        return Ok(Some(hexdump::hex(&h.finalize())));
+    }
+    let Some(p) = table.as_ref().ok().and_then(|g| g.find(name)) else {
+        return Ok(None);
+    };
+    info!(
+        "Hash {name}, {}",
+        size::human(p.sectors() * SECTOR_SIZE as u64)
+    );
+    Ok(Some(hexdump::hex(&hash_sectors(
+        s,
+        p.first_lba,
+        p.sectors(),
+    )?)))
+}
+
+/// Compare the boot chain against a golden manifest, or with `record`,
+/// write the manifest from this device
+pub fn check_boot_chain(s: &Session, manifest: &Path, record: bool) -> Result<(), Failure> {
+    let table =
+        gpt::read(&mut |lba, n| protocol::read_lba(s, lba as u32, n).map_err(Failure::from))?;
+    if record {
+        let mut found: Vec<(String, Value)> = Vec::new();
+        for n in BOOT_CHAIN {
+            if let Some(h) = boot_chain_hash(s, n, &table)? {
+                found.push((n.to_string(), h.into()));
+            }
+        }
+        if found.is_empty() {
+            return Err("Device has none of idbloader, uboot and trust".into());
+        }
+        std::fs::write(manifest, format!("{}\n", Value::Obj(found)))
+            .map_err(|e| format!("{}: {e}", manifest.display()))?;
+        info!("Manifest written to {}", manifest.display());
+        return Ok(());
+    }
+
+    let text =
+        std::fs::read_to_string(manifest).map_err(|e| format!("{}: {e}", manifest.display()))?;
+    let Value::Obj(golden) = json::parse(&text)? else {
+        return Err(format!("{}: expected an object", manifest.display()).into());
+    };
+    let mut bad = Vec::new();
+    for (name, expected) in &golden {
+        let expected = expected
+            .as_str()
+            .ok_or(format!("{}: {name} is not a hash", manifest.display()))?;
+        let status = match boot_chain_hash(s, name, &table)? {
+            Some(h) if h.eq_ignore_ascii_case(expected) => "ok",
+            Some(_) => "MISMATCH",
+            None => "missing",
+        };
+        println!("{name:12} {status}");
+        if status != "ok" {
+            bad.push(name.as_str());
+        }
+    }
+    match bad.is_empty() {
+        true => Ok(()),
+        false => Err(format!("Boot chain differs from the manifest in {}", bad.join(", ")).into()),
+    }
+}
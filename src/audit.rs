@@ -0,0 +1,116 @@
+//! Append-only log of operations that change a device's storage, as JSON
+//! lines with the time, command line, devices, image hashes and outcome.
+//! Image files are the arguments that name existing files, as for exported
+//! scripts.
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+
+use log::error;
+
+use crate::cache;
+use crate::json::{self, Value};
+use crate::metrics::Failure;
+
+/// Where `serve`, `service` and `provision` log unless told otherwise
+pub const DEFAULT_PATH: &str = "rk_boot-audit.jsonl";
+
+static LOG: OnceLock<Mutex<File>> = OnceLock::new();
+
+thread_local! {
+    /// Devices the current command has connected to
+    static DEVICES: RefCell<Vec<Value>> = const { RefCell::new(Vec::new()) };
+}
+
+pub fn open(path: &Path) -> Result<(), String> {
+    let f = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("{}: {e}", path.display()))?;
+    let _ = LOG.set(Mutex::new(f));
+    Ok(())
+}
+
+/// Note a device the current command uses; retries connect again
+pub fn connected(d: Value) {
+    DEVICES.with_borrow_mut(|v| {
+        if !v.contains(&d) {
+            v.push(d);
+        }
+    });
+}
+
+/// The devices noted so far for the current command
+pub fn devices() -> Vec<Value> {
+    DEVICES.with_borrow(Vec::clone)
+}
+
+fn images(args: &[String]) -> Value {
+    let mut seen: Vec<&String> = Vec::new();
+    let images = args
+        .iter()
+        .filter(|a| Path::new(a).is_file())
+        .filter_map(|a| {
+            if seen.contains(&a) {
+                return None;
+            }
+            seen.push(a);
+            let hash = cache::digest_of(Path::new(a));
+            Some(json::obj([
+                ("path", a.as_str().into()),
+                ("sha256", hash.ok().into()),
+            ]))
+        });
+    Value::Arr(images.collect())
+}
+
+/// Finish a command; it is logged if it was a destructive one
+pub fn record(args: &[String], destructive: bool, res: &Result<(), Failure>) {
+    let devices = DEVICES.with_borrow_mut(std::mem::take);
+    let Some(log) = LOG.get().filter(|_| destructive) else {
+        return;
+    };
+    let line = json::obj([
+        ("time", jiff::Timestamp::now().to_string().into()),
+        ("args", args.to_vec().into()),
+        ("devices", Value::Arr(devices)),
+        ("images", images(args)),
+        ("outcome", if res.is_ok() { "ok" } else { "error" }.into()),
+        ("error", res.as_ref().err().map(Failure::to_string).into()),
+    ]);
+    let mut f = log.lock().unwrap();
+    if let Err(e) = writeln!(f, "{line}").and_then(|_| f.sync_data()) {
+        error!("Cannot write audit log: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn audit_log_records_destructive_commands() {
+        let dir = std::env::temp_dir().join(format!("rk_audit_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (log, image) = (dir.join("audit.jsonl"), dir.join("os.img"));
+        std::fs::write(&image, b"abc").unwrap();
+        open(&log).unwrap();
+        let args = ["write", "0", image.to_str().unwrap()].map(String::from);
+        connected(json::obj([("bus", 1.into())]));
+        record(&args, true, &Err("Storage is write-protected".into()));
+        connected(json::obj([("bus", 2.into())]));
+        record(&["info".to_string()], false, &Ok(()));
+        let text = std::fs::read_to_string(&log).unwrap();
+        assert_eq!(text.lines().count(), 1);
+        let v = json::parse(&text).unwrap();
+        assert_eq!(v.get("outcome").and_then(|o| o.as_str()), Some("error"));
+        assert_eq!(v.get("devices").unwrap().as_array().unwrap().len(), 1);
+        let abc = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert!(text.contains(abc), "{text}");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
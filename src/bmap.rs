@@ -0,0 +1,120 @@
+//! bmaptool block maps, listing which blocks of a sparse image hold data
+//!
+//! The format is a small XML file with the image and block size and the
+//! mapped block ranges, each with an optional SHA-256 of its data. Only
+//! version 2 files carry SHA-256; checksums of older ones are ignored.
+
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Range {
+    pub first: u64,
+    pub last: u64,
+    pub sha256: Option<[u8; 32]>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Bmap {
+    pub image_size: u64,
+    pub block_size: u64,
+    pub ranges: Vec<Range>,
+}
+
+impl Bmap {
+    pub fn mapped_bytes(&self) -> u64 {
+        let r = self.ranges.iter();
+        r.map(|r| self.range_bytes(r)).sum()
+    }
+
+    /// Offset and length of a range in the image
+    pub fn range_bytes(&self, r: &Range) -> u64 {
+        let start = r.first * self.block_size;
+        ((r.last + 1) * self.block_size).min(self.image_size) - start.min(self.image_size)
+    }
+}
+
+/// Contents of the first `<name>` element
+fn tag<'a>(text: &'a str, name: &str) -> Option<&'a str> {
+    let start = text.find(&format!("<{name}>"))? + name.len() + 2;
+    let end = start + text[start..].find(&format!("</{name}>"))?;
+    Some(text[start..end].trim())
+}
+
+fn number(text: &str, name: &str) -> Result<u64, String> {
+    let v = tag(text, name).ok_or(format!("bmap has no {name}"))?;
+    v.parse().map_err(|_| format!("bad {name} {v:?} in bmap"))
+}
+
+fn parse_digest(s: &str) -> Result<[u8; 32], String> {
+    let d: Result<Vec<u8>, _> = (0..s.len())
+        .step_by(2)
+        .map(|n| u8::from_str_radix(s.get(n..n + 2).unwrap_or("x"), 16))
+        .collect();
+    d.ok()
+        .and_then(|d| d.try_into().ok())
+        .ok_or(format!("bad SHA-256 {s:?} in bmap"))
+}
+
+pub fn parse(text: &str) -> Result<Bmap, String> {
+    let sha256 = tag(text, "ChecksumType") == Some("sha256");
+    let map = tag(text, "BlockMap").ok_or("bmap has no BlockMap")?;
+    let mut ranges = Vec::new();
+    for r in map.split("<Range").skip(1) {
+        let (attrs, rest) = r.split_once('>').ok_or("bad Range in bmap")?;
+        let (blocks, _) = rest.split_once("</Range").ok_or("bad Range in bmap")?;
+        let blocks = blocks.trim();
+        let (first, last) = blocks.split_once('-').unwrap_or((blocks, blocks));
+        let bad = || format!("bad Range {blocks:?} in bmap");
+        let first: u64 = first.trim().parse().map_err(|_| bad())?;
+        let last: u64 = last.trim().parse().map_err(|_| bad())?;
+        if last < first {
+            return Err(bad());
+        }
+        let chksum = attrs
+            .split_once("chksum=\"")
+            .and_then(|(_, c)| c.split_once('"'))
+            .map(|(c, _)| c);
+        ranges.push(Range {
+            first,
+            last,
+            sha256: match chksum {
+                Some(c) if sha256 => Some(parse_digest(c)?),
+                _ => None,
+            },
+        });
+    }
+    Ok(Bmap {
+        image_size: number(text, "ImageSize")?,
+        block_size: number(text, "BlockSize")?,
+        ranges,
+    })
+}
+
+/// Load a bmap, checking its own checksum where it has one: the SHA-256 of
+/// the file with the checksum itself replaced by zeros
+pub fn load(path: &Path) -> Result<Bmap, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    if tag(&text, "ChecksumType") == Some("sha256")
+        && let Some(c) = tag(&text, "BmapFileChecksum")
+    {
+        let zeroed = text.replacen(c, &"0".repeat(c.len()), 1);
+        if Sha256::digest(zeroed.as_bytes())[..] != parse_digest(c)? {
+            return Err(format!(
+                "{} is corrupt, its checksum does not match",
+                path.display()
+            ));
+        }
+    }
+    parse(&text)
+}
+
+/// `image.wic.bmap` or `image.bmap` next to `image.wic`, as bmaptool finds them
+pub fn find(image: &Path) -> Option<PathBuf> {
+    let mut appended = image.as_os_str().to_owned();
+    appended.push(".bmap");
+    [PathBuf::from(appended), image.with_extension("bmap")]
+        .into_iter()
+        .find(|p| p.is_file())
+}
@@ -0,0 +1,286 @@
+//! First power-on of a new board spin, one stage at a time
+//!
+//! `boot` and `info` do the same in one go, but when a new board does not
+//! come up, what matters is where it stops: whether the mask ROM shows up,
+//! the DDR init blob returns to it, usbplug starts from DRAM, the chip and
+//! its storage answer, and DRAM holds what is written to it. Each stage is
+//! asked for on a terminal, can be skipped, and its outcome is logged.
+
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use clap::Args;
+use clap_num::maybe_hex;
+use log::{error, info, warn};
+
+use crate::chip::Chip;
+use crate::session::Session;
+use crate::{DeviceAddr, Endpoints, Mode, hexdump, loader, memtest, protocol, provision, size};
+
+// What rkbin's DDR init blobs print at unless the chip says otherwise
+const DEFAULT_BAUD: u32 = 1_500_000;
+
+#[derive(Args, Debug)]
+pub struct Options {
+    /// DDR init binary; picked from rkbin if not given
+    #[clap(long, requires = "usbplug")]
+    pub ddr: Option<PathBuf>,
+    /// usbplug binary; picked from rkbin if not given
+    #[clap(long, requires = "ddr")]
+    pub usbplug: Option<PathBuf>,
+    /// rkbin checkout to pick binaries from; defaults to $RKBIN
+    #[clap(long, conflicts_with = "ddr")]
+    pub rkbin: Option<PathBuf>,
+    /// Test DRAM from this address once usbplug runs
+    #[clap(long, value_parser = maybe_hex::<u32>, requires = "memtest_size")]
+    pub memtest_base: Option<u32>,
+    #[clap(long, value_parser = size::bytes_usize, requires = "memtest_base")]
+    pub memtest_size: Option<usize>,
+    /// Run all stages without asking
+    #[clap(long, short)]
+    pub yes: bool,
+}
+
+enum Outcome {
+    Passed,
+    Skipped(&'static str),
+    Failed(String),
+}
+
+/// Ask whether to run a stage; Ok(false) skips it, Err stops the bring-up
+fn ask(name: &str) -> Result<bool, String> {
+    eprint!("Next: {name}. Run it [Y], skip it [s] or stop [q]? ");
+    std::io::stderr().flush().unwrap();
+    let mut l = String::new();
+    std::io::stdin()
+        .lock()
+        .read_line(&mut l)
+        .map_err(|e| e.to_string())?;
+    match l.trim().to_lowercase().as_str() {
+        "" | "y" => Ok(true),
+        "s" => Ok(false),
+        _ => Err(format!("Stopped before {name}")),
+    }
+}
+
+struct Stages {
+    ask: bool,
+    done: Vec<(&'static str, Outcome)>,
+}
+
+impl Stages {
+    /// Run a stage unless skipped; a failed stage ends the bring-up
+    fn run(
+        &mut self,
+        name: &'static str,
+        f: impl FnOnce() -> Result<(), String>,
+    ) -> Result<(), String> {
+        let n = self.done.len() + 1;
+        if self.ask && !ask(name)? {
+            return self.skip(name, "skipped by request");
+        }
+        info!("Stage {n}, {name}");
+        let start = Instant::now();
+        let res = f();
+        let took = start.elapsed().as_secs_f32();
+        match res {
+            Ok(()) => {
+                info!("Stage {n}, {name}: passed in {took:.1}s");
+                self.done.push((name, Outcome::Passed));
+                Ok(())
+            }
+            Err(e) => {
+                error!("Stage {n}, {name}: failed after {took:.1}s: {e}");
+                self.done.push((name, Outcome::Failed(e.clone())));
+                Err(format!("Bring-up failed at {name}: {e}"))
+            }
+        }
+    }
+
+    fn skip(&mut self, name: &'static str, why: &'static str) -> Result<(), String> {
+        info!("Stage {}, {name}: {why}", self.done.len() + 1);
+        self.done.push((name, Outcome::Skipped(why)));
+        Ok(())
+    }
+
+    fn summary(&self) {
+        for (name, o) in &self.done {
+            match o {
+                Outcome::Passed => info!("{name:24} passed"),
+                Outcome::Skipped(why) => info!("{name:24} {why}"),
+                Outcome::Failed(e) => error!("{name:24} failed: {e}"),
+            }
+        }
+    }
+}
+
+/// What to check when the device does not come back after the DDR init
+/// blob `ddr` ran for `waited`
+pub fn ddr_checklist(chip: &Chip, ddr: &[u8], waited: Duration) -> Vec<String> {
+    let expected = match chip.ddr_init_ms {
+        Some(ms) => format!("{} takes about {ms} ms", chip.name),
+        None => "takes well under a second on most chips".into(),
+    };
+    let baud = chip.baud.unwrap_or(DEFAULT_BAUD);
+    let uart = match chip.uart {
+        Some(u) => format!("{u} at {baud} baud"),
+        None => format!("the debug UART, see the schematics, at {baud} baud"),
+    };
+    let mut l = vec![
+        format!(
+            "Waited {:.1}s; DRAM init {expected}, so the blob hung or reset the chip",
+            waited.as_secs_f32()
+        ),
+        format!("Its log, with DRAM type, size and training results, goes to {uart}"),
+    ];
+    let versions = loader::versions(ddr);
+    let version = versions.iter().find(|v| v.contains("DDR"));
+    match version {
+        Some(v) => {
+            l.push(format!("The blob is {v}"));
+            let lower = v.to_lowercase();
+            let known_bad = |b: &&&str| lower.contains(&b.to_lowercase());
+            if let Some(bad) = chip.bad_ddr_blobs.iter().find(known_bad) {
+                l.push(format!(
+                    "{bad} is known to fail on {}, take another from rkbin",
+                    chip.name
+                ));
+            }
+        }
+        None => l.push("The blob has no version string; is it a DDR init blob?".into()),
+    }
+    l.push("The blob has to match the DRAM type, e.g. LPDDR4 or DDR4, of the board".into());
+    l.push("Check the DRAM supply rails, and that nothing holds the SoC in reset".into());
+    l
+}
+
+/// Log what to check when DRAM init does not come back
+pub fn ddr_hints(chip: &Chip, ddr: &[u8], waited: Duration) {
+    warn!("The device did not come back from DRAM init; check:");
+    for l in ddr_checklist(chip, ddr, waited) {
+        warn!("  - {l}");
+    }
+}
+
+/// Walk through the bring-up stages on the device of the session
+pub fn bringup(
+    s: &Session,
+    device: Option<DeviceAddr>,
+    eps: Endpoints,
+    opts: Options,
+) -> Result<(), String> {
+    let mut st = Stages {
+        ask: !opts.yes && std::io::stdin().is_terminal(),
+        done: Vec::new(),
+    };
+    let res = stages(&mut st, s, device, eps, opts);
+    st.summary();
+    res
+}
+
+fn stages(
+    st: &mut Stages,
+    s: &Session,
+    device: Option<DeviceAddr>,
+    eps: Endpoints,
+    opts: Options,
+) -> Result<(), String> {
+    st.run("detect the mask ROM", || {
+        info!("{} in {} mode", s.chip.name, s.mode);
+        match s.mode {
+            Mode::MaskROM | Mode::UsbPlug => Ok(()),
+            m => Err(format!("Device is in {m} mode, not mask ROM")),
+        }
+    })?;
+
+    let mut plugged = None;
+    if s.mode == Mode::MaskROM {
+        let (old, port) = crate::pick(device)?;
+        let (ddr, usbplug) = match (opts.ddr, opts.usbplug) {
+            (Some(d), Some(u)) => (d, u),
+            _ => crate::rkbin_pick(opts.rkbin, s.chip, None)?,
+        };
+        let mut blob = vec![];
+        let start = Instant::now();
+        st.run("run the DDR init blob", || {
+            info!("DDR init: {}", ddr.display());
+            blob = std::fs::read(&ddr).map_err(|e| format!("{}: {e}", ddr.display()))?;
+            crate::run_in(s, &blob, protocol::Region::Sram)?;
+            sleep(crate::ddr_init_delay(s.chip));
+            Ok(())
+        })?;
+        st.run("check the mask ROM is back", || {
+            // A blob that hangs or crashes takes the mask ROM off the bus.
+            let res = match provision::locate(&port) {
+                Some(a) if a == old => return Ok(()),
+                Some(_) => format!("{port} re-enumerated, the DDR init blob reset the chip"),
+                None => format!("{port} is gone, the DDR init blob did not return"),
+            };
+            ddr_hints(s.chip, &blob, start.elapsed());
+            Err(res)
+        })?;
+        st.run("run usbplug", || {
+            info!("usbplug: {}", usbplug.display());
+            let data =
+                std::fs::read(&usbplug).map_err(|e| format!("{}: {e}", usbplug.display()))?;
+            crate::run_in(s, &data, protocol::Region::Dram)?;
+            info!("Waiting for usbplug at port {port}");
+            let Some(new) = provision::reappear(&port, old) else {
+                // usbplug runs from DRAM, which may not work after all.
+                ddr_hints(s.chip, &blob, start.elapsed());
+                return Err(format!("{port} did not come back"));
+            };
+            let p = crate::connect_with(Some(new), eps)?;
+            if p.mode != Mode::UsbPlug {
+                return Err(format!("Device came back in {} mode, not USB plug", p.mode));
+            }
+            plugged = Some(p);
+            Ok(())
+        })?;
+    } else {
+        let why = "skipped, usbplug runs already";
+        st.skip("run the DDR init blob", why)?;
+        st.skip("check the mask ROM is back", why)?;
+        st.skip("run usbplug", why)?;
+    }
+    let s = plugged.as_ref().unwrap_or(s);
+
+    st.run("read chip and flash info", || {
+        protocol::info(s).map_err(|e| e.to_string())?;
+        let fi = protocol::flash_info(s).map_err(|e| e.to_string())?;
+        let bytes = fi.sectors as u64 * protocol::SECTOR_SIZE as u64;
+        info!("Storage: {}", size::human(bytes));
+        let id = protocol::flash_id(s).map_err(|e| e.to_string())?;
+        info!("Flash ID: {}", hexdump::hex(&id));
+        Ok(())
+    })?;
+
+    match (opts.memtest_base, opts.memtest_size) {
+        (Some(base), Some(size)) => st.run("test DRAM", || Ok(memtest::memtest(s, base, size)?)),
+        _ => st.skip("test DRAM", "skipped, no --memtest-base given"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ddr_failures_come_with_a_checklist() {
+        let chips = crate::chip::parse(
+            "name = \"RK3568\"\npid = 0x350a\nddr_init_ms = 300\n\
+             uart = \"UART2 M0\"\nbad_ddr_blobs = \"v1.06, v1.07\"\n",
+        )
+        .unwrap();
+        let blob = b"\0\0DDR Version V1.06 20220624\0\0";
+        let l = ddr_checklist(&chips[0], blob, Duration::from_secs(10));
+        assert!(l[0].contains("about 300 ms"), "{l:?}");
+        assert!(l[1].contains("UART2 M0 at 1500000 baud"), "{l:?}");
+        assert!(l.iter().any(|l| l.contains("known to fail")), "{l:?}");
+        let l = ddr_checklist(&crate::chip::CHIPS[0], b"", Duration::ZERO);
+        assert!(!l.iter().any(|l| l.contains("known to fail")), "{l:?}");
+        assert!(l.iter().any(|l| l.contains("no version string")), "{l:?}");
+    }
+}
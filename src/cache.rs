@@ -0,0 +1,166 @@
+//! Images kept by their SHA-256, so that each is sent to a daemon once
+//!
+//! The client asks whether the daemon has a file's digest before uploading
+//! it, and refers to it as `sha256:<hex>` in command lines. Plans can do the
+//! same, so flashing many boards from one image moves it over the network a
+//! single time. Uploads are hashed as they arrive and kept only if they
+//! match.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+
+use sha2::{Digest, Sha256};
+
+use crate::json::{self, Value};
+use crate::{hexdump, workdir};
+
+const PREFIX: &str = "sha256:";
+
+static DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Keep images in `dir` instead of the default, which `serve` uses as well
+pub fn set_dir(dir: PathBuf) {
+    DIR.set(dir).expect("image cache set up twice");
+}
+
+fn dir() -> &'static Path {
+    DIR.get_or_init(|| workdir::base().join("rk_boot-images").join("sha256"))
+}
+
+/// Lower case hex of the right length, and nothing that leaves the directory
+pub fn valid_digest(d: &str) -> bool {
+    d.len() == 64 && d.bytes().all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// The cached image of a digest, if there is one
+pub fn path(digest: &str) -> Option<PathBuf> {
+    let p = dir().join(digest);
+    (valid_digest(digest) && p.is_file()).then_some(p)
+}
+
+/// A file argument for a cached image, others stay as they are
+pub fn resolve(arg: &str) -> Result<String, String> {
+    let Some(digest) = arg.strip_prefix(PREFIX) else {
+        return Ok(arg.to_string());
+    };
+    match path(digest) {
+        Some(p) => Ok(p.to_string_lossy().to_string()),
+        None if valid_digest(digest) => Err(format!("no image {arg} in {}", dir().display())),
+        None => Err(format!("bad digest {arg:?}")),
+    }
+}
+
+/// Store `len` bytes from `r` as the image of `digest`; what does not hash
+/// to it is thrown away
+pub fn put(digest: &str, r: &mut impl Read, len: u64) -> Result<(), String> {
+    if !valid_digest(digest) {
+        return Err(format!("bad digest {digest:?}"));
+    }
+    std::fs::create_dir_all(dir()).map_err(|e| e.to_string())?;
+    // Each upload gets a file of its own, so that concurrent ones do not mix.
+    let part = workdir::file(digest)?;
+    match receive(part.path(), r, len)? {
+        got if got == digest => part.keep(&dir().join(digest)),
+        got => Err(format!("upload hashes to {got}, not {digest}")),
+    }
+}
+
+/// Copy into a file, hashing on the way
+fn receive(file: &Path, r: &mut impl Read, len: u64) -> Result<String, String> {
+    let mut f = std::fs::File::create(file).map_err(|e| e.to_string())?;
+    let mut h = Sha256::new();
+    let mut buf = vec![0; 1024 * 1024];
+    let mut r = r.take(len);
+    let mut got = 0;
+    loop {
+        let n = r.read(&mut buf).map_err(|e| e.to_string())?;
+        if n == 0 {
+            break;
+        }
+        h.update(&buf[..n]);
+        f.write_all(&buf[..n]).map_err(|e| e.to_string())?;
+        got += n as u64;
+    }
+    if got != len {
+        return Err(format!("upload incomplete, {got} of {len} bytes"));
+    }
+    Ok(hexdump::hex(&h.finalize()))
+}
+
+/// The cached images with their sizes
+pub fn list() -> Value {
+    let mut images: Vec<(String, u64)> = std::fs::read_dir(dir())
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|e| {
+            let d = e.file_name().to_string_lossy().to_string();
+            valid_digest(&d).then(|| (d, e.metadata().map(|m| m.len()).unwrap_or(0)))
+        })
+        .collect();
+    images.sort();
+    Value::Arr(
+        images
+            .into_iter()
+            .map(|(d, size)| json::obj([("digest", d.into()), ("size", size.into())]))
+            .collect(),
+    )
+}
+
+/// The digest of a local file; files unchanged since they were last hashed
+/// in this process are not read again
+pub fn digest_of(file: &Path) -> io::Result<String> {
+    type Key = (PathBuf, u64, SystemTime);
+    static HASHED: Mutex<Option<HashMap<Key, String>>> = Mutex::new(None);
+    let m = file.metadata()?;
+    let key = (file.canonicalize()?, m.len(), m.modified()?);
+    if let Some(d) = HASHED.lock().unwrap().get_or_insert_default().get(&key) {
+        return Ok(d.clone());
+    }
+    let mut h = Sha256::new();
+    io::copy(&mut std::fs::File::open(file)?, &mut h)?;
+    let d = hexdump::hex(&h.finalize());
+    HASHED
+        .lock()
+        .unwrap()
+        .get_or_insert_default()
+        .insert(key, d.clone());
+    Ok(d)
+}
+
+/// How a command line refers to a cached image
+pub fn reference(digest: &str) -> String {
+    format!("{PREFIX}{digest}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_cache_by_digest() {
+        let image = [vec![7; 3000], std::process::id().to_le_bytes().to_vec()].concat();
+        let digest = hexdump::hex(&Sha256::digest(&image));
+        let wrong = hexdump::hex(&Sha256::digest(b"other"));
+        assert!(put(&wrong, &mut image.as_slice(), image.len() as u64).is_err());
+        assert!(path(&wrong).is_none());
+        assert!(put("../x", &mut image.as_slice(), 0).is_err());
+
+        let reference = reference(&digest);
+        assert!(resolve(&reference).unwrap_err().contains("no image"));
+        put(&digest, &mut image.as_slice(), image.len() as u64).unwrap();
+        let p = resolve(&reference).unwrap();
+        assert_eq!(std::fs::read(&p).unwrap(), image);
+        assert_eq!(resolve("boot.img").unwrap(), "boot.img");
+
+        let plan = std::env::temp_dir().join(format!("rk_boot-plan-{digest}"));
+        std::fs::write(&plan, format!("write 0 {reference}\n")).unwrap();
+        let steps = crate::plan::load(&plan).unwrap();
+        assert_eq!(steps[0].args, ["write", "0", p.as_str()]);
+        std::fs::remove_file(&plan).unwrap();
+        std::fs::remove_file(&p).unwrap();
+    }
+}
@@ -0,0 +1,145 @@
+//! What a loader supports, as its capability command tells
+//!
+//! The reply starts with an 8 byte bitmap, as U-Boot's rockusb sends it.
+//! Newer loaders append records of a type byte, a length byte and that
+//! many bytes of value: the storages they can switch to as a bit mask like
+//! the one of the read storage command (type 1), the most sectors one LBA
+//! command may move as u16 (type 2), and the opcodes they implement beyond
+//! the basic ones (type 3). Unknown records are shown as hex.
+
+use clap::ValueEnum;
+use log::{debug, info};
+
+use crate::hexdump;
+use crate::metrics::Failure;
+use crate::protocol::{self, Storage};
+use crate::session::Session;
+
+const BITMAP_SIZE: usize = 8;
+
+/// Bits of the bitmap, by byte and bit
+const BITS: &[(usize, u8, &str)] = &[
+    (0, 0, "direct LBA"),
+    (0, 1, "vendor storage"),
+    (0, 2, "first 4 MiB access"),
+    (0, 3, "read LBA"),
+    (0, 4, "new vendor storage"),
+    (0, 5, "read com log"),
+    (0, 6, "read IDB config"),
+    (0, 7, "read secure mode"),
+    (1, 0, "new IDB"),
+    (1, 1, "switch storage"),
+    (1, 2, "LBA parity"),
+    (1, 3, "read OTP chip"),
+    (1, 4, "switch USB3"),
+];
+
+const STORAGES: u8 = 1;
+const MAX_SECTORS: u8 = 2;
+const OPCODES: u8 = 3;
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    pub bitmap: [u8; BITMAP_SIZE],
+    pub storages: Option<Vec<Storage>>,
+    pub max_sectors: Option<u16>,
+    pub opcodes: Vec<u8>,
+    /// Records of types not known here
+    pub other: Vec<(u8, Vec<u8>)>,
+}
+
+impl Capabilities {
+    pub fn names(&self) -> Vec<&'static str> {
+        BITS.iter()
+            .filter(|(byte, bit, _)| self.bitmap[*byte] & (1 << bit) != 0)
+            .map(|(_, _, name)| *name)
+            .collect()
+    }
+}
+
+pub fn parse(d: &[u8]) -> Result<Capabilities, String> {
+    let Some((bitmap, mut rest)) = d.split_first_chunk::<BITMAP_SIZE>() else {
+        return Err(format!(
+            "Capability reply of {} bytes is too short",
+            d.len()
+        ));
+    };
+    let mut c = Capabilities {
+        bitmap: *bitmap,
+        ..Default::default()
+    };
+    // Zeros pad the reply up to the requested length.
+    while let [kind, len, tail @ ..] = rest
+        && *kind != 0
+    {
+        let len = *len as usize;
+        let value = tail
+            .get(..len)
+            .ok_or(format!("Capability record {kind} overruns the reply"))?;
+        match *kind {
+            STORAGES if len == 4 => {
+                let mask = u32::from_le_bytes(value.try_into().unwrap());
+                let s = Storage::value_variants()
+                    .iter()
+                    .filter(|s| mask & (1 << (**s as u8)) != 0)
+                    .copied();
+                c.storages = Some(s.collect());
+            }
+            MAX_SECTORS if len == 2 => {
+                c.max_sectors = Some(u16::from_le_bytes(value.try_into().unwrap()));
+            }
+            OPCODES => c.opcodes = value.to_vec(),
+            k => c.other.push((k, value.to_vec())),
+        }
+        rest = &tail[len..];
+    }
+    Ok(c)
+}
+
+/// What the loader reports; fails if asking does, and gives why if there
+/// is nothing to go by
+pub fn read(s: &Session) -> Result<Result<Capabilities, String>, Failure> {
+    Ok(match protocol::capability(s)? {
+        Some(d) => parse(&d),
+        None => Err("Loader does not report its capabilities".into()),
+    })
+}
+
+pub fn print(c: &Capabilities) {
+    println!("{:18} {}", "Bitmap", hexdump::hex(&c.bitmap));
+    println!("{:18} {}", "Supports", c.names().join(", "));
+    if let Some(s) = &c.storages {
+        let s: Vec<String> = s.iter().map(Storage::to_string).collect();
+        println!("{:18} {}", "Storages", s.join(", "));
+    }
+    if let Some(n) = c.max_sectors {
+        println!("{:18} {n} sectors", "Max LBA transfer");
+    }
+    if !c.opcodes.is_empty() {
+        let o: Vec<String> = c
+            .opcodes
+            .iter()
+            .map(|&o| match protocol::opcode_name(o) {
+                Some(name) => format!("{o:#04x} ({name})"),
+                None => format!("{o:#04x}"),
+            })
+            .collect();
+        println!("{:18} {}", "Extra opcodes", o.join(" "));
+    }
+    for (k, v) in &c.other {
+        println!("{:18} {}", format!("Record {k}"), hexdump::hex(v));
+    }
+}
+
+/// Move as many sectors per LBA command as the loader says it takes
+pub fn tune(s: &Session) -> Result<(), Failure> {
+    match read(s)? {
+        Ok(Capabilities {
+            max_sectors: Some(n),
+            ..
+        }) => s.set_lba_chunk(n as u32),
+        Ok(_) => debug!("Loader does not tell its largest transfer"),
+        Err(e) => info!("{e}, keeping the default transfer size"),
+    }
+    Ok(())
+}
@@ -0,0 +1,115 @@
+//! How far a plan got on a board, kept across power cycles
+//!
+//! A plan that resets the board halfway, e.g. after writing SPI flash, may
+//! outlast the wait for the board to come back. After each step, the count
+//! of steps done is saved under the board's identity, so that the next run
+//! of the plan on that board, e.g. once it attaches again, goes on after
+//! the last step done. A changed plan starts over; a finished one leaves
+//! no checkpoint behind.
+
+use std::path::{Path, PathBuf};
+
+use log::{debug, warn};
+
+use crate::cache;
+use crate::json::{self, Value};
+
+/// Where `provision` and `service` keep checkpoints unless told otherwise
+pub const DEFAULT_DIR: &str = "rk_boot-checkpoints";
+
+fn file(dir: &Path, id: &str) -> PathBuf {
+    let name: String = id
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() || "-_.".contains(c) {
+            true => c,
+            false => '_',
+        })
+        .collect();
+    dir.join(format!("{name}.json"))
+}
+
+fn plan_digest(plan: &Path) -> Result<String, String> {
+    cache::digest_of(plan).map_err(|e| format!("{}: {e}", plan.display()))
+}
+
+/// Steps of `plan` done on the board, 0 without a checkpoint for this plan
+pub fn load(dir: &Path, id: &str, plan: &Path) -> usize {
+    let f = file(dir, id);
+    let Ok(text) = std::fs::read_to_string(&f) else {
+        return 0;
+    };
+    let v = match json::parse(&text) {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("{}: {e}, starting over", f.display());
+            return 0;
+        }
+    };
+    let saved = v.get("plan_sha256").and_then(Value::as_str);
+    if saved.is_none() || saved != plan_digest(plan).ok().as_deref() {
+        debug!("{} is for another plan", f.display());
+        return 0;
+    }
+    match v.get("done") {
+        Some(Value::Int(n)) if *n > 0 => *n as usize,
+        _ => 0,
+    }
+}
+
+/// Note that the first `done` steps of `plan` are done on the board
+pub fn save(dir: &Path, id: &str, plan: &Path, done: usize) -> Result<(), String> {
+    std::fs::create_dir_all(dir).map_err(|e| format!("{}: {e}", dir.display()))?;
+    let v = json::obj([
+        ("time", jiff::Timestamp::now().to_string().into()),
+        ("id", id.into()),
+        ("plan", plan.to_string_lossy().to_string().into()),
+        ("plan_sha256", plan_digest(plan)?.into()),
+        ("done", done.into()),
+    ]);
+    let f = file(dir, id);
+    // Write aside and rename, so that a crash leaves the old checkpoint.
+    let tmp = f.with_extension("json.tmp");
+    std::fs::write(&tmp, format!("{v}\n"))
+        .and_then(|()| std::fs::rename(&tmp, &f))
+        .map_err(|e| format!("{}: {e}", f.display()))
+}
+
+/// Forget the board's progress, as when its plan is finished
+pub fn clear(dir: &Path, id: &str) {
+    let _ = std::fs::remove_file(file(dir, id));
+}
+
+/// Whether the board has a plan left unfinished
+pub fn pending(dir: &Path, id: &str) -> bool {
+    file(dir, id).is_file()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plans_resume_from_checkpoints() {
+        let dir = std::env::temp_dir().join(format!("rk_boot-checkpoints-{}", std::process::id()));
+        let plan = dir.with_extension("plan");
+        std::fs::write(
+            &plan,
+            "@spinor write 0 spl.img\nreset\nwrite 0 rootfs.img\n",
+        )
+        .unwrap();
+        let id = "1-2.3";
+        assert_eq!(load(&dir, id, &plan), 0);
+        save(&dir, id, &plan, 2).unwrap();
+        assert!(pending(&dir, id));
+        assert_eq!(load(&dir, id, &plan), 2);
+        assert_eq!(load(&dir, "1-2.4", &plan), 0);
+
+        // A changed plan starts over.
+        std::fs::write(&plan, "reset\n").unwrap();
+        assert_eq!(load(&dir, id, &plan), 0);
+        clear(&dir, id);
+        assert!(!pending(&dir, id));
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_file(&plan).unwrap();
+    }
+}
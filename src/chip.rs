@@ -0,0 +1,439 @@
+//! The chips this tool knows, built in or defined in TOML files
+//!
+//! Files in `~/.config/rk_boot/chips.d/*.toml` add chips, or replace built-in
+//! ones with the same product ID, for silicon this build does not know yet:
+//!
+//! ```toml
+//! [[chip]]
+//! name = "RK3576"
+//! pid = 0x350e
+//! rkbin_prefix = "rk3576"
+//! sram_base = 0x3ff80000
+//! sram_size = 0x10000
+//! sram_load = 0x3ff81000
+//! dram_load = 0x40200000
+//! ```
+//!
+//! Only name and pid are required; `rkbin_prefix` defaults to the name in
+//! lower case. `uid_offset` and `uid_size` tell where in OTP the chip's
+//! unique ID is, in bytes. A file with a single chip can leave out `[[chip]]`.
+//!
+//! For when DRAM init does not come back, `ddr_init_ms` is how long it
+//! takes, `uart` where the blob prints, e.g. "UART2 M0, TX GPIO0_B5, RX
+//! GPIO0_B6", `baud` at what rate, and `bad_ddr_blobs` a comma-separated
+//! list of blob versions known to fail, e.g. "v1.05, v1.06".
+//!
+//! Some chips share a product ID, e.g. RK3566 and RK3568. The chip of the
+//! active profile picks among them, or the chip ID a loader reports; the
+//! mask ROM cannot tell.
+//!
+//! `mask_rom` names the variant of the USB download the ROM expects, see
+//! [`crate::maskrom`]; the default is "rk3366". `pad_split_crc`, 0 or 1,
+//! overrides the variant's padding of code that would split its CRC.
+
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use log::{debug, warn};
+use serde::Deserialize;
+
+use crate::maskrom::{self, MaskRomVariant};
+use crate::protocol::Region;
+
+/// A Rockchip SoC as seen by this tool
+#[derive(Debug)]
+pub struct Chip {
+    pub name: &'static str,
+    /// USB product ID in mask ROM mode
+    pub pid: u16,
+    /// Prefix of the DDR init and usbplug binaries in rkbin
+    pub rkbin_prefix: &'static str,
+    /// Base and size of the SRAM that the 0x471 stage runs from, if known
+    pub sram: Option<(u32, u32)>,
+    /// Where the mask ROM puts 0x471 code, within `sram`, if known
+    pub sram_load: Option<u32>,
+    /// Where the mask ROM puts 0x472 code in DRAM, if known
+    pub dram_load: Option<u32>,
+    /// Offset and size of the unique ID in OTP, if known
+    pub uid: Option<(u32, u16)>,
+    /// How long DRAM init takes before the mask ROM answers again
+    pub ddr_init_ms: Option<u32>,
+    /// Debug UART and its pins, and its rate
+    pub uart: Option<&'static str>,
+    pub baud: Option<u32>,
+    /// DDR init blob versions that are known to fail
+    pub bad_ddr_blobs: &'static [&'static str],
+    /// How the mask ROM takes code
+    pub mask_rom: &'static MaskRomVariant,
+}
+
+impl Chip {
+    /// Load address of code sent to a region, and how much fits there
+    pub fn target(&self, region: Region) -> Option<(u32, Option<u32>)> {
+        match region {
+            Region::Sram => {
+                let addr = self.sram_load?;
+                let limit = self
+                    .sram
+                    .map(|(base, size)| (base + size).saturating_sub(addr));
+                Some((addr, limit))
+            }
+            Region::Dram => self.dram_load.map(|a| (a, None)),
+        }
+    }
+}
+
+/// A chip of which only the product ID and names are known
+const fn chip(name: &'static str, pid: u16, rkbin_prefix: &'static str) -> Chip {
+    Chip {
+        name,
+        pid,
+        rkbin_prefix,
+        sram: None,
+        sram_load: None,
+        dram_load: None,
+        uid: None,
+        ddr_init_ms: None,
+        uart: None,
+        baud: None,
+        bad_ddr_blobs: &[],
+        mask_rom: &maskrom::RK3366,
+    }
+}
+
+/// Chips sharing a product ID come in the order they are assumed in when
+/// nothing tells them apart.
+///
+/// Load addresses are where mainline U-Boot links the stages that the boot
+/// ROM loads, in arch/arm/mach-rockchip/*/Kconfig: TPL_TEXT_BASE for code
+/// sent to SRAM, SPL_TEXT_BASE for code sent to DRAM. Chips whose first
+/// stage only comes from rkbin are left without them. Unique IDs are the
+/// `id` cells of the eFuse or OTP nodes in Linux's device trees.
+///
+/// All of them take the mask ROM download in 4 KiB chunks, as rkdeveloptool
+/// sends it to every chip; no chip here is known to need another variant.
+pub const CHIPS: &[Chip] = &[
+    chip("RK3366", 0x350a, "rk3366"),
+    chip("RK3036", 0x301a, "rk3036"),
+    chip("RK3066", 0x300a, "rk3066"),
+    chip("RK3128", 0x310c, "rk3128"),
+    chip("RK3188", 0x310b, "rk3188"),
+    chip("RK3228", 0x320b, "rk322x"),
+    Chip {
+        // bus_intmem in rk3288.dtsi
+        sram: Some((0xff70_0000, 0x1_8000)),
+        sram_load: Some(0xff70_4000),
+        dram_load: Some(0),
+        uid: Some((0x07, 16)),
+        ..chip("RK3288", 0x320a, "rk3288")
+    },
+    chip("RK3308", 0x330e, "rk3308"),
+    Chip {
+        sram_load: Some(0xff0e_1000),
+        dram_load: Some(0),
+        ..chip("RK3326", 0x330d, "rk3326")
+    },
+    Chip {
+        sram_load: Some(0xff0e_1000),
+        dram_load: Some(0),
+        ..chip("PX30", 0x330d, "px30")
+    },
+    Chip {
+        sram_load: Some(0xff09_1000),
+        dram_load: Some(0),
+        uid: Some((0x07, 16)),
+        ..chip("RK3328", 0x320c, "rk3328")
+    },
+    Chip {
+        sram_load: Some(0xff8c_1000),
+        dram_load: Some(0),
+        ..chip("RK3368", 0x330a, "rk3368")
+    },
+    Chip {
+        // 192 KiB of INTMEM, per the RK3399 TRM's address map
+        sram: Some((0xff8c_0000, 0x3_0000)),
+        sram_load: Some(0xff8c_2000),
+        dram_load: Some(0),
+        uid: Some((0x07, 16)),
+        ..chip("RK3399", 0x330c, "rk3399")
+    },
+    Chip {
+        uid: Some((0x0a, 16)),
+        ..chip("RK3566", 0x350a, "rk3566")
+    },
+    Chip {
+        uid: Some((0x0a, 16)),
+        ..chip("RK3568", 0x350a, "rk3568")
+    },
+    Chip {
+        // system_sram2 in rk3588-base.dtsi
+        sram: Some((0xff00_1000, 0xe_f000)),
+        uid: Some((0x07, 16)),
+        ..chip("RK3588", 0x350b, "rk3588")
+    },
+];
+
+static LOADED: OnceLock<Vec<Chip>> = OnceLock::new();
+
+fn leak(s: &str) -> &'static str {
+    Box::leak(s.to_string().into_boxed_str())
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Fields {
+    name: Option<String>,
+    pid: Option<u32>,
+    rkbin_prefix: Option<String>,
+    sram_base: Option<u32>,
+    sram_size: Option<u32>,
+    sram_load: Option<u32>,
+    dram_load: Option<u32>,
+    uid_offset: Option<u32>,
+    uid_size: Option<u32>,
+    ddr_init_ms: Option<u32>,
+    uart: Option<String>,
+    baud: Option<u32>,
+    bad_ddr_blobs: Option<String>,
+    mask_rom: Option<String>,
+    pad_split_crc: Option<u32>,
+}
+
+impl Fields {
+    fn chip(self) -> Result<Chip, String> {
+        let name = self.name.ok_or("chip without name")?;
+        let pid = self.pid.ok_or(format!("{name} has no pid"))?;
+        let pid = u16::try_from(pid).map_err(|_| format!("{name}: pid {pid:#x} is too large"))?;
+        let sram = match (self.sram_base, self.sram_size) {
+            (Some(base), Some(size)) => Some((base, size)),
+            (None, None) => None,
+            _ => return Err(format!("{name}: give both sram_base and sram_size")),
+        };
+        let uid = match (self.uid_offset, self.uid_size) {
+            (Some(offset), Some(size)) => {
+                let size = u16::try_from(size)
+                    .map_err(|_| format!("{name}: uid_size {size} is too large"))?;
+                Some((offset, size))
+            }
+            (None, None) => None,
+            _ => return Err(format!("{name}: give both uid_offset and uid_size")),
+        };
+        let prefix = self.rkbin_prefix.unwrap_or(name.to_lowercase());
+        let bad: Vec<&'static str> = self
+            .bad_ddr_blobs
+            .iter()
+            .flat_map(|l| l.split(','))
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .map(leak)
+            .collect();
+        let mask_rom = match self.mask_rom {
+            Some(v) => maskrom::find(&v).ok_or(format!("{name}: unknown mask_rom {v:?}"))?,
+            None => &maskrom::RK3366,
+        };
+        let mask_rom = match self.pad_split_crc {
+            Some(p @ (0 | 1)) if (p == 1) != mask_rom.pad_split_crc => {
+                &*Box::leak(Box::new(MaskRomVariant {
+                    pad_split_crc: p == 1,
+                    ..*mask_rom
+                }))
+            }
+            Some(0 | 1) | None => mask_rom,
+            Some(p) => return Err(format!("{name}: pad_split_crc is 0 or 1, not {p}")),
+        };
+        Ok(Chip {
+            name: leak(&name),
+            pid,
+            rkbin_prefix: leak(&prefix),
+            sram,
+            sram_load: self.sram_load,
+            dram_load: self.dram_load,
+            uid,
+            ddr_init_ms: self.ddr_init_ms,
+            uart: self.uart.as_deref().map(leak),
+            baud: self.baud,
+            bad_ddr_blobs: Box::leak(bad.into_boxed_slice()),
+            mask_rom,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct File {
+    chip: Vec<Fields>,
+}
+
+/// Chips defined in a TOML file
+pub fn parse(text: &str) -> Result<Vec<Chip>, String> {
+    let table: toml::Table = toml::from_str(text).map_err(|e| e.to_string())?;
+    let fields = match table.contains_key("chip") {
+        true => toml::from_str::<File>(text).map(|f| f.chip),
+        false if table.is_empty() => Ok(Vec::new()),
+        false => toml::from_str::<Fields>(text).map(|f| vec![f]),
+    }
+    .map_err(|e| e.to_string())?;
+    fields.into_iter().map(Fields::chip).collect()
+}
+
+/// Where chip definitions are read from
+pub fn config_dir() -> Option<PathBuf> {
+    let config = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))?;
+    Some(config.join("rk_boot").join("chips.d"))
+}
+
+/// All chips defined in the `.toml` files of a directory; broken files are
+/// skipped with a warning
+pub fn load_dir(dir: &Path) -> Vec<Chip> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.extension().is_some_and(|e| e == "toml"))
+        .collect();
+    files.sort();
+    let mut chips = Vec::new();
+    for f in files {
+        let res = std::fs::read_to_string(&f)
+            .map_err(|e| e.to_string())
+            .and_then(|t| parse(&t));
+        match res {
+            Ok(c) => {
+                debug!("{}: {} chips", f.display(), c.len());
+                chips.extend(c);
+            }
+            Err(e) => warn!("Ignoring {}: {e}", f.display()),
+        }
+    }
+    chips
+}
+
+/// Chips from the config directory, then built-in ones
+pub fn all() -> impl Iterator<Item = &'static Chip> {
+    let loaded = LOADED.get_or_init(|| config_dir().map(|d| load_dir(&d)).unwrap_or_default());
+    loaded.iter().chain(CHIPS)
+}
+
+pub fn by_pid(pid: u16) -> Option<&'static Chip> {
+    all().find(|c| c.pid == pid)
+}
+
+/// The chips with a product ID; defined ones replace built-in ones
+pub fn sharing(pid: u16) -> Vec<&'static Chip> {
+    let mut chips: Vec<_> = all().filter(|c| c.pid == pid).collect();
+    let builtin = |c: &&Chip| CHIPS.iter().any(|b| std::ptr::eq(*c, b));
+    let defined = chips.iter().take_while(|c| !builtin(c)).count();
+    if defined > 0 {
+        chips.truncate(defined);
+    }
+    chips
+}
+
+/// Chip names match with or without the "RK" and regardless of case
+pub fn same_name(name: &str, other: &str) -> bool {
+    let bare = |s: &str| {
+        let s = s.to_ascii_lowercase();
+        s.strip_prefix("rk").map(str::to_string).unwrap_or(s)
+    };
+    bare(name) == bare(other)
+}
+
+/// The one of `chips` named `name`, if any
+pub fn named(chips: &[&'static Chip], name: &str) -> Option<&'static Chip> {
+    chips.iter().find(|c| same_name(c.name, name)).copied()
+}
+
+/// The names of chips, for messages
+pub fn names(chips: &[&Chip]) -> String {
+    match chips {
+        [] => String::new(),
+        [c] => c.name.to_string(),
+        [first @ .., last] => {
+            let first: Vec<_> = first.iter().map(|c| c.name).collect();
+            format!("{} or {}", first.join(", "), last.name)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn built_in_load_addresses() {
+        let rk3399 = by_pid(0x330c).unwrap();
+        assert_eq!(
+            rk3399.target(Region::Sram),
+            Some((0xff8c_2000, Some(0x2_e000)))
+        );
+        assert_eq!(rk3399.target(Region::Dram), Some((0, None)));
+        let rk3588 = by_pid(0x350b).unwrap();
+        assert_eq!(rk3588.target(Region::Sram), None);
+        assert!(rk3588.sram.is_some() && rk3588.uid.is_some());
+        for c in CHIPS {
+            if let (Some((base, size)), Some(load)) = (c.sram, c.sram_load) {
+                assert!((base..base + size).contains(&load), "{}", c.name);
+            }
+        }
+    }
+
+    #[test]
+    fn chips_are_told_apart() {
+        assert_eq!(by_pid(0x350b).unwrap().name, "RK3588");
+        assert_eq!(by_pid(0x330c).unwrap().name, "RK3399");
+        assert!(by_pid(0x1234).is_none());
+        let pids: Vec<_> = CHIPS.iter().map(|c| (c.name, c.pid)).collect();
+        for (i, (name, _)) in pids.iter().enumerate() {
+            assert!(!pids[..i].iter().any(|(n, _)| n == name), "{name} twice");
+        }
+        let shared = sharing(0x350a);
+        assert_eq!(names(&shared), "RK3366, RK3566 or RK3568");
+        assert_eq!(named(&shared, "3568").unwrap().name, "RK3568");
+        assert_eq!(named(&shared, "rk3566").unwrap().name, "RK3566");
+        assert!(named(&shared, "3588").is_none());
+        assert_eq!(names(&sharing(0x350b)), "RK3588");
+        assert_eq!(names(&sharing(0x330d)), "RK3326 or PX30");
+    }
+
+    #[test]
+    fn chips_from_toml() {
+        let text = "# lab boards\n\
+            [[chip]]\n\
+            name = \"RK3576\"\n\
+            pid = 0x350e # mask ROM\n\
+            sram_base = 0x3ff8_0000\n\
+            sram_size = 0x10000\n\
+            sram_load = 0x3ff81000\n\
+            [[chip]]\n\
+            name = \"RK3399\"\n\
+            pid = 0x330c\n\
+            rkbin_prefix = \"rk33\"\n";
+        let chips = parse(text).unwrap();
+        assert_eq!(chips.len(), 2);
+        let c = &chips[0];
+        assert_eq!(
+            (c.name, c.pid, c.rkbin_prefix),
+            ("RK3576", 0x350e, "rk3576")
+        );
+        assert_eq!(c.target(Region::Sram), Some((0x3ff81000, Some(0xf000))));
+        assert_eq!(c.target(Region::Dram), None);
+        assert_eq!(chips[1].rkbin_prefix, "rk33");
+
+        assert!(parse("name = \"X\"\n").is_err());
+        assert!(parse("pid = \"1\"\n").is_err());
+        assert!(parse("name = \"X\"\npid = 1\nsram_base = 0\n").is_err());
+        assert!(parse("name = \"X\"\nusb = 2\n").is_err());
+
+        let dir = std::env::temp_dir().join(format!("rk_chips_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.toml"), "name = \"RK9999\"\npid = 0x9999\n").unwrap();
+        std::fs::write(dir.join("b.toml"), "pid = oops\n").unwrap();
+        std::fs::write(dir.join("notes.txt"), "pid = 1\n").unwrap();
+        let chips = load_dir(&dir);
+        assert_eq!(chips.iter().map(|c| c.name).collect::<Vec<_>>(), ["RK9999"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,156 @@
+//! Forward command lines to a daemon started via `rk_boot serve`
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+
+use log::{debug, info};
+
+use crate::json::{self, Value};
+use crate::{cache, size};
+
+/// Send a request; returns the status code and a reader for the body
+fn request(
+    remote: &str,
+    method: &str,
+    path: &str,
+    body: &mut dyn Read,
+    len: u64,
+) -> io::Result<(u16, BufReader<TcpStream>)> {
+    let mut s = TcpStream::connect(remote)?;
+    let auth = match std::env::var("RK_BOOT_TOKEN") {
+        Ok(t) => format!("Authorization: Bearer {}\r\n", t.trim()),
+        Err(_) => String::new(),
+    };
+    write!(
+        s,
+        "{method} {path} HTTP/1.1\r\nHost: {remote}\r\n{auth}Content-Length: {len}\r\nConnection: close\r\n\r\n"
+    )?;
+    io::copy(body, &mut s)?;
+
+    let mut r = BufReader::new(s);
+    let mut line = String::new();
+    r.read_line(&mut line)?;
+    let status = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .ok_or(io::Error::other(format!("bad response {line:?}")))?;
+    loop {
+        let mut h = String::new();
+        r.read_line(&mut h)?;
+        if h.trim_end().is_empty() {
+            break;
+        }
+    }
+    Ok((status, r))
+}
+
+fn request_json(remote: &str, method: &str, path: &str, body: &str) -> Result<Value, String> {
+    let (status, mut r) = request(
+        remote,
+        method,
+        path,
+        &mut body.as_bytes(),
+        body.len() as u64,
+    )
+    .map_err(|e| format!("{remote}: {e}"))?;
+    let mut text = String::new();
+    r.read_to_string(&mut text).map_err(|e| e.to_string())?;
+    let v = json::parse(&text)?;
+    if status >= 400 {
+        let e = v.get("error").and_then(Value::as_str).unwrap_or("unknown");
+        return Err(format!("{remote}: {status} {e}"));
+    }
+    Ok(v)
+}
+
+/// Make a local file available on the daemon, unless it has it already
+fn upload(remote: &str, file: &Path) -> Result<String, String> {
+    let digest = cache::digest_of(file).map_err(|e| format!("{}: {e}", file.display()))?;
+    let path = format!("/blobs/{digest}");
+    let reference = cache::reference(&digest);
+    if request_json(remote, "GET", &path, "").is_ok() {
+        info!("{} is on {remote} already as {reference}", file.display());
+        return Ok(reference);
+    }
+    let mut f = std::fs::File::open(file).map_err(|e| e.to_string())?;
+    let len = f.metadata().map_err(|e| e.to_string())?.len();
+    info!(
+        "Upload {} ({}) as {reference}",
+        file.display(),
+        size::human(len)
+    );
+    let (status, _) =
+        request(remote, "PUT", &path, &mut f, len).map_err(|e| format!("{remote}: {e}"))?;
+    if status >= 400 {
+        return Err(format!(
+            "{remote}: upload of {} failed with {status}",
+            file.display()
+        ));
+    }
+    Ok(reference)
+}
+
+/// The arguments of this process without `--remote`
+pub fn forwarded_args() -> Vec<String> {
+    let mut args = Vec::new();
+    let mut it = std::env::args().skip(1);
+    while let Some(a) = it.next() {
+        if a == "--remote" {
+            it.next();
+        } else if !a.starts_with("--remote=") {
+            args.push(a);
+        }
+    }
+    args
+}
+
+/// Run a command line on the daemon and follow its log. Local files among
+/// the arguments are uploaded first; output files stay on the daemon.
+pub fn run(remote: &str, args: Vec<String>) -> Result<(), String> {
+    let args = args
+        .into_iter()
+        .map(|a| {
+            let p = Path::new(&a);
+            if p.is_file() {
+                upload(remote, p)
+            } else {
+                Ok(a)
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    debug!("Remote args: {args:?}");
+
+    let body = json::obj([("args", args.into())]).to_string();
+    let job = request_json(remote, "POST", "/jobs", &body)?;
+    let id = job.get("id").ok_or("no job ID in response")?;
+    info!("Job {id} on {remote}");
+
+    let (_, r) = request(
+        remote,
+        "GET",
+        &format!("/jobs/{id}/log"),
+        &mut io::empty(),
+        0,
+    )
+    .map_err(|e| format!("{remote}: {e}"))?;
+    // The log ends with the job's final state.
+    let mut last = String::new();
+    for l in r.lines() {
+        let l = l.map_err(|e| e.to_string())?;
+        if !last.is_empty() {
+            eprintln!("{last}");
+        }
+        last = l;
+    }
+    let job = json::parse(&last)?;
+    match job.get("status").and_then(Value::as_str) {
+        Some("done") => Ok(()),
+        _ => Err(job
+            .get("error")
+            .and_then(Value::as_str)
+            .unwrap_or("job failed")
+            .to_string()),
+    }
+}
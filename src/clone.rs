@@ -0,0 +1,338 @@
+//! Read the whole storage into an image, leaving out all-zero blocks
+//!
+//! Raw images become sparse files whose holes read back as zeros. Android
+//! sparse images (`.simg`) store zero runs as fill chunks instead, which
+//! fastboot and simg2img understand; their size is rounded up to 4 KiB.
+//! Zstandard images (`.zst`) keep zero runs as RLE blocks and the rest as
+//! raw blocks; that is all the compression there is, but any zstd reads
+//! them.
+//! Cloning into another device reads the source in a thread of its own,
+//! with a few windows in flight, while the target is written.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::mpsc::{Receiver, sync_channel};
+
+use clap::ValueEnum;
+use log::info;
+
+use crate::flash::{self, check_capacity, check_writable};
+use crate::metrics::Failure;
+use crate::protocol::{self, SECTOR_SIZE};
+use crate::session::Session;
+use crate::{deadline, progress, size};
+
+const BLOCK: usize = 4096;
+const WINDOW_SECTORS: u32 = 8192;
+
+pub const SPARSE_MAGIC: u32 = 0xed26ff3a;
+const SPARSE_HEADER_SIZE: u16 = 28;
+const CHUNK_HEADER_SIZE: u16 = 12;
+const CHUNK_RAW: u16 = 0xcac1;
+const CHUNK_FILL: u16 = 0xcac2;
+/// Windows read ahead from the source device
+const IN_FLIGHT: usize = 2;
+
+/// RFC 8878
+const ZSTD_MAGIC: u32 = 0xfd2fb528;
+/// No content size, checksum or dictionary; a window of 128 KiB
+const ZSTD_FRAME_HEADER: [u8; 2] = [0x00, (17 - 10) << 3];
+/// Largest block the window allows
+const ZSTD_BLOCK: usize = 128 * 1024;
+const ZSTD_RAW: u32 = 0;
+const ZSTD_RLE: u32 = 1;
+
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    Raw,
+    AndroidSparse,
+    Zstd,
+}
+
+impl Format {
+    /// Android sparse for `.simg` files, Zstandard for `.zst`, raw
+    /// otherwise
+    pub fn for_path(p: &Path) -> Self {
+        match p.extension().and_then(|e| e.to_str()) {
+            Some("simg") => Self::AndroidSparse,
+            Some("zst") => Self::Zstd,
+            _ => Self::Raw,
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub bytes: u64,
+    pub zero: u64,
+}
+
+fn io_err(e: std::io::Error) -> String {
+    format!("Cannot write image: {e}")
+}
+
+fn sparse_header(blocks: u32, chunks: u32) -> Vec<u8> {
+    let mut h = SPARSE_MAGIC.to_le_bytes().to_vec();
+    h.extend_from_slice(&1_u16.to_le_bytes());
+    h.extend_from_slice(&0_u16.to_le_bytes());
+    h.extend_from_slice(&SPARSE_HEADER_SIZE.to_le_bytes());
+    h.extend_from_slice(&CHUNK_HEADER_SIZE.to_le_bytes());
+    h.extend_from_slice(&(BLOCK as u32).to_le_bytes());
+    h.extend_from_slice(&blocks.to_le_bytes());
+    h.extend_from_slice(&chunks.to_le_bytes());
+    // No checksum
+    h.extend_from_slice(&0_u32.to_le_bytes());
+    h
+}
+
+fn chunk_header(kind: u16, blocks: usize, data: usize) -> Vec<u8> {
+    let mut h = kind.to_le_bytes().to_vec();
+    h.extend_from_slice(&0_u16.to_le_bytes());
+    h.extend_from_slice(&(blocks as u32).to_le_bytes());
+    h.extend_from_slice(&((CHUNK_HEADER_SIZE as usize + data) as u32).to_le_bytes());
+    h
+}
+
+/// A Zstandard block: a zero run as RLE, anything else as it is
+fn zstd_block(data: &[u8], zero: bool, last: bool) -> Vec<u8> {
+    let (kind, body) = match zero {
+        true => (ZSTD_RLE, &[0][..]),
+        false => (ZSTD_RAW, data),
+    };
+    let h = (data.len() as u32) << 3 | kind << 1 | last as u32;
+    let mut b = h.to_le_bytes()[..3].to_vec();
+    b.extend_from_slice(body);
+    b
+}
+
+/// Copy `sectors` sectors of storage to `out`
+pub fn clone(
+    read: &mut dyn FnMut(u32, u32) -> Result<Vec<u8>, Failure>,
+    sectors: u64,
+    out: &mut File,
+    format: Format,
+) -> Result<Stats, Failure> {
+    let mut stats = Stats::default();
+    let mut chunks = 0;
+    match format {
+        Format::AndroidSparse => out.write_all(&sparse_header(0, 0)).map_err(io_err)?,
+        Format::Zstd => out
+            .write_all(&ZSTD_MAGIC.to_le_bytes())
+            .and_then(|()| out.write_all(&ZSTD_FRAME_HEADER))
+            .map_err(io_err)?,
+        Format::Raw => {}
+    }
+    let mut lba = 0;
+    while lba < sectors {
+        let n = (sectors - lba).min(WINDOW_SECTORS as u64) as u32;
+        let mut d = read(lba as u32, n)?;
+        if format == Format::AndroidSparse {
+            d.resize(d.len().next_multiple_of(BLOCK), 0);
+        }
+        // Runs of blocks that are all zero or not
+        let mut runs: Vec<(bool, usize)> = Vec::new();
+        for b in d.chunks(BLOCK) {
+            let zero = b.iter().all(|&x| x == 0);
+            match runs.last_mut() {
+                Some((z, len)) if *z == zero => *len += b.len(),
+                _ => runs.push((zero, b.len())),
+            }
+        }
+        let mut at = 0;
+        for (zero, len) in runs {
+            let data = &d[at..at + len];
+            match (format, zero) {
+                (Format::Raw, true) => {
+                    out.seek(SeekFrom::Current(len as i64)).map_err(io_err)?;
+                }
+                (Format::Raw, false) => out.write_all(data).map_err(io_err)?,
+                (Format::Zstd, _) => {
+                    for b in data.chunks(ZSTD_BLOCK) {
+                        out.write_all(&zstd_block(b, zero, false)).map_err(io_err)?;
+                    }
+                }
+                (Format::AndroidSparse, true) => {
+                    out.write_all(&chunk_header(CHUNK_FILL, len / BLOCK, 4))
+                        .and_then(|_| out.write_all(&0_u32.to_le_bytes()))
+                        .map_err(io_err)?;
+                    chunks += 1;
+                }
+                (Format::AndroidSparse, false) => {
+                    out.write_all(&chunk_header(CHUNK_RAW, len / BLOCK, len))
+                        .and_then(|_| out.write_all(data))
+                        .map_err(io_err)?;
+                    chunks += 1;
+                }
+            }
+            stats.zero += if zero { len as u64 } else { 0 };
+            at += len;
+        }
+        stats.bytes += d.len() as u64;
+        lba += n as u64;
+        deadline::progress(format!("cloned {lba} of {sectors} sectors"));
+        let b = SECTOR_SIZE as u64;
+        progress::advance(lba * b, sectors * b);
+    }
+    match format {
+        Format::Raw => out.set_len(stats.bytes).map_err(io_err)?,
+        // An empty last block ends the frame.
+        Format::Zstd => out
+            .write_all(&zstd_block(&[], false, true))
+            .map_err(io_err)?,
+        Format::AndroidSparse => {
+            let blocks = (stats.bytes / BLOCK as u64) as u32;
+            out.seek(SeekFrom::Start(0))
+                .and_then(|_| out.write_all(&sparse_header(blocks, chunks)))
+                .map_err(io_err)?;
+        }
+    }
+    info!(
+        "Cloned {}, {} of them in all-zero blocks",
+        size::human(stats.bytes),
+        size::human(stats.zero)
+    );
+    Ok(stats)
+}
+
+/// Clone into a new file at `path`
+pub fn to_file(
+    read: &mut dyn FnMut(u32, u32) -> Result<Vec<u8>, Failure>,
+    sectors: u64,
+    path: &Path,
+    format: Option<Format>,
+) -> Result<Stats, Failure> {
+    let format = format.unwrap_or(Format::for_path(path));
+    info!(
+        "Clone {} to {} as {format:?}",
+        size::human(sectors * SECTOR_SIZE as u64),
+        path.display()
+    );
+    let mut f = File::create(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    let res = clone(read, sectors, &mut f, format);
+    if res.is_err() {
+        // Half an image is of no use and may be large.
+        let _ = std::fs::remove_file(path);
+    }
+    res
+}
+
+/// What the reading thread has sent, as a stream
+struct Windows {
+    rx: Receiver<Vec<u8>>,
+    cur: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for Windows {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.cur.len() {
+            match self.rx.recv() {
+                Ok(d) => (self.cur, self.pos) = (d, 0),
+                // The source stopped early; its error tells why.
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.cur.len() - self.pos);
+        buf[..n].copy_from_slice(&self.cur[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Copy all of the source device's storage to the target device
+pub fn between(from: &Session, to: &Session, delta: bool) -> Result<flash::Stats, Failure> {
+    let sectors = protocol::flash_info(from)?.sectors as u64;
+    check_capacity(to, 0, sectors)?;
+    check_writable(to, 0)?;
+    info!("Clone {}", size::human(sectors * SECTOR_SIZE as u64));
+    let (tx, rx) = sync_channel(IN_FLIGHT);
+    std::thread::scope(|s| {
+        let source = s.spawn(move || -> Result<(), Failure> {
+            let mut lba = 0;
+            while lba < sectors {
+                let n = (sectors - lba).min(WINDOW_SECTORS as u64) as u32;
+                if tx.send(protocol::read_lba(from, lba as u32, n)?).is_err() {
+                    break;
+                }
+                lba += n as u64;
+            }
+            Ok(())
+        });
+        let mut src = Windows {
+            rx,
+            cur: Vec::new(),
+            pos: 0,
+        };
+        let opts = flash::Options {
+            delta,
+            ..Default::default()
+        };
+        let len = sectors * SECTOR_SIZE as u64;
+        let res = flash::write_stream(to, 0, &mut src, len, &opts);
+        // Unblock the source if the target gave up first.
+        drop(src);
+        source.join().unwrap()?;
+        let stats = res?;
+        info!(
+            "Cloned {}, wrote {}, {} unchanged",
+            size::human(len),
+            size::human(stats.written),
+            size::human(stats.unchanged)
+        );
+        Ok(stats)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What a frame of raw and RLE blocks holds
+    fn unzstd(d: &[u8]) -> Vec<u8> {
+        assert_eq!(d[..4], ZSTD_MAGIC.to_le_bytes());
+        assert_eq!(d[4..6], ZSTD_FRAME_HEADER);
+        let (mut out, mut at) = (vec![], 6);
+        loop {
+            let h = u32::from_le_bytes([d[at], d[at + 1], d[at + 2], 0]);
+            let len = (h >> 3) as usize;
+            at += 3;
+            match (h >> 1) & 3 {
+                ZSTD_RAW => {
+                    out.extend_from_slice(&d[at..at + len]);
+                    at += len;
+                }
+                ZSTD_RLE => {
+                    out.resize(out.len() + len, d[at]);
+                    at += 1;
+                }
+                k => panic!("block type {k}"),
+            }
+            if h & 1 != 0 {
+                assert_eq!(at, d.len());
+                return out;
+            }
+        }
+    }
+
+    #[test]
+    fn zstd_keeps_zeros_as_runs() {
+        assert_eq!(Format::for_path(Path::new("a.zst")), Format::Zstd);
+        let sectors = 3 * WINDOW_SECTORS as u64 + 5;
+        let mut disk = vec![0u8; sectors as usize * SECTOR_SIZE];
+        disk[5000..9000].fill(0x5a);
+        let end = disk.len();
+        disk[end - 700..end - 3].fill(7);
+        let read = &mut |lba: u32, n: u32| {
+            let at = lba as usize * SECTOR_SIZE;
+            Ok(disk[at..at + n as usize * SECTOR_SIZE].to_vec())
+        };
+        let path = std::env::temp_dir().join(format!("rk_clone_{}.zst", std::process::id()));
+        let stats = to_file(read, sectors, &path, None).unwrap();
+        let img = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(stats.bytes, disk.len() as u64);
+        assert!(img.len() < 3 * BLOCK);
+        assert!(unzstd(&img) == disk);
+    }
+}
@@ -0,0 +1,141 @@
+//! Time limit for a whole operation
+//!
+//! The deadline belongs to the thread running the command, so that jobs of
+//! the daemon each have their own. USB transfers give up once it has
+//! passed; the failure tells how far the operation got, as long-running
+//! steps note their progress. A command run on its own also keeps that
+//! progress in a file, with the options that continue where it stopped,
+//! e.g. `--resume-at` for a write.
+
+use std::cell::{Cell, RefCell};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::json;
+use crate::metrics::Failure;
+
+/// Where a command run on its own keeps its progress when out of time
+pub const STATE_FILE: &str = "rk_boot-deadline.json";
+
+#[derive(Default)]
+struct Progress {
+    done: String,
+    /// Options to continue with
+    resume: Option<String>,
+}
+
+/// Deadline of each job or step of the daemons that do not set their own
+static PER_JOB: OnceLock<Duration> = OnceLock::new();
+
+thread_local! {
+    static DEADLINE: Cell<Option<(Instant, Duration)>> = const { Cell::new(None) };
+    static PROGRESS: RefCell<Progress> = RefCell::default();
+    static STATE: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+}
+
+/// Durations like `90`, `90s`, `10m`, `1h` or `500ms`
+pub fn parse(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (n, unit) = s.split_at(split);
+    let n: u64 = n.parse().map_err(|_| format!("bad duration {s:?}"))?;
+    Ok(match unit {
+        "ms" => Duration::from_millis(n),
+        "" | "s" => Duration::from_secs(n),
+        "m" => Duration::from_secs(n * 60),
+        "h" => Duration::from_secs(n * 3600),
+        _ => return Err(format!("bad unit in {s:?}, use ms, s, m or h")),
+    })
+}
+
+/// Start the clock for this thread
+pub fn set(limit: Duration) {
+    DEADLINE.set(Some((Instant::now() + limit, limit)));
+    PROGRESS.take();
+}
+
+/// Give jobs without a deadline of their own this one; only the first
+/// call counts
+pub fn set_per_job(limit: Duration) {
+    let _ = PER_JOB.set(limit);
+}
+
+pub fn per_job() -> Option<Duration> {
+    PER_JOB.get().copied()
+}
+
+/// Keep the progress in `file` when the deadline passes
+pub fn keep_in(file: Option<PathBuf>) {
+    STATE.set(file);
+}
+
+/// Note how far the operation got, for when it runs out of time
+pub fn progress(p: String) {
+    PROGRESS.with_borrow_mut(|s| s.done = p);
+}
+
+/// Note the options that continue the operation from here, such as
+/// `--resume-at 0x4000`
+pub fn resume_with(opts: String) {
+    PROGRESS.with_borrow_mut(|s| s.resume = Some(opts));
+}
+
+/// Write the progress to the state file, if there is one; returns it
+fn save(limit: Duration, p: &Progress) -> Option<PathBuf> {
+    let file = STATE.with_borrow(Clone::clone)?;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let v = json::obj([
+        ("time", jiff::Timestamp::now().to_string().into()),
+        ("args", args.join(" ").into()),
+        ("deadline_ms", (limit.as_millis() as u64).into()),
+        ("progress", p.done.as_str().into()),
+        (
+            "resume",
+            p.resume.as_deref().map_or(json::Value::Null, Into::into),
+        ),
+    ]);
+    match std::fs::write(&file, format!("{v}\n")) {
+        Ok(()) => Some(file),
+        Err(e) => {
+            warn!("{}: {e}", file.display());
+            None
+        }
+    }
+}
+
+pub fn expired() -> bool {
+    DEADLINE.get().is_some_and(|(d, _)| Instant::now() >= d)
+}
+
+/// At most `timeout`, and no longer than the deadline leaves
+pub fn clamp(timeout: Duration) -> Duration {
+    match DEADLINE.get() {
+        Some((d, _)) => timeout.min(d.saturating_duration_since(Instant::now())),
+        None => timeout,
+    }
+}
+
+/// Fail once the deadline has passed
+pub fn check() -> Result<(), Failure> {
+    if let Some((_, limit)) = DEADLINE.get()
+        && expired()
+    {
+        let mut msg = format!("Deadline of {limit:?} exceeded, operation timed out");
+        PROGRESS.with_borrow(|p| {
+            if !p.done.is_empty() {
+                msg += &format!("; {}", p.done);
+            }
+            if let Some(r) = &p.resume {
+                msg += &format!("; continue with {r}");
+            }
+            if let Some(f) = save(limit, p) {
+                msg += &format!("; progress kept in {}", f.display());
+            }
+        });
+        return Err(Failure::Timeout(msg));
+    }
+    Ok(())
+}
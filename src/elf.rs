@@ -0,0 +1,209 @@
+//! Flatten ELF executables into the raw binaries that the mask ROM and the
+//! loader take
+//!
+//! Loadable segments are placed by physical address, gaps between them
+//! filled with zeros. Zero-initialised data after the last segment's file
+//! contents is left to the code's startup.
+
+const MAGIC: &[u8; 4] = b"\x7fELF";
+const PT_LOAD: u32 = 1;
+// Anything larger is most likely segments far apart, e.g. SRAM and DRAM.
+const MAX_SIZE: u64 = 64 * 1024 * 1024;
+
+pub struct Image {
+    pub load: u32,
+    pub entry: u32,
+    pub data: Vec<u8>,
+}
+
+struct Segment {
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+}
+
+pub fn is_elf(d: &[u8]) -> bool {
+    d.starts_with(MAGIC)
+}
+
+fn get(d: &[u8], at: usize, len: usize) -> Result<u64, String> {
+    let b = at
+        .checked_add(len)
+        .and_then(|end| d.get(at..end))
+        .ok_or("ELF file is truncated")?;
+    Ok(b.iter().rev().fold(0, |v, &x| v << 8 | x as u64))
+}
+
+pub fn flatten(d: &[u8]) -> Result<Image, String> {
+    let wide = match d.get(4) {
+        Some(1) => false,
+        Some(2) => true,
+        _ => return Err("Unknown ELF class".into()),
+    };
+    if d.get(5) != Some(&1) {
+        return Err("Only little-endian ELF files are supported".into());
+    }
+    let w = if wide { 8 } else { 4 };
+    let entry = get(d, 24, w)?;
+    let phoff = get(d, 24 + w, w)? as usize;
+    let (phentsize, phnum) = match wide {
+        true => (get(d, 54, 2)?, get(d, 56, 2)?),
+        false => (get(d, 42, 2)?, get(d, 44, 2)?),
+    };
+
+    let mut segments = Vec::new();
+    for n in 0..phnum as usize {
+        let p = (phentsize as usize)
+            .checked_mul(n)
+            .and_then(|o| o.checked_add(phoff))
+            .ok_or("ELF program headers are out of the file")?;
+        if get(d, p, 4)? as u32 != PT_LOAD {
+            continue;
+        }
+        let s = match wide {
+            true => Segment {
+                offset: get(d, p + 8, 8)?,
+                vaddr: get(d, p + 16, 8)?,
+                paddr: get(d, p + 24, 8)?,
+                filesz: get(d, p + 32, 8)?,
+            },
+            false => Segment {
+                offset: get(d, p + 4, 4)?,
+                vaddr: get(d, p + 8, 4)?,
+                paddr: get(d, p + 12, 4)?,
+                filesz: get(d, p + 16, 4)?,
+            },
+        };
+        if s.paddr.checked_add(s.filesz).is_none()
+            || s.vaddr.checked_add(s.filesz).is_none()
+            || s.offset.checked_add(s.filesz).is_none()
+        {
+            return Err(format!(
+                "ELF segment at {:#x} of {:#x} bytes overflows",
+                s.paddr, s.filesz
+            ));
+        }
+        if s.filesz > 0 {
+            segments.push(s);
+        }
+    }
+    let load = segments
+        .iter()
+        .map(|s| s.paddr)
+        .min()
+        .ok_or("ELF file has nothing to load")?;
+    let end = segments.iter().map(|s| s.paddr + s.filesz).max().unwrap();
+    if end - load > MAX_SIZE {
+        return Err(format!(
+            "Segments span {:#x}..{end:#x}, too far apart for one binary",
+            load
+        ));
+    }
+    if end > u32::MAX as u64 + 1 {
+        return Err(format!("Segments end at {end:#x}, beyond 32-bit addresses"));
+    }
+
+    let mut data = vec![0; (end - load) as usize];
+    for s in &segments {
+        let src = d
+            .get(s.offset as usize..(s.offset + s.filesz) as usize)
+            .ok_or("ELF segment is out of the file")?;
+        let at = (s.paddr - load) as usize;
+        data[at..at + src.len()].copy_from_slice(src);
+    }
+    // The entry point is a virtual address; the code is placed physically.
+    let entry = segments
+        .iter()
+        .find(|s| (s.vaddr..s.vaddr + s.filesz).contains(&entry))
+        .map_or(entry, |s| entry - s.vaddr + s.paddr);
+    Ok(Image {
+        load: load as u32,
+        entry: entry as u32,
+        data,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 32-bit ELF file with one loadable segment
+    fn elf32(paddr: u32, filesz: u32, offset: u32) -> Vec<u8> {
+        let mut d = vec![0; 0x60];
+        d[..4].copy_from_slice(MAGIC);
+        d[4] = 1;
+        d[5] = 1;
+        d[24..28].copy_from_slice(&paddr.wrapping_add(4).to_le_bytes());
+        d[28..32].copy_from_slice(&0x34u32.to_le_bytes());
+        d[42..44].copy_from_slice(&32u16.to_le_bytes());
+        d[44..46].copy_from_slice(&1u16.to_le_bytes());
+        let p = 0x34;
+        d[p..p + 4].copy_from_slice(&PT_LOAD.to_le_bytes());
+        d[p + 4..p + 8].copy_from_slice(&offset.to_le_bytes());
+        d[p + 8..p + 12].copy_from_slice(&paddr.to_le_bytes());
+        d[p + 12..p + 16].copy_from_slice(&paddr.to_le_bytes());
+        d[p + 16..p + 20].copy_from_slice(&filesz.to_le_bytes());
+        d.extend_from_slice(b"code");
+        d
+    }
+
+    #[test]
+    fn flattens() {
+        let i = flatten(&elf32(0xff8c_2000, 4, 0x60)).unwrap();
+        assert_eq!((i.load, i.entry), (0xff8c_2000, 0xff8c_2004));
+        assert_eq!(i.data, b"code");
+    }
+
+    #[test]
+    fn rejects_malformed() {
+        assert!(flatten(b"\x7fELF").is_err());
+        assert!(flatten(&elf32(0x1000, 4, 0x60)[..0x40]).is_err());
+        // Segments out of the file, or ending past any address
+        assert!(flatten(&elf32(0x1000, 8, 0x60)).is_err());
+        assert!(flatten(&elf32(0x1000, 4, u32::MAX - 1)).is_err());
+        assert!(flatten(&elf32(0xffff_fffe, 4, 0x60)).is_err());
+        let mut d = elf32(0x1000, 4, 0x60);
+        d[4] = 2;
+        d[32..40].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(flatten(&d).is_err());
+    }
+
+    #[test]
+    fn elf_is_flattened() {
+        // ELF32 with two loadable segments, a gap between them, and a note
+        let mut d = vec![0; 0x200];
+        d[..6].copy_from_slice(b"\x7fELF\x01\x01");
+        let put =
+            |d: &mut Vec<u8>, at: usize, v: u32| d[at..at + 4].copy_from_slice(&v.to_le_bytes());
+        put(&mut d, 24, 0x8000_0010);
+        put(&mut d, 28, 0x34);
+        d[42] = 32;
+        d[44] = 3;
+        // type, offset, vaddr, paddr, filesz
+        for (n, ph) in [
+            [1, 0x100, 0x8000_0000, 0xff00_1000, 0x20],
+            [4, 0x180, 0, 0, 0x10],
+            [1, 0x140, 0x8000_0040, 0xff00_1040, 0x8],
+        ]
+        .iter()
+        .enumerate()
+        {
+            for (k, v) in ph.iter().enumerate() {
+                put(&mut d, 0x34 + n * 32 + k * 4, *v);
+            }
+        }
+        d[0x100..0x120].fill(0xaa);
+        d[0x140..0x148].fill(0xbb);
+
+        let img = flatten(&d).unwrap();
+        assert_eq!((img.load, img.entry), (0xff00_1000, 0xff00_1010));
+        let mut expected = vec![0xaa; 0x20];
+        expected.resize(0x40, 0);
+        expected.extend([0xbb; 8]);
+        assert_eq!(img.data, expected);
+
+        d[5] = 2;
+        assert!(flatten(&d).is_err());
+    }
+}
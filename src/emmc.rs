@@ -0,0 +1,218 @@
+//! Decode eMMC registers (JEDEC JESD84-B51) for identity and wear
+//!
+//! The loaders have no request for the card registers, so they come from
+//! dumps taken on a running system; on a device, only the flash info the
+//! loader reports is shown.
+//!
+//! CID and CSD are given as 32 hex digits, most significant byte first, as
+//! in `/sys/class/mmc_host/mmc0/mmc0:0001/{cid,csd}` on a running system.
+//! EXT_CSD is 512 bytes, raw or as hex text like debugfs' `ext_csd`.
+
+use std::path::Path;
+
+use crate::protocol::{FlashInfo, SECTOR_SIZE};
+use crate::size;
+
+const EXT_CSD_SIZE: usize = 512;
+// Byte offsets into EXT_CSD
+const EXT_CSD_REV: usize = 192;
+const SEC_COUNT: usize = 212;
+const PRE_EOL_INFO: usize = 267;
+const LIFE_TIME_EST_A: usize = 268;
+const LIFE_TIME_EST_B: usize = 269;
+
+pub fn parse_hex(s: &str) -> Result<Vec<u8>, String> {
+    let h: String = s.chars().filter(|c| !c.is_whitespace()).collect();
+    let h = h.trim_start_matches("0x");
+    if let Some(c) = h.chars().find(|c| !c.is_ascii_hexdigit()) {
+        return Err(format!("{c:?} is not a hex digit in {s:?}"));
+    }
+    if !h.len().is_multiple_of(2) {
+        return Err(format!("odd number of hex digits in {s:?}"));
+    }
+    (0..h.len())
+        .step_by(2)
+        .map(|n| u8::from_str_radix(&h[n..n + 2], 16).map_err(|e| format!("{s:?}: {e}")))
+        .collect()
+}
+
+fn register(s: &str) -> Result<[u8; 16], String> {
+    parse_hex(s)?
+        .try_into()
+        .map_err(|_| format!("expected 16 bytes in {s:?}"))
+}
+
+fn load_ext_csd(f: &Path) -> Result<Vec<u8>, String> {
+    let d = std::fs::read(f).map_err(|e| format!("{}: {e}", f.display()))?;
+    let d = match d.len() {
+        EXT_CSD_SIZE => d,
+        _ => parse_hex(&String::from_utf8_lossy(&d))?,
+    };
+    if d.len() != EXT_CSD_SIZE {
+        return Err(format!("EXT_CSD must be {EXT_CSD_SIZE} bytes"));
+    }
+    Ok(d)
+}
+
+/// Bits `hi` down to `lo` of a 128-bit register, bit 127 first
+fn bits(r: &[u8; 16], hi: u32, lo: u32) -> u32 {
+    let v = u128::from_be_bytes(*r);
+    ((v >> lo) & ((1 << (hi - lo + 1)) - 1)) as u32
+}
+
+fn manufacturer(mid: u8) -> &'static str {
+    match mid {
+        0x02 | 0x45 => "SanDisk",
+        0x11 => "Kioxia (Toshiba)",
+        0x13 | 0xfe => "Micron",
+        0x15 => "Samsung",
+        0x70 => "Kingston",
+        0x88 => "Foresee",
+        0x90 => "SK Hynix",
+        0x9b => "YMTC",
+        _ => "unknown",
+    }
+}
+
+/// Lines of `name: value` describing a CID
+fn decode_cid(cid: &str, ext_csd_rev: Option<u8>) -> Result<Vec<(String, String)>, String> {
+    let r = register(cid)?;
+    let mid = bits(&r, 127, 120) as u8;
+    let name: String = r[3..9].iter().map(|&c| c as char).collect();
+    let prv = bits(&r, 55, 48);
+    let month = bits(&r, 15, 12);
+    // The year field wraps around; from revision 5, it counts from 2013.
+    let mut year = 1997 + bits(&r, 11, 8);
+    if ext_csd_rev.is_some_and(|rev| rev >= 5) && year < 2010 {
+        year += 16;
+    }
+    Ok(vec![
+        (
+            "Manufacturer".into(),
+            format!("{} ({mid:#04x})", manufacturer(mid)),
+        ),
+        ("OEM ID".into(), format!("{:#04x}", bits(&r, 111, 104))),
+        ("Product".into(), name.trim().to_string()),
+        ("Revision".into(), format!("{}.{}", prv >> 4, prv & 0xf)),
+        ("Serial".into(), format!("{:#010x}", bits(&r, 47, 16))),
+        ("Manufactured".into(), format!("{year}-{month:02}")),
+    ])
+}
+
+/// Capacity in bytes from a CSD; None means it is in EXT_CSD instead
+fn csd_capacity(csd: &str) -> Result<Option<u64>, String> {
+    let r = register(csd)?;
+    let c_size = bits(&r, 73, 62) as u64;
+    if c_size == 0xfff {
+        return Ok(None);
+    }
+    let mult = 1 << (bits(&r, 49, 47) + 2);
+    let block_len = 1 << bits(&r, 83, 80);
+    Ok(Some((c_size + 1) * mult * block_len))
+}
+
+fn life_time(v: u8) -> String {
+    match v {
+        0 => "not reported".into(),
+        1..=10 => format!("{}-{}% used", (v - 1) * 10, v * 10),
+        11 => "exceeded".into(),
+        _ => format!("reserved value {v:#04x}"),
+    }
+}
+
+fn decode_ext_csd(d: &[u8]) -> Vec<(String, String)> {
+    let sectors = u32::from_le_bytes(d[SEC_COUNT..SEC_COUNT + 4].try_into().unwrap());
+    let pre_eol = match d[PRE_EOL_INFO] {
+        0 => "not reported".to_string(),
+        1 => "normal".into(),
+        2 => "warning, 80% of reserved blocks consumed".into(),
+        3 => "urgent, 90% of reserved blocks consumed".into(),
+        v => format!("reserved value {v:#04x}"),
+    };
+    vec![
+        ("EXT_CSD revision".into(), d[EXT_CSD_REV].to_string()),
+        ("Capacity".into(), size(sectors as u64 * SECTOR_SIZE as u64)),
+        ("Pre-EOL".into(), pre_eol),
+        ("Life time (SLC)".into(), life_time(d[LIFE_TIME_EST_A])),
+        ("Life time (MLC)".into(), life_time(d[LIFE_TIME_EST_B])),
+    ]
+}
+
+/// Whether EXT_CSD says the storage is worn enough to be screened out
+fn worn(d: &[u8]) -> bool {
+    d[PRE_EOL_INFO] >= 2 || d[LIFE_TIME_EST_A] >= 9 || d[LIFE_TIME_EST_B] >= 9
+}
+
+fn print(rows: &[(String, String)]) {
+    for (k, v) in rows {
+        println!("{k:18} {v}");
+    }
+}
+
+/// Decode register dumps taken elsewhere, e.g. on the running system
+pub fn report(cid: Option<&str>, csd: Option<&str>, ext_csd: Option<&Path>) -> Result<(), String> {
+    let ext_csd = ext_csd.map(load_ext_csd).transpose()?;
+    if let Some(c) = cid {
+        print(&decode_cid(c, ext_csd.as_ref().map(|d| d[EXT_CSD_REV]))?);
+    }
+    if let Some(c) = csd
+        && let Some(bytes) = csd_capacity(c)?
+    {
+        print(&[("Capacity (CSD)".into(), size(bytes))]);
+    }
+    if let Some(d) = ext_csd {
+        print(&decode_ext_csd(&d));
+        if worn(&d) {
+            return Err("Storage is worn".into());
+        }
+    }
+    Ok(())
+}
+
+pub fn report_flash_info(fi: &FlashInfo) {
+    print(&decode_flash_info(fi));
+}
+
+fn decode_flash_info(fi: &FlashInfo) -> Vec<(String, String)> {
+    let sectors = fi.sectors;
+    let block = fi.block_sectors;
+    vec![
+        ("Capacity".into(), size(sectors as u64 * SECTOR_SIZE as u64)),
+        ("Block size".into(), size(block as u64 * SECTOR_SIZE as u64)),
+        (
+            "Manufacturer code".into(),
+            format!("{:#04x}", fi.manufacturer),
+        ),
+        ("Chip selects".into(), format!("{:#04x}", fi.chip_select)),
+    ]
+}
+
+fn size(bytes: u64) -> String {
+    format!("{bytes} bytes ({})", size::human(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex() {
+        assert_eq!(parse_hex("0x01 ff\n"), Ok(vec![1, 0xff]));
+        for bad in ["1", "0g", "ä0", "1ä", "0x+1"] {
+            assert!(parse_hex(bad).is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn registers() {
+        // A Samsung card of 2021
+        let cid = decode_cid("150100424a544434520b5b1a8e83af00", Some(8)).unwrap();
+        assert!(cid[0].1.starts_with("Samsung"), "{:?}", cid[0]);
+        assert!(decode_cid("1501", None).is_err());
+        assert_eq!(csd_capacity("d05e00320f5903ffffffffef92400000"), Ok(None));
+        let mut d = vec![0; EXT_CSD_SIZE];
+        assert!(!worn(&d));
+        d[LIFE_TIME_EST_B] = 10;
+        assert!(worn(&d));
+    }
+}
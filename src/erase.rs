@@ -0,0 +1,111 @@
+//! Erasing a whole device, with interlocks against erasing the wrong one
+//!
+//! The Rockchip boot area, which the mask ROM loads the first stage from,
+//! spans sectors 64 to 16383 and is kept unless asked for. On eMMC, vendor
+//! storage with serial numbers and MAC addresses lives inside it at sectors
+//! 7168 to 7679.
+
+use std::io::{BufRead, IsTerminal};
+use std::ops::Range;
+
+use log::{info, warn};
+
+use crate::metrics::Failure;
+use crate::protocol::{self, SECTOR_SIZE};
+use crate::session::Session;
+use crate::{flash, gpt, hexdump, size};
+
+pub const BOOT_AREA: Range<u32> = 64..16384;
+pub const VENDOR_STORAGE: Range<u32> = 7168..7680;
+// eMMC erases 16 MiB quickly; the default chunk is sized for SPI NOR.
+const ERASE_CHUNK_SECTORS: u16 = 32768;
+
+pub struct Options {
+    pub boot: bool,
+    pub except_vendor_storage: bool,
+    /// The flash ID, as otherwise typed in
+    pub confirm: Option<String>,
+}
+
+fn subtract(ranges: Vec<Range<u32>>, cut: &Range<u32>) -> Vec<Range<u32>> {
+    ranges
+        .into_iter()
+        .flat_map(|r| [r.start..r.end.min(cut.start), r.start.max(cut.end)..r.end])
+        .filter(|r| !r.is_empty())
+        .collect()
+}
+
+/// Sector ranges to erase on storage with `total` sectors
+pub fn ranges(total: u32, opts: &Options) -> Vec<Range<u32>> {
+    let mut r: Vec<Range<u32>> = std::iter::once(0..total).collect();
+    if !opts.boot {
+        r = subtract(r, &BOOT_AREA);
+    }
+    if opts.except_vendor_storage {
+        r = subtract(r, &VENDOR_STORAGE);
+    }
+    r
+}
+
+fn confirm(id: &str, given: Option<String>) -> Result<(), String> {
+    let answer = match given {
+        Some(a) => a,
+        None => {
+            if !std::io::stdin().is_terminal() {
+                return Err("Not a terminal; pass the flash ID via --confirm".into());
+            }
+            eprint!("Type the flash ID {id} to erase: ");
+            let mut l = String::new();
+            std::io::stdin()
+                .lock()
+                .read_line(&mut l)
+                .map_err(|e| e.to_string())?;
+            l
+        }
+    };
+    if !answer.trim().eq_ignore_ascii_case(id) {
+        return Err("Flash ID does not match, nothing erased".into());
+    }
+    Ok(())
+}
+
+pub fn erase_all(s: &Session, opts: Options) -> Result<(), Failure> {
+    let fi = protocol::flash_info(s)?;
+    let id = hexdump::hex(&protocol::flash_id(s)?);
+    let total = fi.sectors;
+    let bytes = total as u64 * SECTOR_SIZE as u64;
+
+    warn!(
+        "This destroys the data on the {} storage with flash ID {id}:",
+        size::human(bytes)
+    );
+    match gpt::read(&mut |lba, n| protocol::read_lba(s, lba as u32, n).map_err(Failure::from))? {
+        Ok(g) => {
+            for p in g.partitions() {
+                let size = p.sectors() * SECTOR_SIZE as u64;
+                warn!("  partition {}, {}", p.name(), size::human(size));
+            }
+        }
+        Err(e) => warn!("  no readable partition table ({e})"),
+    }
+    if opts.boot {
+        warn!(
+            "  the boot area, sectors {BOOT_AREA:?}; the device only boots in mask ROM mode afterwards"
+        );
+    }
+    if opts.boot && !opts.except_vendor_storage {
+        warn!("  vendor storage, sectors {VENDOR_STORAGE:?}, with serial numbers and MACs");
+    }
+    let ranges = ranges(total, &opts);
+    if let Some(r) = ranges.first() {
+        flash::check_writable(s, r.start as u64)?;
+    }
+    confirm(&id, opts.confirm.clone())?;
+
+    for r in ranges {
+        info!("Erase sectors {r:?}");
+        protocol::erase_lba_chunked(s, r.start, r.len() as u32, ERASE_CHUNK_SECTORS)?;
+    }
+    info!("Erased");
+    Ok(())
+}
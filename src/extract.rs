@@ -0,0 +1,804 @@
+//! Files from ext2/3/4 and FAT file systems on the device, read in place
+//!
+//! Only the sectors that the path leads through are read, so that telling
+//! what a returned unit runs, e.g. from `/etc/os-release`, takes moments
+//! instead of a dump of the partition. Symbolic links are followed within
+//! the file system. Nothing is written, and a journal that was not replayed
+//! is ignored; files that are encrypted or compressed cannot be read.
+
+use std::collections::VecDeque;
+use std::io::Write;
+use std::path::Path;
+
+use log::{debug, info};
+
+use crate::gpt;
+use crate::metrics::Failure;
+use crate::protocol::SECTOR_SIZE;
+
+const SS: u64 = SECTOR_SIZE as u64;
+/// Give up on larger files, which are better dumped and mounted
+const MAX_FILE_SIZE: u64 = 64 * 1024 * 1024;
+const MAX_LINKS: usize = 16;
+/// Blocks of ext4 are 1 KiB to 64 KiB
+const EXT_MAX_LOG_BLOCK: u32 = 6;
+/// Levels of an ext4 extent tree below the inode
+const EXT_MAX_DEPTH: u16 = 5;
+
+/// The file system's structures are where they are said to be, but may
+/// be cut short
+fn field<const N: usize>(d: &[u8], at: usize) -> Result<[u8; N], String> {
+    d.get(at..)
+        .and_then(|d| d.first_chunk::<N>())
+        .copied()
+        .ok_or(format!(
+            "File system structure of {} bytes cut short at {at:#x}",
+            d.len()
+        ))
+}
+
+fn get16(d: &[u8], at: usize) -> Result<u16, String> {
+    field(d, at).map(u16::from_le_bytes)
+}
+
+fn get32(d: &[u8], at: usize) -> Result<u32, String> {
+    field(d, at).map(u32::from_le_bytes)
+}
+
+/// A partition, read as bytes
+struct Volume<'a> {
+    read: &'a mut dyn FnMut(u64, u32) -> Result<Vec<u8>, Failure>,
+    first_lba: u64,
+    sectors: u64,
+}
+
+impl Volume<'_> {
+    fn bytes(&mut self, offset: u64, len: u64) -> Result<Vec<u8>, Failure> {
+        if len == 0 {
+            return Ok(vec![]);
+        }
+        let end = offset.saturating_add(len);
+        if end > self.len() {
+            return Err(format!("File system refers past its partition, to {end:#x}").into());
+        }
+        let (first, last) = (offset / SS, end.div_ceil(SS));
+        let d = (self.read)(self.first_lba + first, (last - first) as u32)?;
+        let at = (offset - first * SS) as usize;
+        Ok(d[at..at + len as usize].to_vec())
+    }
+
+    fn len(&self) -> u64 {
+        self.sectors * SS
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    File,
+    Dir,
+    Link,
+    Other,
+}
+
+/// What paths are resolved on
+trait Fs {
+    type Node: Clone;
+    fn root(&self, v: &mut Volume) -> Result<Self::Node, Failure>;
+    fn kind(&self, n: &Self::Node) -> Kind;
+    fn size(&self, n: &Self::Node) -> u64;
+    /// All of a file, link target or directory
+    fn data(&self, v: &mut Volume, n: &Self::Node) -> Result<Vec<u8>, Failure>;
+    fn entries(
+        &self,
+        v: &mut Volume,
+        dir: &Self::Node,
+    ) -> Result<Vec<(String, Self::Node)>, Failure>;
+    /// Whether a name in a directory matches
+    fn same(&self, a: &str, b: &str) -> bool {
+        a == b
+    }
+}
+
+/// What a path leads to
+#[derive(Debug, PartialEq, Eq)]
+pub enum Content {
+    File(Vec<u8>),
+    /// Names in a directory, with a slash after subdirectories
+    Dir(Vec<String>),
+}
+
+fn resolve<F: Fs>(fs: &F, v: &mut Volume, path: &str) -> Result<F::Node, Failure> {
+    let mut parts: VecDeque<String> = path.split('/').map(str::to_string).collect();
+    // The directories leading to the current one, for ".."
+    let mut stack = vec![fs.root(v)?];
+    let mut links = 0;
+    while let Some(p) = parts.pop_front() {
+        match p.as_str() {
+            "" | "." => continue,
+            ".." => {
+                if stack.len() > 1 {
+                    stack.pop();
+                }
+                continue;
+            }
+            _ => {}
+        }
+        let dir = stack.last().unwrap();
+        if fs.kind(dir) != Kind::Dir {
+            return Err(format!("{path}: not a directory before {p}").into());
+        }
+        let (_, node) = fs
+            .entries(v, dir)?
+            .into_iter()
+            .find(|(name, _)| fs.same(name, &p))
+            .ok_or(format!("{path}: {p} not found"))?;
+        if fs.kind(&node) != Kind::Link {
+            stack.push(node);
+            continue;
+        }
+        links += 1;
+        if links > MAX_LINKS {
+            return Err(format!("{path}: too many symbolic links").into());
+        }
+        let target = String::from_utf8_lossy(&fs.data(v, &node)?).to_string();
+        debug!("{p} links to {target}");
+        if target.starts_with('/') {
+            stack.truncate(1);
+        }
+        for t in target.split('/').rev() {
+            parts.push_front(t.to_string());
+        }
+    }
+    Ok(stack.pop().unwrap())
+}
+
+fn content<F: Fs>(fs: &F, v: &mut Volume, path: &str) -> Result<Content, Failure> {
+    let n = resolve(fs, v, path)?;
+    match fs.kind(&n) {
+        Kind::Dir => {
+            let mut names: Vec<String> = fs
+                .entries(v, &n)?
+                .into_iter()
+                .filter(|(name, _)| name != "." && name != "..")
+                .map(|(name, n)| match fs.kind(&n) {
+                    Kind::Dir => format!("{name}/"),
+                    _ => name,
+                })
+                .collect();
+            names.sort();
+            Ok(Content::Dir(names))
+        }
+        Kind::File if fs.size(&n) > MAX_FILE_SIZE => Err(format!(
+            "{path} has {} bytes, dump the partition instead",
+            fs.size(&n)
+        )
+        .into()),
+        Kind::File => Ok(Content::File(fs.data(v, &n)?)),
+        _ => Err(format!("{path} is neither a file nor a directory").into()),
+    }
+}
+
+const EXT_MAGIC: u16 = 0xef53;
+const EXT_INCOMPAT_COMPRESSION: u32 = 0x1;
+const EXT_INCOMPAT_META_BG: u32 = 0x10;
+const EXT_INCOMPAT_64BIT: u32 = 0x80;
+const EXT_EXTENTS_FL: u32 = 0x80000;
+const EXT_INLINE_DATA_FL: u32 = 0x1000_0000;
+const EXT_ENCRYPT_FL: u32 = 0x800;
+const EXTENT_MAGIC: u16 = 0xf30a;
+const EXT_ROOT: u32 = 2;
+
+struct Ext {
+    block: u64,
+    inodes_per_group: u64,
+    inode_size: u64,
+    desc_size: u64,
+    /// Where the group descriptors start, in bytes
+    descs: u64,
+    is64: bool,
+}
+
+#[derive(Clone, Debug)]
+struct Inode {
+    n: u32,
+    mode: u16,
+    size: u64,
+    flags: u32,
+    block: [u8; 60],
+}
+
+impl Ext {
+    fn new(sb: &[u8]) -> Result<Self, Failure> {
+        let incompat = get32(sb, 0x60)?;
+        if incompat & (EXT_INCOMPAT_COMPRESSION | EXT_INCOMPAT_META_BG) != 0 {
+            return Err(format!("Unsupported ext4 features {incompat:#x}").into());
+        }
+        let log_block = get32(sb, 0x18)?;
+        if log_block > EXT_MAX_LOG_BLOCK {
+            return Err(format!("Bad ext4 block size {log_block}").into());
+        }
+        let block = 1024 << log_block;
+        let is64 = incompat & EXT_INCOMPAT_64BIT != 0;
+        Ok(Self {
+            block,
+            inodes_per_group: get32(sb, 0x28)? as u64,
+            inode_size: match get32(sb, 0x4c)? {
+                0 => 128,
+                _ => get16(sb, 0x58)? as u64,
+            },
+            desc_size: match is64 {
+                true => get16(sb, 0xfe)? as u64,
+                false => 32,
+            },
+            descs: (get32(sb, 0x14)? as u64 + 1) * block,
+            is64,
+        })
+    }
+
+    fn inode(&self, v: &mut Volume, n: u32) -> Result<Inode, Failure> {
+        if n == 0 || self.inodes_per_group == 0 {
+            return Err(format!("Bad inode number {n}").into());
+        }
+        let (group, index) = (
+            (n as u64 - 1) / self.inodes_per_group,
+            (n as u64 - 1) % self.inodes_per_group,
+        );
+        let desc = v.bytes(self.descs + group * self.desc_size, self.desc_size)?;
+        let mut table = get32(&desc, 0x8)? as u64;
+        if self.is64 && self.desc_size >= 0x2c {
+            table |= (get32(&desc, 0x28)? as u64) << 32;
+        }
+        let at = table
+            .checked_mul(self.block)
+            .and_then(|t| t.checked_add(index * self.inode_size))
+            .ok_or(format!("Inode {n} is out of reach"))?;
+        let d = v.bytes(at, 128)?;
+        let mut size = get32(&d, 0x4)? as u64;
+        size |= (get32(&d, 0x6c)? as u64) << 32;
+        Ok(Inode {
+            n,
+            mode: get16(&d, 0)?,
+            size,
+            flags: get32(&d, 0x20)?,
+            block: d[0x28..0x64].try_into().unwrap(),
+        })
+    }
+
+    /// Runs of blocks, as logical block, physical block and length, from a
+    /// node of an extent tree; below the inode, nodes are one level lower
+    /// than their parent
+    fn extents(
+        &self,
+        v: &mut Volume,
+        node: &[u8],
+        parent: Option<u16>,
+        runs: &mut Vec<(u64, u64, u64)>,
+    ) -> Result<(), Failure> {
+        if get16(node, 0)? != EXTENT_MAGIC {
+            return Err("Bad extent tree".into());
+        }
+        let (entries, depth) = (get16(node, 2)? as usize, get16(node, 6)?);
+        if depth > EXT_MAX_DEPTH || parent.is_some_and(|p| depth + 1 != p) {
+            return Err(format!("Bad extent tree depth {depth}").into());
+        }
+        for e in node[12..].chunks_exact(12).take(entries) {
+            let logical = get32(e, 0)? as u64;
+            if depth == 0 {
+                let len = match get16(e, 4)? {
+                    // Above that, the blocks are allocated but unwritten.
+                    l if l > 32768 => continue,
+                    l => l as u64,
+                };
+                let start = (get16(e, 6)? as u64) << 32 | get32(e, 8)? as u64;
+                runs.push((logical, start, len));
+            } else {
+                let leaf = (get16(e, 8)? as u64) << 32 | get32(e, 4)? as u64;
+                let at = leaf.checked_mul(self.block).ok_or("Bad extent tree")?;
+                let d = v.bytes(at, self.block)?;
+                self.extents(v, &d, Some(depth), runs)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs of blocks from the block map of ext2 and ext3, up to `count`
+    fn mapped(
+        &self,
+        v: &mut Volume,
+        i: &Inode,
+        count: u64,
+    ) -> Result<Vec<(u64, u64, u64)>, Failure> {
+        let per = self.block / 4;
+        let mut runs = vec![];
+        let ptrs = (0..15)
+            .map(|n| get32(&i.block, n * 4))
+            .collect::<Result<Vec<u32>, _>>()?;
+        for (n, &p) in ptrs[..12].iter().enumerate() {
+            if p != 0 {
+                runs.push((n as u64, p as u64, 1));
+            }
+        }
+        let mut logical = 12;
+        for (depth, &p) in ptrs[12..].iter().enumerate() {
+            let span = per.pow(depth as u32 + 1);
+            if logical >= count {
+                break;
+            }
+            if p != 0 {
+                self.indirect(v, p as u64, depth, logical, count, &mut runs)?;
+            }
+            logical += span;
+        }
+        Ok(runs)
+    }
+
+    fn indirect(
+        &self,
+        v: &mut Volume,
+        block: u64,
+        depth: usize,
+        logical: u64,
+        count: u64,
+        runs: &mut Vec<(u64, u64, u64)>,
+    ) -> Result<(), Failure> {
+        let per = self.block / 4;
+        let span = per.pow(depth as u32);
+        let d = v.bytes(block * self.block, self.block)?;
+        for n in 0..per {
+            let at = logical + n * span;
+            if at >= count {
+                break;
+            }
+            match get32(&d, n as usize * 4)? as u64 {
+                0 => {}
+                p if depth == 0 => runs.push((at, p, 1)),
+                p => self.indirect(v, p, depth - 1, at, count, runs)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Fs for Ext {
+    type Node = Inode;
+
+    fn root(&self, v: &mut Volume) -> Result<Inode, Failure> {
+        self.inode(v, EXT_ROOT)
+    }
+
+    fn kind(&self, n: &Inode) -> Kind {
+        match n.mode & 0xf000 {
+            0x4000 => Kind::Dir,
+            0x8000 => Kind::File,
+            0xa000 => Kind::Link,
+            _ => Kind::Other,
+        }
+    }
+
+    fn size(&self, n: &Inode) -> u64 {
+        n.size
+    }
+
+    fn data(&self, v: &mut Volume, i: &Inode) -> Result<Vec<u8>, Failure> {
+        if i.flags & EXT_ENCRYPT_FL != 0 {
+            return Err(format!("Inode {} is encrypted", i.n).into());
+        }
+        let fast_link = self.kind(i) == Kind::Link && i.flags & EXT_EXTENTS_FL == 0;
+        if i.flags & EXT_INLINE_DATA_FL != 0 || fast_link && i.size < 60 {
+            if i.size > 60 {
+                return Err(format!("Inode {} has inline data in attributes", i.n).into());
+            }
+            return Ok(i.block[..i.size as usize].to_vec());
+        }
+        // Holes read as zeros, so the size is all there is to go by.
+        if i.size > v.len() {
+            return Err(format!(
+                "Inode {} has {} bytes, more than its partition",
+                i.n, i.size
+            )
+            .into());
+        }
+        let count = i.size.div_ceil(self.block);
+        let runs = match i.flags & EXT_EXTENTS_FL {
+            0 => self.mapped(v, i, count)?,
+            _ => {
+                let mut runs = vec![];
+                self.extents(v, &i.block, None, &mut runs)?;
+                runs
+            }
+        };
+        let mut d = vec![0; i.size as usize];
+        for (logical, start, len) in runs {
+            let at = logical.saturating_mul(self.block);
+            if at >= i.size {
+                continue;
+            }
+            let n = len.saturating_mul(self.block).min(i.size - at);
+            let from = start
+                .checked_mul(self.block)
+                .ok_or(format!("Inode {} has blocks out of reach", i.n))?;
+            let part = v.bytes(from, n)?;
+            d[at as usize..(at + n) as usize].copy_from_slice(&part);
+        }
+        Ok(d)
+    }
+
+    fn entries(&self, v: &mut Volume, dir: &Inode) -> Result<Vec<(String, Inode)>, Failure> {
+        let d = self.data(v, dir)?;
+        let mut found = vec![];
+        let mut at = 0;
+        // Hashed directories keep their index where plain entries skip it.
+        while at + 8 <= d.len() {
+            let (n, len, name_len) = (get32(&d, at)?, get16(&d, at + 4)? as usize, d[at + 6]);
+            if len < 8 {
+                break;
+            }
+            let end = (at + 8 + name_len as usize).min(d.len());
+            if n != 0 && name_len != 0 {
+                let name = String::from_utf8_lossy(&d[at + 8..end]).to_string();
+                found.push((name, n));
+            }
+            at += len;
+        }
+        found
+            .into_iter()
+            .map(|(name, n)| Ok((name, self.inode(v, n)?)))
+            .collect()
+    }
+}
+
+const FAT_ATTR_VOLUME: u8 = 0x08;
+const FAT_ATTR_DIR: u8 = 0x10;
+const FAT_ATTR_LONG_NAME: u8 = 0x0f;
+
+struct Fat {
+    cluster: u64,
+    /// Where the FAT, the root directory of FAT12/16 and clusters start,
+    /// in bytes
+    fat: u64,
+    root: (u64, u64),
+    data: u64,
+    /// 12, 16 or 32
+    bits: u8,
+    clusters: u32,
+    root_cluster: u32,
+}
+
+#[derive(Clone, Debug)]
+struct Entry {
+    first: u32,
+    size: u64,
+    attr: u8,
+    root: bool,
+}
+
+impl Fat {
+    fn new(b: &[u8]) -> Option<Self> {
+        if b.len() < 512 {
+            return None;
+        }
+        let bps = get16(b, 11).ok()? as u64;
+        let spc = b[13] as u64;
+        let reserved = get16(b, 14).ok()? as u64;
+        let fats = b[16] as u64;
+        if b[510..512] != [0x55, 0xaa]
+            || ![512, 1024, 2048, 4096].contains(&bps)
+            || !spc.is_power_of_two()
+            || reserved == 0
+            || fats == 0
+        {
+            return None;
+        }
+        let root_entries = get16(b, 17).ok()? as u64;
+        let total = match get16(b, 19).ok()? {
+            0 => get32(b, 32).ok()? as u64,
+            n => n as u64,
+        };
+        let fat_sectors = match get16(b, 22).ok()? {
+            0 => get32(b, 36).ok()? as u64,
+            n => n as u64,
+        };
+        let root_sectors = (root_entries * 32).div_ceil(bps);
+        let data_sector = reserved + fats * fat_sectors + root_sectors;
+        let clusters = (total.checked_sub(data_sector)? / spc) as u32;
+        let bits = match clusters {
+            ..4085 => 12,
+            4085..65525 => 16,
+            _ => 32,
+        };
+        Some(Self {
+            cluster: spc * bps,
+            fat: reserved * bps,
+            root: ((reserved + fats * fat_sectors) * bps, root_sectors * bps),
+            data: data_sector * bps,
+            bits,
+            clusters,
+            root_cluster: match bits {
+                32 => get32(b, 44).ok()?,
+                _ => 0,
+            },
+        })
+    }
+
+    fn next(&self, v: &mut Volume, c: u32) -> Result<Option<u32>, Failure> {
+        let c = c as u64;
+        let (at, len) = match self.bits {
+            12 => (c + c / 2, 2),
+            16 => (c * 2, 2),
+            _ => (c * 4, 4),
+        };
+        let d = v.bytes(self.fat + at, len)?;
+        let n = match self.bits {
+            12 if c % 2 == 1 => get16(&d, 0)? as u32 >> 4,
+            12 => get16(&d, 0)? as u32 & 0xfff,
+            16 => get16(&d, 0)? as u32,
+            _ => get32(&d, 0)? & 0x0fff_ffff,
+        };
+        let end = match self.bits {
+            12 => 0xff8,
+            16 => 0xfff8,
+            _ => 0x0fff_fff8,
+        };
+        Ok((n >= 2 && n < end).then_some(n))
+    }
+
+    fn chain(&self, v: &mut Volume, first: u32) -> Result<Vec<u32>, Failure> {
+        let mut chain = vec![];
+        let mut c = Some(first).filter(|&c| c >= 2);
+        while let Some(n) = c {
+            if n - 2 >= self.clusters || chain.len() > self.clusters as usize {
+                return Err(format!("Bad cluster chain from {first}").into());
+            }
+            chain.push(n);
+            c = self.next(v, n)?;
+        }
+        Ok(chain)
+    }
+}
+
+/// A long name from its pieces, which come last one first
+fn long_name(pieces: &[(u8, Vec<u16>)]) -> Option<String> {
+    let mut pieces = pieces.to_vec();
+    pieces.sort_by_key(|(seq, _)| *seq);
+    let units: Vec<u16> = pieces
+        .into_iter()
+        .flat_map(|(_, u)| u)
+        .take_while(|&u| u != 0)
+        .collect();
+    match units.is_empty() {
+        true => None,
+        false => String::from_utf16(&units).ok(),
+    }
+}
+
+fn short_name(e: &[u8]) -> String {
+    let text = |b: &[u8], lower: bool| {
+        let s = String::from_utf8_lossy(b).trim_end().to_string();
+        match lower {
+            true => s.to_ascii_lowercase(),
+            false => s,
+        }
+    };
+    let base = text(&e[..8], e[12] & 0x08 != 0);
+    let ext = text(&e[8..11], e[12] & 0x10 != 0);
+    match ext.is_empty() {
+        true => base,
+        false => format!("{base}.{ext}"),
+    }
+}
+
+impl Fs for Fat {
+    type Node = Entry;
+
+    fn root(&self, _: &mut Volume) -> Result<Entry, Failure> {
+        Ok(Entry {
+            first: self.root_cluster,
+            size: 0,
+            attr: FAT_ATTR_DIR,
+            root: true,
+        })
+    }
+
+    fn kind(&self, n: &Entry) -> Kind {
+        match n.attr & FAT_ATTR_DIR {
+            0 => Kind::File,
+            _ => Kind::Dir,
+        }
+    }
+
+    fn size(&self, n: &Entry) -> u64 {
+        n.size
+    }
+
+    fn data(&self, v: &mut Volume, n: &Entry) -> Result<Vec<u8>, Failure> {
+        if n.root && self.bits != 32 {
+            return v.bytes(self.root.0, self.root.1);
+        }
+        let mut d = vec![];
+        for c in self.chain(v, n.first)? {
+            d.extend(v.bytes(self.data + (c as u64 - 2) * self.cluster, self.cluster)?);
+            if self.kind(n) == Kind::File && d.len() as u64 >= n.size {
+                break;
+            }
+        }
+        if self.kind(n) == Kind::File {
+            if (d.len() as u64) < n.size {
+                return Err("File is cut short by its cluster chain".into());
+            }
+            d.truncate(n.size as usize);
+        }
+        Ok(d)
+    }
+
+    fn entries(&self, v: &mut Volume, dir: &Entry) -> Result<Vec<(String, Entry)>, Failure> {
+        let d = self.data(v, dir)?;
+        let mut found = vec![];
+        let mut pieces = vec![];
+        for e in d.chunks_exact(32) {
+            match (e[0], e[11]) {
+                (0, _) => break,
+                (0xe5, _) => pieces.clear(),
+                (seq, FAT_ATTR_LONG_NAME) => {
+                    if seq & 0x40 != 0 {
+                        pieces.clear();
+                    }
+                    let units = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30]
+                        .iter()
+                        .map(|&at| get16(e, at))
+                        .collect::<Result<_, _>>()?;
+                    pieces.push((seq & 0x1f, units));
+                }
+                (_, attr) if attr & FAT_ATTR_VOLUME != 0 => pieces.clear(),
+                (_, attr) => {
+                    let name = long_name(&pieces).unwrap_or_else(|| short_name(e));
+                    pieces.clear();
+                    let first = (get16(e, 20)? as u32) << 16 | get16(e, 26)? as u32;
+                    let entry = Entry {
+                        // ".." of a directory in the root points at cluster 0.
+                        root: first == 0 && attr & FAT_ATTR_DIR != 0,
+                        first: match first {
+                            0 if attr & FAT_ATTR_DIR != 0 => self.root_cluster,
+                            c => c,
+                        },
+                        size: get32(e, 28)? as u64,
+                        attr,
+                    };
+                    found.push((name, entry));
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    fn same(&self, a: &str, b: &str) -> bool {
+        a.eq_ignore_ascii_case(b)
+    }
+}
+
+/// Read a file, or list a directory, on a GPT partition
+pub fn extract(
+    read: &mut dyn FnMut(u64, u32) -> Result<Vec<u8>, Failure>,
+    partition: &str,
+    path: &str,
+) -> Result<Content, Failure> {
+    let g = gpt::read(read)??;
+    let p = g
+        .find(partition)
+        .ok_or(format!("No partition {partition}"))?;
+    let mut v = Volume {
+        read,
+        first_lba: p.first_lba,
+        sectors: p.sectors(),
+    };
+    let head = v.bytes(0, 2048)?;
+    if get16(&head, 1024 + 0x38)? == EXT_MAGIC {
+        info!("{partition} has an ext2/3/4 file system");
+        return content(&Ext::new(&head[1024..])?, &mut v, path);
+    }
+    if let Some(fat) = Fat::new(&head) {
+        info!("{partition} has a FAT{} file system", fat.bits);
+        return content(&fat, &mut v, path);
+    }
+    Err(format!("No ext2/3/4 or FAT file system on {partition}").into())
+}
+
+/// Write a file to `output` or stdout, or only its lines that contain
+/// `grep`; print a directory's names
+pub fn show(c: Content, output: Option<&Path>, grep: Option<&str>) -> Result<(), String> {
+    match (c, output, grep) {
+        (Content::Dir(names), ..) => {
+            for n in names {
+                println!("{n}");
+            }
+            Ok(())
+        }
+        (Content::File(d), _, Some(g)) => {
+            for l in String::from_utf8_lossy(&d)
+                .lines()
+                .filter(|l| l.contains(g))
+            {
+                println!("{l}");
+            }
+            Ok(())
+        }
+        (Content::File(d), Some(o), None) => {
+            std::fs::write(o, d).map_err(|e| format!("{}: {e}", o.display()))
+        }
+        (Content::File(d), None, None) => {
+            std::io::stdout().write_all(&d).map_err(|e| e.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ext(sb: &mut [u8]) -> Result<Ext, Failure> {
+        sb[0x38..0x3a].copy_from_slice(&EXT_MAGIC.to_le_bytes());
+        Ext::new(sb)
+    }
+
+    #[test]
+    fn fields_cut_short() {
+        assert_eq!(get16(&[1, 2], 0), Ok(0x201));
+        assert!(get16(&[1, 2], 1).is_err());
+        assert!(get32(&[1, 2, 3, 4], usize::MAX).is_err());
+        assert!(Ext::new(&[0; 0x60]).is_err());
+        assert!(Fat::new(&[0; 16]).is_none());
+    }
+
+    #[test]
+    fn ext_refuses_what_does_not_add_up() {
+        let mut sb = vec![0; 1024];
+        sb[0x18] = 40;
+        assert!(ext(&mut sb).is_err());
+        sb[0x18] = 2;
+        sb[0x28] = 8;
+        let fs = ext(&mut sb).unwrap();
+        assert_eq!(fs.block, 4096);
+
+        let disk = vec![0u8; 64 * 1024];
+        let read = &mut |lba: u64, n: u32| {
+            let at = lba as usize * SECTOR_SIZE;
+            Ok(disk[at..at + n as usize * SECTOR_SIZE].to_vec())
+        };
+        let mut v = Volume {
+            read,
+            first_lba: 0,
+            sectors: 128,
+        };
+        let mut i = Inode {
+            n: 12,
+            mode: 0x8000,
+            size: u64::MAX,
+            flags: EXT_EXTENTS_FL,
+            block: [0; 60],
+        };
+        // Sizes past the partition, extent trees deeper than can be, and
+        // blocks out of reach
+        assert!(
+            fs.data(&mut v, &i)
+                .unwrap_err()
+                .to_string()
+                .contains("more than")
+        );
+        i.size = 4096;
+        i.block[..2].copy_from_slice(&EXTENT_MAGIC.to_le_bytes());
+        i.block[2] = 1;
+        i.block[6] = 9;
+        assert!(
+            fs.data(&mut v, &i)
+                .unwrap_err()
+                .to_string()
+                .contains("depth")
+        );
+        i.block[6] = 0;
+        i.block[12 + 4] = 1;
+        i.block[12 + 6..12 + 12].copy_from_slice(&[0xff; 6]);
+        assert!(fs.data(&mut v, &i).is_err());
+        i.block[12 + 6..12 + 12].copy_from_slice(&[0, 0, 1, 0, 0, 0]);
+        assert_eq!(fs.data(&mut v, &i).unwrap(), vec![0; 4096]);
+        assert!(v.bytes(u64::MAX - 1, 4).is_err());
+    }
+}
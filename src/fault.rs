@@ -0,0 +1,157 @@
+//! Failures on demand, to exercise retries, resuming and reports, and to
+//! rehearse what operators do about them, without sabotaging hardware
+//!
+//! Only in builds with the `fault-injection` feature, where `--inject-fault`
+//! takes specs of the form `KIND:POINT:N`, for the Nth transfer at that
+//! point in the process, or `KIND:POINT:N+` for it and all after it:
+//!
+//! - KIND is `fail`, a transfer error; `stall`, a transfer that takes its
+//!   whole timeout and times out; or `disconnect`, as if the device left
+//! - POINT is `bulk-in`, `bulk-out`, `control-out` or `any`
+//!
+//! E.g. `--inject-fault stall:bulk-in:100 --inject-fault disconnect:any:500+`
+
+use std::io;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use log::warn;
+
+use crate::protocol::Transport;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Fail,
+    Stall,
+    Disconnect,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Point {
+    BulkIn,
+    BulkOut,
+    ControlOut,
+    Any,
+}
+
+const POINTS: &[(Point, &str)] = &[
+    (Point::BulkIn, "bulk-in"),
+    (Point::BulkOut, "bulk-out"),
+    (Point::ControlOut, "control-out"),
+    (Point::Any, "any"),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Fault {
+    kind: Kind,
+    point: Point,
+    /// The transfer to fail, counting from 1
+    nth: u64,
+    /// Fail those after it as well
+    onward: bool,
+}
+
+/// Transfers so far, by point, `Any` counting all of them
+static COUNTS: [AtomicU64; 4] = [const { AtomicU64::new(0) }; 4];
+static FAULTS: OnceLock<Vec<Fault>> = OnceLock::new();
+
+pub fn parse(spec: &str) -> Result<Fault, String> {
+    let err = || format!("bad fault {spec:?}, expected KIND:POINT:N or KIND:POINT:N+");
+    let [kind, point, nth] = spec.split(':').collect::<Vec<_>>()[..] else {
+        return Err(err());
+    };
+    let kind = match kind {
+        "fail" => Kind::Fail,
+        "stall" => Kind::Stall,
+        "disconnect" => Kind::Disconnect,
+        _ => return Err(err()),
+    };
+    let point = POINTS
+        .iter()
+        .find(|(_, name)| *name == point)
+        .map(|(p, _)| *p)
+        .ok_or_else(err)?;
+    let (nth, onward) = match nth.strip_suffix('+') {
+        Some(n) => (n, true),
+        None => (nth, false),
+    };
+    let nth = nth.parse().ok().filter(|&n| n > 0).ok_or_else(err)?;
+    Ok(Fault {
+        kind,
+        point,
+        nth,
+        onward,
+    })
+}
+
+/// The faults for devices connected from now on; only the first call counts
+pub fn set(faults: Vec<Fault>) {
+    let _ = FAULTS.set(faults);
+}
+
+pub fn configured() -> Vec<Fault> {
+    FAULTS.get().cloned().unwrap_or_default()
+}
+
+/// A transport that fails as told
+pub struct Faulty<T> {
+    inner: T,
+    faults: Vec<Fault>,
+}
+
+impl<T: Transport> Faulty<T> {
+    pub fn new(inner: T, faults: Vec<Fault>) -> Self {
+        Self { inner, faults }
+    }
+
+    /// Count the transfer, and fail it if it is one to fail
+    fn check(&self, point: Point, timeout: Duration) -> io::Result<()> {
+        let nth = COUNTS[point as usize].fetch_add(1, Ordering::Relaxed) + 1;
+        let any = COUNTS[Point::Any as usize].fetch_add(1, Ordering::Relaxed) + 1;
+        let hit = self.faults.iter().find(|f| {
+            let n = match f.point {
+                Point::Any => any,
+                p if p == point => nth,
+                _ => return false,
+            };
+            n == f.nth || f.onward && n > f.nth
+        });
+        let Some(f) = hit else {
+            return Ok(());
+        };
+        let name = POINTS[point as usize].1;
+        warn!("Injecting {:?} into {name} transfer {nth}", f.kind);
+        Err(match f.kind {
+            Kind::Fail => io::Error::other("injected failure"),
+            Kind::Stall => {
+                std::thread::sleep(timeout);
+                io::ErrorKind::TimedOut.into()
+            }
+            Kind::Disconnect => io::Error::new(io::ErrorKind::NotConnected, "injected disconnect"),
+        })
+    }
+}
+
+impl<T: Transport> Transport for Faulty<T> {
+    fn bulk_out(&self, ep: u8, data: Vec<u8>, timeout: Duration) -> io::Result<usize> {
+        self.check(Point::BulkOut, timeout)?;
+        self.inner.bulk_out(ep, data, timeout)
+    }
+
+    fn bulk_in(&self, ep: u8, size: usize, timeout: Duration) -> io::Result<Vec<u8>> {
+        self.check(Point::BulkIn, timeout)?;
+        self.inner.bulk_in(ep, size, timeout)
+    }
+
+    fn control_out(
+        &self,
+        request: u8,
+        index: u16,
+        data: &[u8],
+        timeout: Duration,
+    ) -> io::Result<usize> {
+        self.check(Point::ControlOut, timeout)?;
+        self.inner.control_out(request, index, data, timeout)
+    }
+}
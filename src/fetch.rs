@@ -0,0 +1,215 @@
+//! Images streamed from an HTTP or HTTPS server, for stations that boot
+//! from the network and have no room to keep them
+//!
+//! A connection that breaks or stalls is made again with a range request
+//! for what is still missing, so a hiccup late in a large image does not
+//! start the download over. Servers that ignore ranges send the image from
+//! the start again, of which the part already read is skipped.
+//!
+//! HTTPS checks the server against the Mozilla root certificates that
+//! rustls comes with.
+
+use std::io::{self, Read, Seek, SeekFrom};
+use std::thread::sleep;
+use std::time::Duration;
+
+use log::{debug, info, warn};
+
+use crate::size;
+
+/// Connections made again for one read before giving up
+const RETRIES: u32 = 5;
+const BACKOFF: Duration = Duration::from_secs(1);
+/// A server that sends nothing for this long is taken to have gone away
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub fn is_url(s: &str) -> bool {
+    s.starts_with("http://") || s.starts_with("https://")
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Url {
+    pub https: bool,
+    /// Host and port
+    pub host: String,
+    pub path: String,
+}
+
+impl std::fmt::Display for Url {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let scheme = if self.https { "https" } else { "http" };
+        write!(f, "{scheme}://{}{}", self.host, self.path)
+    }
+}
+
+pub fn parse(url: &str) -> Result<Url, String> {
+    let (https, rest) = match (url.strip_prefix("https://"), url.strip_prefix("http://")) {
+        (Some(rest), _) => (true, rest),
+        (None, Some(rest)) => (false, rest),
+        (None, None) => return Err(format!("{url}: not an http:// or https:// URL")),
+    };
+    let (host, path) = match rest.find('/') {
+        Some(n) => (&rest[..n], &rest[n..]),
+        None => (rest, "/"),
+    };
+    if host.is_empty() {
+        return Err(format!("{url}: no host"));
+    }
+    let host = match (host.contains(':'), https) {
+        (true, _) => host.to_string(),
+        (false, true) => format!("{host}:443"),
+        (false, false) => format!("{host}:80"),
+    };
+    Ok(Url {
+        https,
+        host,
+        path: path.to_string(),
+    })
+}
+
+struct Response {
+    status: u16,
+    /// Bytes of the body
+    len: Option<u64>,
+    /// First byte of the body in the whole image, and the image's size
+    range: Option<(u64, Option<u64>)>,
+    body: Box<dyn Read + Send + Sync>,
+}
+
+/// `bytes START-END/TOTAL` of a `Content-Range` header
+fn content_range(v: &str) -> Option<(u64, Option<u64>)> {
+    let v = v.trim().strip_prefix("bytes ")?;
+    let (range, total) = v.split_once('/')?;
+    let (start, _) = range.split_once('-')?;
+    Some((start.trim().parse().ok()?, total.trim().parse().ok()))
+}
+
+fn agent() -> ureq::Agent {
+    ureq::AgentBuilder::new().timeout_read(READ_TIMEOUT).build()
+}
+
+fn get(agent: &ureq::Agent, url: &Url, from: u64) -> io::Result<Response> {
+    let r = match agent
+        .get(&url.to_string())
+        .set("Range", &format!("bytes={from}-"))
+        .call()
+    {
+        Ok(r) | Err(ureq::Error::Status(_, r)) => r,
+        Err(e) => return Err(io::Error::other(e)),
+    };
+    Ok(Response {
+        status: r.status(),
+        len: r.header("Content-Length").and_then(|v| v.trim().parse().ok()),
+        range: r.header("Content-Range").and_then(content_range),
+        body: r.into_reader(),
+    })
+}
+
+/// An image on an HTTP server, read as if it were a file
+pub struct Ranged {
+    agent: ureq::Agent,
+    url: Url,
+    name: String,
+    len: u64,
+    pos: u64,
+    body: Option<Box<dyn Read + Send + Sync>>,
+}
+
+impl Ranged {
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Connect for the rest of the image from `pos`
+    fn connect(&mut self) -> io::Result<()> {
+        let r = get(&self.agent, &self.url, self.pos)?;
+        let mut body = r.body;
+        match (r.status, r.range) {
+            (206, Some((start, _))) if start == self.pos => {}
+            (206, _) => return Err(io::Error::other("server sent another range")),
+            (200, _) => {
+                if self.pos > 0 {
+                    warn!("{} ignores ranges, skipping {} bytes", self.name, self.pos);
+                }
+                io::copy(&mut (&mut body).take(self.pos), &mut io::sink())?;
+            }
+            (status, _) => return Err(io::Error::other(format!("HTTP status {status}"))),
+        }
+        self.body = Some(body);
+        Ok(())
+    }
+}
+
+/// Open an image by URL, learning its size
+pub fn open(url: &str) -> Result<Ranged, String> {
+    let u = parse(url)?;
+    let agent = agent();
+    let r = get(&agent, &u, 0).map_err(|e| format!("{url}: {e}"))?;
+    let len = match (r.status, r.range, r.len) {
+        (206, Some((0, Some(total))), _) => total,
+        (200, _, Some(len)) => len,
+        (200 | 206, _, _) => return Err(format!("{url}: the server does not tell the size")),
+        (status, _, _) => return Err(format!("{url}: HTTP status {status}")),
+    };
+    info!("{url}: {}", size::human(len));
+    Ok(Ranged {
+        agent,
+        url: u,
+        name: url.to_string(),
+        len,
+        pos: 0,
+        body: Some(r.body),
+    })
+}
+
+impl Read for Ranged {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let want = buf.len().min((self.len - self.pos.min(self.len)) as usize);
+        if want == 0 {
+            return Ok(0);
+        }
+        let mut tries = 0;
+        loop {
+            let res = match self.body.is_some() {
+                true => Ok(()),
+                false => self.connect(),
+            }
+            .and_then(|()| self.body.as_mut().unwrap().read(&mut buf[..want]));
+            match res {
+                Ok(0) => debug!("{}: connection closed at byte {}", self.name, self.pos),
+                Ok(n) => {
+                    self.pos += n as u64;
+                    return Ok(n);
+                }
+                Err(e) => warn!("{}: {e} at byte {}", self.name, self.pos),
+            }
+            // Closed early or broken: ask for the rest.
+            self.body = None;
+            tries += 1;
+            if tries > RETRIES {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("gave up after {RETRIES} retries"),
+                ));
+            }
+            sleep(BACKOFF * tries);
+            info!("{}: resuming at byte {}", self.name, self.pos);
+        }
+    }
+}
+
+impl Seek for Ranged {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let to = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(d) => self.len.checked_add_signed(d),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+        }
+        .ok_or(io::Error::from(io::ErrorKind::InvalidInput))?;
+        if to != self.pos {
+            self.pos = to;
+            self.body = None;
+        }
+        Ok(to)
+    }
+}
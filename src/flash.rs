@@ -0,0 +1,694 @@
+//! Write images to the loader's storage
+//!
+//! Images are streamed in chunks rather than loaded whole. With `delta`,
+//! each chunk is read back first and only the blocks that differ are
+//! written, so re-flashing a mostly unchanged image takes a fraction of the
+//! time: reading is much faster than writing on eMMC. Verification reads
+//! back what was written, per partition, and compares digests with the
+//! image.
+//!
+//! Chunks go to the storage in ascending order, so a write cut short by the
+//! device disconnecting can go on from the chunk that was under way.
+//!
+//! The image is read on a thread of its own, into a small ring of chunk
+//! buffers: reading, or decompressing, goes on while the device writes,
+//! and waits for a free buffer when it gets ahead, so memory stays at
+//! [`READ_AHEAD`] chunks however fast the source or slow the device.
+
+use std::cell::Cell;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::mpsc::{Receiver, SyncSender, sync_channel};
+
+use clap::ValueEnum;
+use log::{debug, info, warn};
+use sha2::{Digest, Sha256};
+
+use crate::bmap::Bmap;
+use crate::erase::{BOOT_AREA, VENDOR_STORAGE};
+use crate::json::{self, Value};
+use crate::metrics::Failure;
+use crate::protocol::{self, SECTOR_SIZE};
+use crate::session::Session;
+use crate::{deadline, gpt, hexdump, progress, retry, size};
+
+pub const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+/// Chunks read ahead of the device; a chunk takes about 100 ms over USB 2
+/// and 10 ms over USB 3, so this covers hiccups of the source either way
+pub const READ_AHEAD: usize = 3;
+/// Protective MBR, GPT header and 128 entries; the backup copy has no MBR
+const GPT_SECTORS: u64 = 34;
+// Granularity of comparisons in delta mode
+const DELTA_BLOCK: usize = 64 * 1024;
+
+#[derive(Debug, Default)]
+pub struct Options {
+    pub delta: bool,
+    /// Write only the blocks mapped here
+    pub bmap: Option<Bmap>,
+    /// Read back and compare digests afterwards
+    pub verify: Option<Verify>,
+    /// Areas of the storage to leave as they are
+    pub preserve: Vec<Preserve>,
+    /// Sector an interrupted write of the same image got to; what comes
+    /// before is on the storage already
+    pub resume: Option<u32>,
+}
+
+thread_local! {
+    /// Whether to probe for write protection before writing
+    static PROBE_WRITE_PROTECT: Cell<bool> = const { Cell::new(false) };
+    /// Sector of the chunk being written
+    static REACHED: Cell<u32> = const { Cell::new(0) };
+}
+
+/// Sector to resume an interrupted write at
+pub fn resume_point() -> u32 {
+    REACHED.get()
+}
+
+/// Areas a whole-disk image can be written around
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Preserve {
+    /// The boot area with idbloader and U-Boot, sectors 64 to 16383
+    Boot,
+    /// Both copies of the GPT
+    Gpt,
+    /// eMMC vendor storage with serial numbers and MAC addresses
+    Vendor,
+}
+
+impl Preserve {
+    /// The sectors of the area on storage with `total` sectors
+    pub fn sectors(self, total: u64) -> Vec<Range<u64>> {
+        let r = |r: Range<u32>| r.start as u64..r.end as u64;
+        match self {
+            Self::Boot => vec![r(BOOT_AREA)],
+            Self::Gpt => vec![0..GPT_SECTORS, total.saturating_sub(GPT_SECTORS - 1)..total],
+            Self::Vendor => vec![r(VENDOR_STORAGE)],
+        }
+    }
+}
+
+/// What a write did, in bytes unless noted
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub written: u64,
+    /// Left alone in delta mode as the storage already had it
+    pub unchanged: u64,
+    /// Not in the bmap, i.e. don't care
+    pub skipped: u64,
+    /// Left alone as asked for with `Options::preserve`
+    pub preserved: u64,
+    /// USB transfers that were tried again
+    pub retried: u64,
+    pub verified: u64,
+}
+
+impl Stats {
+    pub fn to_json(&self) -> Value {
+        let sectors = |b: u64| Value::from(b.div_ceil(SECTOR_SIZE as u64));
+        json::obj([
+            ("written_sectors", sectors(self.written)),
+            ("unchanged_sectors", sectors(self.unchanged)),
+            ("skipped_sectors", sectors(self.skipped)),
+            ("preserved_sectors", sectors(self.preserved)),
+            ("verified_sectors", sectors(self.verified)),
+            ("retried_transfers", self.retried.into()),
+        ])
+    }
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Verify {
+    Sha256,
+    Crc32,
+}
+
+static CRC32: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+
+enum Hasher {
+    Sha256(Sha256),
+    Crc32(crc::Digest<'static, u32>),
+}
+
+impl Hasher {
+    fn new(v: Verify) -> Self {
+        match v {
+            Verify::Sha256 => Self::Sha256(Sha256::new()),
+            Verify::Crc32 => Self::Crc32(CRC32.digest()),
+        }
+    }
+
+    fn update(&mut self, d: &[u8]) {
+        match self {
+            Self::Sha256(h) => h.update(d),
+            Self::Crc32(h) => h.update(d),
+        }
+    }
+
+    fn finish(self) -> String {
+        match self {
+            Self::Sha256(h) => hexdump::hex(&h.finalize()),
+            Self::Crc32(h) => format!("{:08x}", h.finalize()),
+        }
+    }
+}
+
+/// Part of the image written to the storage, in bytes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Extent {
+    pub lba: u32,
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// Result of reading back one piece
+#[derive(Debug)]
+pub struct Check {
+    /// Partition the piece is in, if any
+    pub partition: Option<String>,
+    pub extent: Extent,
+    pub expected: String,
+    pub found: String,
+}
+
+/// Split extents where partitions start and end, naming the pieces
+fn split(extents: &[Extent], parts: &[(String, u64, u64)]) -> Vec<(Option<String>, Extent)> {
+    let mut pieces = Vec::new();
+    for e in extents {
+        let end = e.lba as u64 * SECTOR_SIZE as u64 + e.len;
+        let mut at = e.lba as u64;
+        while at * (SECTOR_SIZE as u64) < end {
+            let part = parts.iter().find(|(_, f, l)| (*f..=*l).contains(&at));
+            let next = match part {
+                Some((_, _, l)) => l + 1,
+                None => parts
+                    .iter()
+                    .map(|p| p.1)
+                    .filter(|&f| f > at)
+                    .min()
+                    .unwrap_or(u64::MAX),
+            };
+            let piece_end = end.min(next.saturating_mul(SECTOR_SIZE as u64));
+            let start = at * SECTOR_SIZE as u64;
+            let offset = e.offset + start - e.lba as u64 * SECTOR_SIZE as u64;
+            let extent = Extent {
+                lba: at as u32,
+                offset,
+                len: piece_end - start,
+            };
+            pieces.push((part.map(|p| p.0.clone()), extent));
+            at = piece_end.div_ceil(SECTOR_SIZE as u64);
+        }
+    }
+    pieces
+}
+
+/// Digest of `len` bytes padded to whole sectors, read by `read(offset, len)`
+fn digest(
+    kind: Verify,
+    len: u64,
+    read: &mut dyn FnMut(u64, usize) -> Result<Vec<u8>, Failure>,
+) -> Result<String, Failure> {
+    let mut h = Hasher::new(kind);
+    let padded = len.next_multiple_of(SECTOR_SIZE as u64);
+    let mut done = 0;
+    while done < padded {
+        let n = (padded - done).min(CHUNK_SIZE as u64) as usize;
+        h.update(&read(done, n)?);
+        done += n as u64;
+    }
+    Ok(h.finish())
+}
+
+/// Read back the extents of `image` written to the storage, per partition
+pub fn verify(
+    s: &Session,
+    image: &mut dyn Image,
+    extents: &[Extent],
+    kind: Verify,
+) -> Result<Vec<Check>, Failure> {
+    let read = &mut |lba, n| protocol::read_lba(s, lba as u32, n).map_err(Failure::from);
+    let parts: Vec<(String, u64, u64)> = match gpt::read(read)? {
+        Ok(g) => g
+            .partitions()
+            .map(|p| (p.name(), p.first_lba, p.last_lba))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    let mut checks = Vec::new();
+    for (partition, e) in split(extents, &parts) {
+        let expected = digest(kind, e.len, &mut |at, n| {
+            let mut d = vec![0; n];
+            let avail = e.len.saturating_sub(at).min(n as u64) as usize;
+            image
+                .seek(SeekFrom::Start(e.offset + at))
+                .map_err(|e| e.to_string())?;
+            image
+                .read_exact(&mut d[..avail])
+                .map_err(|e| e.to_string())?;
+            Ok(d)
+        })?;
+        let found = digest(kind, e.len, &mut |at, n| {
+            let lba = e.lba + (at / SECTOR_SIZE as u64) as u32;
+            Ok(protocol::read_lba(s, lba, (n / SECTOR_SIZE) as u32)?)
+        })?;
+        checks.push(Check {
+            partition,
+            extent: e,
+            expected,
+            found,
+        });
+    }
+    Ok(checks)
+}
+
+fn report(checks: &[Check]) -> Result<(), Failure> {
+    let mut failed = 0;
+    for c in checks {
+        let ok = c.expected == c.found;
+        failed += !ok as usize;
+        println!(
+            "{:16} {:>10} {:>12} {} {}",
+            c.partition.as_deref().unwrap_or("-"),
+            c.extent.lba,
+            c.extent.len,
+            c.found,
+            if ok { "ok" } else { "MISMATCH" }
+        );
+    }
+    match failed {
+        0 => Ok(()),
+        n => Err(Failure::Verify(format!(
+            "Verification failed for {n} of {} ranges",
+            checks.len()
+        ))),
+    }
+}
+
+/// Refuse sectors beyond the end of the storage up front, rather than
+/// failing halfway through
+pub fn check_capacity(s: &Session, lba: u64, sectors: u64) -> Result<(), Failure> {
+    let total = protocol::flash_info(s)?.sectors as u64;
+    let end = lba + sectors;
+    if end > total {
+        return Err(format!(
+            "Sectors {lba:#x}..{end:#x} exceed the storage, which has {total:#x} sectors; {} too many",
+            size::human((end - total) * SECTOR_SIZE as u64)
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Probe for write protection before writing, for this thread; see
+/// `protocol::write_protected`
+pub fn set_probe_write_protect(on: bool) {
+    PROBE_WRITE_PROTECT.set(on);
+}
+
+/// Fail early if the first sector to write is write-protected, if told to
+/// probe for it; otherwise the write itself fails
+pub fn check_writable(s: &Session, lba: u64) -> Result<(), Failure> {
+    if PROBE_WRITE_PROTECT.get() && protocol::write_protected(s, lba as u32)? {
+        return Err(format!("Storage is write-protected at sector {lba:#x}").into());
+    }
+    Ok(())
+}
+
+/// Byte ranges of `new` that differ from `old`, in whole blocks, with
+/// neighbouring blocks merged
+fn changed(old: &[u8], new: &[u8]) -> Vec<std::ops::Range<usize>> {
+    let mut ranges: Vec<std::ops::Range<usize>> = Vec::new();
+    for (n, (o, d)) in old
+        .chunks(DELTA_BLOCK)
+        .zip(new.chunks(DELTA_BLOCK))
+        .enumerate()
+    {
+        if o == d {
+            continue;
+        }
+        let r = n * DELTA_BLOCK..n * DELTA_BLOCK + d.len();
+        match ranges.last_mut() {
+            Some(l) if l.end == r.start => l.end = r.end,
+            _ => ranges.push(r),
+        }
+    }
+    ranges
+}
+
+fn subtract(ranges: Vec<Range<usize>>, cut: &Range<usize>) -> Vec<Range<usize>> {
+    ranges
+        .into_iter()
+        .flat_map(|r| [r.start..r.end.min(cut.start), r.start.max(cut.end)..r.end])
+        .filter(|r| !r.is_empty())
+        .collect()
+}
+
+/// Sectors left alone as asked for with `Options::preserve`
+fn preserved(s: &Session, opts: &Options) -> Result<Vec<Range<u64>>, Failure> {
+    match opts.preserve.is_empty() {
+        true => Ok(Vec::new()),
+        false => {
+            let total = protocol::flash_info(s)?.sectors as u64;
+            Ok(opts
+                .preserve
+                .iter()
+                .flat_map(|p| p.sectors(total))
+                .collect())
+        }
+    }
+}
+
+/// The parts of `extents` outside the sectors in `keep`
+fn outside(extents: &[Extent], keep: &[Range<u64>]) -> Vec<Extent> {
+    let sector = SECTOR_SIZE as u64;
+    let mut pieces: Vec<Extent> = extents.to_vec();
+    for k in keep {
+        pieces = pieces
+            .into_iter()
+            .flat_map(|e| {
+                let (first, end) = (e.lba as u64, e.lba as u64 * sector + e.len);
+                let piece = |from: u64, to: u64| Extent {
+                    lba: from as u32,
+                    offset: e.offset + (from - first) * sector,
+                    len: end.min(to * sector) - from * sector,
+                };
+                let last = end.div_ceil(sector);
+                [
+                    (first < k.start).then(|| piece(first, last.min(k.start))),
+                    (k.end < last).then(|| piece(first.max(k.end), last)),
+                ]
+            })
+            .flatten()
+            .collect();
+    }
+    pieces
+}
+
+/// Write `len` bytes from `src` starting at sector `lba`
+pub fn write_stream(
+    s: &Session,
+    lba: u32,
+    src: &mut (dyn Read + Send),
+    len: u64,
+    opts: &Options,
+) -> Result<Stats, Failure> {
+    let keep = preserved(s, opts)?;
+    let (free_tx, free_rx) = sync_channel(READ_AHEAD);
+    let (full_tx, full_rx) = sync_channel(READ_AHEAD);
+    for _ in 0..READ_AHEAD {
+        free_tx.send(Vec::with_capacity(CHUNK_SIZE)).unwrap();
+    }
+    std::thread::scope(|scope| {
+        scope.spawn(move || read_ahead(src, len, free_rx, full_tx));
+        // Returning or unwinding drops the channels, which stops the reader.
+        write_chunks(s, lba, len, &keep, opts, full_rx, free_tx)
+    })
+}
+
+/// Read `len` bytes into buffers from `free`, passing them on to `full`
+/// padded to whole sectors
+fn read_ahead(
+    src: &mut (dyn Read + Send),
+    len: u64,
+    free: Receiver<Vec<u8>>,
+    full: SyncSender<Result<Vec<u8>, String>>,
+) {
+    let mut done = 0;
+    while done < len {
+        let Ok(mut buf) = free.recv() else {
+            return;
+        };
+        let n = (len - done).min(CHUNK_SIZE as u64) as usize;
+        // The last sector is padded with zeros.
+        buf.clear();
+        buf.resize(n.next_multiple_of(SECTOR_SIZE), 0);
+        let chunk = src
+            .read_exact(&mut buf[..n])
+            .map(|()| buf)
+            .map_err(|e| format!("reading image at {done:#x}: {e}"));
+        let failed = chunk.is_err();
+        if full.send(chunk).is_err() || failed {
+            return;
+        }
+        done += n as u64;
+    }
+}
+
+fn write_chunks(
+    s: &Session,
+    lba: u32,
+    len: u64,
+    keep: &[Range<u64>],
+    opts: &Options,
+    full: Receiver<Result<Vec<u8>, String>>,
+    free: SyncSender<Vec<u8>>,
+) -> Result<Stats, Failure> {
+    let mut stats = Stats::default();
+    let mut done = 0;
+    while done < len {
+        let n = (len - done).min(CHUNK_SIZE as u64) as usize;
+        let buf = full
+            .recv()
+            .map_err(|_| format!("reading image at {done:#x}: reader stopped"))??;
+        let padded = buf.len();
+        let data = &buf[..];
+        let at = lba + (done / SECTOR_SIZE as u64) as u32;
+        let before = opts
+            .resume
+            .map_or(0, |r| r.saturating_sub(at) as usize * SECTOR_SIZE);
+        if before >= padded {
+            stats.unchanged += padded as u64;
+            done += n as u64;
+            let _ = free.send(buf);
+            progress::advance(done, len);
+            continue;
+        }
+        REACHED.set(at);
+        let mut ranges = match opts.delta {
+            true => {
+                let old = protocol::read_lba(s, at, (padded / SECTOR_SIZE) as u32)?;
+                let ranges = changed(&old, data);
+                debug!("Sector {at:#x}: {} changed ranges", ranges.len());
+                ranges
+            }
+            false => std::iter::once(0..padded).collect(),
+        };
+        ranges = subtract(ranges, &(0..before));
+        let differ: usize = ranges.iter().map(|r| r.len()).sum();
+        stats.unchanged += (padded - differ) as u64;
+        // Preserved sectors, as byte offsets into the chunk
+        for k in keep {
+            let start = k.start.saturating_sub(at as u64) as usize * SECTOR_SIZE;
+            let end = k
+                .end
+                .saturating_sub(at as u64)
+                .min(padded as u64 / SECTOR_SIZE as u64);
+            let end = end as usize * SECTOR_SIZE;
+            if start < end {
+                ranges = subtract(ranges, &(start..end));
+            }
+        }
+        for r in &ranges {
+            let first = at + (r.start / SECTOR_SIZE) as u32;
+            protocol::write_lba(s, first, &data[r.clone()])?;
+            stats.written += r.len() as u64;
+        }
+        stats.preserved += (differ - ranges.iter().map(|r| r.len()).sum::<usize>()) as u64;
+        done += n as u64;
+        let _ = free.send(buf);
+        deadline::progress(format!("wrote {done} of {len} bytes at sector {lba:#x}"));
+        let next = lba + (done / SECTOR_SIZE as u64) as u32;
+        deadline::resume_with(format!("--resume-at {next:#x}"));
+        progress::advance(done, len);
+    }
+    Ok(stats)
+}
+
+/// Hashes what is read through it
+struct Hashing<'a> {
+    inner: &'a mut (dyn Read + Send),
+    hash: Sha256,
+}
+
+impl Read for Hashing<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hash.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Write the mapped ranges of a sparse image, checking their checksums
+fn write_mapped(
+    s: &Session,
+    lba: u32,
+    f: &mut dyn Image,
+    bmap: &Bmap,
+    opts: &Options,
+) -> Result<Stats, Failure> {
+    if !bmap.block_size.is_multiple_of(SECTOR_SIZE as u64) {
+        return Err(format!("bmap block size {} is not whole sectors", bmap.block_size).into());
+    }
+    let mut stats = Stats::default();
+    for r in &bmap.ranges {
+        let start = r.first * bmap.block_size;
+        let len = bmap.range_bytes(r);
+        f.seek(SeekFrom::Start(start)).map_err(|e| e.to_string())?;
+        let mut h = Hashing {
+            inner: f,
+            hash: Sha256::new(),
+        };
+        let at = lba + (start / SECTOR_SIZE as u64) as u32;
+        let s = write_stream(s, at, &mut h, len, opts)?;
+        let digest: [u8; 32] = h.hash.finalize().into();
+        if r.sha256.is_some_and(|d| d != digest) {
+            return Err(format!(
+                "Blocks {}-{} do not match the bmap, SHA-256 {}",
+                r.first,
+                r.last,
+                hexdump::hex(&digest)
+            )
+            .into());
+        }
+        stats.written += s.written;
+        stats.unchanged += s.unchanged;
+        stats.preserved += s.preserved;
+    }
+    Ok(stats)
+}
+
+/// An image to write, which verifying reads again
+pub trait Image: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> Image for T {}
+
+pub fn write(s: &Session, lba: u32, file: &Path, opts: &Options) -> Result<Stats, Failure> {
+    let err = |e: std::io::Error| match e.kind() {
+        std::io::ErrorKind::NotFound => Failure::Config(format!("{}: {e}", file.display())),
+        _ => Failure::Other(format!("{}: {e}", file.display())),
+    };
+    let mut f = File::open(file).map_err(err)?;
+    let len = f.metadata().map_err(err)?.len();
+    write_image(s, lba, &file.display().to_string(), &mut f, len, opts)
+}
+
+/// Write the `len` bytes of an image, `name` in messages
+pub fn write_image(
+    s: &Session,
+    lba: u32,
+    name: &str,
+    f: &mut dyn Image,
+    len: u64,
+    opts: &Options,
+) -> Result<Stats, Failure> {
+    let err = |e: std::io::Error| format!("{name}: {e}");
+    let (retries, (_, downshifts)) = (retry::retries(), s.integrity());
+    let extents: Vec<Extent> = match &opts.bmap {
+        Some(b) => b
+            .ranges
+            .iter()
+            .map(|r| Extent {
+                lba: lba + (r.first * b.block_size / SECTOR_SIZE as u64) as u32,
+                offset: r.first * b.block_size,
+                len: b.range_bytes(r),
+            })
+            .collect(),
+        None => vec![Extent {
+            lba,
+            offset: 0,
+            len,
+        }],
+    };
+    if let (Some(first), Some(end)) = (
+        extents.iter().map(|e| e.lba as u64).min(),
+        extents
+            .iter()
+            .map(|e| e.lba as u64 + e.len.div_ceil(SECTOR_SIZE as u64))
+            .max(),
+    ) {
+        check_capacity(s, first, end - first)?;
+        check_writable(s, first)?;
+    }
+    if let Some(r) = opts.resume {
+        info!("Resuming at sector {r:#x}");
+    }
+    let res = match &opts.bmap {
+        Some(b) if b.image_size != len => {
+            return Err(Failure::Config(format!(
+                "{name} has {len} bytes, the bmap is for {}",
+                b.image_size
+            )));
+        }
+        Some(b) => {
+            info!("Writing {} mapped bytes of {len}", b.mapped_bytes());
+            write_mapped(s, lba, f, b, opts).map(|st| Stats {
+                skipped: len.saturating_sub(b.mapped_bytes()),
+                ..st
+            })
+        }
+        None => write_stream(s, lba, f, len, opts),
+    };
+    let mut stats = match res {
+        Ok(stats) => stats,
+        Err(Failure::Disconnect(e)) => {
+            let at = resume_point();
+            return Err(Failure::Disconnect(format!(
+                "{e}. What comes before sector {at:#x} is written; continue with \
+                 --resume-at {at:#x}, or with --wait to do so once the device is back"
+            )));
+        }
+        Err(e) => return Err(e),
+    };
+    if let Some(kind) = opts.verify {
+        info!("Verifying");
+        let extents = outside(&extents, &preserved(s, opts)?);
+        let mut checks = verify(s, f, &extents, kind)?;
+        let bad: Vec<Extent> = checks
+            .iter()
+            .filter(|c| c.expected != c.found)
+            .map(|c| c.extent.clone())
+            .collect();
+        // Data corrupted on the way counts as a failed transfer, so the
+        // pieces may well be written again at a smaller transfer size
+        if !bad.is_empty() {
+            warn!("{} pieces differ, writing them again", bad.len());
+            // In full, even below where the write resumed
+            let again = Options {
+                preserve: opts.preserve.clone(),
+                ..Default::default()
+            };
+            for e in &bad {
+                s.transfer_failed();
+                f.seek(SeekFrom::Start(e.offset)).map_err(err)?;
+                write_stream(s, e.lba, &mut (&mut *f).take(e.len), e.len, &again)?;
+            }
+            checks = verify(s, f, &extents, kind)?;
+        }
+        report(&checks)?;
+        stats.verified = extents.iter().map(|e| e.len).sum();
+    }
+    // Failed LBA commands that were tried again are among these.
+    let (_, downshifts_now) = s.integrity();
+    stats.retried = retry::retries() - retries;
+    if downshifts_now > downshifts {
+        warn!(
+            "Reduced the transfer size {} times, check the USB cable and hubs",
+            downshifts_now - downshifts
+        );
+    }
+    info!(
+        "Wrote {} at sector {lba:#x}, {} unchanged, {} skipped, {} preserved, {} verified, {} retries",
+        size::human(stats.written),
+        size::human(stats.unchanged),
+        size::human(stats.skipped),
+        size::human(stats.preserved),
+        size::human(stats.verified),
+        stats.retried
+    );
+    Ok(stats)
+}
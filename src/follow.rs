@@ -0,0 +1,85 @@
+//! Following a device that may drop off the bus after a command
+//!
+//! Code run from the mask ROM, DDR init in particular, may reset the device
+//! or start a loader, after which it enumerates again at another address,
+//! e.g. in USB plug mode. Whether it does differs between chips and blobs,
+//! so after such a command the device is watched for a moment, and if it
+//! goes, waited for to come back on the same port.
+
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use crate::metrics::Failure;
+use crate::{DeviceAddr, provision};
+
+/// How long a device that is going to drop off takes to
+const SETTLE: Duration = Duration::from_secs(2);
+/// How long it takes to come back
+const REAPPEAR: Duration = Duration::from_secs(10);
+const POLL: Duration = Duration::from_millis(100);
+
+/// Where the device at `port`, last at `addr`, is once it has settled;
+/// `None` if it stayed
+pub fn settle(port: &str, addr: DeviceAddr) -> Result<Option<DeviceAddr>, Failure> {
+    watch(|| provision::locate(port), addr, SETTLE, REAPPEAR)
+        .map_err(|()| Failure::Disconnect(format!("{port} left the bus and did not come back")))
+}
+
+/// Watch where `locate` finds the device for `settle`; once it has left
+/// `addr`, wait at most `reappear` for it to come back
+pub fn watch(
+    mut locate: impl FnMut() -> Option<DeviceAddr>,
+    addr: DeviceAddr,
+    settle: Duration,
+    reappear: Duration,
+) -> Result<Option<DeviceAddr>, ()> {
+    let start = Instant::now();
+    let mut left = None;
+    loop {
+        match locate() {
+            Some(a) if a != addr => {
+                info!("Device is back at {a}");
+                return Ok(Some(a));
+            }
+            Some(_) if left.is_none() && start.elapsed() >= settle => return Ok(None),
+            Some(_) => {}
+            None if left.is_none() => {
+                info!("Device left {addr}, waiting for it to come back");
+                left = Some(Instant::now());
+            }
+            None => {}
+        }
+        if left.is_some_and(|t| t.elapsed() >= reappear) {
+            return Err(());
+        }
+        sleep(POLL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn devices_are_followed_when_they_move() {
+        let at = |address| Some(DeviceAddr { bus: 1, address });
+        let ms = Duration::from_millis;
+        // Stays put
+        assert_eq!(watch(|| at(5), at(5).unwrap(), ms(300), ms(300)), Ok(None));
+        // Drops off and comes back at another address
+        let mut seen = [at(5), None, None, at(6)].into_iter();
+        let got = watch(|| seen.next().flatten(), at(5).unwrap(), ms(300), ms(1000));
+        assert_eq!(got, Ok(at(6)));
+        // Drops off for good
+        assert_eq!(watch(|| None, at(5).unwrap(), ms(100), ms(300)), Err(()));
+        assert!(crate::may_move(&["run".into(), "ddr.bin".into()]));
+        assert!(!crate::may_move(&[
+            "run".into(),
+            "--load-addr".into(),
+            "0".into(),
+            "x".into()
+        ]));
+    }
+}
@@ -0,0 +1,573 @@
+//! GUID partition table, see UEFI spec chapter 5
+
+use zerocopy::{FromBytes, FromZeros, IntoBytes};
+use zerocopy_derive::{FromBytes, Immutable, IntoBytes};
+
+use crate::metrics::Failure;
+use crate::protocol::SECTOR_SIZE;
+
+const SIGNATURE: &[u8; 8] = b"EFI PART";
+const CRC32: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+
+/// Writes sectors at an LBA, be it to a device or into a file
+pub type Write<'a> = dyn FnMut(u64, &[u8]) -> Result<(), Failure> + 'a;
+
+#[derive(Clone, Debug, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C, packed)]
+pub struct Header {
+    pub signature: [u8; 8],
+    pub revision: u32,
+    pub header_size: u32,
+    pub header_crc: u32,
+    pub _reserved: u32,
+    pub my_lba: u64,
+    pub alternate_lba: u64,
+    pub first_usable_lba: u64,
+    pub last_usable_lba: u64,
+    pub disk_guid: [u8; 16],
+    pub entries_lba: u64,
+    pub num_entries: u32,
+    pub entry_size: u32,
+    pub entries_crc: u32,
+}
+
+#[derive(Clone, Debug, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C, packed)]
+pub struct Entry {
+    pub type_guid: [u8; 16],
+    pub unique_guid: [u8; 16],
+    pub first_lba: u64,
+    pub last_lba: u64,
+    pub attributes: u64,
+    pub name: [u16; 36],
+}
+
+impl Entry {
+    pub fn is_used(&self) -> bool {
+        self.type_guid != [0; 16]
+    }
+
+    pub fn name(&self) -> String {
+        let n = self.name;
+        let end = n.iter().position(|&c| c == 0).unwrap_or(n.len());
+        String::from_utf16_lossy(&n[..end])
+    }
+
+    pub fn sectors(&self) -> u64 {
+        self.last_lba - self.first_lba + 1
+    }
+}
+
+fn utf16_name(name: &str) -> Result<[u16; 36], String> {
+    let mut n = [0; 36];
+    let u: Vec<u16> = name.encode_utf16().collect();
+    if u.len() > n.len() {
+        return Err(format!(
+            "Partition name {name} is longer than 36 characters"
+        ));
+    }
+    n[..u.len()].copy_from_slice(&u);
+    Ok(n)
+}
+
+/// A version 4 GUID from the standard library's per-process random keys
+fn random_guid() -> [u8; 16] {
+    use std::hash::{BuildHasher, Hasher};
+    let mut g = [0; 16];
+    for c in g.chunks_exact_mut(8) {
+        let mut h = std::collections::hash_map::RandomState::new().build_hasher();
+        h.write_u128(
+            std::time::SystemTime::UNIX_EPOCH
+                .elapsed()
+                .unwrap()
+                .as_nanos(),
+        );
+        c.copy_from_slice(&h.finish().to_le_bytes());
+    }
+    g[7] = (g[7] & 0x0f) | 0x40;
+    g[8] = (g[8] & 0x3f) | 0x80;
+    g
+}
+
+/// Well-known partition types by a short name
+pub const TYPES: [(&str, &str); 5] = [
+    ("linux", "0fc63daf-8483-4772-8e79-3d69d8477de4"),
+    ("efi", "c12a7328-f81f-11d2-ba4b-00a0c93ec93b"),
+    ("swap", "0657fd6d-a4ab-43c4-84e5-0933c84b4f4f"),
+    ("basic", "ebd0a0a2-b9e5-4433-87c0-68b6b72699c7"),
+    ("home", "933ac7e1-2eb4-4f13-b844-0e14e2aef915"),
+];
+
+/// Parse a GUID in textual form or a name from `TYPES`
+pub fn parse_guid(s: &str) -> Result<[u8; 16], String> {
+    let s = TYPES.iter().find(|(n, _)| *n == s).map_or(s, |(_, g)| g);
+    // Dashes after 8, 4, 4 and 4 hex digits, and hex digits only otherwise
+    let well_formed = s.len() == 36
+        && s.bytes().enumerate().all(|(n, c)| match n {
+            8 | 13 | 18 | 23 => c == b'-',
+            _ => c.is_ascii_hexdigit(),
+        });
+    if !well_formed {
+        return Err(format!("bad GUID {s:?}"));
+    }
+    let h: String = s.chars().filter(|&c| c != '-').collect();
+    let mut b = [0; 16];
+    for (n, v) in b.iter_mut().enumerate() {
+        *v = u8::from_str_radix(&h[2 * n..2 * n + 2], 16).unwrap();
+    }
+    // The first three fields are little endian.
+    b[0..4].reverse();
+    b[4..6].reverse();
+    b[6..8].reverse();
+    Ok(b)
+}
+
+/// Mixed-endian textual form, as in `8da63339-0007-60c0-c436-083ac8230908`
+pub fn guid_to_string(g: &[u8; 16]) -> String {
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{}",
+        u32::from_le_bytes(g[0..4].try_into().unwrap()),
+        u16::from_le_bytes(g[4..6].try_into().unwrap()),
+        u16::from_le_bytes(g[6..8].try_into().unwrap()),
+        g[8],
+        g[9],
+        g[10..]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>()
+    )
+}
+
+#[derive(Clone, Debug)]
+pub struct Gpt {
+    pub header: Header,
+    pub entries: Vec<Entry>,
+}
+
+impl Gpt {
+    /// An empty table with 128 entries for a disk of `total` sectors
+    pub fn empty(total: u64) -> Result<Gpt, String> {
+        let mut g = Gpt {
+            header: Header {
+                signature: *SIGNATURE,
+                revision: 0x10000,
+                header_size: 92,
+                header_crc: 0,
+                _reserved: 0,
+                my_lba: 1,
+                alternate_lba: 0,
+                first_usable_lba: 34,
+                last_usable_lba: 0,
+                disk_guid: random_guid(),
+                entries_lba: 2,
+                num_entries: 128,
+                entry_size: 128,
+                entries_crc: 0,
+            },
+            entries: vec![Entry::new_zeroed(); 128],
+        };
+        let last = total
+            .checked_sub(1)
+            .ok_or_else(|| too_small(total, &g.header))?;
+        g.fit(last)?;
+        Ok(g)
+    }
+
+    /// Partitions in use, in table order
+    pub fn partitions(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.iter().filter(|e| e.is_used())
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Entry> {
+        self.partitions().find(|e| e.name() == name)
+    }
+
+    /// Header sector and entry array with fresh CRCs
+    pub fn to_bytes(&self) -> (Vec<u8>, Vec<u8>) {
+        let mut h = self.header;
+        let size = h.entry_size as usize;
+        let mut entries = vec![0; self.entries.len() * size];
+        for (c, e) in entries.chunks_exact_mut(size).zip(&self.entries) {
+            c[..std::mem::size_of::<Entry>()].copy_from_slice(e.as_bytes());
+        }
+        h.entries_crc = CRC32.checksum(&entries);
+        h.header_crc = 0;
+        let mut sector = vec![0; SECTOR_SIZE];
+        sector[..std::mem::size_of::<Header>()].copy_from_slice(h.as_bytes());
+        let crc = CRC32.checksum(&sector[..h.header_size as usize]);
+        sector[16..20].copy_from_slice(&crc.to_le_bytes());
+        (sector, entries)
+    }
+
+    /// Adapt to a disk whose last sector is `last`, moving the end of the
+    /// usable area; returns whether anything had to change
+    pub fn fit(&mut self, last: u64) -> Result<bool, String> {
+        let last_usable = last
+            .checked_sub(entries_sectors(&self.header) as u64 + 1)
+            .filter(|&l| l >= self.header.first_usable_lba)
+            .ok_or_else(|| too_small(last + 1, &self.header))?;
+        let (alternate, usable) = (self.header.alternate_lba, self.header.last_usable_lba);
+        if alternate == last && usable == last_usable {
+            return Ok(false);
+        }
+        if let Some(p) = self.partitions().find(|p| p.last_lba > last_usable) {
+            return Err(format!("Partition {} extends beyond the disk", p.name()));
+        }
+        *self = self.primary(last);
+        self.header.last_usable_lba = last_usable;
+        Ok(true)
+    }
+
+    /// Where the free space after `lba` ends
+    fn free_until(&self, lba: u64) -> u64 {
+        self.partitions()
+            .map(|p| p.first_lba)
+            .filter(|&f| f > lba)
+            .min()
+            .map_or(self.header.last_usable_lba, |f| f - 1)
+    }
+
+    fn check_free(&self, first: u64, last: u64, except: Option<&str>) -> Result<(), String> {
+        let (lo, hi) = (self.header.first_usable_lba, self.header.last_usable_lba);
+        if first < lo || last > hi || last < first {
+            return Err(format!("Sectors {first}..={last} are outside {lo}..={hi}"));
+        }
+        match self
+            .partitions()
+            .filter(|p| Some(p.name().as_str()) != except)
+            .find(|p| first <= p.last_lba && p.first_lba <= last)
+        {
+            Some(p) => Err(format!("Sectors {first}..={last} overlap {}", p.name())),
+            None => Ok(()),
+        }
+    }
+
+    /// Add a partition at `first`, or after the last one, aligned to `align`
+    /// sectors; without a size, it takes up the free space after its start.
+    pub fn add(
+        &mut self,
+        name: &str,
+        type_guid: [u8; 16],
+        first: Option<u64>,
+        sectors: Option<u64>,
+        align: u64,
+    ) -> Result<(), String> {
+        if self.find(name).is_some() {
+            return Err(format!("Partition {name} exists already"));
+        }
+        let first = first.unwrap_or_else(|| {
+            let end = self.partitions().map(|p| p.last_lba + 1).max();
+            end.unwrap_or(self.header.first_usable_lba)
+                .next_multiple_of(align.max(1))
+        });
+        let last = match sectors {
+            Some(n) => {
+                first
+                    .checked_add(n)
+                    .filter(|_| n > 0)
+                    .ok_or(format!("Partition {name} of {n} sectors does not fit"))?
+                    - 1
+            }
+            None => self.free_until(first),
+        };
+        self.check_free(first, last, None)?;
+        let slot = self
+            .entries
+            .iter_mut()
+            .find(|e| !e.is_used())
+            .ok_or("Partition table is full")?;
+        *slot = Entry {
+            type_guid,
+            unique_guid: random_guid(),
+            first_lba: first,
+            last_lba: last,
+            attributes: 0,
+            name: utf16_name(name)?,
+        };
+        Ok(())
+    }
+
+    /// Change the size of a partition, keeping its start; without a size,
+    /// it takes up the free space after it
+    pub fn resize(&mut self, name: &str, sectors: Option<u64>) -> Result<(), String> {
+        let p = *self.find(name).ok_or(format!("No partition {name}"))?;
+        let first = p.first_lba;
+        let last = match sectors {
+            Some(n) => {
+                first
+                    .checked_add(n)
+                    .filter(|_| n > 0)
+                    .ok_or(format!("Partition {name} of {n} sectors does not fit"))?
+                    - 1
+            }
+            None => self.free_until(first),
+        };
+        self.check_free(first, last, Some(name))?;
+        let e = self
+            .entries
+            .iter_mut()
+            .find(|e| e.is_used() && e.name() == name);
+        e.unwrap().last_lba = last;
+        Ok(())
+    }
+
+    pub fn delete(&mut self, name: &str) -> Result<(), String> {
+        let e = self
+            .entries
+            .iter_mut()
+            .find(|e| e.is_used() && e.name() == name);
+        *e.ok_or(format!("No partition {name}"))? = Entry::new_zeroed();
+        Ok(())
+    }
+
+    /// The primary copy for a disk whose last sector is `last`
+    pub fn primary(&self, last: u64) -> Gpt {
+        let mut g = self.clone();
+        g.header.my_lba = 1;
+        g.header.alternate_lba = last;
+        g.header.entries_lba = 2;
+        g
+    }
+
+    /// The backup copy for a disk whose last sector is `last`
+    pub fn backup(&self, last: u64) -> Gpt {
+        let mut g = self.clone();
+        g.header.my_lba = last;
+        g.header.alternate_lba = 1;
+        g.header.entries_lba = last - entries_sectors(&g.header) as u64;
+        g
+    }
+}
+
+/// Master boot record covering the disk with one GPT protective partition
+pub fn protective_mbr(total: u64) -> Vec<u8> {
+    let mut m = vec![0; SECTOR_SIZE];
+    let p = &mut m[446..462];
+    p[1..4].copy_from_slice(&[0x00, 0x02, 0x00]);
+    p[4] = 0xee;
+    p[5..8].copy_from_slice(&[0xff, 0xff, 0xff]);
+    p[8..12].copy_from_slice(&1_u32.to_le_bytes());
+    p[12..16].copy_from_slice(&((total - 1).min(u32::MAX as u64) as u32).to_le_bytes());
+    m[510..].copy_from_slice(&[0x55, 0xaa]);
+    m
+}
+
+/// Parse a header sector and check its CRC
+pub fn parse_header(sector: &[u8]) -> Result<Header, String> {
+    let (h, _) = Header::read_from_prefix(sector).map_err(|_| "short GPT header")?;
+    if h.signature != *SIGNATURE {
+        return Err("no GPT signature".into());
+    }
+    let size = h.header_size as usize;
+    if !(92..=SECTOR_SIZE).contains(&size) {
+        return Err(format!("bad GPT header size {size}"));
+    }
+    let mut raw = sector[..size].to_vec();
+    raw[16..20].fill(0);
+    let crc = CRC32.checksum(&raw);
+    let expected = h.header_crc;
+    if crc != expected {
+        return Err(format!("GPT header CRC {crc:08x}, expected {expected:08x}"));
+    }
+    Ok(h)
+}
+
+/// Parse the entry array that belongs to a header and check its CRC
+pub fn parse_entries(h: &Header, data: &[u8]) -> Result<Vec<Entry>, String> {
+    let (n, size) = (h.num_entries as usize, h.entry_size as usize);
+    if size < std::mem::size_of::<Entry>() || n * size > data.len() {
+        return Err(format!("bad GPT entry layout, {n} x {size} bytes"));
+    }
+    let crc = CRC32.checksum(&data[..n * size]);
+    let expected = h.entries_crc;
+    if crc != expected {
+        return Err(format!(
+            "GPT entries CRC {crc:08x}, expected {expected:08x}"
+        ));
+    }
+    Ok(data[..n * size]
+        .chunks_exact(size)
+        .map(|c| Entry::read_from_prefix(c).unwrap().0)
+        .collect())
+}
+
+pub fn entries_sectors(h: &Header) -> u32 {
+    (h.num_entries * h.entry_size).div_ceil(SECTOR_SIZE as u32)
+}
+
+/// The disk cannot hold a GPT like `h` and a sector for partitions
+fn too_small(total: u64, h: &Header) -> String {
+    let min = h.first_usable_lba + entries_sectors(h) as u64 + 2;
+    format!("A disk of {total} sectors is too small for a GPT, it takes at least {min}")
+}
+
+/// Read a GPT copy through a function reading `count` sectors at `lba`;
+/// fails if reading does, and gives why if there is no intact copy
+pub fn read_at(
+    read: &mut dyn FnMut(u64, u32) -> Result<Vec<u8>, Failure>,
+    lba: u64,
+) -> Result<Result<Gpt, String>, Failure> {
+    let header = match parse_header(&read(lba, 1)?) {
+        Ok(h) => h,
+        Err(e) => return Ok(Err(e)),
+    };
+    let my_lba = header.my_lba;
+    if my_lba != lba {
+        return Ok(Err(format!("GPT header at {lba} claims to be at {my_lba}")));
+    }
+    let data = read(header.entries_lba, entries_sectors(&header))?;
+    Ok(parse_entries(&header, &data).map(|entries| Gpt { header, entries }))
+}
+
+/// Read the primary GPT
+pub fn read(
+    read: &mut dyn FnMut(u64, u32) -> Result<Vec<u8>, Failure>,
+) -> Result<Result<Gpt, String>, Failure> {
+    read_at(read, 1)
+}
+
+/// Rewrite whichever copy of the GPT is broken, missing or not where it
+/// belongs on a disk of `total` sectors from the one that is intact, also
+/// moving the end of the usable area when the disk size has changed.
+/// Returns what was found to be wrong.
+pub fn repair(
+    read: &mut dyn FnMut(u64, u32) -> Result<Vec<u8>, Failure>,
+    write: &mut Write,
+    total: u64,
+    dry_run: bool,
+) -> Result<Vec<String>, Failure> {
+    let last = total
+        .checked_sub(1)
+        .ok_or("The storage reports no sectors")?;
+    let mut found = Vec::new();
+    let primary = read_at(read, 1)?;
+    let stale = primary
+        .as_ref()
+        .ok()
+        .map(|p| p.header.alternate_lba)
+        .filter(|&a| a != last);
+    let backup = match (read_at(read, last)?, stale) {
+        (Err(_), Some(a)) => read_at(read, a)?,
+        (b, _) => b,
+    };
+    let mut g = match (primary, backup) {
+        (Ok(p), Err(e)) => {
+            found.push(format!("backup GPT: {e}"));
+            p
+        }
+        (Ok(p), Ok(_)) => p,
+        (Err(e), Ok(b)) => {
+            found.push(format!("primary GPT: {e}"));
+            b.primary(last)
+        }
+        (Err(p), Err(b)) => {
+            return Err(format!("No intact GPT; primary: {p}; backup: {b}").into());
+        }
+    };
+
+    let alternate = g.header.alternate_lba;
+    if g.fit(last)? {
+        found.push(format!(
+            "GPT is for a disk ending at {alternate}, this one ends at {last}"
+        ));
+    }
+
+    for copy in [g.primary(last), g.backup(last)] {
+        let (h, e) = copy.to_bytes();
+        let (h_lba, e_lba) = (copy.header.my_lba, copy.header.entries_lba);
+        let on_disk = read(e_lba, entries_sectors(&copy.header))?;
+        if read(h_lba, 1)? != h || on_disk[..e.len()] != e {
+            found.push(format!("rewrite GPT copy at {h_lba}"));
+            if !dry_run {
+                write(e_lba, &e)?;
+                write(h_lba, &h)?;
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// Write both copies of a GPT to a disk whose last sector is `last`
+pub fn write_both(g: &Gpt, last: u64, write: &mut Write) -> Result<(), Failure> {
+    for copy in [g.primary(last), g.backup(last)] {
+        let (h, e) = copy.to_bytes();
+        write(copy.header.entries_lba, &e)?;
+        write(copy.header.my_lba, &h)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_disks() {
+        for total in [0, 1, 34, 67] {
+            let e = Gpt::empty(total).err().unwrap();
+            assert!(e.contains("at least 68"), "{e}");
+        }
+        let mut g = Gpt::empty(68).unwrap();
+        assert_eq!({ g.header.last_usable_lba }, 34);
+        assert!(g.fit(66).is_err());
+        assert!(g.add("a", [1; 16], None, Some(0), 1).is_err());
+        assert!(g.add("a", [1; 16], Some(u64::MAX), Some(2), 1).is_err());
+        g.add("a", [1; 16], None, None, 1).unwrap();
+        assert_eq!(g.find("a").unwrap().sectors(), 1);
+        // Partitions keep a disk from shrinking below them
+        let mut g = Gpt::empty(1000).unwrap();
+        g.add("a", [1; 16], None, Some(900), 1).unwrap();
+        assert!(g.fit(500).is_err());
+        assert_eq!(g.fit(2000), Ok(true));
+    }
+
+    #[test]
+    fn guids() {
+        let g = parse_guid("8da63339-0007-60c0-c436-083ac8230908").unwrap();
+        assert_eq!(g[..4], [0x39, 0x33, 0xa6, 0x8d]);
+        assert_eq!(guid_to_string(&g), "8da63339-0007-60c0-c436-083ac8230908");
+        assert_eq!(
+            parse_guid("linux"),
+            parse_guid("0fc63daf-8483-4772-8e79-3d69d8477de4")
+        );
+        for bad in [
+            "",
+            "8da63339-0007-60c0-c436-083ac823090",
+            "8da63339-0007-60c0-c436-083ac82309080",
+            "8da633390007-60c0-c436-083ac8230908-",
+            "8da63339-0007-60c0-c436-083ac823090g",
+            "+da63339-0007-60c0-c436-083ac8230908",
+            "8da63339-0007-60c0-c436-083ac82309ä",
+            "8da63339-0007-60c0-c436-083ac823ää",
+        ] {
+            assert!(parse_guid(bad).is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn gpt_templates_scale_with_disk() {
+        use crate::template::{self, Template};
+        use clap::ValueEnum;
+        let total = 8 << 21; // 8 GiB
+        for &t in Template::value_variants() {
+            let g = template::build(t, total).unwrap();
+            let mut parts: Vec<_> = g.partitions().map(|p| (p.first_lba, p.last_lba)).collect();
+            parts.sort();
+            assert_eq!(parts[0].0, 16384);
+            assert!(parts.windows(2).all(|w| w[0].1 + 1 == w[1].0));
+            let last_usable = g.header.last_usable_lba;
+            assert_eq!(parts.last().unwrap().1, last_usable);
+        }
+        let g = template::build(Template::AndroidAb, total).unwrap();
+        assert_eq!(
+            g.find("super").unwrap().sectors(),
+            total * 40 / 100 / 2048 * 2048
+        );
+        let small = template::build(Template::DebianSimple, 256 << 11);
+        assert!(
+            small
+                .unwrap_err()
+                .contains("too small for debian-simple, efi")
+        );
+    }
+}
@@ -0,0 +1,82 @@
+//! A cheap gate before flashing: is the expected board there, as expected?
+//!
+//! The check connects to the device and compares its mode and chip with
+//! what is expected; a loader is also asked for its chip ID. Nothing is
+//! read from or written to storage. The outcome is one JSON object on
+//! stdout, with every check and whether it passed, and the exit status.
+
+use crate::json::{self, Value};
+use crate::metrics::Failure;
+use crate::session::Session;
+use crate::{DeviceAddr, Endpoints, Mode, chip, protocol};
+
+fn check(name: &str, ok: bool, expected: Value, actual: Value) -> Value {
+    json::obj([
+        ("name", name.into()),
+        ("ok", ok.into()),
+        ("expected", expected),
+        ("actual", actual),
+    ])
+}
+
+/// The checks on a connected device
+pub fn checks(s: &Session, mode: Option<Mode>, chip: Option<&str>) -> Result<Vec<Value>, Failure> {
+    let mut checks = vec![];
+    if let Some(m) = mode {
+        let actual = s.mode.to_string();
+        checks.push(check(
+            "mode",
+            s.mode == m,
+            m.to_string().into(),
+            actual.into(),
+        ));
+    }
+    if let Some(c) = chip {
+        let ok = chip::same_name(s.chip.name, c);
+        checks.push(check("chip", ok, c.into(), s.chip.name.into()));
+    }
+    // The mask ROM and U-Boot do not know the command.
+    if s.mode == Mode::UsbPlug {
+        let id = protocol::info(s)?;
+        let expected = chip.unwrap_or(s.chip.name);
+        let ok = chip::same_name(&id, expected);
+        checks.push(check("chip ID", ok, expected.into(), id.into()));
+    }
+    Ok(checks)
+}
+
+/// Check the device and print the outcome; fails if any check does
+pub fn healthcheck(
+    device: Option<DeviceAddr>,
+    eps: Endpoints,
+    mode: Option<Mode>,
+    chip: Option<&str>,
+) -> Result<(), String> {
+    let res = crate::connect_with(device, eps).and_then(|s| {
+        let checks = checks(&s, mode, chip)?;
+        Ok((s.mode.to_string(), s.chip.name, checks))
+    });
+    let (report, ok) = match res {
+        Ok((found_mode, found_chip, checks)) => {
+            let ok = checks
+                .iter()
+                .all(|c| c.get("ok") == Some(&Value::Bool(true)));
+            let report = json::obj([
+                ("ok", ok.into()),
+                ("mode", found_mode.into()),
+                ("chip", found_chip.into()),
+                ("checks", Value::Arr(checks)),
+            ]);
+            (report, ok)
+        }
+        Err(e) => {
+            let report = json::obj([("ok", false.into()), ("error", e.to_string().into())]);
+            (report, false)
+        }
+    };
+    println!("{report}");
+    match ok {
+        true => Ok(()),
+        false => Err("Health check failed".into()),
+    }
+}
@@ -0,0 +1,25 @@
+/// Lower case hex digits of the bytes, e.g. of a digest
+pub fn hex(d: &[u8]) -> String {
+    d.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Print data in the classic `hexdump -C` layout, offset by a base address
+pub fn hexdump(base: u32, data: &[u8]) {
+    for (n, line) in data.chunks(16).enumerate() {
+        let a = base as usize + n * 16;
+        let hex: Vec<String> = line.iter().map(|b| format!("{b:02x}")).collect();
+        let (h1, h2) = hex.split_at(hex.len().min(8));
+        let ascii: String = line
+            .iter()
+            .map(|&b| match b {
+                0x20..=0x7e => b as char,
+                _ => '.',
+            })
+            .collect();
+        println!(
+            "{a:08x}  {:<23}  {:<23}  |{ascii}|",
+            h1.join(" "),
+            h2.join(" ")
+        );
+    }
+}
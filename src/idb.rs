@@ -0,0 +1,218 @@
+//! ID block, the layout of the boot area the mask ROM loads from flash
+//!
+//! Four header sectors, all but the second scrambled with RC4, followed by
+//! DDR init and the loader, each padded to 2 KiB; the loader may be
+//! scrambled as well. This follows rkdeveloptool's `upgrade_loader`.
+
+use std::path::Path;
+
+use log::info;
+
+use crate::erase::BOOT_AREA;
+use crate::flash;
+use crate::loader::Loader;
+use crate::metrics::Failure;
+use crate::protocol::{self, SECTOR_SIZE};
+use crate::session::Session;
+
+/// Rockchip's CRC32, not the common reflected one
+pub static CRC32: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::Algorithm {
+    width: 32,
+    poly: 0x04c10db7,
+    init: 0,
+    refin: false,
+    refout: false,
+    xorout: 0,
+    check: 0x889a9615,
+    residue: 0,
+});
+static CRC16: crc::Crc<u16> = crc::Crc::<u16>::new(&crc::CRC_16_XMODEM);
+
+const RC4_KEY: [u8; 16] = [124, 78, 3, 4, 85, 5, 9, 7, 45, 44, 123, 56, 23, 13, 23, 17];
+pub const TAG: u32 = 0x0ff0aa55;
+const HEADER_SECTORS: usize = 4;
+const ALIGN: usize = 2048;
+
+// Byte offsets in sector 0
+const SEC0_RC4_FLAG: usize = 8;
+const SEC0_BOOT_CODE1_OFFSET: usize = 12;
+const SEC0_BOOT_CODE2_OFFSET: usize = 14;
+const SEC0_BOOT_DATA_SIZE: usize = 506;
+const SEC0_BOOT_CODE_SIZE: usize = 508;
+// Sector 1
+const SEC1_SYS_RESERVED_BLOCK: usize = 0;
+const SEC1_DISK0_SIZE: usize = 2;
+const SEC1_CHIP_TAG: usize = 10;
+// Sector 2
+const SEC2_VC_TAG: usize = 491;
+const SEC2_SEC0_CRC: usize = 494;
+const SEC2_SEC1_CRC: usize = 496;
+const SEC2_BOOT_CODE_CRC: usize = 498;
+const SEC2_CRC_TAG: usize = 506;
+const SEC2_SEC3_CRC: usize = 510;
+
+/// Scramble or unscramble, the key stream starting anew on each call
+pub fn rc4(data: &mut [u8]) {
+    let mut s: [u8; 256] = std::array::from_fn(|n| n as u8);
+    let mut j = 0_u8;
+    for n in 0..256 {
+        j = j
+            .wrapping_add(s[n])
+            .wrapping_add(RC4_KEY[n % RC4_KEY.len()]);
+        s.swap(n, j as usize);
+    }
+    let (mut a, mut b) = (0_u8, 0_u8);
+    for d in data {
+        a = a.wrapping_add(1);
+        b = b.wrapping_add(s[a as usize]);
+        s.swap(a as usize, b as usize);
+        *d ^= s[s[a as usize].wrapping_add(s[b as usize]) as usize];
+    }
+}
+
+pub fn rc4_sectors(data: &mut [u8]) {
+    data.chunks_mut(SECTOR_SIZE).for_each(rc4);
+}
+
+fn put16(d: &mut [u8], at: usize, v: u16) {
+    d[at..at + 2].copy_from_slice(&v.to_le_bytes());
+}
+
+fn put32(d: &mut [u8], at: usize, v: u32) {
+    d[at..at + 4].copy_from_slice(&v.to_le_bytes());
+}
+
+/// The ID block for a DDR init and a loader taken from a loader image
+pub fn make(ddr: &[u8], loader: &[u8], rc4_disabled: bool) -> Vec<u8> {
+    let data_sectors = ddr.len().next_multiple_of(ALIGN) / SECTOR_SIZE;
+    let boot_sectors = loader.len().next_multiple_of(ALIGN) / SECTOR_SIZE;
+    let mut idb = vec![0; (HEADER_SECTORS + data_sectors + boot_sectors) * SECTOR_SIZE];
+    let (header, code) = idb.split_at_mut(HEADER_SECTORS * SECTOR_SIZE);
+    let (sec01, sec23) = header.split_at_mut(2 * SECTOR_SIZE);
+    let (sec0, sec1) = sec01.split_at_mut(SECTOR_SIZE);
+    let (sec2, sec3) = sec23.split_at_mut(SECTOR_SIZE);
+
+    put32(sec0, 0, TAG);
+    put32(sec0, SEC0_RC4_FLAG, rc4_disabled as u32);
+    put16(sec0, SEC0_BOOT_CODE1_OFFSET, HEADER_SECTORS as u16);
+    put16(sec0, SEC0_BOOT_CODE2_OFFSET, HEADER_SECTORS as u16);
+    put16(sec0, SEC0_BOOT_DATA_SIZE, data_sectors as u16);
+    put16(
+        sec0,
+        SEC0_BOOT_CODE_SIZE,
+        (data_sectors + boot_sectors) as u16,
+    );
+
+    put16(sec1, SEC1_SYS_RESERVED_BLOCK, 0xc);
+    put16(sec1, SEC1_DISK0_SIZE, 0xffff);
+    put32(sec1, SEC1_CHIP_TAG, u32::from_le_bytes(*b"RK28"));
+
+    sec2[SEC2_VC_TAG..SEC2_VC_TAG + 2].copy_from_slice(b"VC");
+    sec2[SEC2_CRC_TAG..SEC2_CRC_TAG + 3].copy_from_slice(b"CRC");
+    put16(sec2, SEC2_SEC0_CRC, CRC16.checksum(sec0));
+    put16(sec2, SEC2_SEC1_CRC, CRC16.checksum(sec1));
+    put16(sec2, SEC2_SEC3_CRC, CRC16.checksum(sec3));
+
+    code[..ddr.len()].copy_from_slice(ddr);
+    let boot_at = data_sectors * SECTOR_SIZE;
+    code[boot_at..boot_at + loader.len()].copy_from_slice(loader);
+    // Loader images hold scrambled data unless RC4 is disabled for them;
+    // rkdeveloptool then scrambles it here, whole sectors only, and so do we.
+    if rc4_disabled {
+        rc4_sectors(&mut code[..ddr.len() / SECTOR_SIZE * SECTOR_SIZE]);
+        rc4_sectors(&mut code[boot_at..boot_at + loader.len() / SECTOR_SIZE * SECTOR_SIZE]);
+    }
+    put32(sec2, SEC2_BOOT_CODE_CRC, CRC32.checksum(code));
+
+    rc4(sec0);
+    rc4(sec2);
+    rc4(sec3);
+    idb
+}
+
+fn get16(d: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(d[at..at + 2].try_into().unwrap())
+}
+
+fn get32(d: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(d[at..at + 4].try_into().unwrap())
+}
+
+// U-Boot's newer header, used from RK3568 on, is not scrambled.
+const RKNS_MAGIC: &[u8; 4] = b"RKNS";
+const RKNS_SIZE_AND_NIMAGE: usize = 8;
+const RKNS_IMAGES: usize = 120;
+const RKNS_IMAGE_SIZE: usize = 88;
+
+/// Stages as (name, data)
+type Stages = Vec<(String, Vec<u8>)>;
+
+/// Stages of the ID block in the boot area, as (name, data), as they are
+/// stored: scrambled if the ID block has them scrambled, padded to whole
+/// sectors; fails if reading does, and gives why if there is no ID block
+pub fn read_stages(
+    read: &mut dyn FnMut(u32, u32) -> Result<Vec<u8>, Failure>,
+) -> Result<Result<Stages, String>, Failure> {
+    let lba = BOOT_AREA.start;
+    let sec0 = read(lba, 1)?;
+    if &sec0[..4] == RKNS_MAGIC {
+        let count = (get32(&sec0, RKNS_SIZE_AND_NIMAGE) >> 16).min(4) as usize;
+        return (0..count)
+            .map(|n| {
+                let v = get32(&sec0, RKNS_IMAGES + n * RKNS_IMAGE_SIZE);
+                let (offset, sectors) = (v & 0xffff, v >> 16);
+                Ok((format!("image {n}"), read(lba + offset, sectors)?))
+            })
+            .collect::<Result<_, _>>()
+            .map(Ok);
+    }
+    let mut sec0 = sec0;
+    rc4(&mut sec0);
+    if get32(&sec0, 0) != TAG {
+        return Ok(Err(format!("No ID block at sector {lba}")));
+    }
+    let data = get16(&sec0, SEC0_BOOT_DATA_SIZE) as u32;
+    let code = get16(&sec0, SEC0_BOOT_CODE_SIZE) as u32;
+    let first = lba + get16(&sec0, SEC0_BOOT_CODE1_OFFSET) as u32;
+    Ok(Ok(vec![
+        ("DDR init".into(), read(first, data)?),
+        (
+            "loader".into(),
+            read(first + data, code.saturating_sub(data))?,
+        ),
+    ]))
+}
+
+/// Report the stages installed in the boot area and their versions
+pub fn info(s: &Session) -> Result<(), Failure> {
+    let stages = read_stages(&mut |lba, n| protocol::read_lba(s, lba, n).map_err(Failure::from))??;
+    for (name, data) in stages {
+        crate::loader::print_stage(&name, &data);
+    }
+    Ok(())
+}
+
+/// Write the flash entries of a loader image to the boot area and read
+/// them back
+pub fn upgrade(s: &Session, file: &Path) -> Result<(), Failure> {
+    let data = std::fs::read(file).map_err(|e| format!("{}: {e}", file.display()))?;
+    let l = Loader::parse(data)?;
+    let ddr = l.flash_entry("FlashData")?;
+    let boot = l.flash_entry("FlashBoot")?;
+    let idb = make(ddr, boot, l.header.rc4_disabled != 0);
+
+    let sectors = (idb.len() / SECTOR_SIZE) as u32;
+    if sectors > BOOT_AREA.len() as u32 {
+        return Err(format!("ID block of {sectors} sectors exceeds the boot area").into());
+    }
+    let lba = BOOT_AREA.start;
+    flash::check_capacity(s, lba as u64, sectors as u64)?;
+    flash::check_writable(s, lba as u64)?;
+    info!("Write {} byte ID block at sector {lba}", idb.len());
+    protocol::write_lba(s, lba, &idb)?;
+    if protocol::read_lba(s, lba, sectors)? != idb {
+        return Err("Loader read back differs from what was written".into());
+    }
+    info!("Loader upgraded");
+    Ok(())
+}
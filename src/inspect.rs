@@ -0,0 +1,348 @@
+//! What an image file is, without a device
+//!
+//! Rockchip update images come in two layers: RKFW wraps a boot_merger
+//! loader and an RKAF image, which in turn is a list of partition images.
+//! Besides those, loaders, idbloaders, GPT disk images, Android sparse
+//! images and FIT images are told apart by their first bytes.
+
+use std::path::Path;
+
+use zerocopy::FromBytes;
+use zerocopy_derive::{FromBytes, Immutable, IntoBytes};
+
+use crate::erase::BOOT_AREA;
+use crate::loader::{self, Loader};
+use crate::protocol::SECTOR_SIZE;
+use crate::{clone, gpt, idb, placement, size};
+
+/// Header of an RKFW image, as rkImageMaker writes it
+#[derive(Clone, Debug, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C, packed)]
+struct RkfwHeader {
+    tag: [u8; 4],
+    size: u16,
+    version: u32,
+    merge_version: u32,
+    year: u16,
+    month: u8,
+    day: u8,
+    hour: u8,
+    minute: u8,
+    second: u8,
+    chip: u32,
+    loader_offset: u32,
+    loader_size: u32,
+    image_offset: u32,
+    image_size: u32,
+}
+
+/// A partition image in an RKAF image, as afptool writes it
+#[derive(Clone, Debug, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C, packed)]
+struct RkafPart {
+    name: [u8; 32],
+    file: [u8; 60],
+    nand_size: u32,
+    pos: u32,
+    /// Sector on the storage, or 0xffffffff if not written there
+    nand_addr: u32,
+    padded_size: u32,
+    size: u32,
+}
+
+#[derive(Clone, Debug, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C, packed)]
+struct RkafHeader {
+    magic: [u8; 4],
+    length: u32,
+    model: [u8; 0x22],
+    id: [u8; 0x1e],
+    manufacturer: [u8; 0x38],
+    _unknown: u32,
+    version: u32,
+    count: u32,
+    parts: [RkafPart; 16],
+}
+
+#[derive(Clone, Debug, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C, packed)]
+struct SparseHeader {
+    magic: u32,
+    major: u16,
+    minor: u16,
+    header_size: u16,
+    chunk_header_size: u16,
+    block_size: u32,
+    blocks: u32,
+    chunks: u32,
+    checksum: u32,
+}
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+
+fn text(b: &[u8]) -> String {
+    let end = b.iter().position(|&c| c == 0).unwrap_or(b.len());
+    String::from_utf8_lossy(&b[..end]).trim().to_string()
+}
+
+fn line(name: &str, value: impl std::fmt::Display) {
+    println!("{name:18} {value}");
+}
+
+fn be32(d: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(d.get(at..at + 4)?.try_into().unwrap()))
+}
+
+fn rkfw(d: &[u8]) -> Result<(), String> {
+    let (h, _) = RkfwHeader::read_from_prefix(d).map_err(|_| "RKFW header is cut short")?;
+    let (year, month, day, hour, minute) = (h.year, h.month, h.day, h.hour, h.minute);
+    line("Version", format!("{:#x}", { h.version }));
+    line(
+        "Released",
+        format!("{year}-{month:02}-{day:02} {hour:02}:{minute:02}"),
+    );
+    line("Chip", format!("{:#x}", { h.chip }));
+    let part = |offset: u32, len: u32, what: &str| {
+        d.get(offset as usize..offset as usize + len as usize)
+            .ok_or(format!("{what} at {offset:#x} is beyond the end"))
+    };
+    let l = part(h.loader_offset, h.loader_size, "Loader")?;
+    let i = part(h.image_offset, h.image_size, "RKAF image")?;
+    println!();
+    println!(
+        "Loader at {:#x}, {}",
+        { h.loader_offset },
+        size::human(l.len() as u64)
+    );
+    loader::print(&Loader::parse(l.to_vec())?)?;
+    println!();
+    println!(
+        "RKAF image at {:#x}, {}",
+        { h.image_offset },
+        size::human(i.len() as u64)
+    );
+    rkaf(i)
+}
+
+fn rkaf(d: &[u8]) -> Result<(), String> {
+    let (h, _) = RkafHeader::read_from_prefix(d).map_err(|_| "RKAF header is cut short")?;
+    line("Model", text(&h.model));
+    line("Manufacturer", text(&h.manufacturer));
+    line("Version", format!("{:#x}", { h.version }));
+    for p in h.parts.iter().take(h.count.min(16) as usize) {
+        let at = match p.nand_addr {
+            0xffff_ffff => "not on storage".to_string(),
+            a => format!("sector {a:#x}"),
+        };
+        println!(
+            "{:18} {}, {}, {at}",
+            text(&p.name),
+            text(&p.file),
+            size::human(p.size as u64)
+        );
+    }
+    Ok(())
+}
+
+fn idbloader(d: &[u8]) -> Result<(), String> {
+    // The file starts where the boot area does.
+    let stages = idb::read_stages(&mut |lba, n| {
+        let from = (lba - BOOT_AREA.start) as usize * SECTOR_SIZE;
+        let mut s = d.get(from..).unwrap_or_default().to_vec();
+        s.resize(n as usize * SECTOR_SIZE, 0);
+        Ok(s)
+    })??;
+    for (name, data) in stages {
+        loader::print_stage(&name, &data);
+    }
+    Ok(())
+}
+
+fn disk(d: &[u8]) -> Result<(), String> {
+    let g = gpt::read(&mut |lba, n| {
+        let (from, len) = (lba as usize * SECTOR_SIZE, n as usize * SECTOR_SIZE);
+        let mut s = d.get(from..).unwrap_or_default().to_vec();
+        s.resize(len, 0);
+        Ok(s)
+    })??;
+    line("Disk GUID", gpt::guid_to_string(&{ g.header.disk_guid }));
+    for e in g.entries.iter().filter(|e| e.is_used()) {
+        let guid = gpt::guid_to_string(&{ e.type_guid });
+        let kind = gpt::TYPES
+            .iter()
+            .find(|(_, g)| *g == guid)
+            .map_or(guid.as_str(), |(n, _)| n);
+        println!(
+            "{:18} sector {:#x}, {}, {kind}",
+            e.name(),
+            { e.first_lba },
+            size::human(e.sectors() * SECTOR_SIZE as u64)
+        );
+    }
+    Ok(())
+}
+
+fn sparse(d: &[u8]) -> Result<(), String> {
+    let (h, _) = SparseHeader::read_from_prefix(d).map_err(|_| "Sparse header is cut short")?;
+    let (major, minor) = (h.major, h.minor);
+    line("Version", format!("{major}.{minor}"));
+    line("Block size", size::human(h.block_size as u64));
+    line(
+        "Expanded",
+        size::human(h.blocks as u64 * h.block_size as u64),
+    );
+    line("Chunks", h.chunks);
+    Ok(())
+}
+
+/// The string properties of the root node and of the nodes under /images
+fn fit(d: &[u8]) -> Result<(), String> {
+    let bad = || "Flattened device tree is cut short".to_string();
+    let structs = be32(d, 8).ok_or_else(bad)? as usize;
+    let strings = be32(d, 12).ok_or_else(bad)? as usize;
+    let mut path: Vec<String> = Vec::new();
+    let mut at = structs;
+    loop {
+        let token = be32(d, at).ok_or_else(bad)?;
+        at += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let name = text(d.get(at..).ok_or_else(bad)?);
+                at = (at + name.len() + 1).next_multiple_of(4);
+                if path.len() == 2 && path[1] == "images" {
+                    println!("{name}");
+                }
+                path.push(name);
+            }
+            FDT_END_NODE => {
+                path.pop();
+            }
+            FDT_PROP => {
+                let len = be32(d, at).ok_or_else(bad)? as usize;
+                let name_at = be32(d, at + 4).ok_or_else(bad)? as usize;
+                let value = d.get(at + 8..at + 8 + len).ok_or_else(bad)?;
+                at = (at + 8 + len).next_multiple_of(4);
+                let name = text(d.get(strings + name_at..).ok_or_else(bad)?);
+                let shown = ["description", "type", "arch", "os", "compression", "load"];
+                let is_text = value.last() == Some(&0)
+                    && value[..len - 1]
+                        .iter()
+                        .all(|b| b.is_ascii_graphic() || *b == b' ');
+                let interesting = path.len() == 1 || (path.len() == 3 && path[1] == "images");
+                if interesting && shown.contains(&name.as_str()) {
+                    let v = match is_text {
+                        true => text(value),
+                        false => value.iter().map(|b| format!("{b:02x}")).collect(),
+                    };
+                    line(&format!("  {name}"), v);
+                }
+            }
+            FDT_NOP => {}
+            _ => return Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Format {
+    Rkfw,
+    Rkaf,
+    Loader,
+    Idbloader,
+    Disk,
+    Sparse,
+    Fit,
+}
+
+impl Format {
+    fn name(self) -> &'static str {
+        match self {
+            Format::Rkfw => "Rockchip update image (RKFW)",
+            Format::Rkaf => "Rockchip partition images (RKAF)",
+            Format::Loader => "boot_merger loader",
+            Format::Idbloader => "idbloader",
+            Format::Disk => "disk image with a GPT",
+            Format::Sparse => "Android sparse image",
+            Format::Fit => "FIT image",
+        }
+    }
+}
+
+/// Format of an image starting with `head`
+pub fn identify(head: &[u8]) -> Option<Format> {
+    let magic: [u8; 4] = head.get(..4)?.try_into().unwrap();
+    Some(match &magic {
+        b"RKFW" => Format::Rkfw,
+        b"RKAF" => Format::Rkaf,
+        b"BOOT" | b"LDR " => Format::Loader,
+        _ if u32::from_le_bytes(magic) == clone::SPARSE_MAGIC => Format::Sparse,
+        _ if u32::from_be_bytes(magic) == FDT_MAGIC => Format::Fit,
+        _ => match placement::identify(head)?.lba? {
+            0 => Format::Disk,
+            _ => Format::Idbloader,
+        },
+    })
+}
+
+/// Tell what an image file is and show what it holds
+pub fn inspect(file: &Path) -> Result<(), String> {
+    let d = std::fs::read(file).map_err(|e| format!("{}: {e}", file.display()))?;
+    let format = identify(&d).ok_or(format!(
+        "{}: not an image format known here",
+        file.display()
+    ))?;
+    line("Format", format.name());
+    line("Size", size::human(d.len() as u64));
+    if let Some(at) = placement::identify(&d).and_then(|k| k.lba) {
+        line("Belongs at", format!("sector {at:#x}"));
+    }
+    match format {
+        Format::Rkfw => rkfw(&d),
+        Format::Rkaf => rkaf(&d),
+        Format::Loader => loader::print(&Loader::parse(d)?),
+        Format::Idbloader => idbloader(&d),
+        Format::Disk => disk(&d),
+        Format::Sparse => sparse(&d),
+        Format::Fit => fit(&d),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inspect_tells_formats() {
+        assert_eq!(identify(b"BOOT\0\0"), Some(Format::Loader));
+        assert_eq!(identify(b"RKFW\0\0"), Some(Format::Rkfw));
+        assert_eq!(identify(b"RKAF\0\0"), Some(Format::Rkaf));
+        let sparse = clone::SPARSE_MAGIC.to_le_bytes();
+        assert_eq!(identify(&sparse), Some(Format::Sparse));
+        assert_eq!(identify(&[0xd0, 0x0d, 0xfe, 0xed]), Some(Format::Fit));
+        assert_eq!(identify(&[0; 1024]), None);
+
+        let file = std::env::temp_dir().join(format!("rk_boot-inspect-{}", std::process::id()));
+        let idb = idb::make(&[1; 1000], &[2; 3000], false);
+        assert_eq!(identify(&idb), Some(Format::Idbloader));
+        std::fs::write(&file, &idb).unwrap();
+        inspect(&file).unwrap();
+
+        let mut g = gpt::Gpt::empty(256).unwrap();
+        let linux = gpt::parse_guid("linux").unwrap();
+        g.add("rootfs", linux, Some(64), Some(128), 1).unwrap();
+        let mut disk = vec![0; 256 * 512];
+        gpt::write_both(&g, 255, &mut |lba, d| {
+            disk[lba as usize * 512..][..d.len()].copy_from_slice(d);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(identify(&disk), Some(Format::Disk));
+        std::fs::write(&file, &disk).unwrap();
+        inspect(&file).unwrap();
+        std::fs::remove_file(&file).unwrap();
+    }
+}
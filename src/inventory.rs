@@ -0,0 +1,115 @@
+//! Inventory of attached devices for asset tracking
+//!
+//! Without probing, what USB tells: IDs, chip, speed, port and mode, which
+//! takes opening the device but not claiming it. Probing connects to each
+//! device that runs a loader and collects its chip ID, flash, installed
+//! loader and serial number. Devices in mask ROM mode only have what USB
+//! tells.
+
+use log::warn;
+use nusb::Speed;
+
+use crate::json::{self, Value};
+use crate::metrics::Failure;
+use crate::{DeviceAddr, Mode, hexdump, idb, loader, protocol, vendor};
+
+fn probe(addr: DeviceAddr) -> Result<Vec<(String, Value)>, Failure> {
+    let s = crate::connect(Some(addr))?;
+    let mode = s.mode;
+    if !matches!(mode, Mode::UsbPlug | Mode::Rockusb) {
+        return Err(format!("in {mode} mode, run boot first").into());
+    }
+    let fi = protocol::flash_info(&s)?;
+    let read = &mut |lba, n| protocol::read_lba(&s, lba, n).map_err(Failure::from);
+    let stages = idb::read_stages(read)?.unwrap_or_default();
+    let versions: Vec<Value> = stages
+        .iter()
+        .flat_map(|(_, d)| loader::versions(d))
+        .map(Value::from)
+        .collect();
+    let (sectors, block) = (fi.sectors, fi.block_sectors);
+    Ok(vec![
+        ("chip_id".into(), protocol::info(&s)?.into()),
+        (
+            "flash_id".into(),
+            hexdump::hex(&protocol::flash_id(&s)?).into(),
+        ),
+        (
+            "flash".into(),
+            json::obj([
+                (
+                    "bytes",
+                    (sectors as u64 * protocol::SECTOR_SIZE as u64).into(),
+                ),
+                ("block_sectors", block.into()),
+                ("manufacturer", fi.manufacturer.into()),
+            ]),
+        ),
+        ("loader".into(), Value::Arr(versions)),
+        ("sn".into(), vendor::serial(read)?.into()),
+    ])
+}
+
+/// Everything known about one device; probing failures end up in `error`
+fn entry(d: &nusb::DeviceInfo, probe_it: bool) -> Value {
+    let Value::Obj(mut o) = crate::server::device_json(d) else {
+        unreachable!()
+    };
+    let release = d.device_version();
+    o.push((
+        "usb_release".into(),
+        format!("{:x}.{:02x}", release >> 8, release & 0xff).into(),
+    ));
+    let speed = d.speed().map(|s| match s {
+        Speed::Low => "low",
+        Speed::Full => "full",
+        Speed::High => "high",
+        Speed::Super => "super",
+        Speed::SuperPlus => "super+",
+        _ => "unknown",
+    });
+    o.push(("speed".into(), speed.into()));
+    o.push(("port".into(), crate::port_path(d).into()));
+    o.push(("serial".into(), d.serial_number().into()));
+    // Telling the mode takes the configuration descriptors.
+    match crate::usb_mode(d) {
+        Ok(m) => o.push(("mode".into(), m.to_string().into())),
+        Err(e) => o.push(("mode_error".into(), e.into())),
+    }
+    if probe_it {
+        let addr = DeviceAddr {
+            bus: d.bus_number(),
+            address: d.device_address(),
+        };
+        match probe(addr) {
+            Ok(fields) => o.extend(fields),
+            Err(e) => {
+                warn!("{addr}: {e}");
+                o.push(("error".into(), e.to_string().into()));
+            }
+        }
+    }
+    Value::Obj(o)
+}
+
+pub fn list(as_json: bool, probe_it: bool) {
+    let entries: Vec<Value> = crate::rockchip_devices()
+        .map(|d| entry(&d, probe_it))
+        .collect();
+    if as_json {
+        println!("{}", Value::Arr(entries));
+        return;
+    }
+    for e in entries {
+        let Value::Obj(fields) = e else { continue };
+        let line: Vec<String> = fields
+            .iter()
+            .filter(|(_, v)| *v != Value::Null)
+            .map(|(k, v)| match v {
+                Value::Str(s) => format!("{k}={s}"),
+                v => format!("{k}={v}"),
+            })
+            .collect();
+        println!("{}", line.join(" "));
+    }
+}
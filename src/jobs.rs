@@ -0,0 +1,363 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use log::{Log, Metadata, Record};
+
+use crate::DeviceAddr;
+use crate::json::{self, Value};
+use crate::metrics::Failure;
+use crate::protocol::{TRANSFERRED, WRITTEN};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Status {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Queued => "queued",
+            Self::Running => "running",
+            Self::Done => "done",
+            Self::Failed => "failed",
+        };
+        write!(f, "{s}")
+    }
+}
+
+struct State {
+    status: Status,
+    error: Option<String>,
+    log: Vec<String>,
+}
+
+/// A command line run on behalf of a remote client
+pub struct Job {
+    pub id: u64,
+    pub args: Vec<String>,
+    /// None means whichever device is found first
+    pub device: Option<DeviceAddr>,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl Job {
+    fn update(&self, f: impl FnOnce(&mut State)) {
+        f(&mut self.state.lock().unwrap());
+        self.changed.notify_all();
+    }
+
+    fn ended(&self) -> bool {
+        matches!(
+            self.state.lock().unwrap().status,
+            Status::Done | Status::Failed
+        )
+    }
+
+    pub fn to_json(&self) -> Value {
+        let s = self.state.lock().unwrap();
+        json::obj([
+            ("id", self.id.into()),
+            ("args", self.args.clone().into()),
+            ("device", self.device.map(|d| d.to_string()).into()),
+            ("status", s.status.to_string().into()),
+            ("error", s.error.clone().into()),
+            ("log_lines", s.log.len().into()),
+        ])
+    }
+
+    /// Block until there are log lines after `from` or the job has ended;
+    /// returns the new lines and whether the job has ended.
+    pub fn wait_log(&self, from: usize) -> (Vec<String>, bool) {
+        let s = self.state.lock().unwrap();
+        let s = self
+            .changed
+            .wait_while(s, |s| {
+                s.log.len() <= from && matches!(s.status, Status::Queued | Status::Running)
+            })
+            .unwrap();
+        let ended = matches!(s.status, Status::Done | Status::Failed);
+        (s.log[from.min(s.log.len())..].to_vec(), ended)
+    }
+}
+
+/// Where jobs may write files, if they are confined
+static CONFINED: OnceLock<Vec<PathBuf>> = OnceLock::new();
+
+/// Let jobs write files only below `dirs`, for a daemon whose command lines
+/// come from remote clients
+pub fn confine(dirs: &[PathBuf]) -> Result<(), String> {
+    let dirs = dirs
+        .iter()
+        .map(|d| d.canonicalize().map_err(|e| format!("{}: {e}", d.display())))
+        .collect::<Result<_, _>>()?;
+    CONFINED
+        .set(dirs)
+        .map_err(|_| "jobs are confined already".to_string())
+}
+
+pub fn confined() -> bool {
+    CONFINED.get().is_some()
+}
+
+/// Fail unless a job may write `path`: in a directory it is confined to,
+/// and not through a link
+pub fn may_write(path: &Path) -> Result<(), String> {
+    let Some(dirs) = CONFINED.get() else {
+        return Ok(());
+    };
+    let refused = || {
+        let dirs: Vec<_> = dirs.iter().map(|d| d.display().to_string()).collect();
+        format!(
+            "{}: jobs may only write files below {}",
+            path.display(),
+            dirs.join(", ")
+        )
+    };
+    let (Some(parent), Some(Component::Normal(_))) = (path.parent(), path.components().next_back())
+    else {
+        return Err(refused());
+    };
+    let parent = match parent.as_os_str().is_empty() {
+        true => Path::new("."),
+        false => parent,
+    };
+    let Ok(parent) = parent.canonicalize() else {
+        return Err(refused());
+    };
+    let linked = std::fs::symlink_metadata(path).is_ok_and(|m| !m.is_file());
+    if linked || !dirs.iter().any(|d| parent.starts_with(d)) {
+        return Err(refused());
+    }
+    Ok(())
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Arc<Job>>> = const { RefCell::new(None) };
+}
+
+/// Passes everything on to env_logger and additionally records the lines
+/// logged while running a job, so that clients can follow its progress.
+pub struct Logger(pub env_logger::Logger);
+
+impl Log for Logger {
+    fn enabled(&self, m: &Metadata) -> bool {
+        self.0.enabled(m)
+    }
+
+    fn log(&self, r: &Record) {
+        self.0.log(r);
+        if !self.0.matches(r) {
+            return;
+        }
+        CURRENT.with(|j| {
+            if let Some(j) = &*j.borrow() {
+                let line = format!("{} {}", r.level(), r.args());
+                j.update(|s| s.log.push(line));
+            }
+        });
+    }
+
+    fn flush(&self) {
+        self.0.flush();
+    }
+}
+
+pub fn init_logger(l: env_logger::Logger) {
+    log::set_max_level(l.filter());
+    log::set_boxed_logger(Box::new(Logger(l))).unwrap();
+}
+
+/// The failure a panic, i.e. a bug, stands for; it is of no particular
+/// class
+fn panic_failure(e: Box<dyn std::any::Any + Send>) -> Failure {
+    match e.downcast::<String>() {
+        Ok(s) => Failure::Other(*s),
+        Err(e) => match e.downcast::<&str>() {
+            Ok(s) => Failure::Other(s.to_string()),
+            Err(_) => Failure::Other("unknown panic".into()),
+        },
+    }
+}
+
+/// Run a command line and audit it; a panic fails the job rather than
+/// the server
+pub fn run_caught(args: &[String]) -> Result<(), Failure> {
+    let res = catch_unwind(AssertUnwindSafe(|| crate::run_args(args)))
+        .unwrap_or_else(|e| Err(panic_failure(e)));
+    crate::audit(args, &res);
+    res
+}
+
+/// What is in use right now
+#[derive(Default)]
+struct Slots {
+    per_bus: HashMap<Option<u8>, usize>,
+    devices: HashSet<Option<DeviceAddr>>,
+    busy_since: Option<Instant>,
+    busy: Duration,
+    /// When the last job ended
+    ended: Option<Instant>,
+}
+
+impl Slots {
+    fn active(&self) -> usize {
+        self.per_bus.values().sum()
+    }
+}
+
+/// All jobs of this process. A device runs one job at a time, and devices
+/// on one bus, i.e., host controller, share a limited number of slots, so
+/// that big transfers do not starve each other into timeouts.
+pub struct Jobs {
+    jobs: Mutex<Vec<Arc<Job>>>,
+    next_id: AtomicU64,
+    max_per_bus: usize,
+    /// Jobs queued or running at most
+    max_jobs: usize,
+    slots: Mutex<Slots>,
+    freed: Condvar,
+    started: Instant,
+}
+
+impl Jobs {
+    pub fn new(max_per_bus: usize, max_jobs: usize) -> Self {
+        Self {
+            jobs: Mutex::default(),
+            next_id: AtomicU64::new(0),
+            max_per_bus: max_per_bus.max(1),
+            max_jobs: max_jobs.max(1),
+            slots: Mutex::default(),
+            freed: Condvar::new(),
+            started: Instant::now(),
+        }
+    }
+
+    fn acquire(&self, device: Option<DeviceAddr>) {
+        let bus = device.map(|d| d.bus);
+        let slots = self.slots.lock().unwrap();
+        let mut slots = self
+            .freed
+            .wait_while(slots, |s| {
+                s.devices.contains(&device)
+                    || s.per_bus.get(&bus).copied().unwrap_or(0) >= self.max_per_bus
+            })
+            .unwrap();
+        if slots.active() == 0 {
+            slots.busy_since = Some(Instant::now());
+        }
+        *slots.per_bus.entry(bus).or_default() += 1;
+        slots.devices.insert(device);
+    }
+
+    fn release(&self, device: Option<DeviceAddr>) {
+        let mut slots = self.slots.lock().unwrap();
+        *slots.per_bus.get_mut(&device.map(|d| d.bus)).unwrap() -= 1;
+        slots.devices.remove(&device);
+        slots.ended = Some(Instant::now());
+        if slots.active() == 0
+            && let Some(t) = slots.busy_since.take()
+        {
+            slots.busy += t.elapsed();
+        }
+        self.freed.notify_all();
+    }
+
+    pub fn submit(self: &Arc<Self>, args: Vec<String>) -> Result<Arc<Job>, String> {
+        let device = crate::job_device(&args)?;
+        let mut all = self.jobs.lock().unwrap();
+        if all.iter().filter(|j| !j.ended()).count() >= self.max_jobs {
+            return Err(format!("{} jobs are queued or running already", self.max_jobs));
+        }
+        let job = Arc::new(Job {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed) + 1,
+            args,
+            device,
+            state: Mutex::new(State {
+                status: Status::Queued,
+                error: None,
+                log: Vec::new(),
+            }),
+            changed: Condvar::new(),
+        });
+        all.push(job.clone());
+        drop(all);
+
+        let jobs = self.clone();
+        let j = job.clone();
+        std::thread::spawn(move || {
+            jobs.acquire(j.device);
+            j.update(|s| s.status = Status::Running);
+            let start = Instant::now();
+            CURRENT.with(|c| *c.borrow_mut() = Some(j.clone()));
+            let res = run_caught(&j.args);
+            CURRENT.with(|c| *c.borrow_mut() = None);
+            jobs.release(j.device);
+            crate::metrics::job_finished(start.elapsed(), res.as_ref().err());
+            j.update(|s| match res {
+                Ok(()) => s.status = Status::Done,
+                Err(e) => {
+                    s.status = Status::Failed;
+                    s.error = Some(e.to_string());
+                }
+            });
+        });
+        Ok(job)
+    }
+
+    pub fn get(&self, id: u64) -> Option<Arc<Job>> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|j| j.id == id)
+            .cloned()
+    }
+
+    pub fn list(&self) -> Vec<Arc<Job>> {
+        self.jobs.lock().unwrap().clone()
+    }
+
+    /// How long no job has been queued or running, if none is
+    pub fn idle(&self) -> Option<Duration> {
+        let waiting = self.list().iter().any(|j| !j.ended());
+        let since = self.slots.lock().unwrap().ended.unwrap_or(self.started);
+        (!waiting).then(|| since.elapsed())
+    }
+
+    /// Job counts and the throughput over the time any job was running
+    pub fn stats(&self) -> Value {
+        let mut counts: HashMap<Status, usize> = HashMap::new();
+        for j in self.list() {
+            *counts.entry(j.state.lock().unwrap().status).or_default() += 1;
+        }
+        let count = |s| counts.get(&s).copied().unwrap_or(0);
+        let slots = self.slots.lock().unwrap();
+        let busy = slots.busy + slots.busy_since.map_or(Duration::ZERO, |t| t.elapsed());
+        let bytes = TRANSFERRED.load(Ordering::Relaxed);
+        let rate = match busy.as_secs_f64() {
+            0.0 => 0.0,
+            t => bytes as f64 / t,
+        };
+        json::obj([
+            ("queued", count(Status::Queued).into()),
+            ("running", count(Status::Running).into()),
+            ("done", count(Status::Done).into()),
+            ("failed", count(Status::Failed).into()),
+            ("max_per_bus", self.max_per_bus.into()),
+            ("max_jobs", self.max_jobs.into()),
+            ("bytes", bytes.into()),
+            ("written_bytes", WRITTEN.load(Ordering::Relaxed).into()),
+            ("busy_seconds", busy.as_secs_f64().into()),
+            ("bytes_per_second", rate.into()),
+        ])
+    }
+}
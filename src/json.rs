@@ -0,0 +1,232 @@
+//! Just enough JSON for reports, manifests and the daemon API; parsing
+//! is left to serde_json
+
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Arr(Vec<Value>),
+    /// Keeps insertion order so output is stable and readable
+    Obj(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Self::Obj(o) => o.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Self::Arr(a) => Some(a),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Self::Str(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Self::Str(s)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Self::Bool(b)
+    }
+}
+
+macro_rules! from_int {
+    ($($t:ty),*) => {$(
+        impl From<$t> for Value {
+            fn from(i: $t) -> Self {
+                Self::Int(i as i64)
+            }
+        }
+    )*};
+}
+from_int!(u8, u16, u32, u64, usize, i32, i64);
+
+impl From<f64> for Value {
+    fn from(f: f64) -> Self {
+        Self::Float(f)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(o: Option<T>) -> Self {
+        o.map_or(Self::Null, Into::into)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(v: Vec<T>) -> Self {
+        Self::Arr(v.into_iter().map(Into::into).collect())
+    }
+}
+
+/// Build an object from key-value pairs
+pub fn obj<const N: usize>(pairs: [(&str, Value); N]) -> Value {
+    Value::Obj(pairs.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+}
+
+fn write_str(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    f.write_str("\"")
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Null => f.write_str("null"),
+            Self::Bool(b) => write!(f, "{b}"),
+            Self::Int(i) => write!(f, "{i}"),
+            Self::Float(x) if x.is_finite() => write!(f, "{x}"),
+            Self::Float(_) => f.write_str("null"),
+            Self::Str(s) => write_str(f, s),
+            Self::Arr(a) => {
+                f.write_str("[")?;
+                for (n, v) in a.iter().enumerate() {
+                    if n > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{v}")?;
+                }
+                f.write_str("]")
+            }
+            Self::Obj(o) => {
+                f.write_str("{")?;
+                for (n, (k, v)) in o.iter().enumerate() {
+                    if n > 0 {
+                        f.write_str(",")?;
+                    }
+                    write_str(f, k)?;
+                    write!(f, ":{v}")?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+impl From<serde_json::Value> for Value {
+    fn from(v: serde_json::Value) -> Self {
+        use serde_json::Value as J;
+        match v {
+            J::Null => Self::Null,
+            J::Bool(b) => Self::Bool(b),
+            J::Number(n) => n
+                .as_i64()
+                .map_or_else(|| Self::Float(n.as_f64().unwrap_or(f64::NAN)), Self::Int),
+            J::String(s) => Self::Str(s),
+            J::Array(a) => Self::Arr(a.into_iter().map(Into::into).collect()),
+            J::Object(o) => Self::Obj(o.into_iter().map(|(k, v)| (k, v.into())).collect()),
+        }
+    }
+}
+
+/// Parse with serde_json, which also refuses nesting deep enough to run
+/// out of stack on input from the network
+pub fn parse(s: &str) -> Result<Value, String> {
+    serde_json::from_str::<serde_json::Value>(s)
+        .map(Into::into)
+        .map_err(|e| format!("JSON: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let v = obj([
+            ("name", "a \"quoted\"\n\tline".into()),
+            ("n", (-12i64).into()),
+            ("x", Value::Float(1.5)),
+            ("list", vec![true, false].into()),
+            ("none", Value::Null),
+            ("bell", "\u{7}".into()),
+        ]);
+        assert_eq!(parse(&v.to_string()).unwrap(), v);
+        let v = parse(" { \"a\" : [ 1 , 2.5e3 , \"\\/\" ] } ").unwrap();
+        assert_eq!(
+            v.get("a").unwrap().as_array().unwrap()[2].as_str(),
+            Some("/")
+        );
+    }
+
+    #[test]
+    fn unicode_escapes() {
+        let v = parse(r#""\u00e9 \uD83D\uDE00 \ud83d\ude00""#).unwrap();
+        assert_eq!(v.as_str(), Some("\u{e9} \u{1f600} \u{1f600}"));
+        assert_eq!(parse("\"😀\"").unwrap().as_str(), Some("😀"));
+        let lone = [
+            r#""\uD83D""#,
+            r#""\uD83Dx""#,
+            r#""\uD83D\u0041""#,
+            r#""\uDE00""#,
+            r#""\u12""#,
+        ];
+        for bad in lone {
+            assert!(parse(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        for bad in [
+            "",
+            "[1,",
+            "{\"a\" 1}",
+            "[1] 2",
+            "nul",
+            "\"open",
+            "{1: 2}",
+            "[1,]",
+        ] {
+            assert!(parse(bad).is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let ok = "[".repeat(64) + &"]".repeat(64);
+        assert!(parse(&ok).is_ok());
+        // Not even the stack of a small thread runs out.
+        let bomb = "[{\"a\":".repeat(100_000);
+        let t = std::thread::Builder::new().stack_size(512 * 1024);
+        let r = t.spawn(move || parse(&bomb).is_err()).unwrap().join();
+        assert!(r.unwrap());
+    }
+}
@@ -0,0 +1,283 @@
+//! Loader images made by boot_merger, as rkdeveloptool takes them
+//!
+//! A header lists three sets of entries: 471 and 472 ones go to the mask
+//! ROM over USB (DDR init and usbplug), loader ones to the boot area on
+//! flash (`FlashData`, DDR init, and `FlashBoot`, the SPL or miniloader).
+//! The file ends in a CRC32 of the rest.
+
+use std::path::Path;
+
+use log::warn;
+use zerocopy::FromBytes;
+use zerocopy_derive::{FromBytes, Immutable, IntoBytes};
+
+use crate::idb::{self, CRC32};
+
+#[derive(Clone, Debug, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C, packed)]
+pub struct Header {
+    pub tag: [u8; 4],
+    pub size: u16,
+    pub version: u32,
+    pub merge_version: u32,
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub chip: u32,
+    pub count_471: u8,
+    pub offset_471: u32,
+    pub size_471: u8,
+    pub count_472: u8,
+    pub offset_472: u32,
+    pub size_472: u8,
+    pub loader_count: u8,
+    pub loader_offset: u32,
+    pub loader_size: u8,
+    pub sign_flag: u8,
+    pub rc4_disabled: u8,
+    pub _reserved: [u8; 57],
+}
+
+#[derive(Clone, Debug, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C, packed)]
+pub struct Entry {
+    pub size: u8,
+    pub kind: u32,
+    pub name: [u16; 20],
+    pub data_offset: u32,
+    pub data_size: u32,
+    pub delay: u32,
+}
+
+impl Entry {
+    pub fn name(&self) -> String {
+        let n = self.name;
+        let end = n.iter().position(|&c| c == 0).unwrap_or(n.len());
+        String::from_utf16_lossy(&n[..end])
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// Downloaded to SRAM via request 0x471
+    Ddr = 1,
+    /// Downloaded to DRAM via request 0x472
+    Usbplug = 2,
+    /// Written to flash
+    Flash = 4,
+}
+
+pub struct Loader {
+    pub header: Header,
+    pub data: Vec<u8>,
+}
+
+impl Loader {
+    pub fn parse(data: Vec<u8>) -> Result<Loader, String> {
+        let (header, _) = Header::read_from_prefix(&data).map_err(|_| "Loader is too short")?;
+        if &header.tag != b"BOOT" && &header.tag != b"LDR " {
+            return Err(format!("Not a loader, tag {:02x?}", header.tag));
+        }
+        if let Some((rest, crc)) = data.split_last_chunk::<4>()
+            && CRC32.checksum(rest) != u32::from_le_bytes(*crc)
+        {
+            warn!("Loader CRC does not match, the file may be corrupt");
+        }
+        let l = Loader { header, data };
+        for k in [Kind::Ddr, Kind::Usbplug, Kind::Flash] {
+            for e in l.entries(k)? {
+                l.entry_data(&e)?;
+            }
+        }
+        Ok(l)
+    }
+
+    pub fn entries(&self, kind: Kind) -> Result<Vec<Entry>, String> {
+        let h = &self.header;
+        let (count, offset, size) = match kind {
+            Kind::Ddr => (h.count_471, h.offset_471, h.size_471),
+            Kind::Usbplug => (h.count_472, h.offset_472, h.size_472),
+            Kind::Flash => (h.loader_count, h.loader_offset, h.loader_size),
+        };
+        (0..count as usize)
+            .map(|n| {
+                let at = offset as usize + n * size as usize;
+                let d = self.data.get(at..).unwrap_or_default();
+                let (e, _) =
+                    Entry::read_from_prefix(d).map_err(|_| "Loader entry out of bounds")?;
+                Ok(e)
+            })
+            .collect()
+    }
+
+    pub fn entry_data(&self, e: &Entry) -> Result<&[u8], String> {
+        let (at, len) = (e.data_offset as usize, e.data_size as usize);
+        self.data
+            .get(at..at + len)
+            .ok_or(format!("Data of loader entry {} out of bounds", e.name()))
+    }
+
+    /// Data of the flash entry with the given name
+    pub fn flash_entry(&self, name: &str) -> Result<&[u8], String> {
+        let entries = self.entries(Kind::Flash)?;
+        let e = entries
+            .iter()
+            .find(|e| e.name() == name)
+            .ok_or(format!("Loader has no {name} entry"))?;
+        self.entry_data(e)
+    }
+}
+
+// Strings that stages print on boot and carry their version in
+const VERSION_MARKERS: &[&str] = &[
+    "DDR Version",
+    "DDR V",
+    "U-Boot SPL",
+    "U-Boot 20",
+    "Boot1 Release Time",
+    "OP-TEE version",
+    "NOTICE:  BL31: v",
+];
+const MIN_STRING: usize = 8;
+
+/// Version strings in a stage, which may be RC4-scrambled
+pub fn versions(data: &[u8]) -> Vec<String> {
+    let mut plain = data.to_vec();
+    idb::rc4_sectors(&mut plain[..data.len() / 512 * 512]);
+    let mut found = Vec::new();
+    for d in [data, &plain] {
+        for s in d.split(|b| !(b.is_ascii_graphic() || *b == b' ')) {
+            let s = String::from_utf8_lossy(s).trim().to_string();
+            if s.len() >= MIN_STRING
+                && VERSION_MARKERS.iter().any(|m| s.contains(m))
+                && !found.contains(&s)
+            {
+                found.push(s);
+            }
+        }
+    }
+    found
+}
+
+pub fn print_stage(name: &str, data: &[u8]) {
+    println!("{name:18} {} bytes", data.len());
+    for v in versions(data) {
+        println!("{:18}   {v}", "");
+    }
+}
+
+/// Describe a loader image file
+pub fn info(file: &Path) -> Result<(), String> {
+    let data = std::fs::read(file).map_err(|e| format!("{}: {e}", file.display()))?;
+    print(&Loader::parse(data)?)
+}
+
+/// Describe a loader: its header, and its stages with their versions
+pub fn print(l: &Loader) -> Result<(), String> {
+    let h = l.header;
+    let (year, month, day) = (h.year, h.month, h.day);
+    let (hour, minute) = (h.hour, h.minute);
+    let chip = h.chip.to_le_bytes();
+    println!("{:18} {}", "Tag", String::from_utf8_lossy(&h.tag));
+    println!("{:18} {:#x}", "Version", { h.version });
+    println!(
+        "{:18} {year}-{month:02}-{day:02} {hour:02}:{minute:02}",
+        "Released"
+    );
+    println!(
+        "{:18} {}",
+        "Chip",
+        String::from_utf8_lossy(&chip).trim_end_matches('\0')
+    );
+    println!(
+        "{:18} {}",
+        "RC4",
+        if h.rc4_disabled != 0 { "off" } else { "on" }
+    );
+    for (kind, label) in [
+        (Kind::Ddr, "471"),
+        (Kind::Usbplug, "472"),
+        (Kind::Flash, "flash"),
+    ] {
+        for e in l.entries(kind)? {
+            print_stage(&format!("{label} {}", e.name()), l.entry_data(&e)?);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A loader for RK3588 from 2024-01-02 03:04:05, RC4 off, with DDR init
+    /// and usbplug of 8 bytes and FlashData and FlashBoot of 4, ending in
+    /// Rockchip's CRC32. Assembled by hand after the `rk_boot_header` and
+    /// `rk_boot_entry` structs in tools/boot_merger.h of Rockchip's U-Boot,
+    /// not made by boot_merger itself; a loader it made would be a stronger
+    /// check.
+    const GOLDEN_LOADER: &[&str] = &[
+        "424f4f5466000001000000000301e80701020304053335383801660000003901",
+        "9f0000003902d800000039000100000000000000000000000000000000000000",
+        "0000000000000000000000000000000000000000000000000000000000000000",
+        "000000000000390100000072006b0033003500380038005f0064006400720000",
+        "000000000000000000000000000000000000004a010000080000000100000039",
+        "0200000075007300620070006c00750067000000000000000000000000000000",
+        "000000000000000000000000520100000800000000000000390400000046006c",
+        "0061007300680044006100740061000000000000000000000000000000000000",
+        "00000000005a0100000400000000000000390400000046006c00610073006800",
+        "42006f006f007400000000000000000000000000000000000000000000005e01",
+        "0000040000000000000010111213141516172021222324252627d0d1d2d3b0b1",
+        "b2b3a135874f",
+    ];
+
+    fn unhex(s: &str) -> Vec<u8> {
+        let s: String = s.split_whitespace().collect();
+        (0..s.len())
+            .step_by(2)
+            .map(|n| u8::from_str_radix(&s[n..n + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn golden_loader_header() {
+        let data = unhex(&GOLDEN_LOADER.concat());
+        let (rest, crc) = data.split_last_chunk::<4>().unwrap();
+        assert_eq!(CRC32.checksum(rest), u32::from_le_bytes(*crc));
+        let l = Loader::parse(data.clone()).unwrap();
+        let h = l.header;
+        assert_eq!(&h.tag, b"BOOT");
+        assert_eq!({ h.size }, 102);
+        assert_eq!({ h.version }, 0x100);
+        assert_eq!({ h.year }, 2024);
+        assert_eq!(
+            (h.month, h.day, h.hour, h.minute, h.second),
+            (1, 2, 3, 4, 5)
+        );
+        assert_eq!(h.chip.to_le_bytes(), *b"3588");
+        assert_eq!(h.rc4_disabled, 1);
+        let names = |k| {
+            l.entries(k)
+                .unwrap()
+                .iter()
+                .map(|e| (e.name(), l.entry_data(e).unwrap().to_vec(), { e.delay }))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(Kind::Ddr),
+            [("rk3588_ddr".into(), (0x10..0x18).collect(), 1)]
+        );
+        assert_eq!(
+            names(Kind::Usbplug),
+            [("usbplug".into(), (0x20..0x28).collect(), 0)]
+        );
+        assert_eq!(
+            l.flash_entry("FlashBoot").unwrap(),
+            [0xb0, 0xb1, 0xb2, 0xb3]
+        );
+        assert_eq!(names(Kind::Flash).len(), 2);
+    }
+}
@@ -0,0 +1,230 @@
+//! Advisory locks on devices, for hosts shared by several users or scripts
+//!
+//! Opening a device takes a lock file named after its USB port, so that a
+//! second rk_boot process gets a "device busy" error instead of interleaving
+//! its commands with the first one's. The port stays the same when the
+//! device re-enumerates, e.g. from mask ROM to usbplug. The lock is held
+//! while a session is open, and the file tells who holds it. Threads of one
+//! process, like jobs of the daemon, share a lock.
+//!
+//! Lock files go to `/run/lock` where everyone may make them there, else to
+//! `$XDG_RUNTIME_DIR`, private to the user. They are only ever written by whoever made
+//! them, and removed on release: a file that is there already is locked
+//! and read, never written, and one that is a link is refused, so that a
+//! file planted in a shared directory cannot make rk_boot write elsewhere.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use log::debug;
+
+/// A lock file, open, and the number of sessions that hold it
+type Held = (PathBuf, File, usize);
+
+/// Locks held by this process, by port
+static HELD: Mutex<Option<HashMap<String, Held>>> = Mutex::new(None);
+
+/// Where lock files go; `RK_BOOT_LOCK_DIR` overrides it
+pub fn dir() -> PathBuf {
+    if let Some(d) = std::env::var_os("RK_BOOT_LOCK_DIR") {
+        return d.into();
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let run_lock = Path::new("/run/lock");
+        let shared = |m: std::fs::Metadata| m.is_dir() && m.permissions().mode() & 0o002 != 0;
+        if std::fs::metadata(run_lock).is_ok_and(shared) {
+            return run_lock.into();
+        }
+    }
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(d) => d.into(),
+        None => std::env::temp_dir(),
+    }
+}
+
+/// The lock file for the device at a port
+pub fn file(port: &str) -> PathBuf {
+    let name = port.replace(['/', '\\', ':', '#'], "_");
+    dir().join(format!("rk_boot-{name}.lock"))
+}
+
+/// Held until dropped
+#[derive(Debug)]
+pub struct Guard {
+    port: String,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let mut held = HELD.lock().unwrap();
+        let held = held.get_or_insert_default();
+        if let Some((path, _, n)) = held.get_mut(&self.port) {
+            *n -= 1;
+            if *n == 0 {
+                // Removed while still locked, so nobody takes over a file
+                // that is about to go; closing the file releases the lock.
+                let _ = std::fs::remove_file(path);
+                held.remove(&self.port);
+                debug!("Released device at port {}", self.port);
+            }
+        }
+    }
+}
+
+/// What `path` names now, unless it is gone; an error if it is a link,
+/// symbolic or hard, as a planted one would be
+fn lstat(path: &Path) -> std::io::Result<Option<std::fs::Metadata>> {
+    let m = match std::fs::symlink_metadata(path) {
+        Ok(m) => m,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    #[cfg(unix)]
+    let links = std::os::unix::fs::MetadataExt::nlink(&m);
+    #[cfg(not(unix))]
+    let links = 1;
+    if !m.is_file() || links > 1 {
+        let e = format!("{} is a link or not a regular file", path.display());
+        return Err(std::io::Error::other(e));
+    }
+    Ok(Some(m))
+}
+
+/// Whether `path` still names the file `f` has open
+fn same_file(path: &Path, f: &File) -> std::io::Result<bool> {
+    let Some(p) = lstat(path)? else {
+        return Ok(false);
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let m = f.metadata()?;
+        Ok(p.dev() == m.dev() && p.ino() == m.ino())
+    }
+    #[cfg(not(unix))]
+    {
+        // Files that are open cannot be replaced.
+        let _ = (p, f);
+        Ok(true)
+    }
+}
+
+fn busy(port: &str, f: &mut File) -> String {
+    let mut holder = String::new();
+    let _ = f.read_to_string(&mut holder);
+    let holder = holder.trim();
+    match holder.split_once(' ') {
+        Some((pid, cmd)) => format!("Device at port {port} is busy (PID {pid}: {cmd})"),
+        None if !holder.is_empty() => format!("Device at port {port} is busy (PID {holder})"),
+        None => format!("Device at port {port} is busy"),
+    }
+}
+
+/// Lock the device at a port, or tell who has it
+pub fn acquire(port: &str) -> Result<Guard, String> {
+    let mut held = HELD.lock().unwrap();
+    let held = held.get_or_insert_default();
+    if let Some((_, _, n)) = held.get_mut(port) {
+        *n += 1;
+        return Ok(Guard { port: port.into() });
+    }
+    let err = |e: std::io::Error| format!("Cannot lock device at port {port}: {e}");
+    let path = file(port);
+    if std::env::var_os("RK_BOOT_LOCK_DIR").is_some() {
+        std::fs::create_dir_all(dir()).map_err(err)?;
+    }
+    let mut f = loop {
+        // A new file cannot be a link; an existing one is only read.
+        let (mut f, created) = match File::options().write(true).create_new(true).open(&path) {
+            Ok(f) => (f, true),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => match File::open(&path) {
+                Ok(f) => (f, false),
+                // Removed by its holder meanwhile
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(err(e)),
+            },
+            Err(e) => return Err(err(e)),
+        };
+        // Replaced or removed since it was opened, if not the same
+        if !same_file(&path, &f).map_err(err)? {
+            continue;
+        }
+        if f.try_lock().is_err() {
+            return Err(busy(port, &mut f));
+        }
+        if !same_file(&path, &f).map_err(err)? {
+            continue;
+        }
+        if created {
+            break f;
+        }
+        // Left behind by a process that did not get to remove it; make a
+        // new one, which is then ours to write.
+        std::fs::remove_file(&path).map_err(err)?;
+    };
+    let me = format!(
+        "{} {}\n",
+        std::process::id(),
+        std::env::args().collect::<Vec<_>>().join(" ")
+    );
+    f.write_all(me.as_bytes()).map_err(err)?;
+    debug!("Locked device at port {port} in {}", path.display());
+    held.insert(port.into(), (path, f, 1));
+    Ok(Guard { port: port.into() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn devices_are_locked_per_port() {
+        let port = format!("test-{}-1.2", std::process::id());
+        let file = file(&port);
+        let other = || std::fs::File::open(&file).unwrap();
+        let a = acquire(&port).unwrap();
+        // Sessions of one process share the lock.
+        let b = acquire(&port).unwrap();
+        assert!(other().try_lock().is_err());
+        let me = format!("{} ", std::process::id());
+        assert!(std::fs::read_to_string(&file).unwrap().starts_with(&me));
+        drop(a);
+        assert!(other().try_lock().is_err());
+        drop(b);
+        assert!(!file.exists());
+
+        // Another process holds it
+        std::fs::write(&file, "4242 rk_boot write 0 os.img\n").unwrap();
+        let held = other();
+        held.try_lock().unwrap();
+        let e = acquire(&port).unwrap_err();
+        assert!(e.contains("busy (PID 4242: rk_boot write"), "{e}");
+        // ... and is gone without removing it
+        drop(held);
+        drop(acquire(&port).unwrap());
+        assert!(!file.exists());
+
+        // Planted links are refused and left alone.
+        #[cfg(unix)]
+        {
+            let name = format!("rk_boot-victim-{}", std::process::id());
+            let target = std::env::temp_dir().join(name);
+            std::fs::write(&target, "keep").unwrap();
+            std::os::unix::fs::symlink(&target, &file).unwrap();
+            assert!(acquire(&port).is_err());
+            std::fs::remove_file(&file).unwrap();
+            std::fs::hard_link(&target, &file).unwrap();
+            assert!(acquire(&port).is_err());
+            std::fs::remove_file(&file).unwrap();
+            assert_eq!(std::fs::read_to_string(&target).unwrap(), "keep");
+            std::fs::remove_file(&target).unwrap();
+        }
+
+        assert!(crate::jobs::Jobs::new(1, 1).idle().is_some());
+    }
+}
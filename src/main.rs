@@ -1,18 +1,101 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::thread::sleep;
 use std::time::{Duration, Instant};
 
-use clap::{Parser, Subcommand};
-use log::{debug, info};
-use nusb::{Device, Interface, Speed, transfer::Direction};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use clap_num::maybe_hex;
+use log::{debug, error, info, warn};
+use nusb::transfer::{Direction, EndpointType};
+use nusb::{Device, Interface, Speed};
 
+use crate::metrics::Failure;
+use crate::session::Session;
+
+mod attest;
+mod audit;
+mod bmap;
+mod bringup;
+mod cache;
+mod capability;
+mod checkpoint;
+mod chip;
+mod client;
+mod clone;
+mod deadline;
+mod elf;
+mod emmc;
+#[cfg(test)]
+mod emulator;
+mod erase;
+mod extract;
+#[cfg(any(test, feature = "fault-injection"))]
+mod fault;
+mod fetch;
+mod flash;
+mod follow;
+mod gpt;
+mod health;
+mod hexdump;
+mod idb;
+mod inspect;
+mod inventory;
+mod jobs;
+mod json;
+mod loader;
+mod lock;
+mod maskrom;
+mod memtest;
+mod metrics;
+mod misc;
+mod parameter;
+mod placement;
+mod plan;
+mod profile;
+mod progress;
 mod protocol;
+mod provision;
+mod regmap;
+mod retry;
+mod rkbin;
+mod sanity;
+mod script;
+mod server;
+mod service;
+mod session;
+mod size;
+mod smoke;
+mod soak;
+mod spinand;
+mod spinor;
+mod template;
+mod trace;
+mod uid;
+mod usbipd;
+mod vendor;
+mod wait;
+mod workdir;
 
 const USB_VID_RK: u16 = 0x2207;
-const USB_PID_RK3366: u16 = 0x350a;
+
+// Time after the deadline for the command to fail on its own
+const DEADLINE_GRACE: Duration = Duration::from_secs(10);
 
 const CLAIM_INTERFACE_TIMEOUT: Duration = Duration::from_secs(1);
 const CLAIM_INTERFACE_PERIOD: Duration = Duration::from_micros(200);
 
+// DRAM training takes a moment before the mask ROM accepts the next stage.
+const DDR_INIT_DELAY: Duration = Duration::from_millis(500);
+const REJOIN_POLL: Duration = Duration::from_secs(1);
+
+/// How long DRAM init takes, as the chip tells or by default
+fn ddr_init_delay(chip: &chip::Chip) -> Duration {
+    chip.ddr_init_ms
+        .map(|ms| Duration::from_millis(ms as u64))
+        .unwrap_or(DDR_INIT_DELAY)
+}
+
 fn claim_interface(d: &Device, ii: u8) -> std::result::Result<Interface, String> {
     let now = Instant::now();
     while Instant::now() <= now + CLAIM_INTERFACE_TIMEOUT {
@@ -28,78 +111,1201 @@ fn claim_interface(d: &Device, ii: u8) -> std::result::Result<Interface, String>
     Err("failure claiming USB interface".into())
 }
 
-pub fn connect() -> (Interface, u8, u8) {
-    let di = nusb::list_devices()
+/// U-Boot's `rockusb` command presents itself as a download gadget and
+/// carries strings that neither the mask ROM nor usbplug have.
+fn is_rockusb_gadget(di: &nusb::DeviceInfo) -> bool {
+    let is_gadget = |s: &str| {
+        let s = s.to_lowercase();
+        s.contains("rockusb") || s.contains("download gadget")
+    };
+    di.product_string().is_some_and(is_gadget)
+        || di
+            .interfaces()
+            .any(|i| i.interface_string().is_some_and(is_gadget))
+}
+
+/// All attached devices with the Rockchip vendor ID
+pub fn rockchip_devices() -> impl Iterator<Item = nusb::DeviceInfo> {
+    nusb::list_devices()
         .unwrap()
-        .find(|d| d.vendor_id() == USB_VID_RK && d.product_id() == USB_PID_RK3366)
-        .expect("Device not found, is it connected and in the right mode?");
+        .filter(|d| d.vendor_id() == USB_VID_RK)
+}
+
+/// Physical location like `1-2.3`, stable across re-enumeration
+pub fn port_path(di: &nusb::DeviceInfo) -> Option<String> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    return di
+        .sysfs_path()
+        .file_name()
+        .map(|f| f.to_string_lossy().to_string());
+    #[cfg(target_os = "windows")]
+    return Some(format!(
+        "{}#{}",
+        di.parent_instance_id().to_string_lossy(),
+        di.port_number()
+    ));
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "windows")))]
+    return None;
+}
+
+/// What to check when the device cannot be opened
+fn access_hint(di: &nusb::DeviceInfo) -> String {
+    #[cfg(target_os = "windows")]
+    return match di.driver() {
+        Some(d) if d.eq_ignore_ascii_case("winusb") => String::new(),
+        d => format!(
+            "; it uses driver {}, bind WinUSB to it instead, e.g. with Zadig",
+            d.unwrap_or("none")
+        ),
+    };
+    #[cfg(not(target_os = "windows"))]
+    {
+        let _ = di;
+        "; check the permissions, e.g. with a udev rule for vendor 2207".into()
+    }
+}
+
+/// Bus number and device address, as shown by `lsusb`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct DeviceAddr {
+    pub bus: u8,
+    pub address: u8,
+}
+
+impl std::str::FromStr for DeviceAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (b, a) = s.split_once(':').ok_or("expected BUS:ADDRESS")?;
+        Ok(Self {
+            bus: b.parse().map_err(|_| format!("bad bus number {b:?}"))?,
+            address: a.parse().map_err(|_| format!("bad device address {a:?}"))?,
+        })
+    }
+}
+
+impl std::fmt::Display for DeviceAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:03}:{:03}", self.bus, self.address)
+    }
+}
+
+/// A device as given on the command line: BUS:ADDRESS, or a port path or
+/// serial number, which stay the same when the device is plugged again
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeviceSel {
+    Addr(DeviceAddr),
+    Id(String),
+}
+
+impl std::str::FromStr for DeviceSel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(a) = s.parse() {
+            return Ok(Self::Addr(a));
+        }
+        match s.is_empty() {
+            true => Err("expected BUS:ADDRESS, a port path or a serial number".into()),
+            false => Ok(Self::Id(s.to_string())),
+        }
+    }
+}
+
+impl DeviceSel {
+    /// The device's address now
+    pub fn resolve(&self) -> Result<DeviceAddr, String> {
+        if let Self::Addr(a) = self {
+            return Ok(*a);
+        }
+        wait::until(|| {
+            let found: Vec<Found> = rockchip_devices().map(|d| describe(&d)).collect();
+            select(&found, self)
+        })
+    }
+}
+
+/// The address of the one device that `sel` means
+pub fn select(found: &[Found], sel: &DeviceSel) -> Result<DeviceAddr, String> {
+    let id = match sel {
+        DeviceSel::Addr(a) => return Ok(*a),
+        DeviceSel::Id(id) => id,
+    };
+    let list = || found.iter().map(|f| format!("\n  {f}")).collect::<String>();
+    let matching: Vec<&Found> = found
+        .iter()
+        .filter(|f| f.port.as_ref() == Some(id) || f.serial.as_ref() == Some(id))
+        .collect();
+    match matching[..] {
+        [f] => Ok(f.addr),
+        [] => Err(format!(
+            "No Rockchip device at port or with serial {id}, found:{}",
+            list()
+        )),
+        _ => Err(format!(
+            "Several devices have serial {id}, pick one with --device BUS:ADDRESS:{}",
+            matching
+                .iter()
+                .map(|f| format!("\n  {f}"))
+                .collect::<String>()
+        )),
+    }
+}
+
+/// A Rockchip device on the bus and whether it can be talked to
+#[derive(Clone, Debug)]
+pub struct Found {
+    pub addr: DeviceAddr,
+    pub pid: u16,
+    pub usable: bool,
+    pub what: String,
+    pub port: Option<String>,
+    pub serial: Option<String>,
+}
+
+impl std::fmt::Display for Found {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} 2207:{:04x} {}", self.addr, self.pid, self.what)?;
+        if let Some(p) = &self.port {
+            write!(f, ", port {p}")?;
+        }
+        if let Some(s) = &self.serial {
+            write!(f, ", serial {s}")?;
+        }
+        Ok(())
+    }
+}
+
+fn describe(di: &nusb::DeviceInfo) -> Found {
+    let has_class = |c: u8, sub: Option<u8>| {
+        di.interfaces()
+            .any(|i| i.class() == c && sub.is_none_or(|s| i.subclass() == s))
+    };
+    let chips = chip::sharing(di.product_id());
+    let (usable, what) = match chips.is_empty() {
+        _ if has_class(0x08, None) => (false, "in USB mass storage (UMS) mode".into()),
+        _ if has_class(0xff, Some(0x42)) => (false, "running Android (ADB/fastboot)".into()),
+        false => (true, format!("{} in rockusb mode", chip::names(&chips))),
+        true => (false, "with an unsupported product ID".into()),
+    };
+    Found {
+        addr: DeviceAddr {
+            bus: di.bus_number(),
+            address: di.device_address(),
+        },
+        pid: di.product_id(),
+        usable,
+        what,
+        port: port_path(di),
+        serial: di.serial_number().map(String::from),
+    }
+}
+
+/// Configuration and endpoints to use instead of the detected ones
+#[derive(Args, Clone, Copy, Debug, Default)]
+pub struct Endpoints {
+    /// USB configuration value to select, for devices with several
+    #[clap(long, global = true)]
+    pub usb_config: Option<u8>,
+    /// Bulk in endpoint address, e.g. 0x81, for devices whose descriptors
+    /// mislead the detection
+    #[clap(long, global = true, value_parser = maybe_hex::<u8>)]
+    pub ep_in: Option<u8>,
+    /// Bulk out endpoint address, e.g. 0x01
+    #[clap(long, global = true, value_parser = maybe_hex::<u8>)]
+    pub ep_out: Option<u8>,
+}
+
+/// An interface alternate setting and its bulk endpoints
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AltSetting {
+    pub interface: u8,
+    pub alt: u8,
+    /// Class, subclass and protocol
+    pub class: (u8, u8, u8),
+    pub bulk_in: Option<u8>,
+    pub bulk_out: Option<u8>,
+}
+
+// What the mask ROM, usbplug and U-Boot's rockusb all announce
+const ROCKUSB_CLASS: (u8, u8, u8) = (0xff, 0x06, 0x05);
+
+/// The setting to talk rockusb on: one with a bulk pair, preferring the
+/// rockusb class, then any vendor-specific one, over e.g. debug interfaces
+pub fn pick_alt_setting(alts: &[AltSetting]) -> Option<&AltSetting> {
+    let usable = || {
+        alts.iter()
+            .filter(|a| a.bulk_in.is_some() && a.bulk_out.is_some())
+    };
+    usable()
+        .find(|a| a.class == ROCKUSB_CLASS)
+        .or_else(|| usable().find(|a| a.class.0 == 0xff))
+        .or_else(|| usable().next())
+}
+
+/// The configuration to talk rockusb in: the active one if it will do, as
+/// switching resets the interfaces, or else the first one that has a
+/// setting to use, preferring one of the rockusb class
+pub fn pick_configuration(configs: &[(u8, Vec<AltSetting>)], active: Option<u8>) -> Option<u8> {
+    let usable = || {
+        configs
+            .iter()
+            .filter(|(_, alts)| pick_alt_setting(alts).is_some())
+    };
+    usable()
+        .find(|(value, _)| Some(*value) == active)
+        .or_else(|| {
+            usable().find(|(_, alts)| pick_alt_setting(alts).unwrap().class == ROCKUSB_CLASS)
+        })
+        .or_else(|| usable().next())
+        .map(|(value, _)| *value)
+}
+
+/// Which of the found devices to use, or exactly why none can be
+pub fn choose(found: &[Found], device: Option<DeviceAddr>) -> Result<usize, String> {
+    let list = || found.iter().map(|f| format!("\n  {f}")).collect::<String>();
+    if found.is_empty() {
+        return Err("No Rockchip device found, is it connected and powered?".into());
+    }
+    if let Some(a) = device {
+        let n = found
+            .iter()
+            .position(|f| f.addr == a)
+            .ok_or_else(|| format!("No Rockchip device at {a}, found:{}", list()))?;
+        if !found[n].usable {
+            return Err(format!(
+                "Device at {a} is {}, put it into mask ROM or loader mode",
+                found[n].what
+            ));
+        }
+        return Ok(n);
+    }
+    let usable: Vec<usize> = (0..found.len()).filter(|&n| found[n].usable).collect();
+    match usable[..] {
+        [n] => Ok(n),
+        [] => Err(format!(
+            "No device in mask ROM or loader mode, found:{}",
+            list()
+        )),
+        _ => Err(format!(
+            "Multiple devices found, pick one with --device BUS:ADDRESS, \
+             a port path or a serial number:{}",
+            list()
+        )),
+    }
+}
+
+/// The interface alternate settings of each configuration
+fn alt_settings(d: &Device) -> Vec<(u8, Vec<AltSetting>)> {
+    d.configurations()
+        .map(|c| {
+            let alts = c
+                .interface_alt_settings()
+                .map(|s| {
+                    let bulk = |dir| {
+                        s.endpoints()
+                            .find(|e| {
+                                e.transfer_type() == EndpointType::Bulk && e.direction() == dir
+                            })
+                            .map(|e| e.address())
+                    };
+                    AltSetting {
+                        interface: s.interface_number(),
+                        alt: s.alternate_setting(),
+                        class: (s.class(), s.subclass(), s.protocol()),
+                        bulk_in: bulk(Direction::In),
+                        bulk_out: bulk(Direction::Out),
+                    }
+                })
+                .collect();
+            (c.configuration_value(), alts)
+        })
+        .collect()
+}
+
+/// The mode of a device from its descriptors, without claiming it
+pub fn usb_mode(di: &nusb::DeviceInfo) -> Result<Mode, String> {
+    let d = di
+        .open()
+        .map_err(|e| format!("Cannot open device: {e}{}", access_hint(di)))?;
+    Mode::detect(di, &d)
+}
+
+pub fn connect(device: Option<DeviceAddr>) -> Result<Session, Failure> {
+    connect_with(device, Endpoints::default())
+}
+
+pub fn connect_with(device: Option<DeviceAddr>, eps: Endpoints) -> Result<Session, Failure> {
+    let (devices, n) = wait::until(|| {
+        let devices: Vec<nusb::DeviceInfo> = rockchip_devices().collect();
+        let found: Vec<Found> = devices.iter().map(describe).collect();
+        for f in &found {
+            debug!("Found {f}");
+        }
+        choose(&found, device).map(|n| (devices, n))
+    })?;
+    let di = &devices[n];
     debug!("{di:?}");
+    let port =
+        port_path(di).unwrap_or_else(|| format!("{}-{}", di.bus_number(), di.device_address()));
+    let lock = lock::acquire(&port)?;
+    let json::Value::Obj(mut identity) = server::device_json(di) else {
+        unreachable!()
+    };
+    identity.push(("port".into(), port_path(di).into()));
+    identity.push(("serial".into(), di.serial_number().into()));
+    audit::connected(json::Value::Obj(identity));
+    let chips = chip::sharing(di.product_id());
+    // The profile tells apart chips with the same product ID, or a loader.
+    let wanted = profile::active().and_then(|p| p.chip.clone());
+    let chip = wanted
+        .and_then(|w| chip::named(&chips, &w))
+        .unwrap_or(chips[0]);
+    match chips.len() {
+        1 => info!("Chip: {}", chip.name),
+        _ => info!(
+            "Chip: {}, taken for one of {}",
+            chip.name,
+            chip::names(&chips)
+        ),
+    }
+    profile::check_chip(chip).map_err(Failure::Config)?;
     let ms = di.manufacturer_string().unwrap_or("[no manufacturer]");
     let ps = di.product_string().unwrap_or("[no product id]");
     info!("Found {ms} {ps}");
 
-    // Just use the first interface
-    let ii = di.interfaces().next().unwrap().interface_number();
-    let d = di.open().unwrap();
-    let i = claim_interface(&d, ii).unwrap();
+    let d = di
+        .open()
+        .map_err(|e| format!("Cannot open device: {e}{}", access_hint(di)))?;
 
     let speed = di.speed().unwrap();
     let packet_size = match speed {
         Speed::Full | Speed::Low => 64,
         Speed::High => 512,
         Speed::Super | Speed::SuperPlus => 1024,
-        _ => panic!("Unknown USB device speed {speed:?}"),
+        _ => return Err(format!("Unknown USB device speed {speed:?}").into()),
     };
     debug!("speed {speed:?} - max packet size: {packet_size}");
 
-    // TODO: Nice error messages when either is not found
-    // We may also hardcode the endpoint to 0x01.
-    let c = d.configurations().next().unwrap();
-    let s = c.interface_alt_settings().next().unwrap();
+    let configs = alt_settings(&d);
+    // Some hubs, and Windows, leave the device unconfigured.
+    let active = d
+        .active_configuration()
+        .ok()
+        .map(|c| c.configuration_value());
+    debug!(
+        "Configurations {:?}, active {active:?}",
+        configs.iter().map(|c| c.0).collect::<Vec<_>>()
+    );
+    let value = match eps.usb_config {
+        Some(v) if configs.iter().any(|c| c.0 == v) => v,
+        Some(v) => return Err(format!("Device has no configuration {v}").into()),
+        None => pick_configuration(&configs, active)
+            .or(configs.first().map(|c| c.0))
+            .ok_or("Device has no configuration")?,
+    };
+    if active != Some(value) {
+        info!("Selecting configuration {value}");
+        if let Err(e) = d.set_configuration(value) {
+            warn!("Cannot select configuration {value}: {e}");
+        }
+    }
+    let alts = &configs.iter().find(|c| c.0 == value).unwrap().1;
+    for a in alts {
+        debug!("{a:?}");
+    }
+    // An override picks the setting it belongs to, if any.
+    let has = |ep: Option<u8>, a: &AltSetting| {
+        ep.is_none_or(|e| [a.bulk_in, a.bulk_out].contains(&Some(e)))
+    };
+    let overridden = alts.iter().find(|a| {
+        (eps.ep_in.is_some() || eps.ep_out.is_some()) && has(eps.ep_in, a) && has(eps.ep_out, a)
+    });
+    let a = overridden
+        .or_else(|| pick_alt_setting(alts))
+        .ok_or("Device has no interface with a pair of bulk endpoints")?;
+    debug!("Using interface {} alt setting {}", a.interface, a.alt);
+    let i = claim_interface(&d, a.interface).map_err(|e| format!("{e}{}", access_hint(di)))?;
+    if a.alt != 0 {
+        i.set_alt_setting(a.alt)
+            .map_err(|e| format!("Cannot select alt setting {}: {e}", a.alt))?;
+    }
+    debug!(
+        "Detected endpoints: in {:#04x?}, out {:#04x?}",
+        a.bulk_in, a.bulk_out
+    );
+    let (e_in_addr, e_out_addr) = match (eps.ep_in.or(a.bulk_in), eps.ep_out.or(a.bulk_out)) {
+        (Some(e_in), Some(e_out)) => (e_in, e_out),
+        _ => return Err("No bulk endpoint pair, give it with --ep-in and --ep-out".into()),
+    };
+    if eps.ep_in.is_some() || eps.ep_out.is_some() {
+        info!("Using endpoints in {e_in_addr:#04x}, out {e_out_addr:#04x}");
+    }
+
+    let mode = Mode::detect(di, &d).unwrap_or_else(|e| {
+        warn!("{e}, so the mode is unknown");
+        Mode::Unknown
+    });
+    info!("Mode: {mode}");
+
+    #[cfg(feature = "fault-injection")]
+    let i = fault::Faulty::new(i, fault::configured());
+    let i = trace::Traced::new(i);
+    let s = Session::open(
+        Arc::new(i),
+        (e_in_addr, e_out_addr),
+        chip,
+        mode,
+        packet_size,
+    )
+    .holding(lock)
+    .on_port(&port);
+    // The mask ROM and U-Boot do not know the command.
+    if chips.len() > 1 && mode == Mode::UsbPlug {
+        let id = protocol::info(&s).unwrap_or_default();
+        if let Some(c) = chip::named(&chips, &id).filter(|c| c.name != chip.name) {
+            info!("Loader reports chip ID {id}, so this is an {}", c.name);
+            profile::check_chip(c).map_err(Failure::Config)?;
+            return Ok(s.with_chip(c));
+        }
+    }
+    Ok(s)
+}
+
+#[derive(Debug, Subcommand)]
+enum EmmcCommand {
+    /// Show capacity, block size and manufacturer code as the loader's
+    /// flash info reports them; the loader cannot read CID, CSD or EXT_CSD
+    Info,
+    /// Decode CID, CSD and EXT_CSD dumps taken on a running system, e.g.
+    /// from /sys/class/mmc_host, for identity and wear; needs no device
+    #[clap(group(clap::ArgGroup::new("registers").required(true).multiple(true)))]
+    Decode {
+        /// CID as 32 hex digits
+        #[clap(long)]
+        cid: Option<String>,
+        /// CSD as 32 hex digits
+        #[clap(long)]
+        csd: Option<String>,
+        /// EXT_CSD, raw or as hex text; fails if the storage is worn
+        #[clap(long)]
+        ext_csd: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum SpinorCommand {
+    /// Read into a file
+    Read {
+        #[clap(value_parser = size::bytes)]
+        offset: u64,
+        #[clap(value_parser = size::bytes)]
+        len: u64,
+        output: PathBuf,
+    },
+    /// Write a file, erasing only where needed and keeping the data around it
+    Write {
+        #[clap(value_parser = size::bytes)]
+        offset: u64,
+        file: PathBuf,
+    },
+    /// Erase whole 4 KiB sectors
+    Erase {
+        #[clap(value_parser = size::bytes)]
+        offset: u64,
+        #[clap(value_parser = size::bytes)]
+        len: u64,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum SpinandCommand {
+    /// List the blocks marked bad
+    BadBlocks,
+    /// Write a file, skipping bad blocks
+    Write {
+        /// Where to start, aligned to a block
+        #[clap(value_parser = size::bytes)]
+        offset: u64,
+        file: PathBuf,
+        /// Size of the area for the image, so that skipping bad blocks does
+        /// not spill into what follows; defaults to the rest of the chip
+        #[clap(long, value_parser = size::bytes)]
+        limit: Option<u64>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum GptCommand {
+    /// Rewrite a broken or misplaced copy of the GPT from the intact one
+    Repair {
+        /// Only report what is wrong
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Add a partition, by default after the last one up to the end
+    Add {
+        name: String,
+        /// Size in bytes, or with a unit; default is all free space after the start
+        #[clap(long, value_parser = size::bytes)]
+        size: Option<u64>,
+        /// First sector; default is after the last partition
+        #[clap(long, value_parser = size::sectors)]
+        start: Option<u64>,
+        /// Align the default start to this many sectors
+        #[clap(long, value_parser = size::sectors, default_value = "2048")]
+        align: u64,
+        /// Type GUID or one of linux, efi, swap, basic, home
+        #[clap(long = "type", default_value = "linux")]
+        type_guid: String,
+    },
+    /// Change a partition's size, keeping its start
+    Resize {
+        name: String,
+        /// Size in bytes, or with a unit; default is all free space after the partition
+        #[clap(long, value_parser = size::bytes)]
+        size: Option<u64>,
+    },
+    /// Delete a partition
+    Delete { name: String },
+    /// Write a new GPT with a named layout, sized for the storage
+    Write {
+        /// Defaults to the profile's template
+        #[clap(long)]
+        template: Option<template::Template>,
+        /// Size of the storage in bytes, or auto to use what the loader reports
+        #[clap(long, default_value = "auto", value_parser = parse_disk_size)]
+        disk_size: DiskSize,
+        /// Replace an existing GPT
+        #[clap(long)]
+        force: bool,
+    },
+    /// Print the GPT of a disk image in parameter.txt form; works offline
+    ToParameter {
+        image: PathBuf,
+        #[clap(long, short)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Clone, Copy, Debug)]
+enum DiskSize {
+    Auto,
+    Bytes(u64),
+}
+
+fn parse_disk_size(s: &str) -> Result<DiskSize, String> {
+    match s {
+        "auto" => Ok(DiskSize::Auto),
+        s => size::bytes(s).map(DiskSize::Bytes),
+    }
+}
+
+#[derive(Debug, Subcommand)]
+enum ParameterCommand {
+    /// Make a protective MBR and primary GPT from a parameter.txt
+    ToGpt {
+        file: PathBuf,
+        #[clap(long, short)]
+        output: PathBuf,
+        /// Size of the storage in bytes, or with a unit
+        #[clap(long, value_parser = size::bytes)]
+        disk_size: u64,
+        /// Type GUID for all partitions, or one of linux, efi, swap, basic, home
+        #[clap(long = "type", default_value = "linux")]
+        type_guid: String,
+    },
+}
 
-    let mut es = s.endpoints();
-    let e_out = es.find(|e| e.direction() == Direction::Out).unwrap();
-    let e_out_addr = e_out.address();
+#[derive(Debug, Subcommand)]
+enum MiscCommand {
+    /// Show the bootloader control block
+    Read {
+        /// Where the block is in misc; AOSP keeps it at 0
+        #[clap(long, value_parser = size::bytes, default_value_t = misc::ROCKCHIP_OFFSET)]
+        offset: u64,
+    },
+    /// Make the next boot start the bootloader or recovery
+    WriteCommand {
+        target: misc::Target,
+        /// Argument for recovery, e.g. --wipe_data; repeat for more
+        #[clap(long = "arg", allow_hyphen_values = true)]
+        args: Vec<String>,
+        /// Where the block is in misc; AOSP keeps it at 0
+        #[clap(long, value_parser = size::bytes, default_value_t = misc::ROCKCHIP_OFFSET)]
+        offset: u64,
+    },
+}
 
-    let mut es = s.endpoints();
-    let e_in = es.find(|e| e.direction() == Direction::In).unwrap();
-    let e_in_addr = e_in.address();
+#[derive(Debug, Subcommand)]
+enum VendorCommand {
+    /// Save all vendor storage items, e.g. serial number and MAC addresses,
+    /// to a JSON file
+    Dump { output: PathBuf },
+    /// Replace all vendor storage items with those from a dump
+    Restore { input: PathBuf },
+}
+
+#[derive(Debug, Subcommand)]
+enum LoaderCommand {
+    /// Show the stages and versions of a boot_merger image, or of the
+    /// loader installed in the device's boot area
+    Info {
+        #[clap(required_unless_present = "from_device")]
+        file: Option<PathBuf>,
+        #[clap(long, conflicts_with = "file")]
+        from_device: bool,
+    },
+}
 
-    for e in es {
-        debug!("{e:?}");
+#[derive(Debug, Subcommand)]
+enum MemCommand {
+    /// Read memory and print a hexdump
+    Read {
+        /// Address or register name
+        addr: String,
+        #[clap(value_parser = size::bytes_usize, default_value = "4")]
+        len: usize,
+        /// Write the raw data to a file instead
+        #[clap(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Write memory from a file or hex bytes such as `deadbeef` or `de:ad:be:ef`
+    Write {
+        /// Address or register name
+        addr: String,
+        data: String,
+    },
+    /// List the named registers known for the chip
+    Regs,
+}
+
+// The loader accesses memory in 32-bit words; registers need exactly that.
+const MEM_ALIGN: usize = 4;
+
+fn aligned_addr(s: &str) -> Result<u32, String> {
+    let addr = maybe_hex::<u32>(s)?;
+    if !(addr as usize).is_multiple_of(MEM_ALIGN) {
+        return Err(format!("{addr:08x} is not {MEM_ALIGN}-byte aligned"));
     }
+    Ok(addr)
+}
+
+fn parse_hex_bytes(s: &str) -> Result<Vec<u8>, String> {
+    let s = s.trim_start_matches("0x");
+    let digits: Vec<char> = s
+        .chars()
+        .filter(|c| !matches!(c, ':' | ' ' | '_'))
+        .collect();
+    if digits.is_empty() || !digits.len().is_multiple_of(2) {
+        return Err(format!(
+            "{s:?} is neither a file nor an even number of hex digits"
+        ));
+    }
+    digits
+        .chunks(2)
+        .map(|d| {
+            let b: String = d.iter().collect();
+            u8::from_str_radix(&b, 16).map_err(|_| format!("{b:?} is not a hex byte"))
+        })
+        .collect()
+}
 
-    (i, e_in_addr, e_out_addr)
+/// What to do after a command succeeded
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+enum Then {
+    /// Reset the device, checking the boot if asked to
+    Reset,
 }
 
 #[derive(Debug, Subcommand)]
 enum Command {
-    /// Run binary code from file
+    /// Run binary code or an ELF executable from file
+    /// In mask ROM mode, code goes to the region's fixed location for the chip.
+    /// In USB plug mode, code goes to `--load-addr` and starts at `--entry`.
     #[clap(verbatim_doc_comment)]
     Run {
-        #[clap(long, short, value_enum, default_value = "sram")]
+        #[clap(
+            long,
+            short,
+            value_enum,
+            default_value = "sram",
+            conflicts_with = "load_addr"
+        )]
         region: protocol::Region,
+        /// Where to write the code; requires USB plug mode
+        #[clap(long, value_parser = aligned_addr)]
+        load_addr: Option<u32>,
+        /// Where to start the code; defaults to the load address
+        #[clap(long, value_parser = maybe_hex::<u32>, requires = "load_addr")]
+        entry: Option<u32>,
         file_name: String,
     },
     /// Get chip information; requires DRAM init + usbplug binary, see
     /// https://github.com/rockchip-linux/rkbin
-    Info,
+    Info {
+        /// In mask ROM mode, first boot usbplug picked from rkbin
+        #[clap(long)]
+        auto_plug: bool,
+        /// rkbin checkout to pick binaries from; defaults to $RKBIN
+        #[clap(long, requires = "auto_plug")]
+        rkbin: Option<PathBuf>,
+    },
+    /// Bring up a new board stage by stage, from mask ROM to usbplug, chip
+    /// and flash info and optionally a DRAM test, logging how each went
+    Bringup {
+        #[command(flatten)]
+        opts: bringup::Options,
+    },
+    /// Initialize DRAM and run usbplug, from given files or picked from rkbin
+    #[clap(verbatim_doc_comment)]
+    Boot {
+        /// Pick the binaries for the detected chip from rkbin
+        #[clap(long, conflicts_with_all = ["ddr", "usbplug"])]
+        auto: bool,
+        /// rkbin checkout to pick binaries from; defaults to $RKBIN
+        #[clap(long)]
+        rkbin: Option<PathBuf>,
+        /// usbplug version to use instead of the newest, e.g. 1.11
+        #[clap(long, requires = "auto")]
+        loader_version: Option<String>,
+        /// DDR init binary, run from SRAM; defaults to the profile's
+        #[clap(requires = "usbplug")]
+        ddr: Option<PathBuf>,
+        /// usbplug binary, run from DRAM
+        usbplug: Option<PathBuf>,
+    },
+    /// Send any request to the loader and show what comes back, for
+    /// trying out commands not known here
+    Raw {
+        #[clap(long, value_parser = maybe_hex::<u8>)]
+        opcode: u8,
+        #[clap(long, value_parser = maybe_hex::<u8>, default_value = "0")]
+        subcode: u8,
+        #[clap(long, value_parser = maybe_hex::<u32>, default_value = "0")]
+        addr: u32,
+        /// The command's size field
+        #[clap(long, value_parser = maybe_hex::<u16>, default_value = "0")]
+        size: u16,
+        #[clap(long, value_enum, default_value = "none")]
+        direction: protocol::Direction,
+        /// Bytes to take in; defaults to the size field
+        #[clap(long, value_parser = size::bytes_u32)]
+        length: Option<u32>,
+        /// Data to send out, from a file or hex bytes such as `deadbeef`
+        #[clap(long, required_if_eq("direction", "out"))]
+        data: Option<String>,
+    },
+    /// Access memory and registers; requires USB plug mode
+    Mem {
+        /// Register map file with `NAME ADDR` lines, extending the built-in one
+        #[clap(long)]
+        regmap: Option<PathBuf>,
+        #[command(subcommand)]
+        cmd: MemCommand,
+    },
+    /// Jump to code in memory, e.g. written via `mem write`; requires USB plug mode
+    Exec {
+        #[clap(value_parser = maybe_hex::<u32>)]
+        addr: u32,
+    },
+    /// Save SRAM contents, e.g. after a DDR init blob crashed; requires USB
+    /// plug mode, so run usbplug first
+    DumpSram {
+        /// Override the chip's SRAM base address
+        #[clap(long, value_parser = maybe_hex::<u32>, requires = "size")]
+        base: Option<u32>,
+        /// Override the chip's SRAM size
+        #[clap(long, value_parser = size::bytes_u32, requires = "base")]
+        size: Option<u32>,
+        output: PathBuf,
+    },
+    /// Test DRAM with classic patterns, overwriting its contents; requires
+    /// USB plug mode
+    Memtest {
+        #[clap(long, value_parser = maybe_hex::<u32>)]
+        base: u32,
+        #[clap(long, value_parser = size::bytes_usize)]
+        size: usize,
+    },
+    /// Reset the device, optionally checking that it boots; requires USB
+    /// plug mode or U-Boot rockusb
+    Reset {
+        #[command(flatten)]
+        smoke: smoke::Options,
+    },
+    /// Write a report on the device's identity and partition hashes
+    Attest {
+        #[clap(long, short)]
+        output: PathBuf,
+        /// Who ran the flashing; defaults to $USER
+        #[clap(long)]
+        operator: Option<String>,
+        /// Add an HMAC-SHA256 of the report under this shared key file; a
+        /// MAC, not a signature: whoever can check it can also make one
+        #[clap(long)]
+        key: Option<PathBuf>,
+        /// Name of the key to note with the MAC, e.g. line-3-2026
+        #[clap(long, requires = "key")]
+        key_id: Option<String>,
+        /// Partition to hash; may be repeated, defaults to all
+        #[clap(long = "partition")]
+        partitions: Vec<String>,
+    },
+    /// Check idbloader, uboot and trust against a golden manifest of hashes
+    Audit {
+        #[clap(long)]
+        manifest: PathBuf,
+        /// Write the manifest from this device instead
+        #[clap(long)]
+        record: bool,
+    },
+    /// Run per-board plans for the devices listed in a manifest
+    Provision {
+        /// JSON or CSV manifest mapping port paths or serials to plans
+        manifest: PathBuf,
+        /// Where to append a JSON line per board
+        #[clap(long, default_value = "provisioned.jsonl")]
+        record: PathBuf,
+        /// Keep waiting for more boards
+        #[clap(long)]
+        watch: bool,
+        /// Where to keep how far each board got, to go on from there
+        #[clap(long, default_value = checkpoint::DEFAULT_DIR)]
+        checkpoints: PathBuf,
+        /// Read back what each write step wrote, see `write --verify`
+        #[clap(long, value_enum)]
+        verify: Option<flash::Verify>,
+    },
+    /// Serve an HTTP API to list devices, upload images and run commands
+    Serve {
+        #[clap(long, default_value = "127.0.0.1:8080")]
+        listen: String,
+        /// Jobs to run at once on devices sharing a USB bus, i.e. host controller
+        #[clap(long, default_value = "1")]
+        max_per_bus: usize,
+        /// Where to keep uploaded images; jobs may write their output files
+        /// here, or to --workdir if given
+        #[clap(long)]
+        image_dir: Option<PathBuf>,
+        /// Exit once no job has run for this long, e.g. 30m
+        #[clap(long, value_parser = deadline::parse)]
+        exit_idle: Option<Duration>,
+        #[command(flatten)]
+        limits: server::Limits,
+    },
+    /// Write an image to the storage
+    #[clap(visible_alias = "write-partition")]
+    Write {
+        /// Sector to start at, or a partition name with an optional offset
+        /// into it, e.g. boot or rootfs+1MiB
+        lba: size::Lba,
+        /// Defaults to the profile's image for the partition; an http:// or
+        /// https:// URL is streamed, resuming where a broken connection stopped
+        file: Option<PathBuf>,
+        /// Read the storage back first and only write the blocks that differ
+        #[clap(long)]
+        delta: bool,
+        /// bmaptool block map to write only the mapped blocks; by default,
+        /// FILE.bmap is used if it exists
+        #[clap(long)]
+        bmap: Option<PathBuf>,
+        /// Write the whole image even if there is a block map next to it
+        #[clap(long, conflicts_with = "bmap")]
+        no_bmap: bool,
+        /// Read back what was written and compare digests, per partition
+        #[clap(long, value_enum)]
+        verify: Option<flash::Verify>,
+        /// Leave this area of the storage as it is; may be repeated
+        #[clap(long, value_enum)]
+        preserve: Vec<flash::Preserve>,
+        /// Write idbloaders, U-Boot's FIT and disk images where they belong
+        /// rather than at the given sector
+        #[clap(long)]
+        adjust_offset: bool,
+        /// Write without asking even where the image overlaps partitions
+        /// other than the one it is named after
+        #[clap(long)]
+        across_partitions: bool,
+        /// Go on with a write the device disconnected during, from the
+        /// sector the error message tells
+        #[clap(long, value_parser = maybe_hex::<u32>)]
+        resume_at: Option<u32>,
+        /// Print sectors written, unchanged, skipped and verified, and
+        /// retried transfers, as JSON on stdout
+        #[clap(long)]
+        json: bool,
+        /// What to do afterwards
+        #[clap(long, value_enum)]
+        then: Option<Then>,
+        #[command(flatten)]
+        smoke: smoke::Options,
+    },
+    /// Read the whole storage into an image, leaving out all-zero blocks,
+    /// or copy it to another device
+    Clone {
+        #[clap(required_unless_present = "to")]
+        output: Option<PathBuf>,
+        /// Defaults to android-sparse for .simg files, zstd for .zst files and
+        /// raw otherwise
+        #[clap(long, conflicts_with = "to")]
+        format: Option<clone::Format>,
+        /// Source device; defaults to --device
+        #[clap(long)]
+        from: Option<DeviceAddr>,
+        /// Target device to copy to instead of a file
+        #[clap(long, conflicts_with = "output")]
+        to: Option<DeviceAddr>,
+        /// Only write the target's blocks that differ from the source
+        #[clap(long, requires = "to")]
+        delta: bool,
+    },
+    /// Write the loader from a boot_merger image to the boot area, like
+    /// rkdeveloptool's upgrade-loader
+    #[clap(visible_alias = "ul")]
+    UpgradeLoader {
+        /// Defaults to the profile's loader
+        file: Option<PathBuf>,
+        /// What to do afterwards
+        #[clap(long, value_enum)]
+        then: Option<Then>,
+        #[command(flatten)]
+        smoke: smoke::Options,
+    },
+    /// Tell what an image file is and show what it holds, without a device
+    Inspect { file: PathBuf },
+    /// Loader images and the loader on flash
+    Loader {
+        #[command(subcommand)]
+        cmd: LoaderCommand,
+    },
+    /// Show what the loader supports, as its capability command tells
+    Capability,
+    /// Print the chip's unique ID from OTP, in the form provisioning data is
+    /// keyed by
+    Uid,
+    /// Print the UART log the loader buffered, for loaders that keep one;
+    /// it is handed over once
+    LoaderLog,
+    /// Make the loader use another storage, or show the current one
+    SwitchStorage { storage: Option<protocol::Storage> },
+    /// SPI NOR flash; switches the loader's storage to it first
+    Spinor {
+        #[command(subcommand)]
+        cmd: SpinorCommand,
+    },
+    /// SPI NAND flash; switches the loader's storage to it first
+    Spinand {
+        #[command(subcommand)]
+        cmd: SpinandCommand,
+    },
+    /// GUID partition table on the device
+    Gpt {
+        #[command(subcommand)]
+        cmd: GptCommand,
+    },
+    /// Legacy parameter.txt partition layouts; works offline
+    Parameter {
+        #[command(subcommand)]
+        cmd: ParameterCommand,
+    },
+    /// Erase the whole storage, after typing back its flash ID
+    EraseAll {
+        /// Also erase the boot area the mask ROM loads from
+        #[clap(long)]
+        boot: bool,
+        /// Keep vendor storage with serial numbers and MAC addresses
+        #[clap(long)]
+        except_vendor_storage: bool,
+        /// Flash ID to confirm with instead of typing it in
+        #[clap(long)]
+        confirm: Option<String>,
+    },
+    /// eMMC identity and health
+    Emmc {
+        #[command(subcommand)]
+        cmd: EmmcCommand,
+    },
+    /// Read a file from an ext2/3/4 or FAT file system on a partition,
+    /// e.g. /etc/os-release, or list a directory
+    Extract {
+        /// GPT partition name, e.g. rootfs
+        partition: String,
+        path: String,
+        /// Write the file here instead of to stdout
+        #[clap(long, short)]
+        output: Option<PathBuf>,
+        /// Print only lines that contain this text
+        #[clap(long, conflicts_with = "output")]
+        grep: Option<String>,
+    },
+    /// Bootloader control block in the misc partition, to steer the next
+    /// boot into recovery or the bootloader
+    Misc {
+        #[command(subcommand)]
+        cmd: MiscCommand,
+    },
+    /// Vendor storage on eMMC, kept across reflashing
+    Vendor {
+        #[command(subcommand)]
+        cmd: VendorCommand,
+    },
+    /// Attach a device shared by usbipd-win on the Windows host into WSL2
+    WslAttach {
+        /// usbipd bus ID, e.g. 2-3; needed when there are several devices
+        #[clap(long)]
+        busid: Option<String>,
+    },
+    /// List attached Rockchip devices
+    List {
+        #[clap(long)]
+        json: bool,
+        /// Connect to devices that run a loader, for chip ID, flash, loader
+        /// versions and serial number
+        #[clap(long)]
+        probe: bool,
+    },
+    /// Check that the device is there in the expected mode, and print a JSON
+    /// report; fails if it is not, e.g. to gate a CI pipeline
+    Healthcheck {
+        #[clap(long)]
+        expect_mode: Option<Mode>,
+        /// e.g. rk3588
+        #[clap(long)]
+        expect_chip: Option<String>,
+    },
+    /// Run the command lines in a file, see `provision`, on one device
+    /// without connecting again for each; after running code from the mask
+    /// ROM, the device is followed if it comes back elsewhere
+    Batch {
+        file: PathBuf,
+        /// Go on with the next command after one failed
+        #[clap(long)]
+        continue_on_error: bool,
+        /// Read back what each write step wrote, see `write --verify`
+        #[clap(long, value_enum)]
+        verify: Option<flash::Verify>,
+    },
+    /// Run a plan on every board that attaches, reporting JSON lines on stdout
+    Service {
+        /// Plan to run on each new board, see `provision`
+        plan: PathBuf,
+        /// Serve Prometheus metrics on this address, e.g. 0.0.0.0:9100
+        #[clap(long)]
+        metrics: Option<String>,
+        /// Where to keep how far each board got, to go on from there
+        #[clap(long, default_value = checkpoint::DEFAULT_DIR)]
+        checkpoints: PathBuf,
+        /// Also send the JSON lines to clients of a Unix socket at this path
+        #[clap(long)]
+        events: Option<PathBuf>,
+        /// Read back what each write step wrote, see `write --verify`
+        #[clap(long, value_enum)]
+        verify: Option<flash::Verify>,
+    },
 }
 
 /// Rockchip mask ROM loader tool
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
+    /// Run the command on a daemon started via `serve`, e.g. lab1:8080,
+    /// sending it the token in $RK_BOOT_TOKEN
+    #[clap(long, global = true)]
+    remote: Option<String>,
+    /// Use the device at BUS:ADDRESS, or at a USB port path such as 1-2.3,
+    /// or with a serial number; needed when there are several
+    #[clap(long, global = true)]
+    device: Option<DeviceSel>,
+    /// Take the chip, loaders, GPT template and images of a board type
+    /// from this profile in the config file
+    #[clap(long, global = true)]
+    profile: Option<String>,
+    /// Config file with profiles; defaults to ~/.config/rk_boot/config.toml
+    #[clap(long, global = true, requires = "profile")]
+    config: Option<PathBuf>,
+    #[clap(flatten)]
+    endpoints: Endpoints,
+    /// Append a JSON line for each write or erase to this file; serve,
+    /// service and provision use rk_boot-audit.jsonl by default
+    #[clap(long, global = true)]
+    audit_log: Option<PathBuf>,
+    /// Do not keep an audit log in serve, service and provision
+    #[clap(long, global = true, conflicts_with = "audit_log")]
+    no_audit_log: bool,
+    /// Write the commands that succeeded to a shell script to replay them
+    #[clap(long, global = true)]
+    export_script: Option<PathBuf>,
+    /// Tries for USB transfers, LBA commands and the command in total;
+    /// default is 1. LBA commands that keep failing shrink their transfers
+    #[clap(long, global = true)]
+    retries: Option<u32>,
+    /// Milliseconds before the first retry, doubling with each up to 30s
+    #[clap(long, global = true)]
+    retry_backoff: Option<u64>,
+    /// Failure classes to retry, default timeout,disconnect,transport
+    #[clap(long, global = true, value_delimiter = ',')]
+    retry_on: Vec<String>,
+    /// Retry policy file, overridden by the options above
+    #[clap(long, global = true)]
+    retry_config: Option<PathBuf>,
+    /// Give up when the command takes longer, e.g. 90s, 10m or 1h; for
+    /// serve, service and provision, when one of their jobs or steps does
+    #[clap(long, global = true, value_parser = deadline::parse)]
+    deadline: Option<Duration>,
+    /// Have the loader append CRC32s to flash info and capabilities, and
+    /// fail on replies that do not match them
+    #[clap(long, global = true)]
+    check_crc: bool,
+    /// Refuse anything that changes the device: writes, erases, eFuses,
+    /// the reset flag and running code
+    #[clap(long, global = true)]
+    read_only: bool,
+    /// Before writing or erasing, write back the first sector to find out
+    /// whether the storage is write-protected, instead of failing halfway
+    #[clap(long, global = true)]
+    probe_write_protect: bool,
+    /// Wait for the device to show up, at most this long if given, e.g.
+    /// 30s or 5m; a write also waits for the device to come back on the
+    /// same port when it disconnects, and goes on where it stopped
+    #[clap(long, global = true, num_args = 0..=1, value_parser = deadline::parse)]
+    wait: Option<Option<Duration>>,
+    /// Fail USB transfers on purpose, as KIND:POINT:N or KIND:POINT:N+,
+    /// e.g. stall:bulk-in:100; KIND is fail, stall or disconnect, POINT is
+    /// bulk-in, bulk-out, control-out or any
+    #[cfg(feature = "fault-injection")]
+    #[clap(long, global = true, value_parser = fault::parse)]
+    inject_fault: Vec<fault::Fault>,
+    /// Write a trace of the USB transfers and retries to this file, for
+    /// chrome://tracing or Perfetto
+    #[clap(long, global = true)]
+    perf_trace: Option<PathBuf>,
+    /// Directory for scratch files and cached images instead of the
+    /// system's temporary directory
+    #[clap(long, global = true)]
+    workdir: Option<PathBuf>,
+    /// Run a command that reads, writes or runs code this many times and
+    /// report the failures
+    #[clap(long, global = true, value_parser = clap::value_parser!(u32).range(1..))]
+    repeat: Option<u32>,
+    /// Repeat until the command fails, at most --repeat times if given
+    #[clap(long, global = true)]
+    until_failure: bool,
     /// Command to run
     #[command(subcommand)]
     cmd: Command,
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
-enum Mode {
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mode {
+    #[value(name = "usbplug")]
     UsbPlug = 1,
+    #[value(name = "maskrom")]
     MaskROM = 2,
-    Unknown = 3,
+    /// U-Boot's rockusb gadget, which implements only the flash opcodes
+    Rockusb = 3,
+    #[value(skip)]
+    Unknown = 4,
 }
 
 impl std::fmt::Display for Mode {
@@ -107,39 +1313,1491 @@ impl std::fmt::Display for Mode {
         let m = match self {
             Self::UsbPlug => "USB plug",
             Self::MaskROM => "mask ROM",
+            Self::Rockusb => "U-Boot rockusb",
             Self::Unknown => "unknown",
         };
         write!(f, "{m}")
     }
 }
 
-fn main() {
-    // Default to log level "info". Otherwise, you get no "regular" logs.
-    let env = env_logger::Env::default().default_filter_or("info");
-    env_logger::Builder::from_env(env).init();
+const DESCRIPTOR_DEVICE: u8 = 1;
+const DESCRIPTOR_TIMEOUT: Duration = Duration::from_secs(1);
+/// Loaders report USB 2.01 in their device descriptor, the mask ROM 2.00
+const BCD_USB_LOADER: u16 = 0x0001;
+
+impl Mode {
+    /// The mode by the bcdUSB of the device descriptor, as Rockchip's own
+    /// tools tell it; the endpoint numbers differ between chips
+    pub fn from_bcd_usb(bcd_usb: u16) -> Self {
+        match bcd_usb & BCD_USB_LOADER {
+            0 => Self::MaskROM,
+            _ => Self::UsbPlug,
+        }
+    }
 
-    let cmd = Cli::parse().cmd;
+    /// The mode of an open device
+    pub fn detect(di: &nusb::DeviceInfo, d: &Device) -> Result<Self, String> {
+        if is_rockusb_gadget(di) {
+            return Ok(Self::Rockusb);
+        }
+        let desc = d
+            .get_descriptor(DESCRIPTOR_DEVICE, 0, 0, DESCRIPTOR_TIMEOUT)
+            .map_err(|e| format!("Cannot read the device descriptor: {e}"))?;
+        let bcd_usb = desc
+            .get(2..4)
+            .ok_or(format!("Device descriptor of {} bytes", desc.len()))?;
+        let bcd_usb = u16::from_le_bytes([bcd_usb[0], bcd_usb[1]]);
+        debug!("bcdUSB {bcd_usb:#06x}");
+        Ok(Self::from_bcd_usb(bcd_usb))
+    }
+}
+
+/// The DDR init and usbplug binaries for the chip from an rkbin checkout
+fn rkbin_pick(
+    rkbin: Option<PathBuf>,
+    chip: &chip::Chip,
+    version: Option<&str>,
+) -> Result<(PathBuf, PathBuf), String> {
+    let dir = rkbin
+        .or(std::env::var_os("RKBIN").map(PathBuf::from))
+        .ok_or("No rkbin directory given, use --rkbin or set $RKBIN")?;
+    rkbin::find(&dir, chip, version)
+}
+
+/// Initialize DRAM and run usbplug from it; requires mask ROM mode
+fn boot(s: &Session, ddr: &Path, usbplug: &Path) -> Result<(), Failure> {
+    info!("DDR init: {}", ddr.display());
+    info!("usbplug: {}", usbplug.display());
+    let read = |f: &Path| std::fs::read(f).map_err(|e| format!("{}: {e}", f.display()));
+    let blob = read(ddr)?;
+    let start = Instant::now();
+    let _steps = progress::steps(2);
+    progress::step(1, "DDR init");
+    run_in(s, &blob, protocol::Region::Sram)?;
+    sleep(ddr_init_delay(s.chip));
+    let data = read(usbplug)?;
+    progress::step(2, "usbplug");
+    // A blob that hangs takes the mask ROM off the bus, so this fails.
+    let res = run_in(s, &data, protocol::Region::Dram);
+    if res.is_err() {
+        bringup::ddr_hints(s.chip, &blob, start.elapsed());
+    }
+    res
+}
 
-    let (i, e_in_addr, e_out_addr) = connect();
+/// The device a command would connect to and its port path, to follow it
+/// across re-enumeration
+fn pick(device: Option<DeviceAddr>) -> Result<(DeviceAddr, String), String> {
+    let devices: Vec<nusb::DeviceInfo> = rockchip_devices().collect();
+    let found: Vec<Found> = devices.iter().map(describe).collect();
+    let di = &devices[choose(&found, device)?];
+    let addr = DeviceAddr {
+        bus: di.bus_number(),
+        address: di.device_address(),
+    };
+    let port = port_path(di).ok_or("Cannot tell the device's port to follow it")?;
+    Ok((addr, port))
+}
 
-    // Good enough as a heuristic; USB plug mode also has no manufacturer string
-    let mode = match e_out_addr {
-        1 => Mode::UsbPlug,
-        2 => Mode::MaskROM,
-        _ => Mode::Unknown,
+/// Boot usbplug from rkbin on a device in mask ROM mode and connect to it
+/// again once it has re-enumerated
+fn plug(
+    s: &Session,
+    device: Option<DeviceAddr>,
+    eps: Endpoints,
+    rkbin: Option<PathBuf>,
+) -> Result<Session, Failure> {
+    let (old, port) = pick(device)?;
+    let (ddr, usbplug) = rkbin_pick(rkbin, s.chip, None)?;
+    let start = Instant::now();
+    boot(s, &ddr, &usbplug)?;
+    info!("Waiting for usbplug at port {port}");
+    let Some(new) = provision::reappear(&port, old) else {
+        let blob = std::fs::read(&ddr).unwrap_or_default();
+        bringup::ddr_hints(s.chip, &blob, start.elapsed());
+        return Err(Failure::Disconnect(format!("{port} did not come back")));
     };
-    info!("Mode: {mode}");
+    let s = connect_with(Some(new), eps)?;
+    if s.mode != Mode::UsbPlug {
+        return Err(Failure::WrongMode(format!(
+            "Device came back in {} mode, not USB plug",
+            s.mode
+        )));
+    }
+    Ok(s)
+}
+
+/// Wait for a device that disconnected from `old` to come back on its port
+/// and connect to it, booting usbplug if it is in mask ROM mode again
+fn rejoin(port: &str, old: DeviceAddr, eps: Endpoints) -> Result<(DeviceAddr, Session), Failure> {
+    info!("Waiting for the device to come back at port {port}");
+    let addr = loop {
+        deadline::check()?;
+        if let Some(a) = provision::locate(port).filter(|a| *a != old) {
+            break a;
+        }
+        sleep(REJOIN_POLL);
+    };
+    let s = connect_with(Some(addr), eps)?;
+    if s.mode != Mode::MaskROM {
+        return Ok((addr, s));
+    }
+    let rkbin = profile::active().and_then(|p| p.rkbin.clone());
+    let s = plug(&s, Some(addr), eps, rkbin)?;
+    Ok((provision::locate(port).unwrap_or(addr), s))
+}
+
+/// Send code to the mask ROM, telling where it lands
+fn run_in(s: &Session, data: &[u8], region: protocol::Region) -> Result<(), Failure> {
+    let chip = s.chip;
+    match chip.target(region) {
+        Some((addr, limit)) => {
+            info!("{region} code goes to {addr:#010x}");
+            if let Some(l) = limit
+                && data.len() > l as usize
+            {
+                return Err(format!(
+                    "{} bytes exceed the {l} bytes of {} SRAM from {addr:#010x}",
+                    data.len(),
+                    chip.name
+                )
+                .into());
+            }
+        }
+        None => warn!("Where {} puts {region} code is unknown", chip.name),
+    }
+    Ok(protocol::run(s, data, &region)?)
+}
+
+/// Reset after a command if asked to, and check the boot
+fn then_reset(s: &Session, then: Option<Then>, smoke: &smoke::Options) -> Result<(), Failure> {
+    match then {
+        Some(Then::Reset) => reset(s, smoke),
+        None if smoke.is_set() => Err("Checking the boot needs --then reset".into()),
+        None => Ok(()),
+    }
+}
+
+/// Reset and check the boot if asked to
+fn reset(s: &Session, smoke: &smoke::Options) -> Result<(), Failure> {
+    let watch = smoke::prepare(smoke)?;
+    protocol::reset(s)?;
+    match watch {
+        Some(w) => Ok(w.wait(Duration::from_secs(smoke.boot_timeout))?),
+        None => Ok(()),
+    }
+}
+
+fn require_mode(mode: Mode, supported: &[Mode]) -> Result<(), Failure> {
+    if !supported.contains(&mode) {
+        let s: Vec<String> = supported.iter().map(|m| m.to_string()).collect();
+        return Err(Failure::WrongMode(format!(
+            "Device is in {mode} mode, command requires {}",
+            s.join(" or ")
+        )));
+    }
+    Ok(())
+}
+
+/// Check a command line for a job; returns the device it is meant for
+pub fn job_device(args: &[String]) -> Result<Option<DeviceAddr>, String> {
+    let args = std::iter::once("rk_boot").chain(args.iter().map(String::as_str));
+    let cli = Cli::try_parse_from(args).map_err(|e| e.to_string())?;
+    if cli.remote.is_some() {
+        return Err("cannot forward from within a job".into());
+    }
+    if cli.export_script.is_some() {
+        return Err("cannot export a script from within a job".into());
+    }
+    if let Command::Serve { .. } | Command::Provision { .. } | Command::Service { .. } = cli.cmd {
+        return Err("cannot serve or provision from within a job".into());
+    }
+    if let Some(p) = output(&cli.cmd) {
+        jobs::may_write(p)?;
+    }
+    if let Command::Batch { file, .. } = &cli.cmd
+        && jobs::confined()
+        && plan::load(file)?.iter().any(|s| !s.hooks.is_empty())
+    {
+        return Err("remote jobs cannot run batches with hooks".into());
+    }
+    cli.device.as_ref().map(DeviceSel::resolve).transpose()
+}
+
+/// The file a command writes on the host, if any
+fn output(cmd: &Command) -> Option<&Path> {
+    let out: Option<&PathBuf> = match cmd {
+        Command::Spinor {
+            cmd: SpinorCommand::Read { output, .. },
+        } => Some(output),
+        Command::Gpt {
+            cmd: GptCommand::ToParameter { output, .. },
+        } => output.as_ref(),
+        Command::Parameter {
+            cmd: ParameterCommand::ToGpt { output, .. },
+        } => Some(output),
+        Command::Vendor {
+            cmd: VendorCommand::Dump { output },
+        } => Some(output),
+        Command::Mem {
+            cmd: MemCommand::Read { output, .. },
+            ..
+        } => output.as_ref(),
+        Command::DumpSram { output, .. } | Command::Attest { output, .. } => Some(output),
+        Command::Clone { output, .. } | Command::Extract { output, .. } => output.as_ref(),
+        Command::Audit {
+            manifest,
+            record: true,
+        } => Some(manifest),
+        _ => None,
+    };
+    out.map(PathBuf::as_path)
+}
+
+/// Whether a command changes the storage
+fn destructive(cmd: &Command) -> bool {
+    match cmd {
+        Command::Write { .. }
+        | Command::UpgradeLoader { .. }
+        | Command::EraseAll { .. }
+        | Command::Clone { to: Some(_), .. } => true,
+        Command::Gpt { cmd } => match cmd {
+            GptCommand::Repair { dry_run } => !dry_run,
+            GptCommand::ToParameter { .. } => false,
+            _ => true,
+        },
+        Command::Spinor { cmd } => !matches!(cmd, SpinorCommand::Read { .. }),
+        Command::Spinand { cmd } => matches!(cmd, SpinandCommand::Write { .. }),
+        Command::Vendor { cmd } => matches!(cmd, VendorCommand::Restore { .. }),
+        Command::Misc { cmd } => matches!(cmd, MiscCommand::WriteCommand { .. }),
+        // Nobody knows what it does.
+        Command::Raw { .. } => true,
+        _ => false,
+    }
+}
+
+/// Whether `--repeat` makes sense for a command
+fn repeatable(cmd: &Command) -> bool {
+    matches!(
+        cmd,
+        Command::Run { .. }
+            | Command::Write { .. }
+            | Command::Clone { .. }
+            | Command::Mem { .. }
+            | Command::Memtest { .. }
+            | Command::Spinor { .. }
+            | Command::Spinand { .. }
+            | Command::Vendor { .. }
+    )
+}
+
+/// Log the outcome of a command line to the audit log, if it changed storage
+pub fn audit(args: &[String], res: &Result<(), Failure>) {
+    let argv = std::iter::once("rk_boot").chain(args.iter().map(String::as_str));
+    let is_destructive = Cli::try_parse_from(argv).is_ok_and(|c| destructive(&c.cmd));
+    audit::record(args, is_destructive, res);
+}
+
+/// Run a command line as if it had been passed to this program
+pub fn run_args(args: &[String]) -> Result<(), Failure> {
+    job_device(args)?;
+    let argv = std::iter::once("rk_boot").chain(args.iter().map(String::as_str));
+    let cli = Cli::try_parse_from(argv).map_err(|e| e.to_string())?;
+    if let Some(d) = cli.deadline.or(deadline::per_job()) {
+        deadline::set(d);
+    }
+    protocol::set_check_crc(cli.check_crc);
+    session::set_read_only(cli.read_only);
+    flash::set_probe_write_protect(cli.probe_write_protect);
+    wait::set(cli.wait);
+    select_profile(&cli)?;
+    let device = cli.device.as_ref().map(DeviceSel::resolve).transpose()?;
+    execute(cli.cmd, device, cli.endpoints)?;
+    script::record(args);
+    Ok(())
+}
+
+fn execute(cmd: Command, device: Option<DeviceAddr>, eps: Endpoints) -> Result<(), Failure> {
+    execute_in(cmd, device, eps, &mut None)
+}
+
+/// Run a command on the connection in `session`, connecting if there is none
+fn execute_in(
+    cmd: Command,
+    device: Option<DeviceAddr>,
+    eps: Endpoints,
+    session: &mut Option<Session>,
+) -> Result<(), Failure> {
+    let cmd = match profile::active() {
+        Some(p) => with_profile(cmd, &p),
+        None => cmd,
+    };
+    let _progress = progress::steps(1);
+    if let Command::Serve {
+        listen,
+        max_per_bus,
+        image_dir,
+        exit_idle,
+        limits,
+    } = cmd
+    {
+        let dir = image_dir.unwrap_or(workdir::base().join("rk_boot-images"));
+        return Ok(server::serve(&listen, dir, max_per_bus, exit_idle, limits)?);
+    }
+    if let Command::Provision {
+        manifest,
+        record,
+        watch,
+        checkpoints,
+        verify,
+    } = cmd
+    {
+        if let Some(v) = verify {
+            plan::set_verify(v);
+        }
+        return Ok(provision::provision(
+            &manifest,
+            &record,
+            watch,
+            &checkpoints,
+        )?);
+    }
+    if let Command::Emmc {
+        cmd: EmmcCommand::Decode { cid, csd, ext_csd },
+    } = &cmd
+    {
+        return Ok(emmc::report(
+            cid.as_deref(),
+            csd.as_deref(),
+            ext_csd.as_deref(),
+        )?);
+    }
+    if let Command::Gpt {
+        cmd: GptCommand::ToParameter { image, output },
+    } = &cmd
+    {
+        let p = parameter::from_gpt_file(image)?;
+        return match output {
+            Some(o) => std::fs::write(o, p).map_err(|e| format!("{}: {e}", o.display()).into()),
+            None => {
+                print!("{p}");
+                Ok(())
+            }
+        };
+    }
+    if let Command::Parameter {
+        cmd:
+            ParameterCommand::ToGpt {
+                file,
+                output,
+                disk_size,
+                type_guid,
+            },
+    } = &cmd
+    {
+        return Ok(parameter::to_gpt_file(file, output, *disk_size, type_guid)?);
+    }
+    if let Command::Loader {
+        cmd: LoaderCommand::Info {
+            file: Some(file), ..
+        },
+    } = &cmd
+    {
+        return Ok(loader::info(file)?);
+    }
+    if let Command::Inspect { file } = &cmd {
+        return Ok(inspect::inspect(file)?);
+    }
+    if let Command::List { json, probe } = cmd {
+        inventory::list(json, probe);
+        return Ok(());
+    }
+    if let Command::Healthcheck {
+        expect_mode,
+        expect_chip,
+    } = cmd
+    {
+        return Ok(health::healthcheck(
+            device,
+            eps,
+            expect_mode,
+            expect_chip.as_deref(),
+        )?);
+    }
+    if let Command::WslAttach { busid } = cmd {
+        return Ok(usbipd::attach(busid.as_deref())?);
+    }
+    if let Command::Service {
+        plan,
+        metrics,
+        checkpoints,
+        events,
+        verify,
+    } = cmd
+    {
+        if let Some(v) = verify {
+            plan::set_verify(v);
+        }
+        return Ok(service::service(
+            &plan,
+            metrics.as_deref(),
+            &checkpoints,
+            events.as_deref(),
+        )?);
+    }
+
+    if let Command::Batch {
+        file,
+        continue_on_error,
+        verify,
+    } = cmd
+    {
+        if let Some(v) = verify {
+            plan::set_verify(v);
+        }
+        return Ok(batch(&file, device, eps, continue_on_error)?);
+    }
+
+    if let Command::Clone {
+        from, to: Some(to), ..
+    } = &cmd
+        && from.or(device) == Some(*to)
+    {
+        return Err(Failure::Config(format!("Cannot clone {to} onto itself")));
+    }
+    // The source of a clone is not the device of the session.
+    let mut source = None;
+    let s: &Session = match (&cmd, session) {
+        (Command::Clone { from: Some(f), .. }, _) => source.insert(connect_with(Some(*f), eps)?),
+        (_, Some(c)) => c,
+        (_, none) => none.insert(connect_with(device, eps)?),
+    };
+    let (chip, mode) = (s.chip, s.mode);
+
+    match cmd {
+        Command::Info { auto_plug, rkbin } => {
+            if auto_plug && mode == Mode::MaskROM {
+                let s = plug(s, device, eps, rkbin)?;
+                protocol::info(&s)?;
+                return Ok(());
+            }
+            require_mode(mode, &[Mode::UsbPlug, Mode::Rockusb])?;
+            protocol::info(s)?;
+        }
+        Command::Run {
+            file_name,
+            region,
+            load_addr,
+            entry,
+        } => {
+            let mut data = std::fs::read(&file_name).map_err(|e| format!("{file_name}: {e}"))?;
+            if load_addr.is_none() && mode == Mode::Rockusb {
+                return Err(Failure::WrongMode(
+                    "U-Boot rockusb does not take code via mask ROM requests".into(),
+                ));
+            }
+            let mut linked = None;
+            if elf::is_elf(&data) {
+                let e = elf::flatten(&data)?;
+                let (l, load, entry) = (e.data.len(), e.load, e.entry);
+                info!("ELF with {l} bytes to load at {load:08x}, entry {entry:08x}");
+                linked = Some((load, entry));
+                data = e.data;
+            }
+            match load_addr {
+                Some(addr) => {
+                    require_mode(mode, &[Mode::UsbPlug])?;
+                    if let Some((load, _)) = linked
+                        && load != addr
+                    {
+                        warn!("Code is linked for {load:08x}, not {addr:08x}");
+                    }
+                    // Keep the ELF entry point's offset into the code.
+                    let elf_entry = linked.map(|(load, e)| addr.wrapping_add(e.wrapping_sub(load)));
+                    let entry = entry.or(elf_entry).unwrap_or(addr);
+                    let l = data.len();
+                    if entry < addr || entry as usize >= addr as usize + l {
+                        warn!("Entry {entry:08x} is outside of the loaded code");
+                    }
+                    info!("Load {l} bytes to {addr:08x}, entry {entry:08x}");
+                    // The loader writes whole words; pad the tail.
+                    let mut data = data;
+                    data.resize(l.next_multiple_of(MEM_ALIGN), 0);
+                    protocol::mem_write(s, addr, &data)?;
+                    protocol::exec(s, entry)?;
+                }
+                None => {
+                    if let Some((load, entry)) = linked {
+                        if let Some((addr, _)) = chip.target(region)
+                            && addr != load
+                        {
+                            warn!("Code is linked for {load:08x}, but goes to {addr:08x}");
+                        }
+                        if entry != load {
+                            warn!(
+                                "Entry {entry:08x} is not at the start, where the mask ROM jumps"
+                            );
+                        }
+                    }
+                    run_in(s, &data, region)?;
+                }
+            }
+        }
+        Command::Boot {
+            auto,
+            rkbin,
+            loader_version,
+            ddr,
+            usbplug,
+        } => {
+            require_mode(mode, &[Mode::MaskROM])?;
+            let (ddr, usbplug) = match (ddr, usbplug) {
+                _ if auto => rkbin_pick(rkbin, chip, loader_version.as_deref())?,
+                (Some(d), Some(u)) => (d, u),
+                _ => return Err("Give DDR init and usbplug binaries, --auto or a profile".into()),
+            };
+            boot(s, &ddr, &usbplug)?;
+        }
+        Command::Bringup { opts } => {
+            bringup::bringup(s, device, eps, opts)?;
+        }
+        Command::Mem { regmap, cmd } => {
+            require_mode(mode, &[Mode::UsbPlug])?;
+            let mut regs = regmap::RegMap::builtin(chip.name);
+            if let Some(f) = regmap {
+                regs.load(&f)?;
+            }
+            match cmd {
+                MemCommand::Read { addr, len, output } => {
+                    let addr = regs.resolve(&addr)?;
+                    if let Some(n) = regs.name(addr) {
+                        info!("{n} @ {addr:08x}");
+                    }
+                    if addr as u64 + len as u64 > 1 << 32 {
+                        return Err(Failure::Config(format!(
+                            "{len} bytes at {addr:08x} exceed the 32-bit address space"
+                        )));
+                    }
+                    let data = protocol::mem_read(s, addr, len)?;
+                    match output {
+                        Some(f) => {
+                            std::fs::write(&f, data).map_err(|e| format!("{}: {e}", f.display()))?
+                        }
+                        None => hexdump::hexdump(addr, &data),
+                    }
+                }
+                MemCommand::Write { addr, data } => {
+                    let addr = regs.resolve(&addr)?;
+                    let data = match std::fs::read(&data) {
+                        Ok(d) => d,
+                        Err(_) => parse_hex_bytes(&data)?,
+                    };
+                    let l = data.len();
+                    if l == 0 {
+                        return Err(Failure::Config("Nothing to write".into()));
+                    }
+                    if !(addr as usize).is_multiple_of(MEM_ALIGN) || !l.is_multiple_of(MEM_ALIGN) {
+                        return Err(Failure::Config(format!(
+                            "Address {addr:08x} and size {l} must be {MEM_ALIGN}-byte aligned"
+                        )));
+                    }
+                    if addr.checked_add(l as u32 - 1).is_none() {
+                        return Err(Failure::Config(format!(
+                            "{l} bytes at {addr:08x} exceed the 32-bit address space"
+                        )));
+                    }
+                    info!("Write {l} bytes at {addr:08x}");
+                    protocol::mem_write(s, addr, &data)?;
+                }
+                MemCommand::Regs => {
+                    for (n, a) in regs.iter() {
+                        println!("{a:08x} {n}");
+                    }
+                }
+            }
+        }
+        Command::Write {
+            lba,
+            file,
+            delta,
+            bmap,
+            no_bmap,
+            verify,
+            preserve,
+            adjust_offset,
+            across_partitions,
+            resume_at,
+            json,
+            then,
+            smoke,
+        } => {
+            require_mode(mode, &[Mode::UsbPlug, Mode::Rockusb])?;
+            let file = file.ok_or("Give an image file or a profile with one for the partition")?;
+            let bmap = match bmap {
+                Some(b) => Some(b),
+                None if no_bmap => None,
+                None => bmap::find(&file).inspect(|b| info!("Using {}", b.display())),
+            };
+            let bmap = bmap.as_deref().map(bmap::load).transpose()?;
+            let mut opts = flash::Options {
+                delta,
+                bmap,
+                verify,
+                preserve,
+                resume: resume_at,
+            };
+            let table = gpt::read(&mut |lba, n| {
+                protocol::read_lba(s, lba as u32, n).map_err(Failure::from)
+            })?;
+            let target = match &lba {
+                size::Lba::Abs(_) => placement::target(&file),
+                size::Lba::Part(name, _) => name.clone(),
+            };
+            let lba = match (&lba, &table) {
+                (size::Lba::Abs(n), _) => *n,
+                (_, Ok(g)) => lba.resolve(g)?,
+                (_, Err(e)) => return Err(e.clone().into()),
+            };
+            let lba = u32::try_from(lba).map_err(|_| format!("Sector {lba} is out of reach"))?;
+            let url = file.to_str().filter(|f| fetch::is_url(f));
+            // Streamed images are not read ahead to look at.
+            let lba = match url {
+                Some(_) => lba,
+                None => placement::check(&file, lba, adjust_offset)?,
+            };
+            if let (Ok(g), None) = (&table, url) {
+                placement::check_partitions(g, &file, lba as u64, &target, across_partitions)?;
+            }
+            let write = |s: &Session, opts: &flash::Options| match url {
+                Some(u) => fetch::open(u).map_err(Failure::from).and_then(|mut r| {
+                    let len = r.len();
+                    flash::write_image(s, lba, u, &mut r, len, opts)
+                }),
+                None => flash::write(s, lba, &file, opts),
+            };
+            let mut at = match wait::enabled() {
+                true => Some(pick(device)?),
+                false => None,
+            };
+            let mut back: Option<Session> = None;
+            let stats = loop {
+                let s = back.as_ref().unwrap_or(s);
+                capability::tune(s)?;
+                match (write(s, &opts), &mut at) {
+                    (Err(e @ Failure::Disconnect(_)), Some((old, port))) => {
+                        warn!("{e}");
+                        opts.resume = Some(flash::resume_point());
+                        let (addr, s) = rejoin(port, *old, eps)?;
+                        (*old, back) = (addr, Some(s));
+                    }
+                    (res, _) => break res?,
+                }
+            };
+            let s = back.as_ref().unwrap_or(s);
+            if json {
+                println!("{}", stats.to_json());
+            }
+            then_reset(s, then, &smoke)?;
+        }
+        Command::Clone {
+            output,
+            format,
+            to,
+            delta,
+            ..
+        } => {
+            require_mode(mode, &[Mode::UsbPlug, Mode::Rockusb])?;
+            if let Some(to) = to {
+                let t = connect(Some(to))?;
+                require_mode(t.mode, &[Mode::UsbPlug, Mode::Rockusb])?;
+                clone::between(s, &t, delta)?;
+                return Ok(());
+            }
+            let sectors = protocol::flash_info(s)?.sectors as u64;
+            let read = &mut |lba, n| protocol::read_lba(s, lba, n).map_err(Failure::from);
+            clone::to_file(read, sectors, &output.unwrap(), format)?;
+        }
+        Command::UpgradeLoader { file, then, smoke } => {
+            require_mode(mode, &[Mode::UsbPlug, Mode::Rockusb])?;
+            let file = file.ok_or("Give a loader file or a profile with one")?;
+            idb::upgrade(s, &file)?;
+            then_reset(s, then, &smoke)?;
+        }
+        Command::Raw {
+            opcode,
+            subcode,
+            addr,
+            size,
+            direction,
+            length,
+            data,
+        } => {
+            require_mode(mode, &[Mode::UsbPlug, Mode::Rockusb])?;
+            let data = match data {
+                Some(d) => std::fs::read(&d).or_else(|_| parse_hex_bytes(&d))?,
+                None => Vec::new(),
+            };
+            let name = protocol::opcode_name(opcode).unwrap_or("unknown");
+            info!("Opcode {opcode:#04x} ({name}), {direction:?}");
+            let r = protocol::Raw {
+                opcode,
+                subcode,
+                address: addr,
+                size,
+                direction,
+                length: length.unwrap_or(size as u32),
+            };
+            let (d, status) = protocol::raw(s, &r, &data)?;
+            if direction == protocol::Direction::In {
+                println!("Data, {} of {} bytes:", d.len(), r.length);
+                hexdump::hexdump(0, &d);
+            }
+            match status {
+                Some(c) => println!(
+                    "Status {}, residue {}, tag {:#x}{}",
+                    c.status,
+                    c.residue,
+                    c.tag,
+                    if c.tag_matches {
+                        ""
+                    } else {
+                        " (not the request's)"
+                    }
+                ),
+                None => println!("No status"),
+            }
+        }
+        Command::Extract {
+            partition,
+            path,
+            output,
+            grep,
+        } => {
+            require_mode(mode, &[Mode::UsbPlug, Mode::Rockusb])?;
+            let read = &mut |lba, n| protocol::read_lba(s, lba as u32, n).map_err(Failure::from);
+            let c = extract::extract(read, &partition, &path)?;
+            extract::show(c, output.as_deref(), grep.as_deref())?;
+        }
+        Command::Misc { cmd } => {
+            require_mode(mode, &[Mode::UsbPlug, Mode::Rockusb])?;
+            let read = &mut |lba, n| protocol::read_lba(s, lba as u32, n).map_err(Failure::from);
+            match cmd {
+                MiscCommand::Read { offset } => misc::print(read, offset)?,
+                MiscCommand::WriteCommand {
+                    target,
+                    args,
+                    offset,
+                } => {
+                    let write = &mut |lba, d: &[u8]| {
+                        protocol::write_lba(s, lba as u32, d).map_err(Failure::from)
+                    };
+                    misc::write_command(read, write, offset, target, &args)?;
+                }
+            }
+        }
+        Command::Vendor { cmd } => {
+            require_mode(mode, &[Mode::UsbPlug, Mode::Rockusb])?;
+            let read = &mut |lba, n| protocol::read_lba(s, lba, n).map_err(Failure::from);
+            match cmd {
+                VendorCommand::Dump { output } => vendor::dump(read, &output)?,
+                VendorCommand::Restore { input } => {
+                    let write =
+                        &mut |lba, d: &[u8]| protocol::write_lba(s, lba, d).map_err(Failure::from);
+                    vendor::restore(read, write, &input)?;
+                }
+            }
+        }
+        Command::Loader { .. } => {
+            require_mode(mode, &[Mode::UsbPlug, Mode::Rockusb])?;
+            idb::info(s)?;
+        }
+        Command::Uid => {
+            require_mode(mode, &[Mode::UsbPlug, Mode::Rockusb])?;
+            println!("{}", uid::canonical(&uid::read(s)?));
+        }
+        Command::LoaderLog => {
+            require_mode(mode, &[Mode::UsbPlug, Mode::Rockusb])?;
+            let c = capability::read(s)??;
+            if !c.names().contains(&"read com log") {
+                return Err("Loader does not keep a log to read".into());
+            }
+            let log = protocol::read_log(s)?;
+            if log.is_empty() {
+                info!("The loader's log is empty");
+            }
+            print!("{}", String::from_utf8_lossy(&log));
+        }
+        Command::Capability => {
+            require_mode(mode, &[Mode::UsbPlug, Mode::Rockusb])?;
+            capability::print(&capability::read(s)??);
+        }
+        Command::SwitchStorage { storage } => {
+            require_mode(mode, &[Mode::UsbPlug, Mode::Rockusb])?;
+            match storage {
+                Some(st) => protocol::change_storage(s, st)?,
+                None => match protocol::read_storage(s)? {
+                    Some(st) => println!("{st}"),
+                    None => println!("unknown"),
+                },
+            }
+        }
+        Command::Spinor { cmd } => {
+            require_mode(mode, &[Mode::UsbPlug, Mode::Rockusb])?;
+            protocol::change_storage(s, protocol::Storage::Spinor)?;
+            match cmd {
+                SpinorCommand::Read {
+                    offset,
+                    len,
+                    output,
+                } => {
+                    let data = spinor::read(s, offset, len)?;
+                    std::fs::write(&output, data)
+                        .map_err(|e| format!("{}: {e}", output.display()))?;
+                }
+                SpinorCommand::Write { offset, file } => {
+                    let data =
+                        std::fs::read(&file).map_err(|e| format!("{}: {e}", file.display()))?;
+                    spinor::write(s, offset, &data)?;
+                }
+                SpinorCommand::Erase { offset, len } => {
+                    spinor::erase(s, offset, len)?;
+                }
+            }
+        }
+        Command::Spinand { cmd } => {
+            require_mode(mode, &[Mode::UsbPlug, Mode::Rockusb])?;
+            protocol::change_storage(s, protocol::Storage::Spinand)?;
+            match cmd {
+                SpinandCommand::BadBlocks => {
+                    let fi = protocol::flash_info(s)?;
+                    let blocks = fi.sectors / (fi.block_sectors as u32).max(1);
+                    for b in spinand::bad_blocks(s, 0, blocks)? {
+                        println!("{b}");
+                    }
+                }
+                SpinandCommand::Write {
+                    offset,
+                    file,
+                    limit,
+                } => {
+                    let data =
+                        std::fs::read(&file).map_err(|e| format!("{}: {e}", file.display()))?;
+                    let limit = limit.unwrap_or(u64::MAX);
+                    spinand::write(s, offset, &data, limit)?;
+                }
+            }
+        }
+        Command::Gpt { cmd } => {
+            require_mode(mode, &[Mode::UsbPlug, Mode::Rockusb])?;
+            let total = protocol::flash_info(s)?.sectors as u64;
+            let read = &mut |lba, n| protocol::read_lba(s, lba as u32, n).map_err(Failure::from);
+            let write =
+                &mut |lba, d: &[u8]| protocol::write_lba(s, lba as u32, d).map_err(Failure::from);
+            match cmd {
+                GptCommand::Repair { dry_run } => {
+                    let found = gpt::repair(read, write, total, dry_run)?;
+                    for f in &found {
+                        warn!("{f}");
+                    }
+                    if found.is_empty() {
+                        info!("GPT is intact");
+                    }
+                }
+                GptCommand::Write {
+                    template,
+                    disk_size,
+                    force,
+                } => {
+                    if gpt::read(read)?.is_ok() && !force {
+                        return Err("Storage has a GPT already, use --force to replace it".into());
+                    }
+                    let total = match disk_size {
+                        DiskSize::Auto => total,
+                        DiskSize::Bytes(b) => (b / protocol::SECTOR_SIZE as u64).min(total),
+                    };
+                    let template = template.ok_or("Give --template or a profile with one")?;
+                    let g = template::build(template, total)?;
+                    write(0, &gpt::protective_mbr(total))?;
+                    gpt::write_both(&g, total - 1, write)?;
+                    for p in g.partitions() {
+                        let (first, last) = (p.first_lba, p.last_lba);
+                        info!("{first:>10} {last:>10} {}", p.name());
+                    }
+                }
+                cmd => {
+                    let mut g = gpt::read(read)?.map_err(|e| format!("{e}, try gpt repair"))?;
+                    g.fit(
+                        total
+                            .checked_sub(1)
+                            .ok_or("The storage reports no sectors")?,
+                    )?;
+                    let sectors = |b: u64| b.div_ceil(protocol::SECTOR_SIZE as u64);
+                    match cmd {
+                        GptCommand::Add {
+                            name,
+                            size,
+                            start,
+                            align,
+                            type_guid,
+                        } => {
+                            let t = gpt::parse_guid(&type_guid)?;
+                            g.add(&name, t, start, size.map(sectors), align)?;
+                        }
+                        GptCommand::Resize { name, size } => g.resize(&name, size.map(sectors))?,
+                        GptCommand::Delete { name } => g.delete(&name)?,
+                        GptCommand::Repair { .. }
+                        | GptCommand::Write { .. }
+                        | GptCommand::ToParameter { .. } => unreachable!(),
+                    }
+                    gpt::write_both(&g, total - 1, write)?;
+                    for p in g.partitions() {
+                        let (first, last) = (p.first_lba, p.last_lba);
+                        info!("{first:>10} {last:>10} {}", p.name());
+                    }
+                }
+            }
+        }
+        Command::EraseAll {
+            boot,
+            except_vendor_storage,
+            confirm,
+        } => {
+            require_mode(mode, &[Mode::UsbPlug, Mode::Rockusb])?;
+            let opts = erase::Options {
+                boot,
+                except_vendor_storage,
+                confirm,
+            };
+            erase::erase_all(s, opts)?;
+        }
+        Command::Emmc {
+            cmd: EmmcCommand::Info,
+        } => {
+            require_mode(mode, &[Mode::UsbPlug, Mode::Rockusb])?;
+            let fi = protocol::flash_info(s)?;
+            emmc::report_flash_info(&fi);
+        }
+        Command::Exec { addr } => {
+            require_mode(mode, &[Mode::UsbPlug])?;
+            protocol::exec(s, addr)?;
+        }
+        Command::DumpSram { base, size, output } => {
+            require_mode(mode, &[Mode::UsbPlug])?;
+            let (base, size) = match (base, size) {
+                (Some(b), Some(s)) => (b, s),
+                _ => chip.sram.ok_or_else(|| {
+                    Failure::Config(format!(
+                        "SRAM of {} unknown, use --base and --size",
+                        chip.name
+                    ))
+                })?,
+            };
+            info!("Dump SRAM, {} at {base:08x}", size::human(size as u64));
+            let data = protocol::mem_read(s, base, size as usize)?;
+            std::fs::write(&output, data).map_err(|e| format!("{}: {e}", output.display()))?;
+            info!("Saved to {}", output.display());
+        }
+        Command::Memtest { base, size } => {
+            require_mode(mode, &[Mode::UsbPlug])?;
+            memtest::memtest(s, base, size)?;
+        }
+        Command::Reset { smoke } => {
+            require_mode(mode, &[Mode::UsbPlug, Mode::Rockusb])?;
+            reset(s, &smoke)?;
+        }
+        Command::Attest {
+            output,
+            operator,
+            key,
+            key_id,
+            partitions,
+        } => {
+            require_mode(mode, &[Mode::UsbPlug, Mode::Rockusb])?;
+            let opts = attest::Options {
+                output,
+                operator,
+                key,
+                key_id,
+                partitions,
+            };
+            attest::attest(s, chip, opts)?;
+        }
+        Command::Audit { manifest, record } => {
+            require_mode(mode, &[Mode::UsbPlug, Mode::Rockusb])?;
+            attest::check_boot_chain(s, &manifest, record)?;
+        }
+        Command::Serve { .. }
+        | Command::Provision { .. }
+        | Command::Service { .. }
+        | Command::Batch { .. }
+        | Command::WslAttach { .. }
+        | Command::Inspect { .. }
+        | Command::List { .. }
+        | Command::Healthcheck { .. }
+        | Command::Parameter { .. }
+        | Command::Emmc {
+            cmd: EmmcCommand::Decode { .. },
+        } => unreachable!(),
+    }
+    Ok(())
+}
 
+/// Whether the device re-enumerates after a command, so that the connection
+/// to it ends
+fn reenumerates(cmd: &Command, mode: Mode) -> bool {
     match cmd {
-        Command::Info => {
-            if mode != Mode::UsbPlug {
-                panic!("Device must be in USB plug mode");
+        Command::Boot { .. } | Command::Reset { .. } => true,
+        Command::Info { auto_plug, .. } => *auto_plug && mode == Mode::MaskROM,
+        Command::Bringup { .. } => mode == Mode::MaskROM,
+        Command::Write { then, .. } | Command::UpgradeLoader { then, .. } => then.is_some(),
+        _ => false,
+    }
+}
+
+/// Whether the device may drop off the bus and come back after a command,
+/// e.g. when DDR init resets it or starts a loader
+fn may_reenumerate(cmd: &Command, mode: Mode) -> bool {
+    matches!(
+        cmd,
+        Command::Run {
+            load_addr: None,
+            ..
+        }
+    ) && mode == Mode::MaskROM
+}
+
+/// Whether the device may come back elsewhere after a command line
+pub fn may_move(args: &[String]) -> bool {
+    let argv = std::iter::once("rk_boot").chain(args.iter().map(String::as_str));
+    // Only the mask ROM runs code without a load address.
+    Cli::try_parse_from(argv).is_ok_and(|c| may_reenumerate(&c.cmd, Mode::MaskROM))
+}
+
+/// A command line of a plan with `--verify` added to writes that do not
+/// verify, if plans are to be verified
+pub fn verifying(args: &[String]) -> Vec<String> {
+    let argv = std::iter::once("rk_boot").chain(args.iter().map(String::as_str));
+    let unverified = matches!(
+        Cli::try_parse_from(argv).map(|c| c.cmd),
+        Ok(Command::Write { verify: None, .. })
+    );
+    match plan::verify().filter(|_| unverified) {
+        Some(kind) => {
+            let kind = kind.to_possible_value().unwrap().get_name().to_string();
+            [args, &["--verify".into(), kind]].concat()
+        }
+        None => args.to_vec(),
+    }
+}
+
+/// A command line of a batch; the batch picks the device
+fn batch_command(args: &[String]) -> Result<Command, String> {
+    if job_device(args)?.is_some() {
+        return Err("give --device to the batch, not its commands".into());
+    }
+    let argv = std::iter::once("rk_boot").chain(args.iter().map(String::as_str));
+    let cli = Cli::try_parse_from(argv).map_err(|e| e.to_string())?;
+    if let Command::Batch { .. } = cli.cmd {
+        return Err("cannot run a batch from within a batch".into());
+    }
+    Ok(cli.cmd)
+}
+
+/// Run the command lines of a file on one connection, which is opened again
+/// only when the device has re-enumerated
+fn batch(
+    file: &Path,
+    device: Option<DeviceAddr>,
+    eps: Endpoints,
+    continue_on_error: bool,
+) -> Result<(), String> {
+    let steps = plan::load(file)?;
+    let (mut addr, port) = pick(device)?;
+    let mut session: Option<Session> = None;
+    let mut moved = false;
+    let mut failed = 0;
+    let _steps = progress::steps(steps.len());
+    for (n, step) in steps.iter().enumerate() {
+        info!("{}:{}: {}", file.display(), step.line, step.args.join(" "));
+        progress::step(n + 1, &step.args.join(" "));
+        let res = (|| -> Result<(), Failure> {
+            let cmd = batch_command(&verifying(&step.args))?;
+            if moved {
+                addr = provision::reappear(&port, addr)
+                    .ok_or(Failure::Disconnect(format!("{port} did not come back")))?;
+                moved = false;
+            }
+            let env = HashMap::from([
+                ("device".to_string(), addr.to_string()),
+                ("port".to_string(), port.clone()),
+                ("step".to_string(), (n + 1).to_string()),
+            ]);
+            plan::run_hooks(step, &plan::hook_env(&env))?;
+            let storage = session.as_ref().and_then(Session::storage);
+            if let Some(s) = step.storage.filter(|s| storage != Some(*s)) {
+                let cmd = Command::SwitchStorage { storage: Some(s) };
+                execute_in(cmd, Some(addr), eps, &mut session)?;
+            }
+            let s = match &mut session {
+                Some(s) => s,
+                none => none.insert(connect_with(Some(addr), eps)?),
+            };
+            moved = reenumerates(&cmd, s.mode);
+            let may_move = may_reenumerate(&cmd, s.mode);
+            let res = execute_in(cmd, Some(addr), eps, &mut session);
+            if may_move
+                && res.is_ok()
+                && let Some(a) = follow::settle(&port, addr)?
+            {
+                addr = a;
+                if let Some(s) = session.take() {
+                    s.close();
+                }
+            }
+            if moved && let Some(s) = session.take() {
+                s.close();
             }
-            protocol::info(&i, e_in_addr, e_out_addr);
+            res
+        })();
+        let held = audit::devices();
+        audit(&step.args, &res);
+        if session.is_some() {
+            held.into_iter().for_each(audit::connected);
         }
-        Command::Run { file_name, region } => {
-            let data = std::fs::read(file_name).unwrap();
-            protocol::run(&i, &data, &region);
+        match res {
+            Ok(()) => script::record(&step.args),
+            Err(e) => {
+                error!("{}:{}: {e}", file.display(), step.line);
+                failed += 1;
+                if !continue_on_error {
+                    return Err(format!(
+                        "Stopped at line {} of {}",
+                        step.line,
+                        file.display()
+                    ));
+                }
+            }
+        }
+    }
+    match failed {
+        0 => Ok(()),
+        n => Err(format!("{n} of {} commands failed", steps.len())),
+    }
+}
+
+/// Use the profile given on the command line, if any, on this thread
+fn select_profile(cli: &Cli) -> Result<(), String> {
+    let p = match &cli.profile {
+        Some(name) => {
+            let file = cli.config.clone().or_else(profile::config_file);
+            let file = file.ok_or("Cannot tell where the config file is, use --config")?;
+            let p = profile::load(&file, name)?;
+            info!("Profile {name} from {}", file.display());
+            Some(p)
         }
+        None => None,
+    };
+    profile::select(p);
+    Ok(())
+}
+
+/// Fill in what the command line leaves out from the profile
+fn with_profile(mut cmd: Command, p: &profile::Profile) -> Command {
+    let fill = |v: &mut Option<PathBuf>, from: &Option<PathBuf>| {
+        if v.is_none() {
+            v.clone_from(from);
+        }
+    };
+    match &mut cmd {
+        Command::Boot {
+            auto,
+            rkbin,
+            ddr,
+            usbplug,
+            ..
+        } => {
+            fill(rkbin, &p.rkbin);
+            if !*auto && ddr.is_none() {
+                fill(ddr, &p.ddr);
+                fill(usbplug, &p.usbplug);
+                *auto = ddr.is_none();
+            }
+        }
+        Command::Info { rkbin, .. } => fill(rkbin, &p.rkbin),
+        Command::Bringup { opts } => {
+            if opts.ddr.is_none() {
+                fill(&mut opts.ddr, &p.ddr);
+                fill(&mut opts.usbplug, &p.usbplug);
+            }
+            fill(&mut opts.rkbin, &p.rkbin);
+        }
+        Command::UpgradeLoader { file, .. } => fill(file, &p.loader),
+        Command::Gpt {
+            cmd: GptCommand::Write { template, .. },
+        } => *template = template.or(p.template),
+        Command::Write {
+            lba: size::Lba::Part(name, _),
+            file,
+            ..
+        } => fill(file, &p.image(name).map(Path::to_path_buf)),
+        _ => {}
+    }
+    cmd
+}
+
+fn retry_policy(cli: &Cli) -> Result<retry::Policy, String> {
+    let mut p = match &cli.retry_config {
+        Some(f) => retry::load(f)?,
+        None => retry::Policy::default(),
+    };
+    if let Some(n) = cli.retries {
+        p.attempts = n.max(1);
+    }
+    if let Some(ms) = cli.retry_backoff {
+        p.backoff = Duration::from_millis(ms);
+    }
+    if !cli.retry_on.is_empty() {
+        p.on = retry::parse_classes(&cli.retry_on)?;
+    }
+    Ok(p)
+}
+
+fn main() {
+    // Default to log level "info". Otherwise, you get no "regular" logs.
+    let env = env_logger::Env::default().default_filter_or("info");
+    jobs::init_logger(env_logger::Builder::from_env(env).build());
+
+    let cli = Cli::parse();
+    if let Err(e) = select_profile(&cli) {
+        error!("{e}");
+        std::process::exit(1);
+    }
+    if let Err(e) = retry_policy(&cli).map(retry::set) {
+        error!("{e}");
+        std::process::exit(1);
+    }
+    protocol::set_check_crc(cli.check_crc);
+    session::set_read_only(cli.read_only);
+    flash::set_probe_write_protect(cli.probe_write_protect);
+    wait::set(cli.wait);
+    #[cfg(feature = "fault-injection")]
+    fault::set(cli.inject_fault.clone());
+    if let Some(w) = &cli.workdir
+        && let Err(e) = workdir::set(w.clone())
+    {
+        error!("{e}");
+        std::process::exit(1);
+    }
+    workdir::sweep();
+    let daemon = matches!(
+        cli.cmd,
+        Command::Serve { .. } | Command::Service { .. } | Command::Provision { .. }
+    );
+    // Jobs and steps of the daemons each keep to the deadline on their own.
+    if let Some(d) = cli.deadline
+        && daemon
+    {
+        deadline::set_per_job(d);
+    } else if let Some(d) = cli.deadline {
+        deadline::set(d);
+        deadline::keep_in(Some(PathBuf::from(deadline::STATE_FILE)));
+        // In case something other than a USB transfer hangs
+        std::thread::spawn(move || {
+            sleep(d + DEADLINE_GRACE);
+            error!("Deadline of {d:?} exceeded, giving up");
+            workdir::cleanup();
+            std::process::exit(1);
+        });
+    }
+    let audit_log = match &cli.audit_log {
+        Some(p) => Some(p.clone()),
+        None if daemon && !cli.no_audit_log => Some(PathBuf::from(audit::DEFAULT_PATH)),
+        None => None,
+    };
+    if let Some(p) = audit_log
+        && let Err(e) = audit::open(&p)
+    {
+        error!("{e}");
+        std::process::exit(1);
+    }
+    if let Some(p) = &cli.perf_trace
+        && let Err(e) = trace::open(p)
+    {
+        error!("{e}");
+        std::process::exit(1);
+    }
+    let remote = cli.remote.is_some();
+    let export = cli.export_script.clone();
+    // Plan and batch steps are recorded one by one instead.
+    let leaf = !matches!(cli.cmd, Command::Provision { .. } | Command::Batch { .. });
+    if export.is_some() {
+        if let Command::Serve { .. } | Command::Service { .. } = cli.cmd {
+            error!("--export-script only applies to a single session");
+            std::process::exit(1);
+        }
+        script::start();
+    }
+    let soak = cli.repeat.is_some() || cli.until_failure;
+    if soak && !repeatable(&cli.cmd) {
+        error!("--repeat and --until-failure apply to commands that read, write or run code");
+        std::process::exit(1);
+    }
+    // Forwarded, the device is looked up where it is.
+    let device = match &cli.device {
+        Some(d) if !remote => match d.resolve() {
+            Ok(a) => Some(a),
+            Err(e) => {
+                error!("{e}");
+                std::process::exit(1);
+            }
+        },
+        _ => None,
+    };
+    let res = match cli.remote {
+        Some(r) if soak => {
+            let s = soak::repeat(cli.repeat, cli.until_failure, || {
+                client::run(&r, client::forwarded_args()).map_err(Failure::from)
+            });
+            s.report();
+            s.result()
+        }
+        Some(r) => client::run(&r, client::forwarded_args()).map_err(Failure::from),
+        // Long-running commands, plans and batches take care of their failures.
+        None if matches!(
+            cli.cmd,
+            Command::Serve { .. }
+                | Command::Service { .. }
+                | Command::Provision { .. }
+                | Command::Batch { .. }
+        ) =>
+        {
+            execute(cli.cmd, device, cli.endpoints)
+        }
+        // Each attempt gets a fresh command, parsed again.
+        None if soak => {
+            let s = soak::repeat(cli.repeat, cli.until_failure, || {
+                retry::command(|| execute(Cli::parse().cmd, device, cli.endpoints))
+            });
+            s.report();
+            s.result()
+        }
+        None => retry::command(|| execute(Cli::parse().cmd, device, cli.endpoints)),
+    };
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !remote && !daemon {
+        audit(&args, &res);
+    }
+    if res.is_ok() && leaf {
+        script::record(&args);
+    }
+    if let Some(p) = export
+        && let Err(e) = script::export(&p)
+    {
+        error!("{e}");
+    }
+    workdir::cleanup();
+    if let Err(e) = res {
+        error!("{e}");
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batch_lines() {
+        let args = |l: &str| plan::split_args(l).unwrap();
+        let cmd = batch_command(&args("boot --auto")).unwrap();
+        assert!(reenumerates(&cmd, Mode::MaskROM));
+        let cmd = batch_command(&args("info --auto-plug")).unwrap();
+        assert!(reenumerates(&cmd, Mode::MaskROM));
+        assert!(!reenumerates(&cmd, Mode::UsbPlug));
+        let cmd = batch_command(&args("write boot+1MiB boot.img")).unwrap();
+        assert!(!reenumerates(&cmd, Mode::UsbPlug));
+        assert!(batch_command(&args("--device 1:2 info")).is_err());
+        assert!(batch_command(&args("batch more.txt")).is_err());
+        assert!(batch_command(&args("serve")).is_err());
+        assert!(batch_command(&args("run --load-addr 0x1000 x.bin")).is_ok());
+        assert!(batch_command(&args("run --load-addr 0x1002 x.bin")).is_err());
+    }
+
+    #[test]
+    fn modes_by_bcd_usb() {
+        assert_eq!(Mode::from_bcd_usb(0x0200), Mode::MaskROM);
+        assert_eq!(Mode::from_bcd_usb(0x0201), Mode::UsbPlug);
+        assert_eq!(Mode::from_bcd_usb(0x0110), Mode::MaskROM);
+        assert_eq!(Mode::from_bcd_usb(0x0311), Mode::UsbPlug);
+    }
+
+    #[test]
+    fn device_selection_reasons() {
+        let found = |bus, usable, what: &str| Found {
+            addr: DeviceAddr { bus, address: 2 },
+            pid: 0x350a,
+            usable,
+            what: what.into(),
+            port: Some(format!("{bus}-1")),
+            serial: Some("ABC".into()),
+        };
+        assert!(choose(&[], None).unwrap_err().contains("No Rockchip"));
+        let ums = [found(1, false, "in USB mass storage (UMS) mode")];
+        let e = choose(&ums, None).unwrap_err();
+        assert!(e.contains("001:002 2207:350a in USB mass storage"), "{e}");
+        let two = [found(1, true, "rockusb"), found(3, true, "rockusb")];
+        assert!(choose(&two, None).unwrap_err().contains("Multiple"));
+        let at = DeviceAddr { bus: 3, address: 2 };
+        assert_eq!(choose(&two, Some(at)), Ok(1));
+        let mixed = [
+            found(1, false, "with an unsupported product ID"),
+            two[0].clone(),
+        ];
+        assert_eq!(choose(&mixed, None), Ok(1));
+
+        let sel = |s: &str| s.parse::<DeviceSel>().unwrap();
+        assert_eq!(sel("3:2"), DeviceSel::Addr(at));
+        assert_eq!(select(&two, &sel("3-1")), Ok(at));
+        let e = select(&two, &sel("ABC")).unwrap_err();
+        assert!(e.contains("Several") && e.contains("003:002"), "{e}");
+        let e = select(&two, &sel("XYZ")).unwrap_err();
+        assert!(
+            e.contains("001:002 2207:350a rockusb, port 1-1, serial ABC"),
+            "{e}"
+        );
+        let one = [found(5, true, "rockusb")];
+        assert_eq!(select(&one, &sel("ABC")).unwrap().bus, 5);
+    }
+
+    #[test]
+    fn picks_rockusb_interface() {
+        let alt = |interface, alt, class, bulk: bool| AltSetting {
+            interface,
+            alt,
+            class,
+            bulk_in: bulk.then_some(0x80 | interface),
+            bulk_out: bulk.then_some(interface),
+        };
+        let debug = alt(0, 0, (0xff, 0x42, 0x01), true);
+        let zero_bandwidth = alt(1, 0, (0xff, 0x06, 0x05), false);
+        let rockusb = alt(1, 1, (0xff, 0x06, 0x05), true);
+        let alts = [debug, zero_bandwidth, rockusb];
+        assert_eq!(pick_alt_setting(&alts), Some(&rockusb));
+        assert_eq!(pick_alt_setting(&alts[..2]), Some(&debug));
+        let cdc = alt(2, 0, (0x0a, 0, 0), true);
+        assert_eq!(pick_alt_setting(&[cdc, debug]), Some(&debug));
+        assert_eq!(pick_alt_setting(&[zero_bandwidth]), None);
+
+        // The active configuration is kept if it will do.
+        let configs = [
+            (1, vec![zero_bandwidth]),
+            (2, vec![debug]),
+            (3, vec![rockusb]),
+        ];
+        assert_eq!(pick_configuration(&configs, Some(2)), Some(2));
+        assert_eq!(pick_configuration(&configs, Some(1)), Some(3));
+        assert_eq!(pick_configuration(&configs, None), Some(3));
+        assert_eq!(pick_configuration(&configs[..2], None), Some(2));
+        assert_eq!(pick_configuration(&configs[..1], Some(1)), None);
     }
 }
@@ -0,0 +1,125 @@
+//! How mask ROMs take code over USB, the 0x471 and 0x472 downloads
+//!
+//! The ROMs agree on the basics: control requests 0xc with the region as
+//! the index, the code in chunks with a CRC-16 after it, and a short last
+//! chunk ending the download. Generations differ in the details, which a
+//! [`MaskRomVariant`] holds; each chip names its variant, chips defined in
+//! TOML with `mask_rom = "NAME"`.
+//!
+//! Some ROMs fail code whose length leaves a single byte in the last
+//! chunk, with the CRC split across two transfers. Such code gets a zero
+//! byte appended before the CRC, which is logged, as the CRC and any digest
+//! of what was sent then cover one byte more than the file. A chip in TOML
+//! can turn this on or off with `pad_split_crc = 1` or `0`.
+
+use log::info;
+
+const CRC: crc::Crc<u16> = crc::Crc::<u16>::new(&crc::CRC_16_IBM_3740);
+
+/// The download details of a family of mask ROMs
+#[derive(Clone, Copy, Debug)]
+pub struct MaskRomVariant {
+    pub name: &'static str,
+    /// Size of the control transfers
+    pub chunk: usize,
+    /// Pad code that would leave the CRC split across two chunks
+    pub pad_split_crc: bool,
+    /// Send a zero byte after a download that fills whole chunks, which
+    /// the ROM would otherwise not see the end of
+    pub zero_terminator: bool,
+}
+
+impl MaskRomVariant {
+    /// The code as sent: padded if need be, with its CRC after it
+    pub fn frame(&self, code: &[u8]) -> Vec<u8> {
+        let mut data = code.to_vec();
+        if self.pad_split_crc && data.len() % self.chunk == self.chunk - 1 {
+            data.push(0);
+        }
+        let checksum = CRC.checksum(&data);
+        if data.len() > code.len() {
+            info!(
+                "Padded {} bytes of code with a zero byte to keep the CRC in one chunk; \
+                 CRC {checksum:04x} over {} bytes",
+                code.len(),
+                data.len()
+            );
+        }
+        // Yes, this must be big endian.
+        data.extend_from_slice(&checksum.to_be_bytes());
+        data
+    }
+
+    /// What to send after `len` bytes of framed code to end the download
+    pub fn terminator(&self, len: usize) -> Option<&'static [u8]> {
+        (self.zero_terminator && len.is_multiple_of(self.chunk)).then_some(&[0])
+    }
+}
+
+/// What rkdeveloptool's download_boot does for every chip: 4 KiB control
+/// transfers, the last one short or followed by a zero byte
+pub const RK3366: MaskRomVariant = MaskRomVariant {
+    name: "rk3366",
+    chunk: 4096,
+    pad_split_crc: true,
+    zero_terminator: true,
+};
+
+pub const VARIANTS: &[&MaskRomVariant] = &[&RK3366];
+
+pub fn find(name: &str) -> Option<&'static MaskRomVariant> {
+    VARIANTS
+        .iter()
+        .find(|v| v.name.eq_ignore_ascii_case(name))
+        .copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc_padding_at_chunk_boundaries() {
+        let rom = &RK3366;
+        // Code length, framed length, whether padded, terminator
+        for (n, framed, padded, ends) in [
+            (4093, 4095, false, false),
+            (4094, 4096, false, true),
+            (4095, 4098, true, false),
+            (4096, 4098, false, false),
+            (8191, 8194, true, false),
+            (8190, 8192, false, true),
+        ] {
+            let code: Vec<u8> = (0..n).map(|i| (i * 7) as u8).collect();
+            let f = rom.frame(&code);
+            assert_eq!(f.len(), framed, "{n}");
+            let body = &f[..framed - 2];
+            assert_eq!(body.len() == n + 1, padded, "{n}");
+            assert_eq!(body[..n], code[..]);
+            assert_eq!(f[framed - 2..], CRC.checksum(body).to_be_bytes(), "{n}");
+            assert_eq!(rom.terminator(f.len()).is_some(), ends, "{n}");
+        }
+
+        let text = "name = \"X\"\npid = 1\npad_split_crc = 0\n";
+        let chip = crate::chip::parse(text).unwrap().remove(0);
+        assert!(!chip.mask_rom.pad_split_crc);
+        assert_eq!(chip.mask_rom.frame(&[0; 4095]).len(), 4097);
+        let text = "name = \"X\"\npid = 1\npad_split_crc = 1\n";
+        let chip = crate::chip::parse(text).unwrap().remove(0);
+        assert!(chip.mask_rom.pad_split_crc);
+        assert!(crate::chip::parse("name = \"X\"\npid = 1\npad_split_crc = 2\n").is_err());
+    }
+
+    #[test]
+    fn golden_mask_rom_trailers() {
+        let rom = &RK3366;
+        // The published check value of CRC-16/CCITT-FALSE, which the mask
+        // ROM wants big endian after the code
+        assert_eq!(rom.frame(b"123456789")[9..], [0x29, 0xb1]);
+        // A byte short of a chunk: padded with a zero, then the CRC
+        let f = rom.frame(&[0xff; 4095]);
+        assert_eq!(f.len(), 4098);
+        assert_eq!(f[4095..], [0x00, 0x11, 0x11]);
+        assert_eq!(rom.terminator(4096), Some(&[0][..]));
+    }
+}
@@ -0,0 +1,110 @@
+use log::{error, info};
+
+use crate::MEM_ALIGN;
+use crate::metrics;
+use crate::protocol;
+use crate::session::Session;
+
+// Work through big ranges piecewise to keep host memory usage low.
+const BLOCK_SIZE: usize = 1024 * 1024;
+// Do not flood the output when a whole range is bad.
+const MAX_REPORTED: usize = 16;
+
+struct Failure {
+    addr: u32,
+    expected: u32,
+    got: u32,
+}
+
+fn words(data: &[u8]) -> impl Iterator<Item = u32> + '_ {
+    data.chunks_exact(4)
+        .map(|w| u32::from_le_bytes(w.try_into().unwrap()))
+}
+
+/// Fill the range with a pattern derived from each word's address, then
+/// read it all back; with the address itself, this catches address lines
+/// that are stuck or shorted, with a bit moving from word to word, data
+/// lines
+fn address_pattern(
+    s: &Session,
+    base: u32,
+    size: usize,
+    pattern: fn(u32) -> u32,
+) -> Result<Vec<Failure>, protocol::Error> {
+    for o in (0..size).step_by(BLOCK_SIZE) {
+        let a = base + o as u32;
+        let l = BLOCK_SIZE.min(size - o);
+        let block: Vec<u8> = (0..l as u32 / 4)
+            .flat_map(|w| pattern(a + w * 4).to_le_bytes())
+            .collect();
+        protocol::mem_write(s, a, &block)?;
+    }
+    let mut failures = Vec::new();
+    for o in (0..size).step_by(BLOCK_SIZE) {
+        let a = base + o as u32;
+        let l = BLOCK_SIZE.min(size - o);
+        let d = protocol::mem_read(s, a, l)?;
+        for (n, got) in words(&d).enumerate() {
+            let addr = a + n as u32 * 4;
+            let expected = pattern(addr);
+            if got != expected {
+                failures.push(Failure {
+                    addr,
+                    expected,
+                    got,
+                });
+            }
+        }
+    }
+    Ok(failures)
+}
+
+/// Run all patterns over `size` bytes at `base` and report
+pub fn memtest(s: &Session, base: u32, size: usize) -> Result<(), metrics::Failure> {
+    if size == 0 || !(base as usize).is_multiple_of(MEM_ALIGN) || !size.is_multiple_of(MEM_ALIGN) {
+        return Err(metrics::Failure::Config(format!(
+            "Base {base:08x} and size {size} must be {MEM_ALIGN}-byte aligned"
+        )));
+    }
+    if base as u64 + size as u64 > 1 << 32 {
+        return Err(metrics::Failure::Config(format!(
+            "{size} bytes at {base:08x} go past the 32-bit address space"
+        )));
+    }
+    let patterns: [(&str, Vec<Failure>); 4] = [
+        (
+            "walking ones",
+            address_pattern(s, base, size, |a| 1 << (a / 4 % 32))?,
+        ),
+        (
+            "walking zeros",
+            address_pattern(s, base, size, |a| !(1 << (a / 4 % 32)))?,
+        ),
+        ("address in address", address_pattern(s, base, size, |a| a)?),
+        ("inverted address", address_pattern(s, base, size, |a| !a)?),
+    ];
+
+    let mut ok = true;
+    for (name, failures) in patterns {
+        if failures.is_empty() {
+            info!("{name}: passed");
+            continue;
+        }
+        ok = false;
+        error!("{name}: {} failures", failures.len());
+        for f in failures.iter().take(MAX_REPORTED) {
+            let (a, e, g) = (f.addr, f.expected, f.got);
+            error!(
+                "  {a:08x}: expected {e:08x}, got {g:08x} (bits {:08x})",
+                e ^ g
+            );
+        }
+        if failures.len() > MAX_REPORTED {
+            error!("  ...");
+        }
+    }
+    match ok {
+        true => Ok(()),
+        false => Err(metrics::Failure::Verify("DRAM test failed".into())),
+    }
+}
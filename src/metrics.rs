@@ -0,0 +1,243 @@
+//! Counters and histograms in the Prometheus text exposition format
+
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::protocol::{Error, TRANSFERRED, WRITTEN};
+
+/// Upper bounds in seconds; the last bucket is `+Inf`
+const TRANSFER_BUCKETS: [f64; 8] = [0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 5.0];
+const RUN_BUCKETS: [f64; 8] = [1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 900.0];
+
+pub struct Histogram {
+    bounds: &'static [f64; 8],
+    counts: [AtomicU64; 9],
+    sum_us: AtomicU64,
+}
+
+impl Histogram {
+    const fn new(bounds: &'static [f64; 8]) -> Self {
+        Self {
+            bounds,
+            counts: [const { AtomicU64::new(0) }; 9],
+            sum_us: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, d: Duration) {
+        let s = d.as_secs_f64();
+        let b = self.bounds.iter().position(|&b| s <= b).unwrap_or(8);
+        self.counts[b].fetch_add(1, Ordering::Relaxed);
+        self.sum_us
+            .fetch_add(d.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        writeln!(out, "# HELP {name} {help}").unwrap();
+        writeln!(out, "# TYPE {name} histogram").unwrap();
+        let mut total = 0;
+        for (n, c) in self.counts.iter().enumerate() {
+            total += c.load(Ordering::Relaxed);
+            let le = match self.bounds.get(n) {
+                Some(b) => b.to_string(),
+                None => "+Inf".into(),
+            };
+            writeln!(out, "{name}_bucket{{le=\"{le}\"}} {total}").unwrap();
+        }
+        let sum = self.sum_us.load(Ordering::Relaxed) as f64 / 1e6;
+        writeln!(out, "{name}_sum {sum}").unwrap();
+        writeln!(out, "{name}_count {total}").unwrap();
+    }
+}
+
+/// Time spent in single USB transfers
+pub static TRANSFER_SECONDS: Histogram = Histogram::new(&TRANSFER_BUCKETS);
+/// Time from start to end of a daemon job or a board's plan
+static JOB_SECONDS: Histogram = Histogram::new(&RUN_BUCKETS);
+static BOARD_SECONDS: Histogram = Histogram::new(&RUN_BUCKETS);
+
+/// Counters with labels, by metric name and label value
+static COUNTERS: Mutex<Vec<(&str, String, u64)>> = Mutex::new(Vec::new());
+
+fn count(metric: &'static str, label: &str) {
+    let mut c = COUNTERS.lock().unwrap();
+    match c.iter_mut().find(|(m, l, _)| *m == metric && l == label) {
+        Some((_, _, n)) => *n += 1,
+        None => c.push((metric, label.to_string(), 1)),
+    }
+}
+
+/// Why a command failed, in coarse classes, so that retry policies can
+/// tell what is worth another try and dashboards cabling from images
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Failure {
+    /// The device left the bus, or did not come back after a reset
+    Disconnect(String),
+    /// A deadline or wait ran out
+    Timeout(String),
+    /// The device is in a mode the command does not work in
+    WrongMode(String),
+    /// The device answered with a failure status
+    Device(String),
+    /// A USB transfer got lost or garbled
+    Transport(String),
+    /// What was read back is not what was written
+    Verify(String),
+    /// Files, plans or options do not add up
+    Config(String),
+    Other(String),
+}
+
+impl Failure {
+    pub fn message(&self) -> &str {
+        match self {
+            Self::Disconnect(m)
+            | Self::Timeout(m)
+            | Self::WrongMode(m)
+            | Self::Device(m)
+            | Self::Transport(m)
+            | Self::Verify(m)
+            | Self::Config(m)
+            | Self::Other(m) => m,
+        }
+    }
+
+    /// The same failure with its message changed by `f`, e.g. to tell
+    /// where it happened
+    pub fn map(self, f: impl FnOnce(String) -> String) -> Self {
+        match self {
+            Self::Disconnect(m) => Self::Disconnect(f(m)),
+            Self::Timeout(m) => Self::Timeout(f(m)),
+            Self::WrongMode(m) => Self::WrongMode(f(m)),
+            Self::Device(m) => Self::Device(f(m)),
+            Self::Transport(m) => Self::Transport(f(m)),
+            Self::Verify(m) => Self::Verify(f(m)),
+            Self::Config(m) => Self::Config(f(m)),
+            Self::Other(m) => Self::Other(f(m)),
+        }
+    }
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+impl From<String> for Failure {
+    fn from(m: String) -> Self {
+        Self::Other(m)
+    }
+}
+
+impl From<&str> for Failure {
+    fn from(m: &str) -> Self {
+        Self::Other(m.into())
+    }
+}
+
+impl From<Error> for Failure {
+    fn from(e: Error) -> Self {
+        let m = e.to_string();
+        match e {
+            Error::Transport(_) => Self::Transport(m),
+            Error::Device(_) => Self::Device(m),
+            Error::Disconnected => Self::Disconnect(m),
+            Error::Aborted(f) => f,
+        }
+    }
+}
+
+impl From<Failure> for String {
+    fn from(f: Failure) -> Self {
+        f.to_string()
+    }
+}
+
+/// The class of a failure, as retry policies and metrics name it
+pub fn category(f: &Failure) -> &'static str {
+    match f {
+        Failure::Disconnect(_) => "disconnect",
+        Failure::Timeout(_) => "timeout",
+        Failure::WrongMode(_) => "wrong_mode",
+        Failure::Device(_) => "device",
+        Failure::Transport(_) => "transport",
+        Failure::Verify(_) => "verify",
+        Failure::Config(_) => "config",
+        Failure::Other(_) => "other",
+    }
+}
+
+fn finished(kind: &'static str, h: &Histogram, d: Duration, err: Option<&Failure>) {
+    h.observe(d);
+    count(kind, if err.is_none() { "done" } else { "failed" });
+    if let Some(e) = err {
+        count("rk_boot_failures_total", category(e));
+    }
+}
+
+pub fn job_finished(d: Duration, err: Option<&Failure>) {
+    finished("rk_boot_jobs_total", &JOB_SECONDS, d, err);
+}
+
+pub fn board_finished(d: Duration, err: Option<&Failure>) {
+    finished("rk_boot_boards_total", &BOARD_SECONDS, d, err);
+}
+
+pub fn render() -> String {
+    let mut out = String::new();
+    let counters = COUNTERS.lock().unwrap();
+    for (metric, label, help) in [
+        (
+            "rk_boot_jobs_total",
+            "status",
+            "Daemon jobs that have ended",
+        ),
+        (
+            "rk_boot_boards_total",
+            "status",
+            "Boards whose plan has ended",
+        ),
+        ("rk_boot_failures_total", "category", "Failures by category"),
+    ] {
+        writeln!(out, "# HELP {metric} {help}").unwrap();
+        writeln!(out, "# TYPE {metric} counter").unwrap();
+        for (_, l, n) in counters.iter().filter(|(m, _, _)| *m == metric) {
+            writeln!(out, "{metric}{{{label}=\"{l}\"}} {n}").unwrap();
+        }
+    }
+    writeln!(
+        out,
+        "# HELP rk_boot_transferred_bytes_total Bytes moved over USB"
+    )
+    .unwrap();
+    writeln!(out, "# TYPE rk_boot_transferred_bytes_total counter").unwrap();
+    let bytes = TRANSFERRED.load(Ordering::Relaxed);
+    writeln!(out, "rk_boot_transferred_bytes_total {bytes}").unwrap();
+    writeln!(
+        out,
+        "# HELP rk_boot_written_bytes_total Bytes written to the storage of devices"
+    )
+    .unwrap();
+    writeln!(out, "# TYPE rk_boot_written_bytes_total counter").unwrap();
+    let bytes = WRITTEN.load(Ordering::Relaxed);
+    writeln!(out, "rk_boot_written_bytes_total {bytes}").unwrap();
+    TRANSFER_SECONDS.render(
+        &mut out,
+        "rk_boot_transfer_duration_seconds",
+        "Duration of single USB transfers",
+    );
+    JOB_SECONDS.render(
+        &mut out,
+        "rk_boot_job_duration_seconds",
+        "Duration of daemon jobs",
+    );
+    BOARD_SECONDS.render(
+        &mut out,
+        "rk_boot_board_duration_seconds",
+        "Duration of a board's plan",
+    );
+    out
+}
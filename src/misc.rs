@@ -0,0 +1,145 @@
+//! The bootloader control block (BCB) in the misc partition
+//!
+//! Android's bootloader_message tells the bootloader what to start on the
+//! next boot: `boot-recovery` starts recovery with the arguments kept in
+//! the block, `bootonce-bootloader` stops in fastboot once. Recovery clears
+//! the block when it is done. Rockchip's U-Boot and recovery keep it 16 KiB
+//! into misc, AOSP at its start.
+
+use clap::ValueEnum;
+use log::info;
+use zerocopy::{FromBytes, FromZeros, IntoBytes};
+use zerocopy_derive::{FromBytes, Immutable, IntoBytes};
+
+use crate::gpt;
+use crate::metrics::Failure;
+use crate::protocol::SECTOR_SIZE;
+
+/// Where Rockchip's U-Boot looks for the block in misc, in bytes
+pub const ROCKCHIP_OFFSET: u64 = 16 * 1024;
+
+#[derive(Clone, Debug, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C, packed)]
+struct Bcb {
+    command: [u8; 32],
+    status: [u8; 32],
+    /// Lines of arguments, the first one being "recovery"
+    recovery: [u8; 768],
+    stage: [u8; 32],
+    reserved: [u8; 1184],
+}
+
+const SECTORS: u32 = (std::mem::size_of::<Bcb>() / SECTOR_SIZE) as u32;
+
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Target {
+    /// Stop in fastboot once
+    Bootloader,
+    /// Start recovery
+    Recovery,
+}
+
+impl Target {
+    fn command(self) -> &'static str {
+        match self {
+            Target::Bootloader => "bootonce-bootloader",
+            Target::Recovery => "boot-recovery",
+        }
+    }
+}
+
+fn text(b: &[u8]) -> String {
+    let end = b.iter().position(|&c| c == 0).unwrap_or(b.len());
+    String::from_utf8_lossy(&b[..end]).to_string()
+}
+
+fn put(field: &mut [u8], s: &str) -> Result<(), String> {
+    // Keep a terminating zero.
+    if s.len() >= field.len() {
+        return Err(format!("{s:?} is longer than {} bytes", field.len() - 1));
+    }
+    field.fill(0);
+    field[..s.len()].copy_from_slice(s.as_bytes());
+    Ok(())
+}
+
+/// The first sector of the block
+fn locate(
+    read: &mut dyn FnMut(u64, u32) -> Result<Vec<u8>, Failure>,
+    offset: u64,
+) -> Result<u64, Failure> {
+    if !offset.is_multiple_of(SECTOR_SIZE as u64) {
+        return Err(format!("Offset {offset} is not a whole number of sectors").into());
+    }
+    let g = gpt::read(read)??;
+    let p = g.find("misc").ok_or("No misc partition")?;
+    let at = offset / SECTOR_SIZE as u64;
+    if at + SECTORS as u64 > p.sectors() {
+        return Err(format!(
+            "misc has {} sectors, too few for the block at {offset}",
+            p.sectors()
+        )
+        .into());
+    }
+    Ok(p.first_lba + at)
+}
+
+fn load(
+    read: &mut dyn FnMut(u64, u32) -> Result<Vec<u8>, Failure>,
+    lba: u64,
+) -> Result<Bcb, Failure> {
+    Ok(Bcb::read_from_bytes(&read(lba, SECTORS)?).unwrap())
+}
+
+/// Print what the block says
+pub fn print(
+    read: &mut dyn FnMut(u64, u32) -> Result<Vec<u8>, Failure>,
+    offset: u64,
+) -> Result<(), Failure> {
+    let lba = locate(read, offset)?;
+    let b = load(read, lba)?;
+    info!("Bootloader control block at sector {lba:#x}");
+    let show = |v: String| match v.is_empty() {
+        true => "(none)".to_string(),
+        false => v,
+    };
+    println!("Command:  {}", show(text(&b.command)));
+    println!("Status:   {}", show(text(&b.status)));
+    println!("Stage:    {}", show(text(&b.stage)));
+    let recovery = text(&b.recovery);
+    for (n, l) in recovery.lines().enumerate() {
+        println!("{:9} {l}", if n == 0 { "Recovery:" } else { "" });
+    }
+    Ok(())
+}
+
+/// Make the next boot start `target`, with arguments for recovery
+pub fn write_command(
+    read: &mut dyn FnMut(u64, u32) -> Result<Vec<u8>, Failure>,
+    write: &mut gpt::Write,
+    offset: u64,
+    target: Target,
+    args: &[String],
+) -> Result<(), Failure> {
+    if target != Target::Recovery && !args.is_empty() {
+        return Err("Only recovery takes arguments".into());
+    }
+    let lba = locate(read, offset)?;
+    let mut b = Bcb::new_zeroed();
+    put(&mut b.command, target.command())?;
+    if target == Target::Recovery {
+        let lines: Vec<&str> = std::iter::once("recovery")
+            .chain(args.iter().map(String::as_str))
+            .collect();
+        put(&mut b.recovery, &format!("{}\n", lines.join("\n")))?;
+    }
+    write(lba, b.as_bytes())?;
+    if load(read, lba)?.as_bytes() != b.as_bytes() {
+        return Err("Bootloader control block does not read back as written".into());
+    }
+    info!(
+        "Next boot starts {}",
+        target.to_possible_value().unwrap().get_name()
+    );
+    Ok(())
+}
@@ -0,0 +1,196 @@
+//! Rockchip's legacy `parameter.txt` partition layouts and GPT
+//!
+//! Partitions are in the `CMDLINE` line as `mtdparts=<id>:<parts>` with
+//! each part `SIZE@OFFSET(NAME)` in sectors; a size of `-` and the `:grow`
+//! flag mean the rest of the disk. `uuid:NAME=GUID` lines fix a partition's
+//! unique GUID.
+
+use std::path::Path;
+
+use crate::gpt::{self, Gpt};
+use crate::protocol::SECTOR_SIZE;
+
+const HEADER: &str = "FIRMWARE_VER: 1.0
+MAGIC: 0x5041524B
+ATAG: 0x00200800
+MACHINE: 0xffffffff
+CHECK_MASK: 0x80
+TYPE: GPT
+";
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Part {
+    pub name: String,
+    pub first: u64,
+    /// None means up to the end of the disk
+    pub sectors: Option<u64>,
+    pub uuid: Option<String>,
+}
+
+fn hex(s: &str) -> Result<u64, String> {
+    let h = s.trim().trim_start_matches("0x").trim_start_matches("0X");
+    u64::from_str_radix(h, 16).map_err(|_| format!("bad number {s:?}"))
+}
+
+pub fn parse(text: &str) -> Result<Vec<Part>, String> {
+    let cmdline = text
+        .lines()
+        .find_map(|l| l.trim().strip_prefix("CMDLINE:"))
+        .ok_or("no CMDLINE line")?;
+    let mtdparts = cmdline
+        .split_whitespace()
+        .find_map(|a| a.strip_prefix("mtdparts="))
+        .ok_or("no mtdparts in CMDLINE")?;
+    let (_, list) = mtdparts.split_once(':').ok_or("mtdparts without an ID")?;
+
+    let mut parts = Vec::new();
+    for p in list.split(',') {
+        let err = || format!("bad partition {p:?}");
+        let (size, rest) = p.split_once('@').ok_or_else(err)?;
+        let (offset, name) = rest.split_once('(').ok_or_else(err)?;
+        let name = name.strip_suffix(')').ok_or_else(err)?;
+        let (name, flags) = name.split_once(':').unwrap_or((name, ""));
+        let sectors = match size.trim() {
+            "-" => None,
+            _ if flags.split(':').any(|f| f == "grow") => None,
+            s => Some(hex(s)?),
+        };
+        parts.push(Part {
+            name: name.to_string(),
+            first: hex(offset)?,
+            sectors,
+            uuid: None,
+        });
+    }
+    for l in text.lines() {
+        if let Some((name, uuid)) = l
+            .trim()
+            .strip_prefix("uuid:")
+            .and_then(|u| u.split_once('='))
+        {
+            let p = parts
+                .iter_mut()
+                .find(|p| p.name == name.trim())
+                .ok_or(format!("uuid for unknown partition {name}"))?;
+            p.uuid = Some(uuid.trim().to_string());
+        }
+    }
+    Ok(parts)
+}
+
+pub fn to_gpt(parts: &[Part], total: u64, type_guid: [u8; 16]) -> Result<Gpt, String> {
+    let mut g = Gpt::empty(total)?;
+    for p in parts {
+        g.add(&p.name, type_guid, Some(p.first), p.sectors, 1)?;
+        if let Some(u) = &p.uuid {
+            let e = g
+                .entries
+                .iter_mut()
+                .find(|e| e.is_used() && e.name() == p.name);
+            e.unwrap().unique_guid = gpt::parse_guid(u)?;
+        }
+    }
+    Ok(g)
+}
+
+pub fn from_gpt(g: &Gpt) -> String {
+    let mut parts: Vec<&gpt::Entry> = g.partitions().collect();
+    parts.sort_by_key(|p| p.first_lba);
+    let last_usable = g.header.last_usable_lba;
+    let list: Vec<String> = parts
+        .iter()
+        .map(|p| {
+            let first = p.first_lba;
+            match p.last_lba == last_usable {
+                true => format!("-@{first:#010x}({}:grow)", p.name()),
+                false => format!("{:#010x}@{first:#010x}({})", p.sectors(), p.name()),
+            }
+        })
+        .collect();
+    let mut s = format!("{HEADER}CMDLINE: mtdparts=rk29xxnand:{}\n", list.join(","));
+    for p in parts {
+        s += &format!(
+            "uuid:{}={}\n",
+            p.name(),
+            gpt::guid_to_string(&p.unique_guid)
+        );
+    }
+    s
+}
+
+/// Write the start of a disk image, protective MBR and primary GPT, for a
+/// disk of `disk_size` bytes; `gpt repair` adds the backup on the device.
+pub fn to_gpt_file(
+    param: &Path,
+    out: &Path,
+    disk_size: u64,
+    type_guid: &str,
+) -> Result<(), String> {
+    let text = std::fs::read_to_string(param).map_err(|e| format!("{}: {e}", param.display()))?;
+    let total = disk_size / SECTOR_SIZE as u64;
+    let g = to_gpt(&parse(&text)?, total, gpt::parse_guid(type_guid)?)?;
+    let (h, e) = g.to_bytes();
+    let mut img = gpt::protective_mbr(total);
+    img.extend(h);
+    img.extend(e);
+    std::fs::write(out, img).map_err(|e| format!("{}: {e}", out.display()))
+}
+
+/// Turn the GPT at the start of a disk image into `parameter.txt` form
+pub fn from_gpt_file(img: &Path) -> Result<String, String> {
+    let data = std::fs::read(img).map_err(|e| format!("{}: {e}", img.display()))?;
+    let g = gpt::read(&mut |lba, n| {
+        let (from, len) = (lba as usize * SECTOR_SIZE, n as usize * SECTOR_SIZE);
+        let mut d = data.get(from..).unwrap_or_default().to_vec();
+        d.resize(len, 0);
+        Ok(d)
+    })??;
+    Ok(from_gpt(&g))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parts_with_uuids(g: &Gpt, mut parts: Vec<Part>) -> Vec<Part> {
+        for p in &mut parts {
+            p.uuid = Some(gpt::guid_to_string(&g.find(&p.name).unwrap().unique_guid));
+        }
+        parts
+    }
+
+    #[test]
+    fn parameter_round_trip() {
+        let text = "FIRMWARE_VER: 1.0\n\
+            CMDLINE: console=ttyFIQ0 mtdparts=rk29xxnand:0x00002000@0x00004000(uboot),\
+            0x00010000@0x00006000(boot),-@0x00016000(rootfs:grow)\n\
+            uuid:rootfs=614e0000-0000-4b53-8000-1d28000054a9\n";
+        let parts = parse(text).unwrap();
+        assert_eq!(parts.len(), 3);
+        assert_eq!((parts[1].first, parts[1].sectors), (0x6000, Some(0x10000)));
+        assert_eq!(parts[2].sectors, None);
+
+        let linux = gpt::parse_guid("linux").unwrap();
+        let g = to_gpt(&parts, 0x100000, linux).unwrap();
+        let r = g.find("rootfs").unwrap();
+        assert_eq!({ r.last_lba }, 0x100000 - 34);
+        assert_eq!(
+            gpt::guid_to_string(&r.unique_guid),
+            "614e0000-0000-4b53-8000-1d28000054a9"
+        );
+        let back = from_gpt(&g);
+        assert!(back.contains(
+            "mtdparts=rk29xxnand:0x00002000@0x00004000(uboot),\
+             0x00010000@0x00006000(boot),-@0x00016000(rootfs:grow)\n"
+        ));
+        assert_eq!(parse(&back).unwrap(), parts_with_uuids(&g, parts));
+        assert!(
+            to_gpt(
+                &parse("CMDLINE: mtdparts=x:0x10@0x0(a)").unwrap(),
+                4096,
+                linux
+            )
+            .is_err()
+        );
+    }
+}
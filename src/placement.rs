@@ -0,0 +1,230 @@
+//! Where well-known images belong on the storage, told by their first bytes
+//!
+//! An idbloader, or U-Boot's u-boot-rockchip.bin that starts with one, goes
+//! to the boot area at sector 64, U-Boot's FIT to sector 0x4000, and a disk
+//! image with a GPT to sector 0. Written elsewhere, the board does not
+//! boot. Loaders and update images are no storage images at all.
+//!
+//! Written at a sector rather than to a partition by name, an image is
+//! taken to be for the partition its file is named after, `boot.img` for
+//! `boot` or `boot_a`. Before it overwrites any other partition, what it
+//! would overwrite is shown, and the write has to be confirmed.
+
+use std::fs::File;
+use std::io::{BufRead, IsTerminal, Read, Write};
+use std::path::Path;
+
+use log::{info, warn};
+
+use crate::erase::BOOT_AREA;
+use crate::gpt::Gpt;
+use crate::protocol::SECTOR_SIZE;
+use crate::{idb, size};
+
+/// Enough to see a GPT header and a FIT's description
+const HEAD: usize = 4096;
+/// Sector of U-Boot's FIT in Rockchip's layout
+pub const UBOOT_LBA: u32 = 0x4000;
+const FIT_MAGIC: [u8; 4] = [0xd0, 0x0d, 0xfe, 0xed];
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Known {
+    pub what: &'static str,
+    /// Sector the image belongs at, or none if it is not for the storage
+    pub lba: Option<u32>,
+}
+
+fn known(what: &'static str, lba: Option<u32>) -> Option<Known> {
+    Some(Known { what, lba })
+}
+
+/// What an image starting with `head` is, if it is a well-known one
+pub fn identify(head: &[u8]) -> Option<Known> {
+    let magic = head.get(..4)?;
+    let mut sec0 = [0; SECTOR_SIZE];
+    let n = head.len().min(SECTOR_SIZE);
+    sec0[..n].copy_from_slice(&head[..n]);
+    idb::rc4(&mut sec0);
+    let contains = |s: &[u8]| head.windows(s.len()).any(|w| w == s);
+    match magic {
+        b"RKNS" => known("an idbloader", Some(BOOT_AREA.start)),
+        _ if sec0[..4] == idb::TAG.to_le_bytes() => known("an idbloader", Some(BOOT_AREA.start)),
+        b"BOOT" | b"LDR " => known("a boot_merger loader, use upgrade-loader", None),
+        b"RKFW" | b"RKAF" => known("a Rockchip update image, unpack it first", None),
+        _ if magic == FIT_MAGIC && contains(b"U-Boot") => known("U-Boot's FIT", Some(UBOOT_LBA)),
+        _ if head.get(SECTOR_SIZE..SECTOR_SIZE + 8) == Some(b"EFI PART") => {
+            known("a disk image with a GPT", Some(0))
+        }
+        _ => None,
+    }
+}
+
+fn head(file: &Path) -> Result<Vec<u8>, String> {
+    let mut head = Vec::with_capacity(HEAD);
+    File::open(file)
+        .and_then(|f| f.take(HEAD as u64).read_to_end(&mut head))
+        .map_err(|e| format!("{}: {e}", file.display()))?;
+    Ok(head)
+}
+
+/// Check an image against where it is to be written. Returns the sector to
+/// write at, which is the image's own if `adjust` is set.
+pub fn check(file: &Path, lba: u32, adjust: bool) -> Result<u32, String> {
+    let Some(k) = identify(&head(file)?) else {
+        return Ok(lba);
+    };
+    let name = file.display();
+    match k.lba {
+        None => warn!("{name} is {}", k.what),
+        Some(at) if at == lba => {}
+        Some(at) if adjust => {
+            info!("{name} is {}, writing it at sector {at:#x}", k.what);
+            return Ok(at);
+        }
+        Some(at) => warn!(
+            "{name} is {}, which belongs at sector {at:#x}, not {lba:#x}; \
+             the board will not boot from it, --adjust-offset writes it there",
+            k.what
+        ),
+    }
+    Ok(lba)
+}
+
+/// The partition an image is for, going by its file name
+pub fn target(file: &Path) -> String {
+    let name = file.file_name().unwrap_or_default().to_string_lossy();
+    name.split('.').next().unwrap_or_default().to_string()
+}
+
+/// Whether partition `name` is `target` or one of its A/B slots
+fn is_target(name: &str, target: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    let target = target.to_ascii_lowercase();
+    let slot = |s| name.strip_suffix(s) == Some(target.as_str());
+    name == target || slot("_a") || slot("_b")
+}
+
+/// The partitions other than `target` that `sectors` sectors from `lba`
+/// overlap, with the sectors in each
+pub fn overlaps(g: &Gpt, lba: u64, sectors: u64, target: &str) -> Vec<(String, u64, u64)> {
+    let end = lba + sectors;
+    g.partitions()
+        .filter(|p| !is_target(&p.name(), target))
+        .filter_map(|p| {
+            let first = p.first_lba.max(lba);
+            let last = p.last_lba.min(end.checked_sub(1)?);
+            (first <= last).then(|| (p.name(), first, last))
+        })
+        .collect()
+}
+
+/// Check that an image written at `lba` stays within the partition it is
+/// for, asking whether to go on if not, unless `across` is set
+pub fn check_partitions(
+    g: &Gpt,
+    file: &Path,
+    lba: u64,
+    target: &str,
+    across: bool,
+) -> Result<(), String> {
+    let head = head(file)?;
+    // Disk images bring their own partitions.
+    if identify(&head).is_some_and(|k| k.lba == Some(0)) {
+        return Ok(());
+    }
+    let len = std::fs::metadata(file)
+        .map_err(|e| format!("{}: {e}", file.display()))?
+        .len();
+    let sectors = len.div_ceil(SECTOR_SIZE as u64);
+    let over = overlaps(g, lba, sectors, target);
+    if over.is_empty() {
+        return Ok(());
+    }
+    warn!(
+        "{} is for {target}; sectors {lba:#x}-{:#x} also overlap:",
+        file.display(),
+        lba + sectors.max(1) - 1
+    );
+    for (name, first, last) in &over {
+        let p = g.find(name).unwrap();
+        warn!(
+            "  {name}, sectors {first:#x}-{last:#x}: {} of its {}",
+            size::human((last - first + 1) * SECTOR_SIZE as u64),
+            size::human(p.sectors() * SECTOR_SIZE as u64)
+        );
+    }
+    if across {
+        return Ok(());
+    }
+    if !std::io::stdin().is_terminal() {
+        return Err("Not a terminal; pass --across-partitions to write anyway".into());
+    }
+    eprint!("Write anyway [y/N]? ");
+    std::io::stderr().flush().unwrap();
+    let mut l = String::new();
+    std::io::stdin()
+        .lock()
+        .read_line(&mut l)
+        .map_err(|e| e.to_string())?;
+    match l.trim().to_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => Err("Nothing written".into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn images_know_their_place() {
+        let lba_of = |head: &[u8]| identify(head).map(|k| k.lba);
+        let idb = idb::make(&[1; 1000], &[2; 3000], false);
+        assert_eq!(lba_of(&idb), Some(Some(64)));
+        assert_eq!(lba_of(b"RKNS\0\0\0\0"), Some(Some(64)));
+        let mut fit = vec![0xd0, 0x0d, 0xfe, 0xed];
+        fit.extend_from_slice(b"\0\0FIT image for U-Boot with bl31");
+        assert_eq!(lba_of(&fit), Some(Some(0x4000)));
+        assert_eq!(lba_of(&[0xd0, 0x0d, 0xfe, 0xed, 0, 0]), None);
+        let mut disk = vec![0; 1024];
+        disk[512..520].copy_from_slice(b"EFI PART");
+        assert_eq!(lba_of(&disk), Some(Some(0)));
+        assert_eq!(lba_of(b"LDR \0\0"), Some(None));
+        assert_eq!(lba_of(&[0x55; 2048]), None);
+
+        let file = std::env::temp_dir().join(format!("rk_place_{}", std::process::id()));
+        std::fs::write(&file, &idb).unwrap();
+        assert_eq!(check(&file, 0, false), Ok(0));
+        assert_eq!(check(&file, 0, true), Ok(64));
+        std::fs::write(&file, [0x55; 100]).unwrap();
+        assert_eq!(check(&file, 0, true), Ok(0));
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn raw_writes_over_other_partitions_need_confirming() {
+        let mut g = Gpt::empty(40000).unwrap();
+        let t = crate::gpt::parse_guid("linux").unwrap();
+        g.add("boot_a", t, Some(16384), Some(4096), 1).unwrap();
+        g.add("rootfs", t, Some(20480), Some(8192), 1).unwrap();
+        assert_eq!(target(Path::new("out/boot.img")), "boot");
+        assert!(overlaps(&g, 16384, 4096, "boot").is_empty());
+        assert_eq!(
+            overlaps(&g, 16384, 5000, "boot"),
+            vec![("rootfs".to_string(), 20480, 21383)]
+        );
+        assert_eq!(overlaps(&g, 20000, 100, "rootfs").len(), 1);
+
+        let dir = std::env::temp_dir().join(format!("rk_boot-overlap-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let img = dir.join("boot.img");
+        std::fs::write(&img, vec![0; 5000 * 512]).unwrap();
+        if !std::io::stdin().is_terminal() {
+            let err = check_partitions(&g, &img, 16384, "boot", false).unwrap_err();
+            assert!(err.contains("--across-partitions"), "{err}");
+        }
+        check_partitions(&g, &img, 16384, "boot", true).unwrap();
+        check_partitions(&g, &img, 30000, "boot", false).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
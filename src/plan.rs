@@ -0,0 +1,265 @@
+//! Plans are text files with one command line per line, e.g.
+//!
+//! ```text
+//! # bring up and check memory
+//! boot --auto
+//! memtest --base 0x200000 --size 0x100000
+//! # U-Boot on SPI NOR, the OS on eMMC
+//! @spinor write 0 u-boot.img
+//! @emmc write 0 rootfs.img
+//! ```
+//!
+//! A leading `@STORAGE` declares the medium a step is for; the loader is
+//! switched to it before the step unless it is known to be there already.
+//! Images in the cache can be given by digest, as `sha256:<hex>`.
+//!
+//! Lines starting with `!` are hooks, shell commands run on the host before
+//! the step that follows, e.g. to make an image for the board at hand:
+//!
+//! ```text
+//! ! mkoverlay --serial "$RK_BOOT_SN" -o overlay.dtbo
+//! @emmc write dtbo overlay.dtbo
+//! ```
+//!
+//! Hooks get what is known about the device in `RK_BOOT_*` environment
+//! variables: `RK_BOOT_DEVICE` (bus-address), `RK_BOOT_PORT`, `RK_BOOT_STEP`
+//! and, when provisioning, `RK_BOOT_ID`, `RK_BOOT_SERIAL`, `RK_BOOT_SN` and
+//! `RK_BOOT_MAC0`, ... A hook that fails fails its step. Jobs of `serve`
+//! run no hooks, as their plans come from remote clients.
+//!
+//! With `--verify`, writes of a plan are read back as if each step had
+//! asked for it, unless a step gives `--verify` itself.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::Command;
+use std::sync::OnceLock;
+
+use clap::ValueEnum;
+use log::info;
+
+use crate::cache;
+use crate::flash::Verify;
+use crate::protocol::Storage;
+
+/// How writes of plans are verified, unless a step says otherwise
+static VERIFY: OnceLock<Verify> = OnceLock::new();
+
+/// Verify the writes of plans run by this process
+pub fn set_verify(kind: Verify) {
+    let _ = VERIFY.set(kind);
+}
+
+pub fn verify() -> Option<Verify> {
+    VERIFY.get().copied()
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Step {
+    pub line: usize,
+    pub storage: Option<Storage>,
+    pub args: Vec<String>,
+    /// Commands to run before the step
+    pub hooks: Vec<String>,
+}
+
+/// Split a line into arguments like a shell would, minus expansions
+pub fn split_args(line: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut cur: Option<String> = None;
+    let mut quote = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('"'), '\\') => {
+                let e = chars.next().ok_or("trailing backslash")?;
+                cur.get_or_insert_default().push(e);
+            }
+            (Some(_), c) => cur.get_or_insert_default().push(c),
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                cur.get_or_insert_default();
+            }
+            (None, '\\') => {
+                let e = chars.next().ok_or("trailing backslash")?;
+                cur.get_or_insert_default().push(e);
+            }
+            (None, c) if c.is_whitespace() => args.extend(cur.take()),
+            (None, c) => cur.get_or_insert_default().push(c),
+        }
+    }
+    if quote.is_some() {
+        return Err(format!("unterminated quote in {line:?}"));
+    }
+    args.extend(cur);
+    Ok(args)
+}
+
+/// Replace `{name}` placeholders in arguments
+pub fn substitute(args: &[String], vars: &HashMap<String, String>) -> Result<Vec<String>, String> {
+    args.iter()
+        .map(|a| {
+            let mut out = String::new();
+            let mut rest = a.as_str();
+            while let Some(start) = rest.find('{') {
+                let Some(len) = rest[start..].find('}') else {
+                    break;
+                };
+                let name = &rest[start + 1..start + len];
+                let v = vars
+                    .get(name)
+                    .ok_or(format!("unknown placeholder {{{name}}}"))?;
+                out.push_str(&rest[..start]);
+                out.push_str(v);
+                rest = &rest[start + len + 1..];
+            }
+            out.push_str(rest);
+            Ok(out)
+        })
+        .collect()
+}
+
+fn step(line: usize, text: &str, hooks: Vec<String>) -> Result<Step, String> {
+    let mut args = split_args(text)?
+        .iter()
+        .map(|a| cache::resolve(a))
+        .collect::<Result<Vec<_>, _>>()?;
+    let storage = match args.first().and_then(|a| a.strip_prefix('@')) {
+        Some(s) => {
+            let s = Storage::from_str(s, true).map_err(|_| format!("unknown storage @{s}"))?;
+            args.remove(0);
+            Some(s)
+        }
+        None => None,
+    };
+    Ok(Step {
+        line,
+        storage,
+        args,
+        hooks,
+    })
+}
+
+/// Read a plan; each step comes with its line number for messages.
+pub fn load(file: &Path) -> Result<Vec<Step>, String> {
+    let text = std::fs::read_to_string(file).map_err(|e| format!("{}: {e}", file.display()))?;
+    let mut steps = Vec::new();
+    let mut hooks = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(hook) = line.strip_prefix('!') {
+            hooks.push(hook.trim().to_string());
+            continue;
+        }
+        let hooks = std::mem::take(&mut hooks);
+        steps.push(
+            step(n + 1, line, hooks).map_err(|e| format!("{}:{}: {e}", file.display(), n + 1))?,
+        );
+    }
+    if !hooks.is_empty() {
+        return Err(format!(
+            "{}: hooks without a step after them",
+            file.display()
+        ));
+    }
+    Ok(steps)
+}
+
+/// The environment of hooks: `vars` as `RK_BOOT_NAME`
+pub fn hook_env(vars: &HashMap<String, String>) -> Vec<(String, String)> {
+    let mut env: Vec<_> = vars
+        .iter()
+        .map(|(k, v)| (format!("RK_BOOT_{}", k.to_uppercase()), v.clone()))
+        .collect();
+    env.sort();
+    env
+}
+
+/// Run the hooks of a step, stopping at the first that fails
+pub fn run_hooks(step: &Step, env: &[(String, String)]) -> Result<(), String> {
+    if !step.hooks.is_empty() && crate::jobs::confined() {
+        return Err(format!("line {}: hooks are not run for remote jobs", step.line));
+    }
+    for hook in &step.hooks {
+        info!("Hook: {hook}");
+        let (shell, flag) = match cfg!(windows) {
+            true => ("cmd", "/C"),
+            false => ("sh", "-c"),
+        };
+        let status = Command::new(shell)
+            .args([flag, hook])
+            .envs(env.iter().map(|(k, v)| (k, v)))
+            .status()
+            .map_err(|e| format!("hook {hook:?}: {e}"))?;
+        if !status.success() {
+            return Err(format!("hook {hook:?} failed, {status}"));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plan_steps_declare_storage() {
+        let file = std::env::temp_dir().join(format!("rk_plan_{}", std::process::id()));
+        std::fs::write(
+            &file,
+            "boot --auto\n@SPINOR write 0 u-boot.img\n\n@emmc write 0 os.img\n",
+        )
+        .unwrap();
+        let steps = load(&file).unwrap();
+        let storages: Vec<_> = steps.iter().map(|s| (s.line, s.storage)).collect();
+        assert_eq!(
+            storages,
+            [
+                (1, None),
+                (2, Some(Storage::Spinor)),
+                (4, Some(Storage::Emmc))
+            ]
+        );
+        assert_eq!(steps[2].args, ["write", "0", "os.img"]);
+        std::fs::write(&file, "@usb write 0 x\n").unwrap();
+        let e = load(&file).unwrap_err();
+        assert!(e.ends_with(":1: unknown storage @usb"), "{e}");
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn plan_hooks_run_with_device_context() {
+        let dir = std::env::temp_dir().join(format!("rk_boot-hooks-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (file, out) = (dir.join("plan"), dir.join("out"));
+        let text = format!(
+            "! echo \"$RK_BOOT_SN $RK_BOOT_STEP\" > {0}\n! echo more >> {0}\nwrite boot x\n",
+            out.display()
+        );
+        std::fs::write(&file, text).unwrap();
+        let steps = load(&file).unwrap();
+        assert_eq!(steps.len(), 1);
+        assert_eq!(steps[0].hooks.len(), 2);
+        let vars = HashMap::from([
+            ("sn".to_string(), "SN42".to_string()),
+            ("step".to_string(), "1".to_string()),
+        ]);
+        let env = hook_env(&vars);
+        assert_eq!(env[0], ("RK_BOOT_SN".to_string(), "SN42".to_string()));
+        run_hooks(&steps[0], &env).unwrap();
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "SN42 1\nmore\n");
+
+        std::fs::write(&file, "! false\nwrite boot x\n! true\n").unwrap();
+        let e = load(&file).unwrap_err();
+        assert!(e.ends_with("hooks without a step after them"), "{e}");
+        std::fs::write(&file, "! exit 3\nwrite boot x\n").unwrap();
+        let steps = load(&file).unwrap();
+        assert!(run_hooks(&steps[0], &env).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,216 @@
+//! Named board profiles from the config file
+//!
+//! `~/.config/rk_boot/config.toml` bundles what a board type needs, so that
+//! `--profile rock5b` stands in for the long command lines of each product:
+//!
+//! ```toml
+//! [profile.rock5b]
+//! chip = "RK3588"
+//! rkbin = "/srv/rkbin"
+//! loader = "rock5b/rk3588_spl_loader_v1.16.113.bin"
+//! template = "rockchip-uboot"
+//! image.uboot = "rock5b/uboot.img"
+//! image.rootfs = "rock5b/rootfs.img"
+//! ```
+//!
+//! `ddr` and `usbplug` name the binaries to boot instead of picking them
+//! from rkbin. Relative paths are relative to the config file. What the
+//! command line gives wins over the profile; a profile with a chip refuses
+//! devices with another.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::chip::{self, Chip};
+use crate::template::Template;
+
+#[derive(Debug, Default)]
+pub struct Profile {
+    pub name: String,
+    pub chip: Option<String>,
+    pub rkbin: Option<PathBuf>,
+    pub ddr: Option<PathBuf>,
+    pub usbplug: Option<PathBuf>,
+    /// boot_merger loader for upgrade-loader
+    pub loader: Option<PathBuf>,
+    /// GPT layout for `gpt write`
+    pub template: Option<Template>,
+    /// Images to write, by partition name
+    pub images: Vec<(String, PathBuf)>,
+}
+
+impl Profile {
+    pub fn image(&self, partition: &str) -> Option<&Path> {
+        self.images
+            .iter()
+            .find(|(p, _)| p == partition)
+            .map(|(_, f)| f.as_path())
+    }
+}
+
+thread_local! {
+    /// Profile of the command on this thread
+    static ACTIVE: RefCell<Option<Rc<Profile>>> = const { RefCell::new(None) };
+}
+
+/// Where profiles are read from unless `--config` says otherwise
+pub fn config_file() -> Option<PathBuf> {
+    Some(chip::config_dir()?.parent()?.join("config.toml"))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    #[serde(default)]
+    profile: toml::Table,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Fields {
+    chip: Option<String>,
+    rkbin: Option<PathBuf>,
+    ddr: Option<PathBuf>,
+    usbplug: Option<PathBuf>,
+    loader: Option<PathBuf>,
+    template: Option<String>,
+    #[serde(default)]
+    image: BTreeMap<String, PathBuf>,
+}
+
+/// Profiles in a config file, in the order they are written there; `dir`
+/// is where relative paths start
+pub fn parse(text: &str, dir: &Path) -> Result<Vec<Profile>, String> {
+    let config: Config = toml::from_str(text).map_err(|e| e.to_string())?;
+    let mut profiles = Vec::new();
+    for (name, v) in config.profile {
+        let err = |e: String| format!("profile {name}: {e}");
+        let f: Fields = v.try_into().map_err(|e: toml::de::Error| err(e.to_string()))?;
+        if f.ddr.is_some() != f.usbplug.is_some() {
+            return Err(err("give both ddr and usbplug".into()));
+        }
+        let template = f
+            .template
+            .map(|t| Template::from_str(&t, true))
+            .transpose()
+            .map_err(err)?;
+        let path = |p: Option<PathBuf>| p.map(|p| dir.join(p));
+        profiles.push(Profile {
+            chip: f.chip,
+            rkbin: path(f.rkbin),
+            ddr: path(f.ddr),
+            usbplug: path(f.usbplug),
+            loader: path(f.loader),
+            template,
+            images: f.image.into_iter().map(|(k, v)| (k, dir.join(v))).collect(),
+            name,
+        });
+    }
+    Ok(profiles)
+}
+
+/// The profile of the given name from a config file
+pub fn load(file: &Path, name: &str) -> Result<Profile, String> {
+    let text = std::fs::read_to_string(file).map_err(|e| format!("{}: {e}", file.display()))?;
+    let dir = file.parent().unwrap_or(Path::new("."));
+    let err = |e| format!("{}: {e}", file.display());
+    parse(&text, dir)
+        .map_err(err)?
+        .into_iter()
+        .find(|p| p.name == name)
+        .ok_or_else(|| err(format!("no profile {name}")))
+}
+
+/// Use a profile for the commands on this thread, or none
+pub fn select(p: Option<Profile>) {
+    ACTIVE.with(|a| *a.borrow_mut() = p.map(Rc::new));
+}
+
+pub fn active() -> Option<Rc<Profile>> {
+    ACTIVE.with(|a| a.borrow().clone())
+}
+
+/// Refuse devices of other chips than the profile's
+pub fn check_chip(chip: &Chip) -> Result<(), String> {
+    match active().and_then(|p| p.chip.clone()) {
+        Some(want) if !want.eq_ignore_ascii_case(chip.name) => Err(format!(
+            "Device is an {}, but the profile is for an {want}",
+            chip.name
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profiles_fill_in_command_lines() {
+        let text = "# boards in the lab\n\
+            [profile.rock5b]\n\
+            chip = \"RK3588\"\n\
+            loader = \"rock5b/loader.bin\"\n\
+            template = \"debian-simple\"\n\
+            image.rootfs = \"rock5b/rootfs.img\" # nightly\n\
+            [profile.evb]\n\
+            ddr = \"/srv/ddr.bin\"\n\
+            usbplug = \"/srv/usbplug.bin\"\n";
+        let dir = std::path::Path::new("/lab");
+        let mut profiles = parse(text, dir).unwrap();
+        assert_eq!(profiles.len(), 2);
+        let p = profiles.remove(0);
+        assert_eq!(p.chip.as_deref(), Some("RK3588"));
+        assert_eq!(p.loader, Some(dir.join("rock5b/loader.bin")));
+        assert_eq!(p.template, Some(Template::DebianSimple));
+        assert_eq!(
+            p.image("rootfs"),
+            Some(dir.join("rock5b/rootfs.img").as_path())
+        );
+        assert_eq!(profiles[0].ddr, Some("/srv/ddr.bin".into()));
+
+        assert!(parse("chip = \"RK3588\"\n", dir).is_err());
+        assert!(parse("[board.x]\n", dir).is_err());
+        assert!(parse("[profile.x]\ntemplate = \"dos\"\n", dir).is_err());
+        assert!(parse("[profile.x]\nddr = \"d.bin\"\n", dir).is_err());
+
+        let parse = |args: &[&str]| {
+            let argv = std::iter::once("rk_boot").chain(args.iter().copied());
+            crate::with_profile(
+                <crate::Cli as clap::Parser>::try_parse_from(argv)
+                    .unwrap()
+                    .cmd,
+                &p,
+            )
+        };
+        match parse(&["write", "rootfs"]) {
+            crate::Command::Write { file, .. } => {
+                assert_eq!(file, Some(dir.join("rock5b/rootfs.img")))
+            }
+            c => panic!("{c:?}"),
+        }
+        match parse(&["write", "rootfs", "other.img"]) {
+            crate::Command::Write { file, .. } => assert_eq!(file, Some("other.img".into())),
+            c => panic!("{c:?}"),
+        }
+        match parse(&["upgrade-loader"]) {
+            crate::Command::UpgradeLoader { file, .. } => assert_eq!(file, p.loader),
+            c => panic!("{c:?}"),
+        }
+        match parse(&["boot"]) {
+            crate::Command::Boot { auto, ddr, .. } => assert!(auto && ddr.is_none()),
+            c => panic!("{c:?}"),
+        }
+
+        let chip = &chip::CHIPS[0];
+        select(Some(p));
+        assert!(check_chip(chip).unwrap_err().contains("RK3588"));
+        select(None);
+        assert!(check_chip(chip).is_ok());
+    }
+}
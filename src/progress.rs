@@ -0,0 +1,204 @@
+//! One progress model for a command and the steps it is made of
+//!
+//! Compound commands, such as booting in stages or running a plan, declare
+//! their steps; steps may have steps of their own, e.g. a plan step that
+//! boots. The long-running parts, downloads, writes and clones, tell how
+//! far they got. Together these make an overall percentage and the current
+//! step, which go to one status line on a terminal or, where a sink is set
+//! as by the flashing station, to JSON events. Like the deadline, the model
+//! belongs to the thread running the command.
+
+use std::cell::RefCell;
+use std::io::{IsTerminal, Write};
+
+use crate::json::Value;
+use crate::size;
+
+/// Where things stand
+#[derive(Clone, Debug, PartialEq)]
+pub struct Report {
+    /// Of the whole command, 0 to 100
+    pub percent: u32,
+    /// Step of the outermost steps, counting from 1, and how many there are
+    pub step: usize,
+    pub steps: usize,
+    /// The current step at each level, outermost first
+    pub name: String,
+    /// Bytes done of the current part, if it tells
+    pub done: u64,
+    pub total: u64,
+}
+
+impl Report {
+    pub fn fields(&self) -> Vec<(&'static str, Value)> {
+        vec![
+            ("percent", self.percent.into()),
+            ("step", self.step.into()),
+            ("steps", self.steps.into()),
+            ("name", self.name.as_str().into()),
+            ("done", self.done.into()),
+            ("total", self.total.into()),
+        ]
+    }
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:>3}%", self.percent)?;
+        if self.steps > 1 {
+            write!(f, " step {} of {}", self.step, self.steps)?;
+        }
+        if !self.name.is_empty() {
+            write!(f, ", {}", self.name)?;
+        }
+        if self.total > 0 {
+            write!(
+                f,
+                ": {} of {}",
+                size::human(self.done),
+                size::human(self.total)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Takes the reports instead of the terminal
+pub type Sink = Box<dyn Fn(&Report)>;
+
+struct Level {
+    step: usize,
+    steps: usize,
+    name: String,
+}
+
+#[derive(Default)]
+struct State {
+    levels: Vec<Level>,
+    done: u64,
+    total: u64,
+    /// What was last told, so that only changes are
+    last: Option<(u32, usize, String)>,
+    /// Whether a status line is on the terminal
+    drawn: bool,
+    sink: Option<Sink>,
+}
+
+thread_local! {
+    static STATE: RefCell<State> = RefCell::default();
+}
+
+/// Send reports to `sink` instead of the terminal, e.g. as JSON events
+pub fn set_sink(sink: Option<Sink>) {
+    STATE.with_borrow_mut(|s| s.sink = sink);
+}
+
+/// Steps of what follows, until the guard is dropped
+pub struct Steps(());
+
+/// What follows has `n` steps, within the current step if there is one
+pub fn steps(n: usize) -> Steps {
+    STATE.with_borrow_mut(|s| {
+        s.levels.push(Level {
+            step: 0,
+            steps: n.max(1),
+            name: String::new(),
+        });
+        s.done = 0;
+        s.total = 0;
+    });
+    Steps(())
+}
+
+impl Drop for Steps {
+    fn drop(&mut self) {
+        STATE.with_borrow_mut(|s| {
+            s.levels.pop();
+            if s.levels.is_empty() {
+                if s.drawn {
+                    eprint!("\r\x1b[K");
+                }
+                s.drawn = false;
+                s.last = None;
+            }
+        });
+    }
+}
+
+/// Step `n` of the innermost steps starts, counting from 1
+pub fn step(n: usize, name: &str) {
+    STATE.with_borrow_mut(|s| {
+        if let Some(l) = s.levels.last_mut() {
+            l.step = n;
+            l.name = name.to_string();
+        }
+        s.done = 0;
+        s.total = 0;
+    });
+    tell();
+}
+
+/// The current part has done `done` bytes of `total`
+pub fn advance(done: u64, total: u64) {
+    STATE.with_borrow_mut(|s| {
+        s.done = done.min(total);
+        s.total = total;
+    });
+    tell();
+}
+
+fn report(s: &State) -> Report {
+    let mut fraction = match s.total {
+        0 => 0.0,
+        t => s.done as f64 / t as f64,
+    };
+    for l in s.levels.iter().rev() {
+        fraction = (l.step.saturating_sub(1) as f64 + fraction) / l.steps as f64;
+    }
+    // A command on its own counts as one step.
+    let (step, steps) = s
+        .levels
+        .iter()
+        .find(|l| l.steps > 1)
+        .map_or((1, 1), |l| (l.step, l.steps));
+    let name = s
+        .levels
+        .iter()
+        .map(|l| l.name.as_str())
+        .filter(|n| !n.is_empty())
+        .collect::<Vec<_>>()
+        .join(": ");
+    Report {
+        percent: (fraction * 100.0).floor() as u32,
+        step,
+        steps,
+        name,
+        done: s.done,
+        total: s.total,
+    }
+}
+
+/// Tell a change of step or of whole percent
+fn tell() {
+    STATE.with_borrow_mut(|s| {
+        if s.levels.is_empty() {
+            return;
+        }
+        let r = report(s);
+        let key = (r.percent, r.step, r.name.clone());
+        if s.last.as_ref() == Some(&key) {
+            return;
+        }
+        s.last = Some(key);
+        match &s.sink {
+            Some(sink) => sink(&r),
+            None if std::io::stderr().is_terminal() => {
+                // Log lines that follow start over it.
+                eprint!("\r\x1b[K{r}\r");
+                let _ = std::io::stderr().flush();
+                s.drawn = true;
+            }
+            None => {}
+        }
+    });
+}
@@ -1,5 +1,7 @@
+use std::cell::Cell;
 use std::io::{self, ErrorKind::TimedOut};
-use std::time::Duration;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
 use clap::ValueEnum;
 
@@ -7,10 +9,15 @@ use async_io::{Timer, block_on};
 use futures_lite::FutureExt;
 use log::{debug, info, warn};
 use nusb::Interface;
-use nusb::transfer::{ControlOut, ControlType, Recipient, RequestBuffer};
-use zerocopy::{FromBytes, IntoBytes};
+use nusb::transfer::{ControlOut, ControlType, Recipient, RequestBuffer, TransferError};
+use zerocopy::byteorder::big_endian::{U16, U32};
+use zerocopy::{FromBytes, FromZeros, IntoBytes};
 use zerocopy_derive::{FromBytes, Immutable, IntoBytes};
 
+use crate::metrics::{self, Failure, TRANSFER_SECONDS};
+use crate::session::Session;
+use crate::{deadline, progress, retry, sanity};
+
 #[allow(non_camel_case_types)]
 #[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
 #[repr(u16)]
@@ -28,21 +35,286 @@ impl std::fmt::Display for Region {
     }
 }
 
-const CRC: crc::Crc<u16> = crc::Crc::<u16>::new(&crc::CRC_16_IBM_3740);
+/// Storage the loader reads and writes, by the numbers it uses
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Storage {
+    Emmc = 1,
+    Sd = 2,
+    Spinand = 8,
+    Spinor = 9,
+}
+
+impl std::fmt::Display for Storage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.to_possible_value()
+            .expect("no values are skipped")
+            .get_name()
+            .fmt(f)
+    }
+}
 
 const USB_REQUEST_SIGNATURE: &[u8; 4] = b"USBC";
 const USB_RESPONSE_SIGNATURE: &[u8; 4] = b"USBS";
 
 const FLAG_DIR_IN: u8 = 0x80;
+const FLAG_DIR_OUT: u8 = 0x00;
 
-// NOTE: more commands are known; to be added later
-#[derive(Clone, Debug, Copy, IntoBytes, Immutable)]
+#[derive(Clone, Debug, Copy, PartialEq, Eq, IntoBytes, Immutable)]
 #[repr(u8)]
 enum Command {
     UnitReady = 0x00,
+    ReadFlashId = 0x01,
+    TestBadBlock = 0x03,
+    ReadSector = 0x04,
+    WriteSector = 0x05,
+    EraseNormal = 0x06,
+    EraseForce = 0x0b,
     Version = 0x0c,
+    ReadLba = 0x14,
+    WriteLba = 0x15,
+    EraseSystemDisk = 0x16,
+    ReadSdram = 0x17,
+    WriteSdram = 0x18,
+    ExecuteSdram = 0x19,
+    ReadFlashInfo = 0x1a,
     Chipinfo = 0x1b,
+    LowFormat = 0x1c,
+    SetResetFlag = 0x1e,
+    WriteEfuse = 0x1f,
+    ReadEfuse = 0x20,
+    ReadSpiFlash = 0x21,
+    WriteSpiFlash = 0x22,
+    WriteNewEfuse = 0x23,
+    ReadNewEfuse = 0x24,
+    EraseLba = 0x25,
+    ReadComLog = 0x28,
+    ChangeStorage = 0x2a,
+    ReadStorage = 0x2b,
     Capability = 0xaa,
+    DeviceReset = 0xff,
+}
+
+/// Data phase of a command
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Data {
+    None,
+    /// A reply of up to this many bytes
+    In(u32),
+    /// A reply of up to this many bytes, which loaders may follow with the
+    /// CRC32 of what they sent
+    InCrc(u32),
+    /// Bytes to the host per unit of the size field
+    InPer(u32),
+    /// Bytes to the device per unit of the size field
+    OutPer(u32),
+}
+
+// Raw NAND sectors come with their spare area.
+const RAW_SECTOR_SIZE: u32 = 528;
+
+/// The opcodes loaders know, with their names, the bytes of the command
+/// block that are meaningful, and their data. Vendor storage has no opcode
+/// of its own, it is read and written with LBA commands.
+const OPCODES: &[(Command, &str, u8, Data)] = &[
+    (Command::UnitReady, "unit ready", 6, Data::None),
+    (
+        Command::ReadFlashId,
+        "read flash ID",
+        6,
+        Data::In(FLASH_ID_SIZE as u32),
+    ),
+    (
+        Command::TestBadBlock,
+        "test bad blocks",
+        10,
+        Data::In(BAD_BLOCK_MAP_SIZE as u32),
+    ),
+    (
+        Command::ReadSector,
+        "read raw sectors",
+        10,
+        Data::InPer(RAW_SECTOR_SIZE),
+    ),
+    (
+        Command::WriteSector,
+        "write raw sectors",
+        10,
+        Data::OutPer(RAW_SECTOR_SIZE),
+    ),
+    (Command::EraseNormal, "erase blocks", 10, Data::None),
+    (
+        Command::EraseForce,
+        "erase blocks, bad ones too",
+        10,
+        Data::None,
+    ),
+    (Command::Version, "read version", 6, Data::In(16)),
+    (
+        Command::ReadLba,
+        "read LBA",
+        10,
+        Data::InPer(SECTOR_SIZE as u32),
+    ),
+    (
+        Command::WriteLba,
+        "write LBA",
+        10,
+        Data::OutPer(SECTOR_SIZE as u32),
+    ),
+    (
+        Command::EraseSystemDisk,
+        "erase system disk",
+        10,
+        Data::None,
+    ),
+    (Command::ReadSdram, "read memory", 6, Data::InPer(1)),
+    (Command::WriteSdram, "write memory", 6, Data::OutPer(1)),
+    (Command::ExecuteSdram, "execute memory", 6, Data::None),
+    (
+        Command::ReadFlashInfo,
+        "read flash info",
+        6,
+        Data::InCrc(FLASH_INFO_SIZE as u32),
+    ),
+    (
+        Command::Chipinfo,
+        "read chip info",
+        6,
+        Data::In(CHIP_INFO_SIZE as u32),
+    ),
+    (Command::LowFormat, "low level format", 6, Data::None),
+    (Command::SetResetFlag, "set reset flag", 6, Data::None),
+    (Command::WriteEfuse, "write eFuse", 6, Data::OutPer(1)),
+    (Command::ReadEfuse, "read eFuse", 6, Data::InPer(1)),
+    (
+        Command::ReadSpiFlash,
+        "read SPI flash",
+        10,
+        Data::InPer(SECTOR_SIZE as u32),
+    ),
+    (
+        Command::WriteSpiFlash,
+        "write SPI flash",
+        10,
+        Data::OutPer(SECTOR_SIZE as u32),
+    ),
+    (
+        Command::WriteNewEfuse,
+        "write eFuse, new layout",
+        6,
+        Data::OutPer(1),
+    ),
+    (
+        Command::ReadNewEfuse,
+        "read eFuse, new layout",
+        6,
+        Data::InPer(1),
+    ),
+    (Command::EraseLba, "erase LBA", 10, Data::None),
+    (
+        Command::ReadComLog,
+        "read com log",
+        6,
+        Data::In(LOG_CHUNK_SIZE as u32),
+    ),
+    (Command::ChangeStorage, "switch storage", 6, Data::None),
+    (Command::ReadStorage, "read storage", 6, Data::In(4)),
+    (
+        Command::Capability,
+        "read capability",
+        6,
+        Data::InCrc(CAPABILITY_SIZE as u32),
+    ),
+    (Command::DeviceReset, "reset", 6, Data::None),
+];
+
+/// Name of an opcode, if it is a known one
+pub fn opcode_name(code: u8) -> Option<&'static str> {
+    OPCODES
+        .iter()
+        .find(|(c, ..)| *c as u8 == code)
+        .map(|(_, name, ..)| *name)
+}
+
+impl Command {
+    fn entry(self) -> &'static (Command, &'static str, u8, Data) {
+        OPCODES.iter().find(|(c, ..)| *c == self).unwrap()
+    }
+
+    fn name(self) -> &'static str {
+        self.entry().1
+    }
+
+    /// Bytes of the command block that are meaningful
+    fn command_length(self) -> u8 {
+        self.entry().2
+    }
+
+    fn data(self) -> Data {
+        self.entry().3
+    }
+
+    /// Whether it changes the device for good, refused in read-only
+    /// sessions
+    fn destructive(self) -> bool {
+        matches!(
+            self,
+            Self::WriteSector
+                | Self::EraseNormal
+                | Self::EraseForce
+                | Self::WriteLba
+                | Self::EraseSystemDisk
+                | Self::ExecuteSdram
+                | Self::LowFormat
+                | Self::SetResetFlag
+                | Self::WriteEfuse
+                | Self::WriteSpiFlash
+                | Self::WriteNewEfuse
+                | Self::EraseLba
+        )
+    }
+}
+
+const CRC32: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+const CRC32_SIZE: usize = 4;
+
+thread_local! {
+    static CHECK_CRC: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Ask for the CRC32 after replies that may carry one, and fail commands
+/// whose reply does not match it; for this thread, i.e. this command
+pub fn set_check_crc(on: bool) {
+    CHECK_CRC.set(on);
+}
+
+fn check_crc() -> bool {
+    CHECK_CRC.get()
+}
+
+/// The payload of a reply that ends in its little endian CRC32
+fn checked(op: Command, mut d: Vec<u8>) -> Result<Vec<u8>, Error> {
+    if d.len() < CRC32_SIZE {
+        return Err(Error::Transport(format!(
+            "{} reply of {} bytes has no CRC32",
+            op.name(),
+            d.len()
+        ))
+        .logged());
+    }
+    let crc = d.split_off(d.len() - CRC32_SIZE);
+    let crc = u32::from_le_bytes(crc.try_into().unwrap());
+    let want = CRC32.checksum(&d);
+    if crc != want {
+        return Err(Error::Transport(format!(
+            "{} reply fails its CRC32, {crc:#010x} instead of {want:#010x}",
+            op.name()
+        ))
+        .logged());
+    }
+    debug!("{} reply matches its CRC32", op.name());
+    Ok(d)
 }
 
 #[derive(Clone, Debug, Copy, FromBytes, IntoBytes, Immutable)]
@@ -50,9 +322,10 @@ enum Command {
 struct RkCommand {
     code: u8,
     subcode: u8,
-    address: u32,
+    // Address and size are big endian, unlike the rest of the request.
+    address: U32,
     _r6: u8,
-    size: u16,
+    size: U16,
     _r9: u8,
     _r10: u8,
     _r11: u8,
@@ -71,6 +344,110 @@ struct Request {
     command: RkCommand,
 }
 
+/// A request to the loader, built from its opcode, so that the direction,
+/// the command length and the data length always go with it
+struct Cbw {
+    op: Command,
+    command: RkCommand,
+    length: u32,
+}
+
+impl Cbw {
+    fn new(op: Command) -> Self {
+        let mut command = RkCommand::new_zeroed();
+        command.code = op as u8;
+        let length = match op.data() {
+            Data::In(n) => n,
+            Data::InCrc(n) if check_crc() => n + CRC32_SIZE as u32,
+            Data::InCrc(n) => n,
+            _ => 0,
+        };
+        Self {
+            op,
+            command,
+            length,
+        }
+    }
+
+    fn address(mut self, address: u32) -> Self {
+        self.command.address = address.into();
+        self
+    }
+
+    /// Sectors, bytes or blocks to act on; for transfers the size counts,
+    /// this also sets the data length
+    fn size(mut self, size: u16) -> Self {
+        self.command.size = size.into();
+        if let Data::InPer(unit) | Data::OutPer(unit) = self.op.data() {
+            self.length = size as u32 * unit;
+        }
+        self
+    }
+
+    fn subcode(mut self, subcode: u8) -> Self {
+        self.command.subcode = subcode;
+        self
+    }
+
+    /// Send the request; returns the length of the data phase
+    fn send(self, s: &Session) -> Result<usize, Error> {
+        if self.op.destructive() {
+            s.allow(self.op.name())?;
+        }
+        let flag = match self.op.data() {
+            Data::In(_) | Data::InCrc(_) | Data::InPer(_) => FLAG_DIR_IN,
+            Data::None | Data::OutPer(_) => FLAG_DIR_OUT,
+        };
+        let req = Request {
+            signature: *USB_REQUEST_SIGNATURE,
+            tag: s.next_tag(),
+            length: self.length,
+            flag,
+            lun: 0,
+            command_length: self.op.command_length(),
+            command: self.command,
+        };
+        debug!("Request: {}", self.op.name());
+        usb_send(s, req.as_bytes().to_vec())?;
+        Ok(self.length as usize)
+    }
+
+    /// Send the request and take its data, padded to the full length
+    fn read(self, s: &Session) -> Result<Vec<u8>, Error> {
+        assert!(
+            matches!(
+                self.op.data(),
+                Data::In(_) | Data::InCrc(_) | Data::InPer(_)
+            ),
+            "{} sends no data to the host",
+            self.op.name()
+        );
+        let op = self.op;
+        let n = self.send(s)?;
+        match op.data() {
+            Data::InCrc(_) if check_crc() => {
+                let mut d = checked(op, usb_read(s, n)?)?;
+                d.resize(n - CRC32_SIZE, 0);
+                Ok(d)
+            }
+            _ => usb_read_n(s, n),
+        }
+    }
+
+    /// Send the request with its data
+    fn write(self, s: &Session, data: &[u8]) -> Result<(), Error> {
+        assert!(
+            matches!(self.op.data(), Data::OutPer(_)) && data.len() == self.length as usize,
+            "{} bytes for {} of {} bytes",
+            data.len(),
+            self.op.name(),
+            self.length
+        );
+        self.send(s)?;
+        usb_send(s, data.to_vec())
+    }
+}
+
 #[derive(Clone, Debug, Copy, FromBytes, IntoBytes, Immutable)]
 #[repr(C, packed)]
 struct Response {
@@ -82,176 +459,770 @@ struct Response {
 
 const RESPONSE_SIZE: usize = std::mem::size_of::<Response>();
 
-fn usb_send(i: &Interface, addr: u8, data: Vec<u8>) {
-    let _: io::Result<usize> = {
-        let timeout = Duration::from_secs(5);
-        let fut = async {
-            let comp = i.bulk_out(addr, data).await;
-            comp.status.map_err(io::Error::other)?;
-            let n = comp.data.actual_length();
-            Ok(n)
-        };
+/// Bytes moved over USB by this process, in either direction
+pub static TRANSFERRED: AtomicU64 = AtomicU64::new(0);
+/// Bytes written to the storage of devices by this process
+pub static WRITTEN: AtomicU64 = AtomicU64::new(0);
 
-        block_on(fut.or(async {
-            Timer::after(timeout).await;
-            Err(TimedOut.into())
-        }))
-    };
+/// Moves bytes to and from a device; implemented for USB interfaces, and
+/// for a software device in tests
+pub trait Transport {
+    fn bulk_out(&self, ep: u8, data: Vec<u8>, timeout: Duration) -> io::Result<usize>;
+    fn bulk_in(&self, ep: u8, size: usize, timeout: Duration) -> io::Result<Vec<u8>>;
+    /// Vendor request to the device, which is how the mask ROM takes code
+    fn control_out(
+        &self,
+        request: u8,
+        index: u16,
+        data: &[u8],
+        timeout: Duration,
+    ) -> io::Result<usize>;
 }
 
-fn usb_read_n(i: &Interface, addr: u8, size: usize) -> Vec<u8> {
-    let mut buf = vec![0_u8; size];
+fn with_timeout<T>(timeout: Duration, fut: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+    block_on(fut.or(async {
+        Timer::after(timeout).await;
+        Err(TimedOut.into())
+    }))
+}
 
-    let _: io::Result<usize> = {
-        let timeout = Duration::from_secs(5);
-        let fut = async {
-            let b = RequestBuffer::new(size);
-            let comp = i.bulk_in(addr, b).await;
-            comp.status.map_err(io::Error::other)?;
+/// A device that is gone fails differently from one that does not answer
+fn transfer_error(e: TransferError) -> io::Error {
+    match e {
+        TransferError::Disconnected => io::Error::new(io::ErrorKind::NotConnected, e),
+        e => io::Error::other(e),
+    }
+}
+
+impl Transport for Interface {
+    fn bulk_out(&self, ep: u8, data: Vec<u8>, timeout: Duration) -> io::Result<usize> {
+        with_timeout(timeout, async {
+            let comp = Interface::bulk_out(self, ep, data).await;
+            comp.status.map_err(transfer_error)?;
+            Ok(comp.data.actual_length())
+        })
+    }
 
-            let n = comp.data.len();
-            buf[..n].copy_from_slice(&comp.data);
-            Ok(n)
+    fn bulk_in(&self, ep: u8, size: usize, timeout: Duration) -> io::Result<Vec<u8>> {
+        with_timeout(timeout, async {
+            let comp = Interface::bulk_in(self, ep, RequestBuffer::new(size)).await;
+            comp.status.map_err(transfer_error)?;
+            Ok(comp.data)
+        })
+    }
+
+    fn control_out(
+        &self,
+        request: u8,
+        index: u16,
+        data: &[u8],
+        timeout: Duration,
+    ) -> io::Result<usize> {
+        let out = ControlOut {
+            control_type: ControlType::Vendor,
+            recipient: Recipient::Device,
+            request,
+            value: 0,
+            index,
+            data,
         };
+        with_timeout(timeout, async {
+            let comp = Interface::control_out(self, out).await;
+            comp.status.map_err(transfer_error)?;
+            Ok(comp.data.actual_length())
+        })
+    }
+}
+
+const BULK_TIMEOUT: Duration = Duration::from_secs(5);
+const CONTROL_TIMEOUT: Duration = Duration::from_millis(25);
+
+fn count_transfer(start: Instant, n: usize) {
+    TRANSFERRED.fetch_add(n as u64, Ordering::Relaxed);
+    TRANSFER_SECONDS.observe(start.elapsed());
+}
 
-        block_on(fut.or(async {
-            Timer::after(timeout).await;
-            Err(TimedOut.into())
-        }))
+fn usb_send(s: &Session, data: Vec<u8>) -> Result<(), Error> {
+    let start = Instant::now();
+    deadline::check()?;
+    let res = retry::transfer(s.retry_policy(), "Bulk out", || {
+        s.transport()
+            .bulk_out(s.e_out, data.clone(), deadline::clamp(BULK_TIMEOUT))
+    });
+    match res {
+        Ok(n) => count_transfer(start, n),
+        Err(e) if e.kind() == io::ErrorKind::NotConnected => {
+            return Err(Error::Disconnected.logged());
+        }
+        Err(e) => warn!(target: TRANSPORT, "Bulk out to {:#04x} failed: {e}", s.e_out),
+    }
+    Ok(())
+}
+
+/// Read up to `size` bytes, as many as the device sends
+fn usb_read(s: &Session, size: usize) -> Result<Vec<u8>, Error> {
+    let start = Instant::now();
+    deadline::check()?;
+    let res = retry::transfer(s.retry_policy(), "Bulk in", || {
+        s.transport()
+            .bulk_in(s.e_in, size, deadline::clamp(BULK_TIMEOUT))
+    });
+    let mut d = match res {
+        Ok(d) => d,
+        Err(e) if e.kind() == io::ErrorKind::NotConnected => {
+            return Err(Error::Disconnected.logged());
+        }
+        Err(e) => {
+            warn!(target: TRANSPORT, "Bulk in from {:#04x} failed: {e}", s.e_in);
+            return Ok(Vec::new());
+        }
     };
+    d.truncate(size);
+    count_transfer(start, d.len());
+    Ok(d)
+}
+
+fn usb_read_n(s: &Session, size: usize) -> Result<Vec<u8>, Error> {
+    let mut buf = usb_read(s, size)?;
+    buf.resize(size, 0);
 
     let l = if buf.len() < 128 { buf.len() } else { 128 };
     let b = &buf[..l];
     debug!("Device says: {b:02x?}");
 
-    buf
+    Ok(buf)
 }
 
-pub fn info(i: &Interface, e_in_addr: u8, e_out_addr: u8) {
-    info!("Read chip info");
+// Log targets for the two ways a command fails, to tell them apart in logs
+// or filter them, e.g. with RUST_LOG=rk_boot::device=debug
+const TRANSPORT: &str = "rk_boot::transport";
+const DEVICE: &str = "rk_boot::device";
 
-    let cmd = RkCommand {
-        code: Command::Chipinfo as u8,
-        subcode: 0,
-        address: 0,
-        _r6: 0,
-        size: 0,
-        _r9: 0,
-        _r10: 0,
-        _r11: 0,
-        _r12: 0,
-    };
+/// Why a command failed. The remedies differ: a failed transfer points at
+/// the cable, hub or power, a device refusing a command at its parameters
+/// or at storage that is locked or write-protected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The request or its reply got lost or garbled on the way
+    Transport(String),
+    /// The device answered with this failure status
+    Device(u8),
+    /// The device left the bus, e.g. unplugged or browned out
+    Disconnected,
+    /// The command may not go on, e.g. as its deadline has passed
+    Aborted(Failure),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Transport(e) => write!(
+                f,
+                "USB transport failed: {e}; check the cable, hub and power supply"
+            ),
+            Self::Device(status) => write!(
+                f,
+                "Device reported failure, status {status:#04x}; check the parameters, \
+                 and whether the storage is locked or write-protected"
+            ),
+            Self::Disconnected => write!(
+                f,
+                "Device disconnected; it was unplugged, lost power or reset"
+            ),
+            Self::Aborted(e) => e.fmt(f),
+        }
+    }
+}
 
-    let tag = 0x13372342;
-    let length = 0x10;
+impl Error {
+    /// Class of the failure, for retry policies and metrics
+    pub fn class(&self) -> &'static str {
+        match self {
+            Self::Transport(_) => "transport",
+            Self::Device(_) => "device",
+            Self::Disconnected => "disconnect",
+            Self::Aborted(e) => metrics::category(e),
+        }
+    }
 
-    let req = Request {
-        signature: *USB_REQUEST_SIGNATURE,
-        tag,
-        length,
-        flag: FLAG_DIR_IN,
-        lun: 0,
-        command_length: 6,
-        command: cmd,
+    /// Log the failure where it belongs, as it comes up
+    fn logged(self) -> Self {
+        match self {
+            Self::Transport(_) | Self::Disconnected => debug!(target: TRANSPORT, "{self}"),
+            Self::Device(_) => debug!(target: DEVICE, "{self}"),
+            Self::Aborted(_) => {}
+        }
+        self
+    }
+}
+
+impl From<Failure> for Error {
+    fn from(f: Failure) -> Self {
+        Self::Aborted(f)
+    }
+}
+
+fn no_response() -> Error {
+    Error::Transport("no valid response from device".into())
+}
+
+fn wrong_tag(r: &Response, s: &Session) -> Error {
+    Error::Transport(format!(
+        "response for tag {:#x}, expected {:#x}",
+        { r.tag },
+        s.tag()
+    ))
+}
+
+/// Read a response, if the device sends one at all
+fn try_response(s: &Session) -> Result<Option<Response>, Error> {
+    let mut buf = usb_read_n(s, RESPONSE_SIZE)?;
+    let Some((at, swapped)) = sanity::status_block(&buf) else {
+        return Ok(None);
+    };
+    if at > 0 {
+        warn!(target: TRANSPORT, "Response after stray bytes {:02x?}", &buf[..at]);
+        buf.drain(..at);
+        buf.extend(usb_read_n(s, at)?);
+    }
+    let (mut res, _) = Response::read_from_prefix(&buf).unwrap();
+    if swapped {
+        sanity::byte_swapped(s, "Response");
+        res.signature = *USB_RESPONSE_SIGNATURE;
+        res.tag = res.tag.swap_bytes();
+        res.residue = res.residue.swap_bytes();
+    }
+    Ok(Some(res))
+}
+
+/// Check the response to the last request; a lost or garbled one may be
+/// worth trying again, the device refusing the command hardly is
+fn response(s: &Session) -> Result<Response, Error> {
+    let Some(res) = try_response(s)? else {
+        return Err(no_response().logged());
     };
+    if res.tag != s.tag() {
+        return Err(wrong_tag(&res, s).logged());
+    }
 
-    let r = req.as_bytes().to_vec();
+    debug!("Metadata: {res:#02x?}");
+    if res.status != 0 {
+        return Err(Error::Device(res.status).logged());
+    }
+    Ok(res)
+}
 
-    usb_send(i, e_out_addr, r);
+/// Read the chip ID, e.g. "3588"
+pub fn info(s: &Session) -> Result<String, Error> {
+    info!("Read chip info");
 
     // The rest is just ffff...
-    // NOTE: not sure if this here is always the same `length` or just
-    // coincidentally in the case of the ChipInfo command.
-    let d = &mut usb_read_n(i, e_in_addr, length as usize)[..4];
-    d.reverse();
-    let s = std::str::from_utf8(d).unwrap();
-    info!("Chip ID: {s} {d:02x?}");
+    let d = Cbw::new(Command::Chipinfo).read(s)?;
+    let id = sanity::chip_id(s, &d);
+    info!("Chip ID: {id} {:02x?}", &d[..4]);
 
-    let buf = &usb_read_n(i, e_in_addr, RESPONSE_SIZE);
-    let (res, _) = Response::read_from_prefix(buf).unwrap();
+    response(s)?;
+    Ok(id)
+}
 
-    assert_eq!(res.signature, *USB_RESPONSE_SIGNATURE);
-    let res_tag = res.tag;
-    assert_eq!(res_tag, tag);
+/// Storage geometry as the loader reports it; sizes are in sectors
+#[derive(Clone, Debug, Copy, FromBytes, IntoBytes, Immutable)]
+#[repr(C, packed)]
+pub struct FlashInfo {
+    pub sectors: u32,
+    pub block_sectors: u16,
+    pub page_sectors: u8,
+    pub ecc_bits: u8,
+    pub access_time: u8,
+    pub manufacturer: u8,
+    pub chip_select: u8,
+}
 
-    debug!("Metadata: {res:#02x?}");
+const FLASH_INFO_SIZE: usize = std::mem::size_of::<FlashInfo>();
+const CHIP_INFO_SIZE: usize = 16;
+
+/// Read the storage geometry
+pub fn flash_info(s: &Session) -> Result<FlashInfo, Error> {
+    let d = Cbw::new(Command::ReadFlashInfo).read(s)?;
+    response(s)?;
+    let (fi, _) = FlashInfo::read_from_prefix(&d).unwrap();
+    let fi = sanity::flash_info(s, fi);
+    debug!("Flash info: {fi:?}");
+    Ok(fi)
 }
 
-const CHUNK_SIZE: usize = 4096;
+const FLASH_ID_SIZE: usize = 5;
 
-// TODO: Are there other requests than this?
-const REQUEST: u8 = 0xc;
+/// Read the ID bytes of the storage the loader uses
+pub fn flash_id(s: &Session) -> Result<Vec<u8>, Error> {
+    let d = Cbw::new(Command::ReadFlashId).read(s)?;
+    response(s)?;
+    debug!("Flash ID: {d:02x?}");
+    Ok(d)
+}
 
-fn usb_out(i: &Interface, data: &[u8], region: &Region, tolerate_timeout: bool) {
-    let index = region.clone() as u16; // where the mask ROM writes this;
-    let out = ControlOut {
-        control_type: ControlType::Vendor,
-        recipient: Recipient::Device,
-        request: REQUEST,
-        value: 0,
-        index,
-        data,
+/// Read bytes of OTP, by the newer opcode if the loader has it
+pub fn read_otp(s: &Session, offset: u32, len: u16, new: bool) -> Result<Vec<u8>, Error> {
+    let op = match new {
+        true => Command::ReadNewEfuse,
+        false => Command::ReadEfuse,
     };
-    let res: io::Result<usize> = {
-        let timeout = Duration::from_millis(25);
-        let fut = async {
-            let comp = i.control_out(out).await;
-            comp.status.map_err(io::Error::other)?;
-            let n = comp.data.actual_length();
-            Ok(n)
+    let d = Cbw::new(op).address(offset).size(len).read(s)?;
+    response(s)?;
+    debug!("OTP at {offset:#x}: {d:02x?}");
+    Ok(d)
+}
+
+pub const SECTOR_SIZE: usize = 512;
+/// Sectors per LBA command to start with; what rkdeveloptool uses as well
+pub const LBA_CHUNK_SECTORS: u32 = 128;
+/// Largest LBA transfer to use even if the loader takes more, 1 MiB
+pub const MAX_LBA_CHUNK_SECTORS: u32 = 2048;
+// Flaky links, e.g. USB 3 risers, often cope with smaller bursts. After this
+// many failed LBA commands, the transfer size is halved, down to the minimum.
+pub const DOWNSHIFT_AFTER: u32 = 2;
+pub const MIN_LBA_CHUNK_SECTORS: u32 = 16;
+
+/// How a transfer is cut into pieces and what happens when one fails;
+/// the control transfers of the mask ROM and the bulk commands of the
+/// loader all go through one
+struct Mover<'a> {
+    /// For the logs
+    what: &'static str,
+    /// Largest piece, read again for each, as it may shrink on the way
+    chunk: &'a dyn Fn(&Session) -> usize,
+    /// Whether a failed piece is tried again as the session's retry policy
+    /// says; if not, retrying is left to the transport
+    retries: bool,
+    /// Whether lost pieces count towards shrinking the LBA transfer size
+    downshift: bool,
+}
+
+const LBA_MOVER: Mover = Mover {
+    what: "LBA command",
+    chunk: &|s| s.lba_chunk() as usize,
+    retries: true,
+    downshift: true,
+};
+
+impl Mover<'_> {
+    /// Move `total` units, handing `f` the offset and size of each piece;
+    /// stops between pieces once the deadline has passed
+    fn each(
+        &self,
+        s: &Session,
+        total: usize,
+        mut f: impl FnMut(usize, usize) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let once = retry::Policy {
+            attempts: 1,
+            ..retry::Policy::default()
         };
+        let p = match self.retries {
+            true => s.retry_policy(),
+            false => &once,
+        };
+        let mut done = 0;
+        while done < total {
+            deadline::check()?;
+            let res = retry::with(p, self.what, Error::class, || {
+                let n = (total - done).min((self.chunk)(s));
+                f(done, n).map(|()| n).inspect_err(|e| {
+                    // The device refusing a command says nothing about the link.
+                    if self.downshift && matches!(e.class(), "transport" | "timeout") {
+                        s.transfer_failed();
+                    }
+                })
+            });
+            done += res?;
+            debug!("{}: {done:#x} of {total:#x} moved", self.what);
+        }
+        Ok(())
+    }
+}
+
+/// Read sectors from storage
+pub fn read_lba(s: &Session, lba: u32, count: u32) -> Result<Vec<u8>, Error> {
+    let mut data = Vec::with_capacity(count as usize * SECTOR_SIZE);
+    LBA_MOVER.each(s, count as usize, |done, n| {
+        let l = Cbw::new(Command::ReadLba)
+            .address(lba + done as u32)
+            .size(n as u16)
+            .send(s)?;
+        let d = usb_read(s, l)?;
+        if d.len() < l {
+            // Take the status off the wire, if there is one
+            try_response(s)?;
+            return Err(Error::Transport(format!("short read, {} of {l} bytes", d.len())).logged());
+        }
+        response(s)?;
+        data.extend_from_slice(&d);
+        Ok(())
+    })?;
+    Ok(data)
+}
+
+/// Write sectors to storage, padding the last one with zeros
+pub fn write_lba(s: &Session, lba: u32, data: &[u8]) -> Result<(), Error> {
+    let mut data = data.to_vec();
+    data.resize(data.len().next_multiple_of(SECTOR_SIZE), 0);
+    LBA_MOVER.each(s, data.len() / SECTOR_SIZE, |done, n| {
+        Cbw::new(Command::WriteLba)
+            .address(lba + done as u32)
+            .size(n as u16)
+            .write(s, &data[done * SECTOR_SIZE..(done + n) * SECTOR_SIZE])?;
+        response(s)?;
+        WRITTEN.fetch_add((n * SECTOR_SIZE) as u64, Ordering::Relaxed);
+        Ok(())
+    })
+}
+
+/// Whether the storage refuses writes at `lba`
+///
+/// The loaders have no request that tells the lock state, so this reads
+/// the sector and writes it back: nothing changes either way, but it is a
+/// write, and it tells about this one sector only. Storage that is
+/// protected further on still fails in the middle of the write.
+pub fn write_protected(s: &Session, lba: u32) -> Result<bool, Error> {
+    let data = read_lba(s, lba, 1)?;
+    Cbw::new(Command::WriteLba)
+        .address(lba)
+        .size(1)
+        .write(s, &data)?;
+    Ok(try_response(s)?.is_none_or(|r| r.status != 0))
+}
+
+// The size field is 16 bits wide; stay well below.
+const SDRAM_CHUNK_SIZE: usize = 16 * 1024;
+const SDRAM_MOVER: Mover = Mover {
+    what: "SDRAM transfer",
+    chunk: &|_| SDRAM_CHUNK_SIZE,
+    retries: false,
+    downshift: false,
+};
+
+/// Read memory through the loader, which can be DRAM as well as registers
+pub fn mem_read(s: &Session, addr: u32, len: usize) -> Result<Vec<u8>, Error> {
+    assert!(
+        addr as u64 + len as u64 <= 1 << 32,
+        "{len} bytes at {addr:08x} exceed the 32-bit address space"
+    );
+    let mut data = Vec::with_capacity(len);
+    SDRAM_MOVER.each(s, len, |done, n| {
+        let a = addr + done as u32;
+        debug!("Read {n} bytes at {a:08x}");
+        let d = Cbw::new(Command::ReadSdram)
+            .address(a)
+            .size(n as u16)
+            .read(s)?;
+        data.extend_from_slice(&d);
+        response(s)?;
+        Ok(())
+    })?;
+    Ok(data)
+}
+
+/// Jump to code previously written to memory
+pub fn exec(s: &Session, addr: u32) -> Result<(), Error> {
+    Cbw::new(Command::ExecuteSdram).address(addr).send(s)?;
+    // Whatever runs now may take over USB before a response is sent.
+    match try_response(s)? {
+        Some(res) if res.status != 0 => return Err(Error::Device(res.status).logged()),
+        Some(_) => info!("Started code at {addr:08x}"),
+        None => warn!("No response after jump to {addr:08x}, code is probably running"),
+    }
+    Ok(())
+}
+
+// SPI NOR erases 64 KiB in about a second; stay well within the timeout.
+const ERASE_CHUNK_SECTORS: u16 = 128;
+
+/// Erase sectors of storage
+pub fn erase_lba(s: &Session, lba: u32, count: u32) -> Result<(), Error> {
+    erase_lba_chunked(s, lba, count, ERASE_CHUNK_SECTORS)
+}
 
-        block_on(fut.or(async {
-            Timer::after(timeout).await;
-            Err(TimedOut.into())
-        }))
+/// Erase sectors with commands covering up to `chunk` sectors, for storage
+/// that erases faster than SPI NOR
+pub fn erase_lba_chunked(s: &Session, lba: u32, count: u32, chunk: u16) -> Result<(), Error> {
+    let mover = Mover {
+        what: "Erase",
+        chunk: &|_| chunk as usize,
+        retries: false,
+        downshift: false,
     };
+    mover.each(s, count as usize, |done, n| {
+        Cbw::new(Command::EraseLba)
+            .address(lba + done as u32)
+            .size(n as u16)
+            .send(s)?;
+        response(s)?;
+        Ok(())
+    })
+}
 
-    // NOTE: The last chunk often seems to time out.
-    if let Err(e) = res {
-        if tolerate_timeout {
-            warn!("{e:?} (tolerated)");
-        } else {
-            panic!("{e:?}");
+const BAD_BLOCK_MAP_SIZE: usize = 64;
+/// Blocks covered by one bad block query, one bit each
+pub const BAD_BLOCK_QUERY: u32 = 8 * BAD_BLOCK_MAP_SIZE as u32;
+
+/// Which of up to `BAD_BLOCK_QUERY` raw NAND blocks are marked bad
+pub fn test_bad_blocks(s: &Session, first: u32, count: u32) -> Result<Vec<bool>, Error> {
+    let count = count.min(BAD_BLOCK_QUERY);
+    let d = Cbw::new(Command::TestBadBlock)
+        .address(first)
+        .size(count as u16)
+        .read(s)?;
+    response(s)?;
+    Ok((0..count as usize)
+        .map(|n| d[n / 8] & (1 << (n % 8)) != 0)
+        .collect())
+}
+
+/// Make the loader use another storage for LBA commands
+pub fn change_storage(s: &Session, storage: Storage) -> Result<(), Error> {
+    Cbw::new(Command::ChangeStorage)
+        .subcode(storage as u8)
+        .send(s)?;
+    response(s)?;
+    s.set_storage(storage);
+    info!("Storage switched to {storage}");
+    Ok(())
+}
+
+/// The storage the loader currently uses, if it is a known one
+pub fn read_storage(s: &Session) -> Result<Option<Storage>, Error> {
+    let d = Cbw::new(Command::ReadStorage).read(s)?;
+    response(s)?;
+    // A bit mask with the bit of the active storage set
+    let mask = u32::from_le_bytes(d[..4].try_into().unwrap());
+    let storage = Storage::value_variants()
+        .iter()
+        .find(|st| mask == 1 << (**st as u8))
+        .copied();
+    if let Some(st) = storage {
+        s.set_storage(st);
+    }
+    Ok(storage)
+}
+
+// Room for the 8 byte bitmap and extension records after it
+const CAPABILITY_SIZE: usize = 64;
+
+/// What the loader supports: a bitmap, possibly followed by more data;
+/// none from older loaders, which do not know the command
+pub fn capability(s: &Session) -> Result<Option<Vec<u8>>, Error> {
+    let n = Cbw::new(Command::Capability).send(s)?;
+    // Shorter replies are not padded, the records end where the data does.
+    let mut d = usb_read(s, n)?;
+    if check_crc() {
+        d = checked(Command::Capability, d)?;
+    }
+    debug!("Capability: {d:02x?}");
+    match try_response(s)? {
+        Some(r) if r.status == 0 => Ok(Some(d)),
+        _ => Ok(None),
+    }
+}
+
+// The loader hands over what it buffered of its UART output, up to this
+// much per command, and forgets it; an empty reply means it is drained.
+const LOG_CHUNK_SIZE: usize = 4096;
+/// Give up on a log that keeps growing as it is read
+const MAX_LOG_SIZE: usize = 1024 * 1024;
+
+/// The UART log the loader buffered, for loaders that announce "read com
+/// log" in their capabilities
+pub fn read_log(s: &Session) -> Result<Vec<u8>, Error> {
+    let mut log = Vec::new();
+    loop {
+        let n = Cbw::new(Command::ReadComLog).send(s)?;
+        let d = usb_read(s, n)?;
+        response(s)?;
+        log.extend_from_slice(&d);
+        if d.len() < n || log.len() >= MAX_LOG_SIZE {
+            debug!("Read {} bytes of log", log.len());
+            return Ok(log);
+        }
+    }
+}
+
+/// Data phase of a raw request
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Direction {
+    None,
+    In,
+    Out,
+}
+
+/// The status a raw request ended with
+#[derive(Debug)]
+pub struct Status {
+    pub tag: u32,
+    /// Whether the tag is the request's
+    pub tag_matches: bool,
+    pub residue: u32,
+    pub status: u8,
+}
+
+/// A request built by hand
+#[derive(Debug)]
+pub struct Raw {
+    pub opcode: u8,
+    pub subcode: u8,
+    pub address: u32,
+    pub size: u16,
+    pub direction: Direction,
+    /// Bytes to take in
+    pub length: u32,
+}
+
+/// Send any request, for loader commands not known here; returns what
+/// came back in the data phase and the status, if any. Known opcodes get
+/// their command length, others that of LBA commands.
+pub fn raw(s: &Session, r: &Raw, data: &[u8]) -> Result<(Vec<u8>, Option<Status>), Error> {
+    let Raw {
+        opcode,
+        subcode,
+        address,
+        size,
+        direction,
+        length,
+    } = *r;
+    let mut command = RkCommand::new_zeroed();
+    command.code = opcode;
+    command.subcode = subcode;
+    command.address = address.into();
+    command.size = size.into();
+    let known = OPCODES.iter().find(|(c, ..)| *c as u8 == opcode);
+    // What unknown opcodes do is anyone's guess.
+    if known.is_none_or(|(c, ..)| c.destructive()) {
+        s.allow(&format!("send opcode {opcode:#04x}"))?;
+    }
+    let command_length = known.map_or(10, |(_, _, l, _)| *l);
+    let (flag, length) = match direction {
+        Direction::In => (FLAG_DIR_IN, length),
+        Direction::Out => (FLAG_DIR_OUT, data.len() as u32),
+        Direction::None => (FLAG_DIR_OUT, 0),
+    };
+    let req = Request {
+        signature: *USB_REQUEST_SIGNATURE,
+        tag: s.next_tag(),
+        length,
+        flag,
+        lun: 0,
+        command_length,
+        command,
+    };
+    debug!("Raw request: {:02x?}", req.as_bytes());
+    usb_send(s, req.as_bytes().to_vec())?;
+    let d = match direction {
+        Direction::In => usb_read(s, length as usize)?,
+        Direction::Out => {
+            usb_send(s, data.to_vec())?;
+            Vec::new()
         }
+        Direction::None => Vec::new(),
+    };
+    let status = try_response(s)?.map(|r| Status {
+        tag: r.tag,
+        tag_matches: r.tag == s.tag(),
+        residue: r.residue,
+        status: r.status,
+    });
+    Ok((d, status))
+}
+
+/// Reset the device; it drops off the bus right away
+pub fn reset(s: &Session) -> Result<(), Error> {
+    Cbw::new(Command::DeviceReset).send(s)?;
+    // Gone already is as good as an answer.
+    if !matches!(try_response(s), Ok(Some(_))) {
+        debug!("No response to reset");
     }
+    info!("Device reset");
+    Ok(())
 }
 
-pub fn run(i: &Interface, data: &[u8], region: &Region) {
-    let mut ext_data = data.to_vec();
-    // avoid splitting checksum across chunks, not sure if needed/why
-    if ext_data.len() % CHUNK_SIZE == 4095 {
-        ext_data.extend_from_slice(&[0]);
+/// Write memory through the loader
+pub fn mem_write(s: &Session, addr: u32, data: &[u8]) -> Result<(), Error> {
+    assert!(
+        addr as u64 + data.len() as u64 <= 1 << 32,
+        "{} bytes at {addr:08x} exceed the 32-bit address space",
+        data.len()
+    );
+    SDRAM_MOVER.each(s, data.len(), |done, n| {
+        let a = addr + done as u32;
+        debug!("Write {n} bytes at {a:08x}");
+        Cbw::new(Command::WriteSdram)
+            .address(a)
+            .size(n as u16)
+            .write(s, &data[done..done + n])?;
+        response(s)?;
+        Ok(())
+    })
+}
+
+// TODO: Are there other requests than this?
+const REQUEST: u8 = 0xc;
+
+fn usb_out(s: &Session, data: &[u8], region: &Region, tolerate_timeout: bool) -> Result<(), Error> {
+    let index = *region as u16; // where the mask ROM writes this;
+    let start = Instant::now();
+    deadline::check()?;
+    // The last chunk's timeout is expected; resending it would corrupt the
+    // download.
+    let res = match tolerate_timeout {
+        true => s
+            .transport()
+            .control_out(REQUEST, index, data, CONTROL_TIMEOUT),
+        false => retry::transfer(s.retry_policy(), "Control out", || {
+            s.transport()
+                .control_out(REQUEST, index, data, CONTROL_TIMEOUT)
+        }),
+    };
+    if let Ok(n) = res {
+        count_transfer(start, n);
     }
-    let checksum = CRC.checksum(&ext_data);
-    // Yes, this must be big endian.
-    ext_data.extend_from_slice(&checksum.to_be_bytes());
+
+    // NOTE: The last chunk often seems to time out.
+    match res {
+        Err(e) if e.kind() == io::ErrorKind::NotConnected => Err(Error::Disconnected.logged()),
+        Err(e) if tolerate_timeout => {
+            warn!(target: TRANSPORT, "{e:?} (tolerated)");
+            Ok(())
+        }
+        Err(e) => Err(Error::Transport(format!("control out: {e}")).logged()),
+        Ok(_) => Ok(()),
+    }
+}
+
+pub fn run(s: &Session, data: &[u8], region: &Region) -> Result<(), Error> {
+    let rom = s.chip.mask_rom;
+    let ext_data = rom.frame(data);
     let l = ext_data.len();
     info!("Send {l} bytes");
 
-    let full_chunks = l / CHUNK_SIZE;
-    info!("Send chunks");
-    for c in 0..full_chunks {
-        let o = c * CHUNK_SIZE;
-        info!("Send chunk {c} at offset {o:08x}");
-        let chunk = &ext_data[o..o + CHUNK_SIZE];
-        debug!("  first bytes: {:02x?}", &chunk[..4]);
-        debug!("  last bytes:  {:02x?}", &chunk[CHUNK_SIZE - 4..CHUNK_SIZE]);
-        usb_out(i, chunk, region, false);
-    }
-    if ext_data.len() % CHUNK_SIZE > 0 {
-        let o = full_chunks * CHUNK_SIZE;
-        let remaining = &ext_data[o..];
-        let l = remaining.len();
-        info!("Send remaining data, {l} bytes");
-        let f = l.min(4);
-        debug!("  first bytes: {:02x?}", &remaining[..f]);
-        if l > 4 {
-            debug!("  last bytes:  {:02x?}", &remaining[l - 4..l]);
+    let mover = Mover {
+        what: "Control transfer",
+        chunk: &|_| rom.chunk,
+        retries: false,
+        downshift: false,
+    };
+    mover.each(s, l, |o, n| {
+        let chunk = &ext_data[o..o + n];
+        info!("Send {n} bytes at offset {o:08x}");
+        debug!("  first bytes: {:02x?}", &chunk[..n.min(4)]);
+        if n > 4 {
+            debug!("  last bytes:  {:02x?}", &chunk[n - 4..]);
         }
-        usb_out(i, remaining, region, true);
-    } else {
-        info!("Send extra zero-byte for 4K-aligned blob");
-        usb_out(i, &[0], region, true);
+        // Only a short last chunk ends the download.
+        usb_out(s, chunk, region, n < rom.chunk)?;
+        progress::advance((o + n) as u64, l as u64);
+        Ok(())
+    })?;
+    if let Some(t) = rom.terminator(l) {
+        info!("Send {} terminating bytes for chunk-aligned blob", t.len());
+        usb_out(s, t, region, true)?;
     }
+    Ok(())
 }
@@ -0,0 +1,296 @@
+//! Flash each board with its own personality according to a manifest that
+//! maps a device identity, i.e., its USB port path or serial number, to a
+//! plan plus per-board data such as a serial number and MAC addresses.
+//!
+//! JSON manifests are a list of `{"id", "plan", "sn", "macs": [...]}`
+//! objects; CSV manifests have an `id,plan,sn,macs` header and separate
+//! multiple MACs by spaces. Plan steps can use `{sn}`, `{mac0}`, `{mac1}`,
+//! ..., `{id}`, `{port}` and `{serial}`. A board whose plan stopped halfway,
+//! e.g. as it took too long to come back from a reset, goes on after the
+//! last step done once it attaches again.
+
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+
+use crate::json::{self, Value};
+use crate::metrics::Failure;
+use crate::{DeviceAddr, checkpoint, follow, jobs, plan, progress};
+
+const POLL_PERIOD: Duration = Duration::from_secs(1);
+// Boards re-enumerate between stages, e.g., after usbplug has started.
+const REAPPEAR_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+struct Entry {
+    id: String,
+    plan: PathBuf,
+    sn: Option<String>,
+    macs: Vec<String>,
+}
+
+fn parse_json(text: &str, dir: &Path) -> Result<Vec<Entry>, String> {
+    let v = json::parse(text)?;
+    let list = v.as_array().ok_or("manifest must be a JSON list")?;
+    list.iter()
+        .map(|e| {
+            let s = |k| e.get(k).and_then(Value::as_str).map(String::from);
+            Ok(Entry {
+                id: s("id").ok_or("manifest entry without id")?,
+                plan: dir.join(s("plan").ok_or("manifest entry without plan")?),
+                sn: s("sn"),
+                macs: e
+                    .get("macs")
+                    .and_then(Value::as_array)
+                    .unwrap_or_default()
+                    .iter()
+                    .filter_map(|m| m.as_str().map(String::from))
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+fn parse_csv(text: &str, dir: &Path) -> Result<Vec<Entry>, String> {
+    let mut lines = text.lines().filter(|l| !l.trim().is_empty());
+    let header: Vec<&str> = lines
+        .next()
+        .unwrap_or("")
+        .split(',')
+        .map(str::trim)
+        .collect();
+    let col = |n| header.iter().position(|h| *h == n);
+    let (Some(id), Some(plan)) = (col("id"), col("plan")) else {
+        return Err("CSV manifest needs id and plan columns".into());
+    };
+    let (sn, macs) = (col("sn"), col("macs"));
+    lines
+        .map(|l| {
+            let f: Vec<&str> = l.split(',').map(str::trim).collect();
+            let get = |c: Option<usize>| c.and_then(|c| f.get(c)).filter(|v| !v.is_empty());
+            Ok(Entry {
+                id: get(Some(id)).ok_or(format!("no id in {l:?}"))?.to_string(),
+                plan: dir.join(get(Some(plan)).ok_or(format!("no plan in {l:?}"))?),
+                sn: get(sn).map(|s| s.to_string()),
+                macs: get(macs)
+                    .map(|m| m.split_whitespace().map(String::from).collect())
+                    .unwrap_or_default(),
+            })
+        })
+        .collect()
+}
+
+fn load(manifest: &Path) -> Result<Vec<Entry>, String> {
+    let text =
+        std::fs::read_to_string(manifest).map_err(|e| format!("{}: {e}", manifest.display()))?;
+    let dir = manifest.parent().unwrap_or(Path::new("."));
+    match manifest.extension().and_then(|e| e.to_str()) {
+        Some("csv") => parse_csv(&text, dir),
+        _ => parse_json(&text, dir),
+    }
+}
+
+/// Find a device by port path or serial number
+pub fn locate(id: &str) -> Option<DeviceAddr> {
+    crate::rockchip_devices()
+        .find(|d| crate::port_path(d).as_deref() == Some(id) || d.serial_number() == Some(id))
+        .map(|d| DeviceAddr {
+            bus: d.bus_number(),
+            address: d.device_address(),
+        })
+}
+
+fn wait_for(id: &str) -> Option<DeviceAddr> {
+    let start = Instant::now();
+    loop {
+        if let Some(d) = locate(id) {
+            return Some(d);
+        }
+        if start.elapsed() > REAPPEAR_TIMEOUT {
+            return None;
+        }
+        sleep(POLL_PERIOD);
+    }
+}
+
+/// Wait for a device to leave `old` and come back at another address, as
+/// after usbplug has started
+pub fn reappear(id: &str, old: DeviceAddr) -> Option<DeviceAddr> {
+    let start = Instant::now();
+    loop {
+        if let Some(d) = locate(id).filter(|d| *d != old) {
+            return Some(d);
+        }
+        if start.elapsed() > REAPPEAR_TIMEOUT {
+            return None;
+        }
+        sleep(POLL_PERIOD);
+    }
+}
+
+/// Run a plan on the device with the given port path or serial number,
+/// following it across re-enumeration; returns how many steps succeeded
+/// and the error. Progress is kept in `checkpoints`, so that a run cut
+/// short goes on where it stopped. `on_step` is told the number of each
+/// step as it starts, the number of steps and the command.
+pub fn apply(
+    id: &str,
+    plan_file: &Path,
+    vars: &HashMap<String, String>,
+    checkpoints: &Path,
+    on_step: &dyn Fn(usize, usize, &[String]),
+) -> (usize, Option<Failure>) {
+    let steps = match plan::load(plan_file) {
+        Ok(s) => s,
+        Err(err) => return (0, Some(Failure::Config(err))),
+    };
+    let done = checkpoint::load(checkpoints, id, plan_file).min(steps.len());
+    if done > 0 {
+        info!("{id}: resuming {} after step {done}", plan_file.display());
+    }
+    // Storage the loader was last switched to; any step without a declared
+    // storage may reset the board or switch on its own.
+    let mut current = None;
+    let _steps = progress::steps(steps.len());
+    for (n, step) in steps.iter().enumerate().skip(done) {
+        let res = plan::substitute(&step.args, vars)
+            .map_err(Failure::Config)
+            .and_then(|args| {
+                let args = crate::verifying(&args);
+                let d =
+                    wait_for(id).ok_or(Failure::Disconnect(format!("{id} did not come back")))?;
+                let device = ["--device".to_string(), d.to_string()];
+                if let Some(s) = step.storage.filter(|s| current != Some(*s)) {
+                    info!("{id}: switch storage to {s}");
+                    jobs::run_caught(
+                        &[&device[..], &["switch-storage".into(), s.to_string()]].concat(),
+                    )?;
+                }
+                let mut env = vars.clone();
+                env.insert("device".into(), d.to_string());
+                env.insert("step".into(), (n + 1).to_string());
+                plan::run_hooks(step, &plan::hook_env(&env))?;
+                info!("{id}: step {}: {}", n + 1, args.join(" "));
+                on_step(n + 1, steps.len(), &args);
+                progress::step(n + 1, &args.join(" "));
+                jobs::run_caught(&[&device[..], &args].concat())?;
+                if crate::may_move(&args) {
+                    follow::settle(id, d)?;
+                }
+                Ok(())
+            });
+        current = step.storage.filter(|_| res.is_ok());
+        let res = res.and_then(|()| Ok(checkpoint::save(checkpoints, id, plan_file, n + 1)?));
+        if let Err(err) = res {
+            let at = format!("{}:{}", plan_file.display(), step.line);
+            return (n, Some(err.map(|e| format!("{at}: {e}"))));
+        }
+    }
+    checkpoint::clear(checkpoints, id);
+    (steps.len(), None)
+}
+
+pub fn provision(
+    manifest: &Path,
+    record: &Path,
+    watch: bool,
+    checkpoints: &Path,
+) -> Result<(), String> {
+    let entries = load(manifest)?;
+    info!("{} boards in {}", entries.len(), manifest.display());
+    let mut record = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(record)
+        .map_err(|e| format!("{}: {e}", record.display()))?;
+
+    let mut done = HashSet::new();
+    let mut unknown = HashSet::new();
+    // Boards that stopped halfway get another go once they have been away,
+    // e.g. power cycled.
+    let mut unfinished = HashSet::new();
+    let mut failed = 0;
+    loop {
+        let present: Vec<(Option<String>, Option<String>)> = crate::rockchip_devices()
+            .map(|d| (crate::port_path(&d), d.serial_number().map(String::from)))
+            .collect();
+        unfinished.retain(|id: &String| {
+            let here = present
+                .iter()
+                .any(|(p, s)| p.as_ref() == Some(id) || s.as_ref() == Some(id));
+            if !here {
+                done.remove(id);
+            }
+            here
+        });
+        for d in crate::rockchip_devices() {
+            let port = crate::port_path(&d);
+            let serial = d.serial_number().map(String::from);
+            let Some(e) = entries
+                .iter()
+                .find(|e| Some(&e.id) == port.as_ref() || Some(&e.id) == serial.as_ref())
+            else {
+                let key = (d.bus_number(), d.device_address());
+                if unknown.insert(key) {
+                    warn!("{port:?} / {serial:?} is not in the manifest");
+                }
+                continue;
+            };
+            if !done.insert(e.id.clone()) {
+                continue;
+            }
+
+            let mut vars = HashMap::from([
+                ("id".to_string(), e.id.clone()),
+                ("port".to_string(), port.clone().unwrap_or_default()),
+                ("serial".to_string(), serial.clone().unwrap_or_default()),
+                ("sn".to_string(), e.sn.clone().unwrap_or_default()),
+            ]);
+            for (n, m) in e.macs.iter().enumerate() {
+                vars.insert(format!("mac{n}"), m.clone());
+            }
+            info!("Provision {} with {}", e.id, e.plan.display());
+            let start = Instant::now();
+            let (steps, err) = apply(&e.id, &e.plan, &vars, checkpoints, &|_, _, _| {});
+            crate::metrics::board_finished(start.elapsed(), err.as_ref());
+            match &err {
+                None => info!("{}: done", e.id),
+                Some(err) => {
+                    error!("{}: {err}", e.id);
+                    failed += 1;
+                    if checkpoint::pending(checkpoints, &e.id) {
+                        unfinished.insert(e.id.clone());
+                    }
+                }
+            }
+            let r = json::obj([
+                ("time", jiff::Timestamp::now().to_string().into()),
+                ("id", e.id.clone().into()),
+                ("port", port.into()),
+                ("serial", serial.into()),
+                ("plan", e.plan.to_string_lossy().to_string().into()),
+                ("sn", e.sn.clone().into()),
+                ("macs", e.macs.clone().into()),
+                ("steps_done", steps.into()),
+                (
+                    "status",
+                    if err.is_none() { "done" } else { "failed" }.into(),
+                ),
+                ("error", err.as_ref().map(Failure::to_string).into()),
+            ]);
+            writeln!(record, "{r}").map_err(|e| e.to_string())?;
+        }
+        if !watch {
+            break;
+        }
+        sleep(POLL_PERIOD);
+    }
+    match failed {
+        0 => Ok(()),
+        n => Err(format!("{n} boards failed")),
+    }
+}
@@ -0,0 +1,100 @@
+use std::path::Path;
+
+use clap_num::maybe_hex;
+
+/// Register names and addresses, keyed by chip name
+const BUILTIN: &[(&str, &[(&str, u32)])] = &[
+    (
+        "RK3399",
+        &[
+            ("PMUGRF", 0xff32_0000),
+            ("PMUGRF_OS_REG2", 0xff32_0308),
+            ("PMUGRF_OS_REG3", 0xff32_030c),
+            ("PMUCRU", 0xff75_0000),
+            ("CRU", 0xff76_0000),
+            ("GRF", 0xff77_0000),
+            ("GRF_SOC_CON0", 0xff77_e200),
+            ("GRF_SOC_CON1", 0xff77_e204),
+            ("GRF_SOC_CON2", 0xff77_e208),
+            ("GRF_SOC_CON3", 0xff77_e20c),
+            ("GRF_SOC_CON4", 0xff77_e210),
+            ("GRF_SOC_CON5", 0xff77_e214),
+            ("UART2", 0xff1a_0000),
+        ],
+    ),
+    (
+        "RK3588",
+        &[
+            ("PMU1GRF", 0xfd58_a000),
+            ("PMU1GRF_OS_REG2", 0xfd58_a208),
+            ("PMU1GRF_OS_REG3", 0xfd58_a20c),
+            ("SYS_GRF", 0xfd58_c000),
+            ("CRU", 0xfd7c_0000),
+            ("UART2", 0xfeb5_0000),
+        ],
+    ),
+];
+
+/// Named registers for one chip, built in and/or loaded from a file
+#[derive(Debug, Default)]
+pub struct RegMap {
+    regs: Vec<(String, u32)>,
+}
+
+impl RegMap {
+    pub fn builtin(chip: &str) -> Self {
+        let regs = BUILTIN
+            .iter()
+            .filter(|(c, _)| *c == chip)
+            .flat_map(|(_, r)| r.iter())
+            .map(|(n, a)| (n.to_string(), *a))
+            .collect();
+        Self { regs }
+    }
+
+    /// Add registers from a file with one `NAME ADDR` or `NAME = ADDR` per
+    /// line; `#` starts a comment. Entries override built-in ones.
+    pub fn load(&mut self, file: &Path) -> Result<(), String> {
+        let text = std::fs::read_to_string(file).map_err(|e| format!("{}: {e}", file.display()))?;
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut parts = line
+                .split(|c: char| c.is_whitespace() || c == '=')
+                .filter(|p| !p.is_empty());
+            let (Some(name), Some(addr), None) = (parts.next(), parts.next(), parts.next()) else {
+                return Err(format!("{}:{}: expected NAME ADDR", file.display(), n + 1));
+            };
+            let addr =
+                maybe_hex::<u32>(addr).map_err(|e| format!("{}:{}: {e}", file.display(), n + 1))?;
+            self.regs.retain(|(r, _)| !r.eq_ignore_ascii_case(name));
+            self.regs.push((name.to_string(), addr));
+        }
+        Ok(())
+    }
+
+    /// Resolve a register name or a plain address
+    pub fn resolve(&self, s: &str) -> Result<u32, String> {
+        if let Ok(a) = maybe_hex::<u32>(s) {
+            return Ok(a);
+        }
+        self.regs
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(s))
+            .map(|(_, a)| *a)
+            .ok_or(format!("{s} is neither an address nor a known register"))
+    }
+
+    pub fn name(&self, addr: u32) -> Option<&str> {
+        self.regs
+            .iter()
+            .find(|(_, a)| *a == addr)
+            .map(|(n, _)| n.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(String, u32)> {
+        self.regs.iter()
+    }
+}
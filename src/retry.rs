@@ -0,0 +1,240 @@
+//! Retry policy shared by USB transfers and whole commands
+//!
+//! Failures are put into the classes of `metrics::Failure`; only those of
+//! the configured classes are retried, after a backoff that doubles with
+//! each attempt up to `MAX_BACKOFF`. By default, nothing is retried.
+//!
+//! A policy file is TOML:
+//!
+//! ```toml
+//! [retry]
+//! attempts = 3
+//! backoff_ms = 200
+//! on = "timeout, disconnect"
+//! ```
+//!
+//! The `[retry]` header may be left out.
+
+use std::cell::Cell;
+use std::io;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::thread::sleep;
+use std::time::Duration;
+
+use log::warn;
+use serde::Deserialize;
+
+use crate::metrics::{self, Failure};
+use crate::{deadline, trace};
+
+pub const CLASSES: &[&str] = &[
+    "disconnect",
+    "timeout",
+    "wrong_mode",
+    "device",
+    "transport",
+    "verify",
+    "config",
+    "other",
+];
+
+/// Longest wait between two attempts, however many there are
+pub const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Policy {
+    /// Tries in total, including the first
+    pub attempts: u32,
+    pub backoff: Duration,
+    pub on: Vec<&'static str>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            attempts: 1,
+            backoff: Duration::from_millis(100),
+            // Lost and garbled LBA replies are transport failures.
+            on: vec!["timeout", "disconnect", "transport"],
+        }
+    }
+}
+
+static POLICY: OnceLock<Policy> = OnceLock::new();
+
+thread_local! {
+    static RETRIES: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Retries on this thread so far
+pub fn retries() -> u64 {
+    RETRIES.get()
+}
+
+/// Set the policy for the rest of the process; only the first call counts
+pub fn set(p: Policy) {
+    let _ = POLICY.set(p);
+}
+
+pub fn policy() -> &'static Policy {
+    POLICY.get_or_init(Policy::default)
+}
+
+pub fn parse_classes(list: &[String]) -> Result<Vec<&'static str>, String> {
+    list.iter()
+        .map(|c| {
+            let c = c.trim();
+            CLASSES.iter().find(|k| **k == c).copied().ok_or(format!(
+                "unknown failure class {c:?}, expected one of {}",
+                CLASSES.join(", ")
+            ))
+        })
+        .collect()
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Fields {
+    attempts: Option<u32>,
+    backoff_ms: Option<u64>,
+    on: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct File {
+    retry: Fields,
+}
+
+/// A policy from the text of a policy file
+pub fn parse(text: &str) -> Result<Policy, String> {
+    let table: toml::Table = toml::from_str(text).map_err(|e| e.to_string())?;
+    let f = match table.contains_key("retry") {
+        true => toml::from_str::<File>(text).map(|f| f.retry),
+        false => toml::from_str::<Fields>(text),
+    }
+    .map_err(|e| e.to_string())?;
+    let mut p = Policy::default();
+    if let Some(n) = f.attempts {
+        p.attempts = n;
+    }
+    if let Some(ms) = f.backoff_ms {
+        p.backoff = Duration::from_millis(ms);
+    }
+    if let Some(l) = f.on {
+        let l: Vec<String> = l.split(',').map(String::from).collect();
+        p.on = parse_classes(&l)?;
+    }
+    Ok(p)
+}
+
+pub fn load(file: &Path) -> Result<Policy, String> {
+    let text = std::fs::read_to_string(file).map_err(|e| format!("{}: {e}", file.display()))?;
+    parse(&text).map_err(|e| format!("{}: {e}", file.display()))
+}
+
+fn io_class(e: &io::Error) -> &'static str {
+    match e.kind() {
+        io::ErrorKind::TimedOut => "timeout",
+        io::ErrorKind::NotConnected => "disconnect",
+        _ => "transport",
+    }
+}
+
+/// Run `f` until it succeeds, fails with an error not to retry, or runs
+/// out of attempts
+pub fn with<T, E: std::fmt::Display>(
+    p: &Policy,
+    what: &str,
+    class: impl Fn(&E) -> &'static str,
+    mut f: impl FnMut() -> Result<T, E>,
+) -> Result<T, E> {
+    let mut backoff = p.backoff;
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Err(e) if attempt < p.attempts && p.on.contains(&class(&e)) && !deadline::expired() => {
+                warn!("{what} failed ({e}), retry {attempt} of {}", p.attempts - 1);
+                trace::retry(what, attempt, &e.to_string());
+                RETRIES.set(RETRIES.get() + 1);
+                sleep(backoff.min(MAX_BACKOFF));
+                backoff = backoff.saturating_mul(2).min(MAX_BACKOFF);
+                attempt += 1;
+            }
+            r => return r,
+        }
+    }
+}
+
+/// A USB transfer, under the policy of its session
+pub fn transfer<T>(p: &Policy, what: &str, f: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    with(p, what, io_class, f)
+}
+
+/// A whole command
+pub fn command(f: impl FnMut() -> Result<(), Failure>) -> Result<(), Failure> {
+    with(policy(), "Command", metrics::category, f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_policy() {
+        let p = Policy {
+            attempts: 3,
+            backoff: Duration::from_millis(1),
+            on: vec!["timeout"],
+        };
+        let class = |e: &io::Error| match e.kind() {
+            io::ErrorKind::TimedOut => "timeout",
+            _ => "other",
+        };
+        let mut tries = 0;
+        let r = with(&p, "test", class, || {
+            tries += 1;
+            match tries {
+                1 | 2 => Err(io::Error::from(io::ErrorKind::TimedOut)),
+                _ => Ok(tries),
+            }
+        });
+        assert_eq!(r.unwrap(), 3);
+
+        tries = 0;
+        let r: io::Result<()> = with(&p, "test", class, || {
+            tries += 1;
+            Err(io::Error::other("stall"))
+        });
+        assert!(r.is_err());
+        assert_eq!(tries, 1);
+
+        tries = 0;
+        let r: io::Result<()> = with(&p, "test", class, || {
+            tries += 1;
+            Err(io::Error::from(io::ErrorKind::TimedOut))
+        });
+        assert!(r.is_err());
+        assert_eq!(tries, 3);
+    }
+
+    #[test]
+    fn retry_policy_file() {
+        let text = r#"
+            # lab bench
+            [retry]
+            attempts = 3
+            backoff_ms = 1_000 # a second
+            on = "timeout, disconnect"
+        "#;
+        let p = parse(text).unwrap();
+        assert_eq!(p.attempts, 3);
+        assert_eq!(p.backoff, Duration::from_secs(1));
+        assert_eq!(p.on, vec!["timeout", "disconnect"]);
+        let err = parse("on = timeout").unwrap_err();
+        assert!(err.contains("line 1"), "{err}");
+        assert!(parse("attempts = 5000000000").is_err());
+        assert!(parse("on = \"cabling\"").is_err());
+    }
+}
@@ -0,0 +1,81 @@
+use std::path::{Path, PathBuf};
+
+use log::debug;
+
+use crate::chip::Chip;
+
+/// Version suffix of rkbin file names, e.g. `_v1.16.bin` => (1, 16)
+fn version(name: &str) -> Option<(u32, u32)> {
+    let stem = name.strip_suffix(".bin")?;
+    let (_, v) = stem.rsplit_once("_v")?;
+    let (major, minor) = v.split_once('.')?;
+    Some((major.parse().ok()?, minor.parse().ok()?))
+}
+
+fn collect(dir: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for e in entries.flatten() {
+        let p = e.path();
+        if p.is_dir() {
+            collect(&p, files);
+        } else {
+            files.push(p);
+        }
+    }
+}
+
+/// Pick the newest matching binary, or the one of the given version.
+/// Among equal versions, the shortest name wins; variants such as eyescan
+/// builds carry extra name parts.
+fn pick(files: &[PathBuf], kinds: &[&str], pin: Option<&str>) -> Option<PathBuf> {
+    let mut candidates: Vec<(&PathBuf, (u32, u32), usize)> = files
+        .iter()
+        .filter_map(|p| {
+            let n = p.file_name()?.to_str()?;
+            if !kinds.iter().any(|k| n.starts_with(k)) {
+                return None;
+            }
+            let v = version(n)?;
+            if let Some(pin) = pin {
+                let pin = pin.trim_start_matches('v');
+                if format!("{}.{:02}", v.0, v.1) != pin && format!("{}.{}", v.0, v.1) != pin {
+                    return None;
+                }
+            }
+            Some((p, v, n.len()))
+        })
+        .collect();
+    for (p, v, _) in &candidates {
+        debug!("  candidate {} (v{}.{:02})", p.display(), v.0, v.1);
+    }
+    candidates.sort_by(|a, b| b.1.cmp(&a.1).then(a.2.cmp(&b.2)));
+    candidates.first().map(|(p, _, _)| (*p).clone())
+}
+
+/// Find the DDR init and usbplug binaries for a chip in an rkbin checkout.
+/// The version pin applies to the usbplug, i.e., the loader.
+pub fn find(
+    dir: &Path,
+    chip: &Chip,
+    loader_version: Option<&str>,
+) -> Result<(PathBuf, PathBuf), String> {
+    let mut files = Vec::new();
+    collect(&dir.join("bin"), &mut files);
+    if files.is_empty() {
+        collect(dir, &mut files);
+    }
+
+    let p = chip.rkbin_prefix;
+    debug!("DDR init binaries for {p}:");
+    let k = format!("{p}_ddr_");
+    let ddr = pick(&files, &[&k], None).ok_or(format!("no {p}_ddr_*.bin in {}", dir.display()))?;
+    debug!("usbplug binaries for {p}:");
+    let (k1, k2) = (format!("{p}_usbplug_"), format!("{p}_usb_plug_"));
+    let usbplug = pick(&files, &[&k1, &k2], loader_version).ok_or(match loader_version {
+        Some(v) => format!("no {p} usbplug version {v} in {}", dir.display()),
+        None => format!("no {p}_usbplug_*.bin in {}", dir.display()),
+    })?;
+    Ok((ddr, usbplug))
+}
@@ -0,0 +1,75 @@
+//! Checks on what the device answers, with fallbacks for clones
+//!
+//! Some clone devices send their answers byte-swapped or shifted. Instead of
+//! failing on them, these fallbacks apply, with a warning:
+//!
+//! - A status block whose signature reads `SBSU` has its words byte-swapped;
+//!   from then on, the device counts as byte-swapped.
+//! - A status block after stray bytes is taken from where its signature
+//!   starts; the bytes it lacks are read after it.
+//! - A chip ID that matches the chip of the session only the wrong way
+//!   around is taken as it is, and the device counts as byte-swapped; so is
+//!   that of a device known to be byte-swapped.
+//! - Flash info of a byte-swapped device is swapped back.
+//!
+//! Unexpected values in reserved fields are only logged.
+
+use log::{debug, warn};
+
+use crate::protocol::FlashInfo;
+use crate::session::Session;
+
+const SIGNATURE: &[u8; 4] = b"USBS";
+const SWAPPED_SIGNATURE: &[u8; 4] = b"SBSU";
+
+/// Where the status block in `buf` starts, and whether it is byte-swapped
+pub fn status_block(buf: &[u8]) -> Option<(usize, bool)> {
+    buf.windows(4).enumerate().find_map(|(at, w)| match w {
+        w if w == SIGNATURE => Some((at, false)),
+        w if w == SWAPPED_SIGNATURE => Some((at, true)),
+        _ => None,
+    })
+}
+
+/// Take the device as one that swaps bytes, e.g. as its status says
+pub fn byte_swapped(s: &Session, what: &str) {
+    if !s.swapped() {
+        warn!("{what} is byte-swapped, as with some clones; swapping answers back");
+        s.set_swapped();
+    }
+}
+
+/// The chip ID from the first bytes of chip info, e.g. "3588"
+pub fn chip_id(s: &Session, info: &[u8]) -> String {
+    let raw = &info[..4];
+    let mut id = raw.to_vec();
+    // The ID is sent reversed, least significant byte first.
+    id.reverse();
+    let text = |b: &[u8]| String::from_utf8_lossy(b).to_string();
+    if s.swapped() || !s.chip.name.ends_with(&text(&id)) && s.chip.name.ends_with(&text(raw)) {
+        byte_swapped(s, "Chip ID");
+        id = raw.to_vec();
+    }
+    if !id.iter().all(u8::is_ascii_alphanumeric) {
+        warn!("Chip ID {id:02x?} is not text; the chip info looks garbled");
+    }
+    if info[4..].iter().any(|&b| b != 0xff) {
+        debug!("Chip info has more than the ID: {:02x?}", &info[4..]);
+    }
+    text(&id)
+}
+
+/// Flash info in the byte order of the host
+pub fn flash_info(s: &Session, mut fi: FlashInfo) -> FlashInfo {
+    if s.swapped() {
+        fi.sectors = fi.sectors.swap_bytes();
+        fi.block_sectors = fi.block_sectors.swap_bytes();
+    }
+    if fi.sectors == 0 {
+        warn!("Loader reports storage of no sectors");
+    }
+    if !fi.block_sectors.is_power_of_two() {
+        warn!("Loader reports blocks of {} sectors", { fi.block_sectors });
+    }
+    fi
+}
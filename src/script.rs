@@ -0,0 +1,87 @@
+//! Record the commands of a session, such as a manual recovery or a plan
+//! run, as a shell script to replay on the next board. The script checks
+//! that the images it uses are still the same ones by their SHA-256.
+
+use std::path::Path;
+use std::sync::Mutex;
+
+use log::info;
+
+use crate::cache;
+
+static SESSION: Mutex<Option<Vec<Vec<String>>>> = Mutex::new(None);
+
+pub fn start() {
+    *SESSION.lock().unwrap() = Some(Vec::new());
+}
+
+/// Drop options that only make sense for this particular board or run
+fn portable(args: &[String]) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut it = args.iter();
+    while let Some(a) = it.next() {
+        if a == "--device" || a == "--export-script" {
+            it.next();
+        } else if !a.starts_with("--device=") && !a.starts_with("--export-script=") {
+            out.push(a.clone());
+        }
+    }
+    out
+}
+
+/// Note a command line that has succeeded
+pub fn record(args: &[String]) {
+    if let Some(s) = SESSION.lock().unwrap().as_mut() {
+        s.push(portable(args));
+    }
+}
+
+fn quote(a: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "_-./:=@%+,".contains(c);
+    if !a.is_empty() && a.chars().all(plain) {
+        a.to_string()
+    } else {
+        format!("'{}'", a.replace('\'', r"'\''"))
+    }
+}
+
+pub fn export(path: &Path) -> Result<(), String> {
+    let cmds = SESSION.lock().unwrap().take().unwrap_or_default();
+
+    let mut images: Vec<(String, String)> = Vec::new();
+    for a in cmds.iter().flatten() {
+        let p = Path::new(a);
+        if p.is_file() && !images.iter().any(|(f, _)| f == a) {
+            let d = cache::digest_of(p).map_err(|e| format!("{a}: {e}"))?;
+            images.push((a.clone(), d));
+        }
+    }
+
+    let mut s = String::from("#!/bin/sh\n");
+    s += &format!(
+        "# Exported by rk_boot {} at {}\nset -e\n",
+        env!("CARGO_PKG_VERSION"),
+        jiff::Timestamp::now()
+    );
+    if !images.is_empty() {
+        s += "\nsha256sum -c - <<'EOF'\n";
+        for (f, h) in &images {
+            s += &format!("{h}  {f}\n");
+        }
+        s += "EOF\n";
+    }
+    s += "\n";
+    for c in &cmds {
+        let line: Vec<String> = c.iter().map(|a| quote(a)).collect();
+        s += &format!("rk_boot {}\n", line.join(" "));
+    }
+
+    std::fs::write(path, s).map_err(|e| format!("{}: {e}", path.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let _ = std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755));
+    }
+    info!("{} commands exported to {}", cmds.len(), path.display());
+    Ok(())
+}
@@ -0,0 +1,498 @@
+//! HTTP API for lab automation
+//!
+//! - `GET /devices` lists attached Rockchip devices
+//! - `GET /images` lists uploaded images, `PUT /images/<name>` uploads one
+//! - `GET /blobs` lists images kept by SHA-256, `GET /blobs/<digest>` tells
+//!   whether one is there, `PUT /blobs/<digest>` uploads one
+//! - `POST /jobs` with `{"args": ["boot", "--auto"]}` runs a command line;
+//!   an argument `image:<name>` refers to an uploaded image, one
+//!   `sha256:<digest>` to an image kept by its digest
+//! - `GET /jobs` and `GET /jobs/<id>` report job status
+//! - `GET /jobs/<id>/log` streams a job's log until it has ended
+//! - `GET /stats` reports job counts and aggregate throughput
+//! - `GET /metrics` exports counters and histograms for Prometheus
+//! - `POST /reset` resets the device; `{"device": "BUS:ADDRESS"}` picks one
+//!
+//! Requests other than `/metrics` need the daemon's token, as
+//! `Authorization: Bearer <token>`. Jobs write files only to the image
+//! directory or `--workdir`, and run no plan hooks.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use clap::Args;
+use log::{debug, info, warn};
+use serde::Deserialize;
+
+use crate::jobs::Jobs;
+use crate::json::{self, Value};
+use crate::{cache, size, workdir};
+
+/// Longest request or header line taken
+const MAX_LINE: u64 = 8 << 10;
+/// Most header lines taken
+const MAX_HEADERS: usize = 100;
+/// Largest JSON request body taken
+const MAX_BODY: u64 = 1 << 20;
+/// Connections served at once; more are turned away
+const MAX_CONNECTIONS: usize = 64;
+/// How long a client may keep a connection silent
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Connections being served
+static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// A slot among `MAX_CONNECTIONS`, freed when dropped
+struct Connection;
+
+impl Connection {
+    fn take() -> Option<Self> {
+        if CONNECTIONS.fetch_add(1, Ordering::SeqCst) < MAX_CONNECTIONS {
+            return Some(Self);
+        }
+        CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
+        None
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct Limits {
+    /// File with the token that clients must send; defaults to
+    /// $RK_BOOT_TOKEN
+    #[clap(long)]
+    pub token_file: Option<PathBuf>,
+    /// Largest image to take in an upload
+    #[clap(long, value_parser = size::bytes, default_value = "64GiB")]
+    pub max_upload: u64,
+    /// Jobs to keep queued or running; more are refused
+    #[clap(long, default_value = "32")]
+    pub max_jobs: usize,
+}
+
+/// What requests are served with
+struct Server {
+    jobs: Arc<Jobs>,
+    images: PathBuf,
+    token: String,
+    max_upload: u64,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    content_length: u64,
+    authorization: Option<String>,
+}
+
+fn read_line(r: &mut impl BufRead) -> io::Result<String> {
+    let mut line = String::new();
+    r.take(MAX_LINE).read_line(&mut line)?;
+    if !line.ends_with('\n') {
+        return Err(io::Error::other("request line too long or cut off"));
+    }
+    Ok(line)
+}
+
+fn read_request(r: &mut impl BufRead) -> io::Result<Request> {
+    let line = read_line(r)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Err(io::Error::other(format!("bad request line {line:?}")));
+    };
+    let mut req = Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        content_length: 0,
+        authorization: None,
+    };
+    for _ in 0..MAX_HEADERS {
+        let h = read_line(r)?;
+        let h = h.trim_end();
+        if h.is_empty() {
+            return Ok(req);
+        }
+        let Some((k, v)) = h.split_once(':') else {
+            continue;
+        };
+        if k.eq_ignore_ascii_case("content-length") {
+            req.content_length = v.trim().parse().map_err(io::Error::other)?;
+        } else if k.eq_ignore_ascii_case("authorization") {
+            req.authorization = Some(v.trim().to_string());
+        }
+    }
+    Err(io::Error::other("too many header lines"))
+}
+
+/// The token clients must send, from a file or the environment
+fn token(file: Option<&Path>) -> Result<String, String> {
+    let t = match file {
+        Some(f) => std::fs::read_to_string(f).map_err(|e| format!("{}: {e}", f.display()))?,
+        None => std::env::var("RK_BOOT_TOKEN").unwrap_or_default(),
+    };
+    match t.trim() {
+        "" => Err("serve needs a token for clients, in --token-file or $RK_BOOT_TOKEN".into()),
+        t => Ok(t.to_string()),
+    }
+}
+
+/// Whether a request carries the token; compared in constant time, so that
+/// response times tell nothing about it
+fn authorized(req: &Request, token: &str) -> bool {
+    let Some(given) = req
+        .authorization
+        .as_deref()
+        .and_then(|a| a.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    let (a, b) = (given.trim().as_bytes(), token.as_bytes());
+    a.len() == b.len() && a.iter().zip(b).fold(0, |d, (x, y)| d | (x ^ y)) == 0
+}
+
+/// The body of a JSON request, unless it is too large
+fn read_body(r: &mut impl Read, len: u64) -> io::Result<Option<String>> {
+    if len > MAX_BODY {
+        return Ok(None);
+    }
+    let mut body = String::new();
+    r.take(len).read_to_string(&mut body)?;
+    Ok(Some(body))
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Content Too Large",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+fn respond(s: &mut TcpStream, status: u16, body: &Value) -> io::Result<()> {
+    let body = format!("{body}\n");
+    write!(
+        s,
+        "HTTP/1.1 {status} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        reason(status),
+        body.len()
+    )
+}
+
+fn respond_metrics(s: &mut TcpStream) -> io::Result<()> {
+    let body = crate::metrics::render();
+    write!(
+        s,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+fn error(s: &mut TcpStream, status: u16, msg: &str) -> io::Result<()> {
+    respond(s, status, &json::obj([("error", msg.into())]))
+}
+
+/// What USB tells about a device
+pub fn device_json(d: &nusb::DeviceInfo) -> Value {
+    json::obj([
+        ("bus", d.bus_number().into()),
+        ("address", d.device_address().into()),
+        ("vid", format!("{:04x}", d.vendor_id()).into()),
+        ("pid", format!("{:04x}", d.product_id()).into()),
+        (
+            "chip",
+            crate::chip::by_pid(d.product_id()).map(|c| c.name).into(),
+        ),
+        ("product", d.product_string().into()),
+    ])
+}
+
+pub fn devices_json() -> Value {
+    Value::Arr(crate::rockchip_devices().map(|d| device_json(&d)).collect())
+}
+
+fn valid_image_name(n: &str) -> bool {
+    !n.is_empty() && !n.starts_with('.') && !n.contains(['/', '\\'])
+}
+
+fn images_json(dir: &Path) -> Value {
+    let mut images: Vec<Value> = std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|e| {
+            let size = e.metadata().map(|m| m.len()).unwrap_or(0);
+            json::obj([
+                ("name", e.file_name().to_string_lossy().to_string().into()),
+                ("size", size.into()),
+            ])
+        })
+        .collect();
+    images.sort_by_key(|i| i.to_string());
+    Value::Arr(images)
+}
+
+/// Body of a job request
+#[derive(Deserialize)]
+struct JobRequest {
+    args: Vec<String>,
+}
+
+/// Body of a reset request; without a device, the only one is reset
+#[derive(Deserialize)]
+struct ResetRequest {
+    device: Option<String>,
+}
+
+/// Turn a job request body into arguments, mapping uploaded image names
+fn job_args(body: &str, images: &Path) -> Result<Vec<String>, String> {
+    let req: JobRequest =
+        serde_json::from_str(body).map_err(|e| format!("expected {{\"args\": [strings]}}: {e}"))?;
+    req.args
+        .into_iter()
+        .map(|a| match a.strip_prefix("image:") {
+            Some(n) if valid_image_name(n) => {
+                let p = images.join(n);
+                if !p.is_file() {
+                    return Err(format!("no image {n}"));
+                }
+                Ok(p.to_string_lossy().to_string())
+            }
+            Some(n) => Err(format!("bad image name {n:?}")),
+            None => cache::resolve(&a),
+        })
+        .collect()
+}
+
+fn stream_log(s: &mut TcpStream, job: &crate::jobs::Job) -> io::Result<()> {
+    write!(
+        s,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nConnection: close\r\n\r\n"
+    )?;
+    let mut n = 0;
+    loop {
+        let (lines, ended) = job.wait_log(n);
+        n += lines.len();
+        for l in lines {
+            writeln!(s, "{l}")?;
+        }
+        s.flush()?;
+        if ended {
+            return writeln!(s, "{}", job.to_json());
+        }
+    }
+}
+
+fn handle(mut s: TcpStream, srv: &Server) -> io::Result<()> {
+    s.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut r = BufReader::new(s.try_clone()?);
+    let req = read_request(&mut r)?;
+    debug!("{} {}", req.method, req.path);
+    let (jobs, images) = (&srv.jobs, srv.images.as_path());
+
+    let path: Vec<&str> = req.path.trim_matches('/').split('/').collect();
+    if path != ["metrics"] && !authorized(&req, &srv.token) {
+        return error(&mut s, 401, "missing or wrong token");
+    }
+    match (req.method.as_str(), path.as_slice()) {
+        ("GET", ["devices"]) => respond(&mut s, 200, &devices_json()),
+        ("GET", ["images"]) => respond(&mut s, 200, &images_json(images)),
+        ("PUT", ["images" | "blobs", _]) if req.content_length > srv.max_upload => {
+            let max = size::human(srv.max_upload);
+            error(&mut s, 413, &format!("images may be {max} at most"))
+        }
+        ("PUT", ["images", name]) => {
+            if !valid_image_name(name) {
+                return error(&mut s, 400, "bad image name");
+            }
+            // Received aside, so that a broken upload never shows as an image
+            let part = workdir::file(name).map_err(io::Error::other)?;
+            let mut f = std::fs::File::create(part.path())?;
+            let n = io::copy(&mut r.by_ref().take(req.content_length), &mut f)?;
+            if n != req.content_length {
+                return error(&mut s, 400, "upload incomplete");
+            }
+            part.keep(&images.join(name)).map_err(io::Error::other)?;
+            info!("Received image {name}, {}", size::human(n));
+            respond(
+                &mut s,
+                201,
+                &json::obj([("name", (*name).into()), ("size", n.into())]),
+            )
+        }
+        ("GET", ["blobs"]) => respond(&mut s, 200, &cache::list()),
+        ("GET", ["blobs", digest]) => match cache::path(digest) {
+            Some(p) => {
+                let size = p.metadata()?.len();
+                respond(
+                    &mut s,
+                    200,
+                    &json::obj([("digest", (*digest).into()), ("size", size.into())]),
+                )
+            }
+            None => error(&mut s, 404, "no such image"),
+        },
+        ("PUT", ["blobs", digest]) => {
+            let mut body = r.by_ref().take(req.content_length);
+            if cache::path(digest).is_some() {
+                io::copy(&mut body, &mut io::sink())?;
+                return respond(&mut s, 200, &json::obj([("digest", (*digest).into())]));
+            }
+            match cache::put(digest, &mut body, req.content_length) {
+                Ok(()) => {
+                    info!(
+                        "Received image sha256:{digest}, {}",
+                        size::human(req.content_length)
+                    );
+                    respond(&mut s, 201, &json::obj([("digest", (*digest).into())]))
+                }
+                Err(e) => error(&mut s, 400, &e),
+            }
+        }
+        ("GET", ["jobs"]) => {
+            let l: Vec<Value> = jobs.list().iter().map(|j| j.to_json()).collect();
+            respond(&mut s, 200, &Value::Arr(l))
+        }
+        ("POST", ["jobs"]) => {
+            let Some(body) = read_body(&mut r, req.content_length)? else {
+                return error(&mut s, 413, "request too large");
+            };
+            match job_args(&body, images).and_then(|a| jobs.submit(a)) {
+                Ok(job) => {
+                    info!("Job {}: {:?}", job.id, job.args);
+                    respond(&mut s, 201, &job.to_json())
+                }
+                Err(e) => error(&mut s, 400, &e),
+            }
+        }
+        ("GET", ["jobs", id, rest @ ..]) => {
+            let Some(job) = id.parse().ok().and_then(|id| jobs.get(id)) else {
+                return error(&mut s, 404, "no such job");
+            };
+            match rest {
+                [] => respond(&mut s, 200, &job.to_json()),
+                ["log"] => stream_log(&mut s, &job),
+                _ => error(&mut s, 404, "not found"),
+            }
+        }
+        ("GET", ["stats"]) => respond(&mut s, 200, &jobs.stats()),
+        ("GET", ["metrics"]) => respond_metrics(&mut s),
+        ("POST", ["reset"]) => {
+            let Some(body) = read_body(&mut r, req.content_length)? else {
+                return error(&mut s, 413, "request too large");
+            };
+            let mut args = vec!["reset".to_string()];
+            if let Ok(ResetRequest { device: Some(d) }) = serde_json::from_str(&body) {
+                args.extend(["--device".into(), d]);
+            }
+            match jobs.submit(args) {
+                Ok(job) => respond(&mut s, 201, &job.to_json()),
+                Err(e) => error(&mut s, 400, &e),
+            }
+        }
+        (
+            _,
+            [
+                "devices" | "images" | "blobs" | "jobs" | "stats" | "metrics" | "reset",
+                ..,
+            ],
+        ) => error(&mut s, 405, "method not allowed"),
+        _ => error(&mut s, 404, "not found"),
+    }
+}
+
+/// How often to look whether the daemon has been idle long enough
+const IDLE_POLL: Duration = Duration::from_secs(1);
+
+pub fn serve(
+    listen: &str,
+    images: PathBuf,
+    max_per_bus: usize,
+    exit_idle: Option<Duration>,
+    limits: Limits,
+) -> Result<(), String> {
+    let token = token(limits.token_file.as_deref())?;
+    std::fs::create_dir_all(&images).map_err(|e| format!("{}: {e}", images.display()))?;
+    cache::set_dir(images.join("sha256"));
+    // Jobs come from the network: they write files only where clients
+    // could have put them anyway, or where the operator said.
+    let mut dirs = vec![images.clone()];
+    if workdir::base() != std::env::temp_dir() {
+        dirs.push(workdir::base().into());
+    }
+    crate::jobs::confine(&dirs)?;
+    let listener = TcpListener::bind(listen).map_err(|e| format!("{listen}: {e}"))?;
+    info!("Listening on {listen}, images in {}", images.display());
+
+    let jobs = Arc::new(Jobs::new(max_per_bus, limits.max_jobs));
+    if let Some(limit) = exit_idle {
+        let jobs = jobs.clone();
+        std::thread::spawn(move || {
+            loop {
+                std::thread::sleep(IDLE_POLL.min(limit));
+                if jobs.idle().is_some_and(|t| t >= limit) {
+                    info!("No jobs for {}s, exiting", limit.as_secs());
+                    workdir::cleanup();
+                    std::process::exit(0);
+                }
+            }
+        });
+    }
+    let srv = Arc::new(Server {
+        jobs,
+        images,
+        token,
+        max_upload: limits.max_upload,
+    });
+    for s in listener.incoming() {
+        let Ok(mut s) = s else {
+            continue;
+        };
+        let Some(conn) = Connection::take() else {
+            let _ = error(&mut s, 503, "too many connections");
+            continue;
+        };
+        let srv = srv.clone();
+        std::thread::spawn(move || {
+            let _conn = conn;
+            if let Err(e) = handle(s, &srv) {
+                warn!("Request failed: {e}");
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Serve only `/metrics` in the background, for modes without the full API
+pub fn serve_metrics(listen: &str) -> Result<(), String> {
+    let listener = TcpListener::bind(listen).map_err(|e| format!("{listen}: {e}"))?;
+    info!("Metrics on http://{listen}/metrics");
+    std::thread::spawn(move || {
+        for mut s in listener.incoming().flatten() {
+            let res = BufReader::new(&s).lines().next().transpose().and_then(|l| {
+                match l.as_deref().and_then(|l| l.split(' ').nth(1)) {
+                    Some("/metrics") => respond_metrics(&mut s),
+                    _ => error(&mut s, 404, "not found"),
+                }
+            });
+            if let Err(e) = res {
+                warn!("Request failed: {e}");
+            }
+        }
+    });
+    Ok(())
+}
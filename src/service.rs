@@ -0,0 +1,246 @@
+//! Zero-touch flashing station: run a plan on every board that attaches
+//!
+//! Status goes to stdout as one JSON object per line, with an `event` of
+//! `attached`, `started`, `step`, `progress`, `done`, `failed` or
+//! `detached`; `progress` has the percentage of the whole plan, the step
+//! and what of it is done, as the progress model tells. The same
+//! lines go to every client of a Unix socket, if one is given, for GUIs and
+//! line control to follow. Optionally, Prometheus metrics are served on
+//! `/metrics`.
+
+use std::collections::HashMap;
+#[cfg(unix)]
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_io::block_on;
+use futures_lite::StreamExt;
+use log::{info, warn};
+use nusb::hotplug::HotplugEvent;
+use nusb::{DeviceId, DeviceInfo};
+
+use crate::json::Value;
+use crate::{checkpoint, chip, metrics, progress, provision};
+
+/// Clients of the event socket
+#[cfg(unix)]
+static SUBSCRIBERS: Mutex<Vec<UnixStream>> = Mutex::new(Vec::new());
+/// A client that takes longer to read an event is dropped
+const EVENT_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Send events to clients that connect to a Unix socket at `path`
+#[cfg(unix)]
+pub fn listen_events(path: &Path) -> Result<(), String> {
+    use std::os::unix::fs::FileTypeExt;
+    let err = |e: std::io::Error| format!("{}: {e}", path.display());
+    // One left behind by an earlier run would be in the way.
+    if let Ok(m) = std::fs::symlink_metadata(path) {
+        if !m.file_type().is_socket() {
+            return Err(format!("{} exists and is not a socket", path.display()));
+        }
+        std::fs::remove_file(path).map_err(err)?;
+    }
+    let l = UnixListener::bind(path).map_err(err)?;
+    info!("Events on {}", path.display());
+    std::thread::spawn(move || {
+        for s in l.incoming().flatten() {
+            if s.set_write_timeout(Some(EVENT_WRITE_TIMEOUT)).is_ok() {
+                SUBSCRIBERS.lock().unwrap().push(s);
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn listen_events(_: &Path) -> Result<(), String> {
+    Err("An event socket needs Unix domain sockets".into())
+}
+
+pub fn emit(event: &str, port: &str, extra: Vec<(&str, Value)>) {
+    let mut o = vec![
+        (
+            "time".to_string(),
+            jiff::Timestamp::now().to_string().into(),
+        ),
+        ("event".to_string(), event.into()),
+        ("port".to_string(), port.into()),
+    ];
+    o.extend(extra.into_iter().map(|(k, v)| (k.to_string(), v)));
+    let line = Value::Obj(o).to_string();
+    println!("{line}");
+    #[cfg(unix)]
+    SUBSCRIBERS
+        .lock()
+        .unwrap()
+        .retain_mut(|s| writeln!(s, "{line}").is_ok());
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    Running,
+    Finished,
+}
+
+struct Service {
+    plan: PathBuf,
+    checkpoints: PathBuf,
+    /// Boards by port path; a port is free again once its board is gone
+    boards: Mutex<HashMap<String, State>>,
+}
+
+impl Service {
+    fn attached(self: &Arc<Self>, di: &DeviceInfo) {
+        let Some(chip) = chip::by_pid(di.product_id()) else {
+            return;
+        };
+        let Some(port) = crate::port_path(di) else {
+            warn!("Cannot follow {di:?} without a port path");
+            return;
+        };
+        // The board re-enumerates as it goes through the plan's stages. One
+        // that stopped halfway and comes back, e.g. power cycled, goes on.
+        match self.boards.lock().unwrap().get(&port) {
+            Some(State::Finished) if checkpoint::pending(&self.checkpoints, &port) => {}
+            Some(_) => return,
+            None => {}
+        }
+        self.boards
+            .lock()
+            .unwrap()
+            .insert(port.clone(), State::Running);
+        emit("attached", &port, vec![("chip", chip.name.into())]);
+
+        let s = self.clone();
+        std::thread::spawn(move || {
+            let vars = HashMap::from([
+                ("id".to_string(), port.clone()),
+                ("port".to_string(), port.clone()),
+            ]);
+            emit("started", &port, vec![]);
+            let at = port.clone();
+            progress::set_sink(Some(Box::new(move |r| {
+                emit("progress", &at, r.fields());
+            })));
+            let start = Instant::now();
+            let step = |n: usize, of: usize, args: &[String]| {
+                emit(
+                    "step",
+                    &port,
+                    vec![
+                        ("step", n.into()),
+                        ("steps", of.into()),
+                        ("command", args.join(" ").into()),
+                    ],
+                )
+            };
+            let (steps, err) = provision::apply(&port, &s.plan, &vars, &s.checkpoints, &step);
+            metrics::board_finished(start.elapsed(), err.as_ref());
+            match err {
+                None => emit("done", &port, vec![("steps_done", steps.into())]),
+                Some(e) => emit(
+                    "failed",
+                    &port,
+                    vec![
+                        ("steps_done", steps.into()),
+                        ("error", e.to_string().into()),
+                    ],
+                ),
+            }
+            s.boards.lock().unwrap().insert(port, State::Finished);
+        });
+    }
+
+    fn detached(&self, port: &str) {
+        let mut boards = self.boards.lock().unwrap();
+        if boards.get(port) == Some(&State::Finished) {
+            boards.remove(port);
+            emit("detached", port, vec![]);
+        }
+    }
+}
+
+// Disconnect events only carry the ID, so remember where devices were.
+fn track(ports: &mut HashMap<DeviceId, String>, di: &DeviceInfo) {
+    if let Some(p) = crate::port_path(di) {
+        ports.insert(di.id(), p);
+    }
+}
+
+pub fn service(
+    plan: &Path,
+    metrics_listen: Option<&str>,
+    checkpoints: &Path,
+    events: Option<&Path>,
+) -> Result<(), String> {
+    crate::plan::load(plan)?;
+    if let Some(l) = metrics_listen {
+        crate::server::serve_metrics(l)?;
+    }
+    if let Some(p) = events {
+        listen_events(p)?;
+    }
+    let mut watch = nusb::watch_devices().map_err(|e| e.to_string())?;
+    let s = Arc::new(Service {
+        plan: plan.to_path_buf(),
+        checkpoints: checkpoints.to_path_buf(),
+        boards: Mutex::default(),
+    });
+    info!("Waiting for boards to run {} on", plan.display());
+
+    let mut ports: HashMap<DeviceId, String> = HashMap::new();
+    for di in crate::rockchip_devices() {
+        track(&mut ports, &di);
+        s.attached(&di);
+    }
+    while let Some(ev) = block_on(watch.next()) {
+        match ev {
+            HotplugEvent::Connected(di) if di.vendor_id() == crate::USB_VID_RK => {
+                track(&mut ports, &di);
+                s.attached(&di);
+            }
+            HotplugEvent::Connected(_) => {}
+            HotplugEvent::Disconnected(id) => {
+                if let Some(p) = ports.remove(&id) {
+                    s.detached(&p);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn service_events_go_to_socket_clients() {
+        use std::io::BufRead;
+        let path = std::env::temp_dir().join(format!("rk_boot-events-{}.sock", std::process::id()));
+        std::fs::write(&path, "").unwrap();
+        assert!(listen_events(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+        listen_events(&path).unwrap();
+        let c = UnixStream::connect(&path).unwrap();
+        c.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        // The listener takes the client on its own thread, so events may
+        // go out before it does.
+        let mut lines = std::io::BufReader::new(c).lines();
+        let line = loop {
+            emit("step", "1-2", vec![("step", 1.into())]);
+            if let Some(Ok(l)) = lines.next() {
+                break l;
+            }
+        };
+        let v = crate::json::parse(&line).unwrap();
+        assert_eq!(v.get("event").and_then(Value::as_str), Some("step"));
+        assert_eq!(v.get("port").and_then(Value::as_str), Some("1-2"));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
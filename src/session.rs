@@ -0,0 +1,248 @@
+//! An open connection to a device, and what is known about it
+//!
+//! Protocol functions take a session rather than a transport and its
+//! endpoints, so that state carried from one command to the next, such as
+//! the storage the loader uses or the tag of the last request, lives in one
+//! place. That includes how the link has been doing: the transfer size and
+//! the failures that shrank it belong to the board, so that one flaky board
+//! does not slow down others served by the same process. A board that
+//! is connected to again, e.g. when a command is retried, starts at the
+//! transfer size it was downshifted to.
+//!
+//! A read-only session refuses the requests that change the device for
+//! good: writes to storage, erases, eFuses, the reset flag and running
+//! code. It is for exploring a device without any way of damaging it.
+
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use log::{debug, info, warn};
+
+use crate::Mode;
+use crate::chip::Chip;
+use crate::metrics::Failure;
+use crate::protocol::{
+    DOWNSHIFT_AFTER, LBA_CHUNK_SECTORS, MAX_LBA_CHUNK_SECTORS, MIN_LBA_CHUNK_SECTORS, Storage,
+    Transport,
+};
+use crate::{lock, retry};
+
+// Any value works, the device just echoes it back.
+const FIRST_TAG: u32 = 0x13372342;
+
+/// Transfer sizes boards were downshifted to, by port
+static DOWNSHIFTED: Mutex<BTreeMap<String, u32>> = Mutex::new(BTreeMap::new());
+
+thread_local! {
+    static READ_ONLY: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Open sessions read-only from now on; for this thread, i.e. this command
+pub fn set_read_only(on: bool) {
+    READ_ONLY.set(on);
+}
+
+pub struct Session {
+    i: Arc<dyn Transport + Send + Sync>,
+    pub e_in: u8,
+    pub e_out: u8,
+    pub chip: &'static Chip,
+    pub mode: Mode,
+    /// Max packet size of the bulk endpoints, by the bus speed
+    pub packet_size: usize,
+    /// Storage the loader uses, once switched to or read
+    storage: Mutex<Option<Storage>>,
+    /// Tag of the last request
+    tag: AtomicU32,
+    /// Whether the device sends its fields byte-swapped, as some clones do
+    swapped: AtomicBool,
+    /// Refuse requests that change the device
+    read_only: bool,
+    /// How failed transfers and LBA commands are tried again
+    retry: retry::Policy,
+    /// Sectors to move per LBA command
+    lba_chunk: AtomicU32,
+    /// Failed transfers since the last downshift, in all, and downshifts
+    failures: AtomicU32,
+    failures_total: AtomicU32,
+    downshifts: AtomicU32,
+    /// Where the board is connected, to remember its transfer size by
+    port: Option<String>,
+    /// Keeps other processes off the device
+    _lock: Option<lock::Guard>,
+}
+
+impl Session {
+    pub fn open(
+        i: Arc<dyn Transport + Send + Sync>,
+        (e_in, e_out): (u8, u8),
+        chip: &'static Chip,
+        mode: Mode,
+        packet_size: usize,
+    ) -> Self {
+        debug!(
+            "Session with {} in {mode} mode, endpoints {e_in:#04x} and {e_out:#04x}{}",
+            chip.name,
+            if READ_ONLY.get() { ", read-only" } else { "" }
+        );
+        Self {
+            i,
+            e_in,
+            e_out,
+            chip,
+            mode,
+            packet_size,
+            storage: Mutex::new(None),
+            tag: AtomicU32::new(FIRST_TAG),
+            swapped: AtomicBool::new(false),
+            read_only: READ_ONLY.get(),
+            retry: retry::policy().clone(),
+            lba_chunk: AtomicU32::new(LBA_CHUNK_SECTORS),
+            failures: AtomicU32::new(0),
+            failures_total: AtomicU32::new(0),
+            downshifts: AtomicU32::new(0),
+            port: None,
+            _lock: None,
+        }
+    }
+
+    /// Hold a lock on the device as long as the session is open
+    pub fn holding(self, lock: lock::Guard) -> Self {
+        Self {
+            _lock: Some(lock),
+            ..self
+        }
+    }
+
+    /// The same session for the board at `port`, starting at the transfer
+    /// size an earlier session with it was downshifted to
+    pub fn on_port(self, port: &str) -> Self {
+        if let Some(n) = DOWNSHIFTED.lock().unwrap().get(port) {
+            debug!("{port} was downshifted to {n} sectors per LBA command before");
+            self.lba_chunk.store(*n, Ordering::Relaxed);
+        }
+        Self {
+            port: Some(port.into()),
+            ..self
+        }
+    }
+
+    /// The same session with its own retry policy instead of the process's
+    pub fn retrying(self, retry: retry::Policy) -> Self {
+        Self { retry, ..self }
+    }
+
+    /// The same session with a chip told apart from others with its
+    /// product ID
+    pub fn with_chip(self, chip: &'static Chip) -> Self {
+        Self { chip, ..self }
+    }
+
+    /// Release the device, e.g. before it re-enumerates
+    pub fn close(self) {
+        debug!(
+            "Session with {} closed after tag {:#x}",
+            self.chip.name,
+            self.tag()
+        );
+    }
+
+    pub fn transport(&self) -> &dyn Transport {
+        self.i.as_ref()
+    }
+
+    /// Tag for the next request
+    pub fn next_tag(&self) -> u32 {
+        self.tag.fetch_add(1, Ordering::Relaxed).wrapping_add(1)
+    }
+
+    /// Tag the response to the last request carries
+    pub fn tag(&self) -> u32 {
+        self.tag.load(Ordering::Relaxed)
+    }
+
+    pub fn storage(&self) -> Option<Storage> {
+        *self.storage.lock().unwrap()
+    }
+
+    pub fn set_storage(&self, s: Storage) {
+        *self.storage.lock().unwrap() = Some(s);
+    }
+
+    pub fn swapped(&self) -> bool {
+        self.swapped.load(Ordering::Relaxed)
+    }
+
+    pub fn set_swapped(&self) {
+        self.swapped.store(true, Ordering::Relaxed);
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Refuse the command if the session is read-only; `what` would change
+    /// the device
+    pub fn allow(&self, what: &str) -> Result<(), Failure> {
+        if self.read_only {
+            return Err(Failure::Config(format!(
+                "Refusing to {what}: the session is read-only"
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn retry_policy(&self) -> &retry::Policy {
+        &self.retry
+    }
+
+    pub fn lba_chunk(&self) -> u32 {
+        self.lba_chunk.load(Ordering::Relaxed)
+    }
+
+    /// Sectors to move per LBA command, e.g. as the loader's capabilities
+    /// allow; no more than the board was downshifted to before
+    pub fn set_lba_chunk(&self, sectors: u32) {
+        let mut n = sectors.clamp(1, MAX_LBA_CHUNK_SECTORS);
+        if let Some(p) = &self.port
+            && let Some(d) = DOWNSHIFTED.lock().unwrap().get(p)
+        {
+            n = n.min(*d);
+        }
+        info!("Transfer {n} sectors per LBA command");
+        self.lba_chunk.store(n, Ordering::Relaxed);
+    }
+
+    /// Transfer and verify failures, and downshifts of the transfer size,
+    /// in this session
+    pub fn integrity(&self) -> (u32, u32) {
+        (
+            self.failures_total.load(Ordering::Relaxed),
+            self.downshifts.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Count a failed transfer, halving the transfer size once enough have
+    /// piled up; returns whether it downshifted
+    pub fn transfer_failed(&self) -> bool {
+        self.failures_total.fetch_add(1, Ordering::Relaxed);
+        if self.failures.fetch_add(1, Ordering::Relaxed) + 1 < DOWNSHIFT_AFTER {
+            return false;
+        }
+        let cur = self.lba_chunk();
+        if cur <= MIN_LBA_CHUNK_SECTORS {
+            return false;
+        }
+        let n = (cur / 2).max(MIN_LBA_CHUNK_SECTORS);
+        let failed = self.failures.swap(0, Ordering::Relaxed);
+        warn!("{failed} failed transfers, downshifting from {cur} to {n} sectors per LBA command");
+        self.lba_chunk.store(n, Ordering::Relaxed);
+        if let Some(p) = &self.port {
+            DOWNSHIFTED.lock().unwrap().insert(p.clone(), n);
+        }
+        self.downshifts.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+}
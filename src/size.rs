@@ -0,0 +1,168 @@
+//! Sizes and offsets on the command line, and sizes in messages
+//!
+//! Numbers are decimal or `0x` hex. Decimal ones may carry a unit: `s` for
+//! 512 byte sectors, `K`, `M`, `G` and `T` with or without `iB` for powers
+//! of 1024, `KB`, `MB`, `GB` and `TB` for powers of 1000, or `B`. Where a
+//! command takes sectors, a plain number is a sector count and one with a
+//! unit is converted. Storage offsets can also be relative to a partition,
+//! as `NAME` or `NAME+OFFSET`.
+
+use std::str::FromStr;
+
+use crate::gpt;
+use crate::protocol::SECTOR_SIZE;
+
+const UNITS: [(&str, u64); 14] = [
+    ("B", 1),
+    ("s", SECTOR_SIZE as u64),
+    ("K", 1 << 10),
+    ("KiB", 1 << 10),
+    ("M", 1 << 20),
+    ("MiB", 1 << 20),
+    ("G", 1 << 30),
+    ("GiB", 1 << 30),
+    ("T", 1 << 40),
+    ("TiB", 1 << 40),
+    ("KB", 1_000),
+    ("MB", 1_000_000),
+    ("GB", 1_000_000_000),
+    ("TB", 1_000_000_000_000),
+];
+
+/// The number and its unit's multiplier, if it has a unit
+fn split(s: &str) -> Result<(u64, Option<u64>), String> {
+    let s = s.trim();
+    if let Some(h) = s.strip_prefix("0x").or(s.strip_prefix("0X")) {
+        let n = u64::from_str_radix(h, 16).map_err(|_| format!("bad number {s:?}"))?;
+        return Ok((n, None));
+    }
+    let at = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(at);
+    let n: u64 = num.parse().map_err(|_| format!("bad number {s:?}"))?;
+    if unit.is_empty() {
+        return Ok((n, None));
+    }
+    let (_, m) = UNITS
+        .iter()
+        .find(|(u, _)| *u == unit.trim())
+        .ok_or(format!(
+            "unknown unit in {s:?}, use s, K, MiB, GB and the like"
+        ))?;
+    Ok((n, Some(*m)))
+}
+
+fn mul(s: &str, n: u64, m: u64) -> Result<u64, String> {
+    n.checked_mul(m).ok_or(format!("{s:?} is too large"))
+}
+
+/// A size or offset in bytes; a plain number is bytes
+pub fn bytes(s: &str) -> Result<u64, String> {
+    let (n, m) = split(s)?;
+    mul(s, n, m.unwrap_or(1))
+}
+
+pub fn bytes_u32(s: &str) -> Result<u32, String> {
+    u32::try_from(bytes(s)?).map_err(|_| format!("{s:?} does not fit into 32 bits"))
+}
+
+pub fn bytes_usize(s: &str) -> Result<usize, String> {
+    usize::try_from(bytes(s)?).map_err(|_| format!("{s:?} is too large"))
+}
+
+/// A count or position in sectors; a plain number is sectors
+pub fn sectors(s: &str) -> Result<u64, String> {
+    match split(s)? {
+        (n, None) => Ok(n),
+        (n, Some(m)) => {
+            let b = mul(s, n, m)?;
+            if !b.is_multiple_of(SECTOR_SIZE as u64) {
+                return Err(format!("{s:?} is not a whole number of sectors"));
+            }
+            Ok(b / SECTOR_SIZE as u64)
+        }
+    }
+}
+
+/// A storage position in sectors, absolute or from a partition's start
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Lba {
+    Abs(u64),
+    Part(String, u64),
+}
+
+impl FromStr for Lba {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with(|c: char| c.is_ascii_digit()) {
+            return sectors(s).map(Self::Abs);
+        }
+        let (name, offset) = s.split_once('+').unwrap_or((s, "0"));
+        Ok(Self::Part(name.to_string(), sectors(offset)?))
+    }
+}
+
+impl Lba {
+    /// The absolute sector, looking partitions up in `g`
+    pub fn resolve(&self, g: &gpt::Gpt) -> Result<u64, String> {
+        let (name, offset) = match self {
+            Self::Abs(lba) => return Ok(*lba),
+            Self::Part(name, offset) => (name, *offset),
+        };
+        let p = g.find(name).ok_or(format!("No partition {name}"))?;
+        if offset >= p.sectors() {
+            return Err(format!(
+                "Offset of {offset} sectors is beyond {name}, which has {}",
+                p.sectors()
+            ));
+        }
+        Ok(p.first_lba + offset)
+    }
+}
+
+/// Bytes in the largest binary unit that keeps a digit before the point,
+/// e.g. `512 B`, `4 KiB` or `1.5 GiB`
+pub fn human(bytes: u64) -> String {
+    const NAMES: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut unit = 0;
+    while unit + 1 < NAMES.len() && bytes >= 1 << (10 * (unit + 1)) {
+        unit += 1;
+    }
+    let v = bytes as f64 / (1_u64 << (10 * unit)) as f64;
+    let s = format!("{v:.1}");
+    let s = s.strip_suffix(".0").unwrap_or(&s);
+    format!("{s} {}", NAMES[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_with_units_and_partitions() {
+        assert_eq!(bytes("4K"), Ok(4096));
+        assert_eq!(bytes("16MiB"), Ok(16 << 20));
+        assert_eq!(bytes("2GB"), Ok(2_000_000_000));
+        assert_eq!(bytes("0x2000"), Ok(0x2000));
+        assert_eq!(bytes("8s"), Ok(4096));
+        assert!(bytes("4Q").is_err());
+        assert!(bytes_u32("4GiB").is_err());
+        assert_eq!(sectors("2048"), Ok(2048));
+        assert_eq!(sectors("1MiB"), Ok(2048));
+        assert!(sectors("1000B").is_err());
+
+        let mut g = gpt::Gpt::empty(40000).unwrap();
+        let t = gpt::parse_guid("linux").unwrap();
+        g.add("boot", t, Some(16384), Some(4096), 1).unwrap();
+        let lba = |s: &str| s.parse::<Lba>().unwrap();
+        assert_eq!(lba("0x40").resolve(&g), Ok(0x40));
+        assert_eq!(lba("boot").resolve(&g), Ok(16384));
+        assert_eq!(lba("boot+1MiB").resolve(&g), Ok(16384 + 2048));
+        assert!(lba("boot+2MiB").resolve(&g).is_err());
+        assert!(lba("rootfs").resolve(&g).is_err());
+
+        assert_eq!(human(512), "512 B");
+        assert_eq!(human(4096), "4 KiB");
+        assert_eq!(human(3 << 29), "1.5 GiB");
+    }
+}
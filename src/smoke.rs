@@ -0,0 +1,150 @@
+//! Check that a board boots after flashing
+//!
+//! Either watch a serial console for a line that only a good boot prints,
+//! or wait for the booted system to show up as a USB gadget. The console is
+//! opened before the reset, so nothing early is missed.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use clap::Args;
+use log::{debug, info};
+
+const POLL_PERIOD: Duration = Duration::from_millis(250);
+
+/// USB vendor and product ID like `1d6b:0104`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UsbId {
+    pub vid: u16,
+    pub pid: u16,
+}
+
+impl std::str::FromStr for UsbId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (v, p) = s.split_once(':').ok_or("expected VID:PID")?;
+        let hex = |x: &str| u16::from_str_radix(x, 16).map_err(|_| format!("bad ID {x:?}"));
+        Ok(Self {
+            vid: hex(v)?,
+            pid: hex(p)?,
+        })
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct Options {
+    /// After the reset, watch this serial console, e.g. /dev/ttyUSB0 or COM3
+    #[clap(long, requires = "expect")]
+    pub serial: Option<PathBuf>,
+    #[clap(long, default_value = "1500000")]
+    pub baud: u32,
+    /// Text on the console that means the board booted, e.g. "login:"
+    #[clap(long, requires = "serial")]
+    pub expect: Option<String>,
+    /// After the reset, wait for a USB device with this VID:PID
+    #[clap(long, conflicts_with = "serial")]
+    pub wait_usb: Option<UsbId>,
+    /// Seconds to wait for the board to boot
+    #[clap(long, default_value = "60")]
+    pub boot_timeout: u64,
+}
+
+impl Options {
+    pub fn is_set(&self) -> bool {
+        self.serial.is_some() || self.wait_usb.is_some()
+    }
+}
+
+pub enum Watch {
+    Serial { port: File, expect: String },
+    Usb(UsbId),
+}
+
+/// Put the port into raw mode, with reads returning after half a second
+fn configure(port: &std::path::Path, baud: u32) -> Result<(), String> {
+    let p = port.to_string_lossy();
+    let (tool, args) = if cfg!(target_os = "windows") {
+        let b = format!("BAUD={baud}");
+        let to = "TO=ON".to_string();
+        (
+            "mode",
+            vec![p.to_string(), b, "DATA=8".into(), "PARITY=N".into(), to],
+        )
+    } else {
+        let args = ["raw", "-echo", "min", "0", "time", "5"].map(String::from);
+        let mut a = vec!["-F".to_string(), p.to_string(), baud.to_string()];
+        a.extend(args);
+        ("stty", a)
+    };
+    let out = std::process::Command::new(tool)
+        .args(&args)
+        .output()
+        .map_err(|e| format!("cannot run {tool}: {e}"))?;
+    if !out.status.success() {
+        let err = String::from_utf8_lossy(&out.stderr);
+        return Err(format!("{tool} {}: {}", args.join(" "), err.trim()));
+    }
+    Ok(())
+}
+
+/// Get ready to watch the boot, before resetting
+pub fn prepare(opts: &Options) -> Result<Option<Watch>, String> {
+    if let (Some(port), Some(expect)) = (&opts.serial, &opts.expect) {
+        configure(port, opts.baud)?;
+        let f = File::open(port).map_err(|e| format!("{}: {e}", port.display()))?;
+        return Ok(Some(Watch::Serial {
+            port: f,
+            expect: expect.clone(),
+        }));
+    }
+    Ok(opts.wait_usb.map(Watch::Usb))
+}
+
+impl Watch {
+    pub fn wait(self, timeout: Duration) -> Result<(), String> {
+        let start = Instant::now();
+        match self {
+            Watch::Serial { mut port, expect } => {
+                info!("Waiting for {expect:?} on the console");
+                let mut seen = Vec::new();
+                let mut buf = [0; 4096];
+                while start.elapsed() < timeout {
+                    let n = port.read(&mut buf).map_err(|e| e.to_string())?;
+                    if n == 0 {
+                        sleep(POLL_PERIOD);
+                        continue;
+                    }
+                    debug!("console: {}", String::from_utf8_lossy(&buf[..n]));
+                    seen.extend_from_slice(&buf[..n]);
+                    if String::from_utf8_lossy(&seen).contains(&expect) {
+                        info!("Booted after {:.1?}", start.elapsed());
+                        return Ok(());
+                    }
+                    // Keep what a marker split across reads needs.
+                    let keep = seen.len().saturating_sub(expect.len());
+                    seen.drain(..keep);
+                }
+                Err(format!("No {expect:?} on the console within {timeout:?}"))
+            }
+            Watch::Usb(id) => {
+                info!("Waiting for USB device {:04x}:{:04x}", id.vid, id.pid);
+                while start.elapsed() < timeout {
+                    let mut devices = nusb::list_devices().map_err(|e| e.to_string())?;
+                    if devices.any(|d| d.vendor_id() == id.vid && d.product_id() == id.pid) {
+                        info!("Booted after {:.1?}", start.elapsed());
+                        return Ok(());
+                    }
+                    sleep(POLL_PERIOD);
+                }
+                Err(format!(
+                    "USB device {:04x}:{:04x} did not show up within {timeout:?}",
+                    id.vid, id.pid
+                ))
+            }
+        }
+    }
+}
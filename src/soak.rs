@@ -0,0 +1,100 @@
+//! Run a command again and again to qualify cables, hubs and boards
+//!
+//! Failures are counted by the classes of `metrics::Failure` and each run
+//! is timed, so that flaky setups show up as numbers rather than anecdotes.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use log::{error, info};
+
+use crate::metrics::{self, Failure};
+
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub runs: u32,
+    /// Failed runs by class
+    pub failed: BTreeMap<&'static str, u32>,
+    pub times: Vec<Duration>,
+}
+
+impl Stats {
+    pub fn failures(&self) -> u32 {
+        self.failed.values().sum()
+    }
+
+    pub fn report(&self) {
+        let classes: Vec<String> = self
+            .failed
+            .iter()
+            .map(|(c, n)| format!("{c} {n}"))
+            .collect();
+        let failed = match self.failures() {
+            0 => "none failed".to_string(),
+            n => format!("{n} failed ({})", classes.join(", ")),
+        };
+        info!("Ran {} times, {failed}", self.runs);
+        if let (Some(min), Some(max)) = (self.times.iter().min(), self.times.iter().max()) {
+            let avg = self.times.iter().sum::<Duration>() / self.times.len() as u32;
+            info!("Run time: min {min:.2?}, avg {avg:.2?}, max {max:.2?}");
+        }
+    }
+
+    pub fn result(&self) -> Result<(), Failure> {
+        match self.failures() {
+            0 => Ok(()),
+            n => Err(format!("{n} of {} runs failed", self.runs).into()),
+        }
+    }
+}
+
+/// Run `f` `times` times, or until it fails; without a count, only a
+/// failure ends it
+pub fn repeat(
+    times: Option<u32>,
+    until_failure: bool,
+    mut f: impl FnMut() -> Result<(), Failure>,
+) -> Stats {
+    let mut s = Stats::default();
+    while times.is_none_or(|n| s.runs < n) {
+        let start = Instant::now();
+        let res = f();
+        s.times.push(start.elapsed());
+        s.runs += 1;
+        if let Err(e) = res {
+            error!("Run {} failed: {e}", s.runs);
+            *s.failed.entry(metrics::category(&e)).or_default() += 1;
+            if until_failure {
+                break;
+            }
+        }
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeat_counts_failures_by_class() {
+        let mut n = 0;
+        let mut flaky = || {
+            n += 1;
+            match n % 4 {
+                0 => Err(Failure::Timeout("read timed out".into())),
+                2 => Err(Failure::Verify("CRC mismatch".into())),
+                _ => Ok(()),
+            }
+        };
+        let s = repeat(Some(8), false, &mut flaky);
+        assert_eq!((s.runs, s.failures(), s.times.len()), (8, 4, 8));
+        assert_eq!(s.failed.get("timeout"), Some(&2));
+        assert_eq!(s.failed.get("verify"), Some(&2));
+        assert_eq!(s.result(), Err("4 of 8 runs failed".into()));
+
+        let s = repeat(None, true, &mut flaky);
+        assert_eq!((s.runs, s.failures()), (2, 1));
+        assert!(repeat(Some(3), true, || Ok(())).result().is_ok());
+    }
+}
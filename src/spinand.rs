@@ -0,0 +1,76 @@
+//! SPI NAND flash through the loader, with raw block addressing
+//!
+//! Images are written block by block, skipping blocks marked bad, as
+//! U-Boot's and Linux' MTD tools do; readers of the image then skip the
+//! same blocks. Running out of good blocks before the end of the area the
+//! image is meant for is an error rather than overwriting what follows.
+
+use log::{info, warn};
+
+use crate::metrics::Failure;
+use crate::protocol::{self, SECTOR_SIZE};
+use crate::session::Session;
+
+pub fn bad_blocks(s: &Session, first: u32, count: u32) -> Result<Vec<u32>, Failure> {
+    let mut bad = Vec::new();
+    let mut b = first;
+    while b < first + count {
+        let n = (first + count - b).min(protocol::BAD_BLOCK_QUERY);
+        let map = protocol::test_bad_blocks(s, b, n)?;
+        bad.extend((0..n).filter(|&k| map[k as usize]).map(|k| b + k));
+        b += n;
+    }
+    Ok(bad)
+}
+
+/// Physical blocks for `needed` image blocks, starting at `first` and not
+/// reaching `end`, skipping bad ones
+fn place(first: u32, end: u32, needed: u32, bad: &[u32]) -> Result<Vec<u32>, String> {
+    let good: Vec<u32> = (first..end)
+        .filter(|b| !bad.contains(b))
+        .take(needed as usize)
+        .collect();
+    if good.len() < needed as usize {
+        return Err(format!(
+            "Image needs {needed} blocks, only {} good ones in blocks {first}..{end}",
+            good.len()
+        ));
+    }
+    Ok(good)
+}
+
+/// Write an image at a block-aligned offset; `limit` is the size of the
+/// area reserved for it in bytes
+pub fn write(s: &Session, offset: u64, data: &[u8], limit: u64) -> Result<(), Failure> {
+    let fi = protocol::flash_info(s)?;
+    let block_sectors = fi.block_sectors as u32;
+    if block_sectors == 0 {
+        return Err("Loader reports no block size".into());
+    }
+    let block = block_sectors as u64 * SECTOR_SIZE as u64;
+    if !offset.is_multiple_of(block) {
+        return Err(format!("Offset {offset:#x} is not aligned to {block} byte blocks").into());
+    }
+    let chip_end = fi.sectors as u64 * SECTOR_SIZE as u64;
+    let end = offset.saturating_add(limit).min(chip_end);
+    if end <= offset {
+        return Err(format!("Offset {offset:#x} is beyond the end of the chip").into());
+    }
+    let (first, last) = ((offset / block) as u32, (end / block) as u32);
+
+    let bad = bad_blocks(s, first, last - first)?;
+    if !bad.is_empty() {
+        warn!("Bad blocks in the area: {bad:?}");
+    }
+    let needed = data.len().div_ceil(block as usize) as u32;
+    let blocks = place(first, last, needed, &bad)?;
+
+    for (chunk, b) in data.chunks(block as usize).zip(&blocks) {
+        let lba = b * block_sectors;
+        protocol::erase_lba(s, lba, block_sectors)?;
+        protocol::write_lba(s, lba, chunk)?;
+    }
+    let skipped = blocks.last().map_or(0, |l| l + 1 - first) - needed;
+    info!("Wrote {needed} blocks of {block} bytes at {offset:#x}, skipped {skipped} bad ones");
+    Ok(())
+}
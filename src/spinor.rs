@@ -0,0 +1,105 @@
+//! SPI NOR flash through the loader
+//!
+//! Writing NOR can only clear bits; setting any bit again needs the whole
+//! 4 KiB erase sector around it erased first. Writes therefore read back
+//! what is there, leave sectors alone that already hold the data, program
+//! without erasing where only bits are cleared, and erase the rest, keeping
+//! the bytes around the written range.
+
+use log::{debug, info};
+
+use crate::flash::check_capacity;
+use crate::metrics::Failure;
+use crate::protocol::{self, SECTOR_SIZE};
+use crate::session::Session;
+use crate::size;
+
+pub const ERASE_SECTOR: u64 = 4096;
+const LBAS: u32 = (ERASE_SECTOR / SECTOR_SIZE as u64) as u32;
+// Compare and write in windows of 64 KiB, the usual NOR block size.
+const WINDOW: u64 = 16 * ERASE_SECTOR;
+
+fn lba(offset: u64) -> u32 {
+    (offset / SECTOR_SIZE as u64) as u32
+}
+
+pub fn read(s: &Session, offset: u64, len: u64) -> Result<Vec<u8>, Failure> {
+    let first = offset / SECTOR_SIZE as u64;
+    let end = (offset + len).div_ceil(SECTOR_SIZE as u64);
+    let d = protocol::read_lba(s, first as u32, (end - first) as u32)?;
+    let skip = (offset % SECTOR_SIZE as u64) as usize;
+    Ok(d[skip..skip + len as usize].to_vec())
+}
+
+pub fn erase(s: &Session, offset: u64, len: u64) -> Result<(), Failure> {
+    if !offset.is_multiple_of(ERASE_SECTOR) || !len.is_multiple_of(ERASE_SECTOR) {
+        return Err(format!(
+            "Offset {offset:#x} and length {len:#x} must be multiples of the {ERASE_SECTOR} byte erase sector"
+        )
+        .into());
+    }
+    check_capacity(s, lba(offset) as u64, lba(len) as u64)?;
+    info!("Erase {} at {offset:#x}", size::human(len));
+    Ok(protocol::erase_lba(s, lba(offset), lba(len))?)
+}
+
+/// What a sector needs to get from `old` to `new`
+#[derive(Debug, PartialEq, Eq)]
+enum Action {
+    Keep,
+    Program,
+    EraseProgram,
+}
+
+fn action(old: &[u8], new: &[u8]) -> Action {
+    if old == new {
+        Action::Keep
+    } else if old.iter().zip(new).all(|(o, n)| n & !o == 0) {
+        Action::Program
+    } else {
+        Action::EraseProgram
+    }
+}
+
+pub fn write(s: &Session, offset: u64, data: &[u8]) -> Result<(), Failure> {
+    let end = offset + data.len() as u64;
+    let start = offset - offset % ERASE_SECTOR;
+    let sectors = lba(end.next_multiple_of(ERASE_SECTOR) - start) as u64;
+    check_capacity(s, lba(start) as u64, sectors)?;
+    let (mut kept, mut erased) = (0, 0);
+    let mut w = start;
+    while w < end {
+        let w_end = (w + WINDOW).min(end.next_multiple_of(ERASE_SECTOR));
+        let old = protocol::read_lba(s, lba(w), lba(w_end - w))?;
+        let mut new = old.clone();
+        let (from, to) = (offset.max(w), end.min(w_end));
+        new[(from - w) as usize..(to - w) as usize]
+            .copy_from_slice(&data[(from - offset) as usize..(to - offset) as usize]);
+
+        for (n, (o, d)) in old
+            .chunks(ERASE_SECTOR as usize)
+            .zip(new.chunks(ERASE_SECTOR as usize))
+            .enumerate()
+        {
+            let at = w + n as u64 * ERASE_SECTOR;
+            let a = action(o, d);
+            debug!("Sector {at:#x}: {a:?}");
+            match a {
+                Action::Keep => {
+                    kept += 1;
+                    continue;
+                }
+                Action::EraseProgram => {
+                    protocol::erase_lba(s, lba(at), LBAS)?;
+                    erased += 1;
+                }
+                Action::Program => {}
+            }
+            protocol::write_lba(s, lba(at), d)?;
+        }
+        w = w_end;
+    }
+    let total = (end.next_multiple_of(ERASE_SECTOR) - start) / ERASE_SECTOR;
+    info!("Wrote {total} sectors at {start:#x}: {kept} unchanged, {erased} erased");
+    Ok(())
+}
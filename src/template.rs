@@ -0,0 +1,92 @@
+//! Named GPT layouts for common board setups
+//!
+//! Partitions follow each other from 8 MiB on, after the boot area that
+//! the mask ROM reads the loader from. Sizes are fixed, a share of the
+//! disk, or the rest of it, so one layout fits any flash size.
+
+use clap::ValueEnum;
+
+use crate::gpt::{self, Gpt};
+use crate::protocol::SECTOR_SIZE;
+use crate::size;
+
+const MIB: u64 = 1024 * 1024 / SECTOR_SIZE as u64;
+const FIRST: u64 = 8 * MIB;
+
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Template {
+    /// Rockchip's Linux SDK: uboot, trust, boot and rootfs
+    RockchipUboot,
+    /// Android with A/B slots and dynamic partitions in super
+    AndroidAb,
+    /// U-Boot, an EFI system partition and a root file system
+    DebianSimple,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Size {
+    Mib(u64),
+    /// Percent of the disk, rounded down to whole MiB
+    Percent(u64),
+    Rest,
+}
+
+impl Template {
+    fn parts(self) -> &'static [(&'static str, Size, &'static str)] {
+        use Size::*;
+        match self {
+            Self::RockchipUboot => &[
+                ("uboot", Mib(4), "linux"),
+                ("trust", Mib(4), "linux"),
+                ("boot", Mib(112), "linux"),
+                ("rootfs", Rest, "linux"),
+            ],
+            Self::AndroidAb => &[
+                ("security", Mib(4), "linux"),
+                ("uboot_a", Mib(4), "linux"),
+                ("uboot_b", Mib(4), "linux"),
+                ("misc", Mib(4), "linux"),
+                ("dtbo_a", Mib(4), "linux"),
+                ("dtbo_b", Mib(4), "linux"),
+                ("vbmeta_a", Mib(1), "linux"),
+                ("vbmeta_b", Mib(1), "linux"),
+                ("boot_a", Mib(64), "linux"),
+                ("boot_b", Mib(64), "linux"),
+                ("metadata", Mib(16), "linux"),
+                ("super", Percent(40), "linux"),
+                ("userdata", Rest, "linux"),
+            ],
+            Self::DebianSimple => &[
+                ("uboot", Mib(8), "linux"),
+                ("efi", Mib(512), "efi"),
+                ("rootfs", Rest, "linux"),
+            ],
+        }
+    }
+}
+
+/// The layout for a disk of `total` sectors
+pub fn build(t: Template, total: u64) -> Result<Gpt, String> {
+    let mut g = Gpt::empty(total)?;
+    let mut first = FIRST;
+    for &(name, size, type_name) in t.parts() {
+        let sectors = match size {
+            Size::Mib(n) => Some(n * MIB),
+            Size::Percent(p) => Some(total * p / 100 / MIB * MIB),
+            Size::Rest => None,
+        };
+        let end = first + sectors.unwrap_or(MIB);
+        if end > g.header.last_usable_lba {
+            return Err(format!(
+                "{} of storage is too small for {}, {name} ends beyond it",
+                size::human(total * SECTOR_SIZE as u64),
+                t.to_possible_value()
+                    .expect("no values are skipped")
+                    .get_name()
+            ));
+        }
+        g.add(name, gpt::parse_guid(type_name)?, Some(first), sectors, 1)?;
+        first += sectors.unwrap_or(0);
+    }
+    Ok(g)
+}
@@ -0,0 +1,148 @@
+//! Time-stamped trace of USB transfers, for performance analysis
+//!
+//! With `--perf-trace FILE`, each transfer is written as a begin event at
+//! submission and an end event at completion, and each retry as an instant
+//! event, in the JSON array format of the Trace Event Format. The file
+//! opens in chrome://tracing or Perfetto. Events are written as they
+//! happen, each followed by a comma, which the format allows to leave
+//! unterminated, so that the trace of a run that crashed opens as well.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::json::{self, Value};
+use crate::protocol::Transport;
+
+struct Trace {
+    out: Mutex<BufWriter<File>>,
+    start: Instant,
+}
+
+static TRACE: OnceLock<Trace> = OnceLock::new();
+static THREADS: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    /// Threads by number, for lanes in the viewer
+    static TID: u64 = THREADS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Trace to `path` from now on
+pub fn open(path: &Path) -> Result<(), String> {
+    let err = |e: io::Error| format!("{}: {e}", path.display());
+    let mut out = BufWriter::new(File::create(path).map_err(err)?);
+    out.write_all(b"[\n").map_err(err)?;
+    let _ = TRACE.set(Trace {
+        out: Mutex::new(out),
+        start: Instant::now(),
+    });
+    Ok(())
+}
+
+pub fn enabled() -> bool {
+    TRACE.get().is_some()
+}
+
+fn event(ph: &str, name: &str, args: Vec<(&str, Value)>) {
+    let Some(t) = TRACE.get() else {
+        return;
+    };
+    let ts = t.start.elapsed().as_nanos() as f64 / 1000.0;
+    let e = json::obj([
+        ("name", name.into()),
+        ("cat", "usb".into()),
+        ("ph", ph.into()),
+        ("ts", ts.into()),
+        ("pid", std::process::id().into()),
+        ("tid", TID.with(|t| *t).into()),
+        (
+            "args",
+            Value::Obj(args.into_iter().map(|(k, v)| (k.into(), v)).collect()),
+        ),
+    ]);
+    let mut out = t.out.lock().unwrap();
+    // A trace that cannot be written is not worth failing the command.
+    let _ = writeln!(out, "{e},").and_then(|()| out.flush());
+}
+
+/// A retry of `what`, attempt `attempt` having failed with `error`
+pub fn retry(what: &str, attempt: u32, error: &str) {
+    event(
+        "i",
+        "retry",
+        vec![
+            ("what", what.into()),
+            ("attempt", attempt.into()),
+            ("error", error.into()),
+        ],
+    );
+}
+
+/// A transfer from submission to completion
+fn traced<T>(
+    name: &str,
+    ep: u8,
+    len: usize,
+    moved: impl Fn(&T) -> usize,
+    f: impl FnOnce() -> io::Result<T>,
+) -> io::Result<T> {
+    if !enabled() {
+        return f();
+    }
+    event("B", name, vec![("ep", ep.into()), ("len", len.into())]);
+    let res = f();
+    let args = match &res {
+        Ok(t) => vec![("bytes", moved(t).into())],
+        Err(e) => vec![("error", e.to_string().into())],
+    };
+    event("E", name, args);
+    res
+}
+
+/// A transport whose transfers are traced
+pub struct Traced<T> {
+    inner: T,
+}
+
+impl<T: Transport> Traced<T> {
+    pub fn new(inner: T) -> Self {
+        Self { inner }
+    }
+}
+
+impl<T: Transport> Transport for Traced<T> {
+    fn bulk_out(&self, ep: u8, data: Vec<u8>, timeout: Duration) -> io::Result<usize> {
+        traced(
+            "bulk out",
+            ep,
+            data.len(),
+            |n| *n,
+            || self.inner.bulk_out(ep, data, timeout),
+        )
+    }
+
+    fn bulk_in(&self, ep: u8, size: usize, timeout: Duration) -> io::Result<Vec<u8>> {
+        traced("bulk in", ep, size, Vec::len, || {
+            self.inner.bulk_in(ep, size, timeout)
+        })
+    }
+
+    fn control_out(
+        &self,
+        request: u8,
+        index: u16,
+        data: &[u8],
+        timeout: Duration,
+    ) -> io::Result<usize> {
+        traced(
+            "control out",
+            request,
+            data.len(),
+            |n| *n,
+            || self.inner.control_out(request, index, data, timeout),
+        )
+    }
+}
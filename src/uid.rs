@@ -0,0 +1,35 @@
+//! The chip's unique ID, burned into OTP at the factory
+//!
+//! Where it is differs between chips, so the chip definition has to say;
+//! see `uid_offset` and `uid_size` in [`crate::chip`]. Provisioning data is
+//! keyed by the canonical form, upper case hex without separators.
+
+use log::debug;
+
+use crate::metrics::Failure;
+use crate::session::Session;
+use crate::{capability, protocol};
+
+/// Read the unique ID of the chip
+pub fn read(s: &Session) -> Result<Vec<u8>, Failure> {
+    let (offset, size) = s.chip.uid.ok_or(format!(
+        "Where {} keeps its unique ID in OTP is unknown; set uid_offset and uid_size in a chip definition",
+        s.chip.name
+    ))?;
+    // Loaders that announce reading OTP take the newer opcode.
+    let new = capability::read(s)?.is_ok_and(|c| c.names().contains(&"read OTP chip"));
+    debug!(
+        "Reading the unique ID by the {} opcode",
+        if new { "new" } else { "old" }
+    );
+    let d = protocol::read_otp(s, offset, size, new)?;
+    if d.iter().all(|&b| b == 0) || d.iter().all(|&b| b == 0xff) {
+        return Err(format!("OTP at {offset:#x} holds no unique ID, it reads {d:02x?}").into());
+    }
+    Ok(d)
+}
+
+/// The form provisioning data is keyed by
+pub fn canonical(uid: &[u8]) -> String {
+    uid.iter().map(|b| format!("{b:02X}")).collect()
+}
@@ -0,0 +1,141 @@
+//! Pass a device from Windows into WSL2 via usbipd-win
+//!
+//! Run from within WSL, this calls `usbipd.exe` through interop; run on
+//! Windows, it calls `usbipd` directly. Binding a device for sharing needs
+//! an administrator shell once per device.
+
+use std::process::Command;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+const ATTACH_TIMEOUT: Duration = Duration::from_secs(10);
+const POLL_PERIOD: Duration = Duration::from_millis(250);
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct UsbipdDevice {
+    pub busid: String,
+    pub vid: u16,
+    pub pid: u16,
+    pub description: String,
+    pub state: String,
+}
+
+fn usbipd() -> &'static str {
+    if cfg!(target_os = "windows") {
+        "usbipd"
+    } else {
+        "usbipd.exe"
+    }
+}
+
+fn run(args: &[&str]) -> Result<String, String> {
+    let out = Command::new(usbipd())
+        .args(args)
+        .output()
+        .map_err(|e| format!("cannot run {}: {e}; is usbipd-win installed?", usbipd()))?;
+    if !out.status.success() {
+        let err = String::from_utf8_lossy(&out.stderr);
+        return Err(format!("usbipd {}: {}", args.join(" "), err.trim()));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).to_string())
+}
+
+/// Parse the `Connected:` section of `usbipd list`, which looks like
+/// `2-3    2207:350b  USB download gadget    Not shared`
+pub fn parse_list(out: &str) -> Vec<UsbipdDevice> {
+    let mut devices = Vec::new();
+    let mut connected = false;
+    for l in out.lines() {
+        let t = l.trim();
+        if t.ends_with(':') && !t.contains(' ') {
+            connected = t == "Connected:";
+            continue;
+        }
+        let mut f = t.split_whitespace();
+        let (Some(busid), Some(id)) = (f.next(), f.next()) else {
+            continue;
+        };
+        let Some((vid, pid)) = id.split_once(':') else {
+            continue;
+        };
+        let (Ok(vid), Ok(pid)) = (u16::from_str_radix(vid, 16), u16::from_str_radix(pid, 16))
+        else {
+            continue;
+        };
+        if !connected {
+            continue;
+        }
+        let rest: Vec<&str> = f.collect();
+        let rest = rest.join(" ");
+        let (description, state) = ["Not shared", "Shared", "Attached"]
+            .iter()
+            .find_map(|s| rest.strip_suffix(s).map(|d| (d.trim(), *s)))
+            .unwrap_or((&rest, ""));
+        devices.push(UsbipdDevice {
+            busid: busid.to_string(),
+            vid,
+            pid,
+            description: description.to_string(),
+            state: state.to_string(),
+        });
+    }
+    devices
+}
+
+/// Share a Rockchip device with WSL and wait for it to show up there
+pub fn attach(busid: Option<&str>) -> Result<(), String> {
+    let all = parse_list(&run(&["list"])?);
+    let found: Vec<&UsbipdDevice> = all
+        .iter()
+        .filter(|d| d.vid == crate::USB_VID_RK)
+        .filter(|d| busid.is_none_or(|b| d.busid == b))
+        .collect();
+    let d = match found.as_slice() {
+        [] => return Err("No Rockchip device in `usbipd list`".into()),
+        [d] => *d,
+        many => {
+            let l: Vec<String> = many
+                .iter()
+                .map(|d| format!("{} ({:04x})", d.busid, d.pid))
+                .collect();
+            return Err(format!(
+                "Several Rockchip devices, pick one via --busid: {}",
+                l.join(", ")
+            ));
+        }
+    };
+    info!(
+        "{} {:04x}:{:04x} {}, {}",
+        d.busid, d.vid, d.pid, d.description, d.state
+    );
+
+    if d.state == "Attached" {
+        info!("Already attached");
+    } else {
+        if d.state == "Not shared" {
+            run(&["bind", "--busid", &d.busid]).map_err(|e| {
+                format!(
+                    "{e}\nRun `usbipd bind --busid {}` in an administrator shell once",
+                    d.busid
+                )
+            })?;
+        }
+        run(&["attach", "--wsl", "--busid", &d.busid])?;
+        info!("Attached {} to WSL", d.busid);
+    }
+
+    if cfg!(target_os = "windows") {
+        return Ok(());
+    }
+    let start = Instant::now();
+    while start.elapsed() < ATTACH_TIMEOUT {
+        if crate::rockchip_devices().any(|l| l.product_id() == d.pid) {
+            return Ok(());
+        }
+        sleep(POLL_PERIOD);
+    }
+    warn!("Device not visible in WSL yet, check `lsusb` and permissions");
+    Ok(())
+}
@@ -0,0 +1,256 @@
+//! Rockchip vendor storage on eMMC, which keeps small items such as the
+//! serial number and MAC addresses
+//!
+//! There are four copies of 64 KiB; each write goes to the next one with a
+//! higher version, so the newest intact copy is the current one. A copy
+//! starts with a header and an item table, followed by the item data.
+//! Dumps are JSON with each item's data as hex, so that serial numbers,
+//! MAC addresses and calibration data survive wiping the storage.
+
+use std::path::Path;
+
+use log::info;
+
+use crate::erase::VENDOR_STORAGE;
+use crate::json::{self, Value};
+use crate::metrics::Failure;
+use crate::{emmc, hexdump};
+
+/// Writes sectors at an LBA
+type Write<'a> = dyn FnMut(u32, &[u8]) -> Result<(), Failure> + 'a;
+
+const COPY_SECTORS: u32 = 128;
+const COPIES: u32 = 4;
+const COPY_SIZE: usize = COPY_SECTORS as usize * 512;
+const TAG: u32 = 0x524f4e56;
+const ITEM_TABLE: usize = 16;
+const ITEM_SIZE: usize = 8;
+const MAX_ITEMS: usize = 126;
+const DATA: usize = 1024;
+// Item data is kept 64 byte aligned; a hash and the version end each copy.
+const ALIGN: usize = 64;
+const DATA_END: usize = COPY_SIZE - 8;
+
+pub const SN_ID: u16 = 1;
+
+/// IDs that Rockchip's kernel driver and U-Boot define
+const NAMES: &[(u16, &str)] = &[
+    (SN_ID, "sn"),
+    (2, "wifi_mac"),
+    (3, "lan_mac"),
+    (4, "bt_mac"),
+    (5, "hdcp_14_hdmi"),
+    (6, "hdcp_14_dp"),
+    (7, "hdcp_2x"),
+    (8, "drm_key"),
+    (9, "playready_cert"),
+    (10, "attention_key"),
+    (11, "playready_root_key_0"),
+    (12, "playready_root_key_1"),
+    (13, "sensor_calibration"),
+    (15, "imei"),
+    (16, "lan_rgmii_dl"),
+    (17, "eink_vcom"),
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Item {
+    pub id: u16,
+    pub data: Vec<u8>,
+}
+
+fn get16(d: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(d[at..at + 2].try_into().unwrap())
+}
+
+fn get32(d: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(d[at..at + 4].try_into().unwrap())
+}
+
+/// Version and items of one copy, if it is intact
+fn parse(copy: &[u8]) -> Option<(u32, Vec<Item>)> {
+    if copy.len() != COPY_SIZE || get32(copy, 0) != TAG {
+        return None;
+    }
+    let version = get32(copy, 4);
+    if get32(copy, COPY_SIZE - 4) != version {
+        return None;
+    }
+    let count = (get16(copy, 10) as usize).min(MAX_ITEMS);
+    let items = (0..count)
+        .map(|n| {
+            let e = ITEM_TABLE + n * ITEM_SIZE;
+            let (offset, size) = (get16(copy, e + 2) as usize, get16(copy, e + 4) as usize);
+            let data = copy.get(DATA + offset..DATA + offset + size)?.to_vec();
+            Some(Item {
+                id: get16(copy, e),
+                data,
+            })
+        })
+        .collect::<Option<Vec<Item>>>()?;
+    Some((version, items))
+}
+
+/// One copy holding `items`
+fn build(version: u32, items: &[Item]) -> Result<Vec<u8>, String> {
+    if items.len() > MAX_ITEMS {
+        return Err(format!("{} items, at most {MAX_ITEMS} fit", items.len()));
+    }
+    let mut c = vec![0; COPY_SIZE];
+    let put16 = |c: &mut [u8], at: usize, v: usize| {
+        c[at..at + 2].copy_from_slice(&(v as u16).to_le_bytes());
+    };
+    c[..4].copy_from_slice(&TAG.to_le_bytes());
+    c[4..8].copy_from_slice(&version.to_le_bytes());
+    put16(&mut c, 8, items.len());
+    put16(&mut c, 10, items.len());
+    let mut offset = 0;
+    for (n, item) in items.iter().enumerate() {
+        let at = DATA + offset;
+        if at + item.data.len() > DATA_END {
+            return Err(format!("Items do not fit into {COPY_SIZE} bytes"));
+        }
+        let e = ITEM_TABLE + n * ITEM_SIZE;
+        put16(&mut c, e, item.id as usize);
+        put16(&mut c, e + 2, offset);
+        put16(&mut c, e + 4, item.data.len());
+        c[at..at + item.data.len()].copy_from_slice(&item.data);
+        offset += item.data.len().next_multiple_of(ALIGN);
+    }
+    put16(&mut c, 12, offset);
+    put16(&mut c, 14, (DATA_END - DATA).saturating_sub(offset));
+    c[COPY_SIZE - 4..].copy_from_slice(&version.to_le_bytes());
+    Ok(c)
+}
+
+/// Index and version of the newest intact copy, and its items
+fn newest(
+    read: &mut dyn FnMut(u32, u32) -> Result<Vec<u8>, Failure>,
+) -> Result<Option<(u32, u32, Vec<Item>)>, Failure> {
+    let mut found = None;
+    for n in 0..COPIES {
+        let d = read(VENDOR_STORAGE.start + n * COPY_SECTORS, COPY_SECTORS)?;
+        if let Some((version, items)) = parse(&d)
+            && found.as_ref().is_none_or(|(_, v, _)| version >= *v)
+        {
+            found = Some((n, version, items));
+        }
+    }
+    Ok(found)
+}
+
+/// Items of the newest intact copy
+pub fn read(
+    read: &mut dyn FnMut(u32, u32) -> Result<Vec<u8>, Failure>,
+) -> Result<Vec<Item>, Failure> {
+    newest(read)?
+        .map(|(_, _, items)| items)
+        .ok_or("No intact vendor storage".into())
+}
+
+/// Replace all items, writing the copy after the newest one with a higher
+/// version, so a torn write leaves the previous copy current
+pub fn write(
+    read: &mut dyn FnMut(u32, u32) -> Result<Vec<u8>, Failure>,
+    write: &mut Write,
+    items: &[Item],
+) -> Result<(), Failure> {
+    let (n, version) = newest(read)?.map_or((0, 1), |(n, v, _)| ((n + 1) % COPIES, v + 1));
+    let copy = build(version, items)?;
+    info!(
+        "Write {} vendor storage items as copy {n}, version {version}",
+        items.len()
+    );
+    write(VENDOR_STORAGE.start + n * COPY_SECTORS, &copy)
+}
+
+fn name(id: u16) -> Option<&'static str> {
+    NAMES.iter().find(|(i, _)| *i == id).map(|(_, n)| *n)
+}
+
+fn to_json(items: &[Item]) -> Value {
+    let items = items.iter().map(|it| {
+        let text = std::str::from_utf8(&it.data)
+            .ok()
+            .map(|t| t.trim_end_matches('\0'))
+            .filter(|t| !t.chars().any(char::is_control));
+        json::obj([
+            ("id", it.id.into()),
+            ("name", name(it.id).into()),
+            ("hex", hexdump::hex(&it.data).into()),
+            ("text", text.into()),
+        ])
+    });
+    json::obj([("items", Value::Arr(items.collect()))])
+}
+
+fn from_json(v: &Value) -> Result<Vec<Item>, String> {
+    let items = v
+        .get("items")
+        .and_then(Value::as_array)
+        .ok_or("expected an \"items\" array")?;
+    items
+        .iter()
+        .map(|it| {
+            let id = match it.get("id") {
+                Some(Value::Int(id)) => u16::try_from(*id).map_err(|_| format!("bad ID {id}"))?,
+                _ => return Err("item without a numeric \"id\"".to_string()),
+            };
+            let hex = it
+                .get("hex")
+                .and_then(Value::as_str)
+                .ok_or(format!("item {id} has no \"hex\" data"))?;
+            Ok(Item {
+                id,
+                data: emmc::parse_hex(hex)?,
+            })
+        })
+        .collect()
+}
+
+/// Save all items to a JSON file
+pub fn dump(
+    read_fn: &mut dyn FnMut(u32, u32) -> Result<Vec<u8>, Failure>,
+    output: &Path,
+) -> Result<(), Failure> {
+    let items = read(read_fn)?;
+    for it in &items {
+        info!(
+            "Item {} ({}): {} bytes",
+            it.id,
+            name(it.id).unwrap_or("?"),
+            it.data.len()
+        );
+    }
+    std::fs::write(output, format!("{}\n", to_json(&items)))
+        .map_err(|e| format!("{}: {e}", output.display()).into())
+}
+
+/// Write back all items from a JSON file made by `dump`
+pub fn restore(
+    read_fn: &mut dyn FnMut(u32, u32) -> Result<Vec<u8>, Failure>,
+    write_fn: &mut Write,
+    input: &Path,
+) -> Result<(), Failure> {
+    let text = std::fs::read_to_string(input).map_err(|e| format!("{}: {e}", input.display()))?;
+    let items = from_json(&json::parse(&text)?).map_err(|e| format!("{}: {e}", input.display()))?;
+    write(read_fn, write_fn, &items)?;
+    if read(read_fn)? != items {
+        return Err("Vendor storage does not read back as written".into());
+    }
+    Ok(())
+}
+
+/// The serial number, if vendor storage has one
+pub fn serial(
+    read_fn: &mut dyn FnMut(u32, u32) -> Result<Vec<u8>, Failure>,
+) -> Result<Option<String>, Failure> {
+    let items = newest(read_fn)?
+        .map(|(_, _, items)| items)
+        .unwrap_or_default();
+    Ok(items.iter().find(|i| i.id == SN_ID).map(|sn| {
+        String::from_utf8_lossy(&sn.data)
+            .trim_end_matches('\0')
+            .to_string()
+    }))
+}
@@ -0,0 +1,83 @@
+//! Waiting for a device to show up, for scripts that start before the
+//! board has been put into mask ROM or loader mode
+//!
+//! With `--wait`, finding the device is tried again until it succeeds, for
+//! as long as given or the deadline allows. Writes also wait for a device
+//! that disconnects to come back, and resume. Like the deadline, the
+//! setting belongs to the thread running the command.
+
+use std::cell::Cell;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use crate::deadline;
+
+const POLL_PERIOD: Duration = Duration::from_millis(250);
+
+thread_local! {
+    /// Whether to wait, and at most how long
+    static WAIT: Cell<Option<Option<Duration>>> = const { Cell::new(None) };
+}
+
+/// Wait for devices from now on, at most the inner duration if there is
+/// one; `None` does not wait
+pub fn set(wait: Option<Option<Duration>>) {
+    WAIT.set(wait);
+}
+
+pub fn enabled() -> bool {
+    WAIT.get().is_some()
+}
+
+/// Try `find` until it succeeds, if waiting; the error is the last one
+pub fn until<T>(mut find: impl FnMut() -> Result<T, String>) -> Result<T, String> {
+    let Some(limit) = WAIT.get() else {
+        return find();
+    };
+    let start = Instant::now();
+    let mut waiting = false;
+    loop {
+        let e = match find() {
+            Ok(t) => return Ok(t),
+            Err(e) => e,
+        };
+        let out = limit.is_some_and(|l| start.elapsed() >= l) || deadline::expired();
+        if out {
+            return Err(format!(
+                "{e}\nGave up waiting after {:.1}s",
+                start.elapsed().as_secs_f32()
+            ));
+        }
+        if !waiting {
+            info!("Waiting for the device: {}", e.lines().next().unwrap_or(""));
+            waiting = true;
+        }
+        sleep(POLL_PERIOD);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finding_devices_waits_if_told() {
+        let mut tries = 0;
+        let mut find = || {
+            tries += 1;
+            match tries {
+                3 => Ok(tries),
+                _ => Err("No Rockchip device found".to_string()),
+            }
+        };
+        assert!(until(&mut find).is_err());
+        set(Some(None));
+        assert_eq!(until(&mut find), Ok(3));
+        set(Some(Some(Duration::from_millis(300))));
+        let e = until(|| Err::<(), _>("gone".to_string())).unwrap_err();
+        assert!(e.starts_with("gone\nGave up waiting"), "{e}");
+        set(None);
+    }
+}
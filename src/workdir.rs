@@ -0,0 +1,190 @@
+//! Scratch space, for stations whose root file system is small
+//!
+//! Files that only live while a command runs go into a directory of this
+//! process below the work directory, `--workdir` or the system's temporary
+//! directory. Each scratch file is removed when dropped, the directory on
+//! exit. A process that crashed or was killed leaves its directory behind;
+//! the next one to start removes it, which it can tell by the lock on it
+//! being free.
+//!
+//! The directory is made anew, only accessible to the user running this
+//! process; one that is there already, be it left over from an earlier
+//! process with the same ID or put there by someone else, is not used.
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use log::{debug, warn};
+
+const PREFIX: &str = "rk_boot-";
+const LOCK: &str = ".lock";
+
+static BASE: OnceLock<PathBuf> = OnceLock::new();
+/// This process's directory, with the lock that marks it as in use
+static OWN: OnceLock<Result<(PathBuf, File), String>> = OnceLock::new();
+static FILES: AtomicU64 = AtomicU64::new(0);
+
+/// Use `dir` for scratch files and the image cache; only the first call counts
+pub fn set(dir: PathBuf) -> Result<(), String> {
+    std::fs::create_dir_all(&dir).map_err(|e| format!("{}: {e}", dir.display()))?;
+    let _ = BASE.set(dir);
+    Ok(())
+}
+
+/// The work directory
+pub fn base() -> &'static Path {
+    BASE.get_or_init(std::env::temp_dir)
+}
+
+fn own() -> Result<&'static Path, String> {
+    match OWN.get_or_init(create) {
+        Ok((d, _)) => Ok(d),
+        Err(e) => Err(e.clone()),
+    }
+}
+
+/// Make and lock this process's directory; once, whichever thread is first
+fn create() -> Result<(PathBuf, File), String> {
+    let d = base().join(format!("{PREFIX}{}", std::process::id()));
+    let err = |e: std::io::Error| format!("{}: {e}", d.display());
+    // Ours from an earlier process with the same ID, if it can be removed
+    if std::fs::symlink_metadata(&d).is_ok() {
+        std::fs::remove_dir_all(&d).map_err(err)?;
+    }
+    make_private(&d).map_err(err)?;
+    let f = File::create_new(d.join(LOCK)).map_err(err)?;
+    f.try_lock().map_err(|e| format!("{}: {e}", d.display()))?;
+    Ok((d, f))
+}
+
+/// Make a directory only its owner can get into, failing if it exists
+#[cfg(unix)]
+fn make_private(d: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+    std::fs::DirBuilder::new().mode(0o700).create(d)?;
+    let m = std::fs::symlink_metadata(d)?;
+    if !m.is_dir() || m.permissions().mode() & 0o077 != 0 {
+        return Err(std::io::Error::other("not a private directory"));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn make_private(d: &Path) -> std::io::Result<()> {
+    std::fs::create_dir(d)
+}
+
+/// A file that is removed when dropped
+#[derive(Debug)]
+pub struct Scratch(PathBuf);
+
+impl Scratch {
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Move the file to `dest`, also across file systems
+    pub fn keep(self, dest: &Path) -> Result<(), String> {
+        let err = |e: std::io::Error| format!("{}: {e}", dest.display());
+        if std::fs::rename(&self.0, dest).is_err() {
+            std::fs::copy(&self.0, dest).map_err(err)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// A new scratch file name, with `name` in it to tell what it is for
+pub fn file(name: &str) -> Result<Scratch, String> {
+    let n = FILES.fetch_add(1, Ordering::Relaxed);
+    Ok(Scratch(own()?.join(format!("{n}-{name}"))))
+}
+
+/// Remove what processes that are gone left behind
+pub fn sweep() {
+    let Ok(entries) = std::fs::read_dir(base()) else {
+        return;
+    };
+    let me = format!("{PREFIX}{}", std::process::id());
+    for e in entries.flatten() {
+        let name = e.file_name().to_string_lossy().to_string();
+        let Some(pid) = name.strip_prefix(PREFIX) else {
+            continue;
+        };
+        if name == me || pid.is_empty() || !pid.bytes().all(|c| c.is_ascii_digit()) {
+            continue;
+        }
+        let d = e.path();
+        // A directory without a lock file is still being set up.
+        let Ok(f) = File::open(d.join(LOCK)) else {
+            continue;
+        };
+        if f.try_lock().is_err() {
+            continue;
+        }
+        match std::fs::remove_dir_all(&d) {
+            Ok(()) => debug!("Removed {} left behind by PID {pid}", d.display()),
+            Err(e) => warn!("Cannot remove {}: {e}", d.display()),
+        }
+    }
+}
+
+/// Remove this process's scratch files, before it exits
+pub fn cleanup() {
+    if let Some(Ok((d, _))) = OWN.get() {
+        let _ = std::fs::remove_dir_all(d);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scratch_files_are_cleaned_up() {
+        let f = file("image").unwrap();
+        std::fs::write(f.path(), b"data").unwrap();
+        let p = f.path().to_path_buf();
+        drop(f);
+        assert!(!p.exists());
+
+        // Kept files move out of the scratch directory.
+        let f = file("image").unwrap();
+        std::fs::write(f.path(), b"data").unwrap();
+        let dest = base().join(format!("rk_boot-kept-{}", std::process::id()));
+        f.keep(&dest).unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), b"data");
+        std::fs::remove_file(&dest).unwrap();
+
+        // What a crashed process left is swept, unlike what is in use.
+        let dead = base().join("rk_boot-4294967295");
+        std::fs::create_dir_all(&dead).unwrap();
+        std::fs::write(dead.join(".lock"), b"").unwrap();
+        std::fs::write(dead.join("0-image"), b"data").unwrap();
+        sweep();
+        assert!(!dead.exists());
+        assert!(p.parent().unwrap().exists());
+
+        // Threads share the one directory, however they race for it.
+        let dirs: Vec<std::path::PathBuf> = (0..8)
+            .map(|_| std::thread::spawn(|| file("x").unwrap().path().to_path_buf()))
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|t| t.join().unwrap().parent().unwrap().to_path_buf())
+            .collect();
+        assert!(dirs.iter().all(|d| *d == dirs[0]));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&dirs[0]).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o700);
+        }
+    }
+}